@@ -7,7 +7,8 @@ use rtt_target::{rprintln, rtt_init_print};
 
 use cortex_m_rt::entry;
 
-use bluepet::storage::FlashStorage;
+use bluepet::storage;
+use pet::dos::listing::Directory;
 use pet::io::Storage;
 
 use stm32f1xx_hal::spi::{Mode, Phase, Polarity, Spi};
@@ -65,7 +66,7 @@ fn main() -> ! {
     let mut spi_flash = Flash::init(spi, cs).unwrap();
 
     spi_flash.erase_all().unwrap_or_default();
-    let mut file_storage = FlashStorage::new(spi_flash);
+    let mut file_storage = storage::mount(spi_flash).unwrap();
 
 
     let filename = "HELLO";
@@ -76,19 +77,19 @@ fn main() -> ! {
         file_storage.next_filename_byte(c as u8);
     }
 
-    file_storage.start_save();
+    file_storage.start_save().unwrap();
 
     let mut i = 0;
     for b in FILEDATA {
-        file_storage.save_data_byte(i, *b);
+        file_storage.save_data_byte(i, *b).unwrap();
         i += 1;
     }
-    file_storage.end_save();
+    file_storage.end_save().unwrap();
 
     rprintln!("done. writen file as {}", filename);
 
-    file_storage.ensure_directory();
-    for entry in &file_storage.directory {
+    let mut cursor = 0;
+    while let Ok(Some(entry)) = file_storage.next_entry(&mut cursor) {
         rprintln!("{:?}", entry);
     }
     loop {}
@@ -1,3 +1,89 @@
 #![no_std]
 
+#[cfg(test)]
+extern crate std;
+
 pub mod storage;
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use pet::io::{OpenName, Storage};
+    use spi_memory::prelude::*;
+    use spi_memory::series25::Flash;
+
+    use super::storage::{self, sim::SimulatedFlash, CAPACITY};
+
+    #[test]
+    fn simulated_flash_is_nor_flash() {
+        let chip = SimulatedFlash::new(CAPACITY as usize);
+        let mut flash = Flash::init(chip.spi(), chip.cs()).unwrap();
+        assert_eq!(flash.read_jedec_id().unwrap().mfr_code(), 0xc8);
+
+        flash.write_bytes(0x1000, &mut [0x0f, 0xf0]).unwrap();
+        flash.write_bytes(0x1000, &mut [0x3c, 0x3c]).unwrap();
+        let mut data = [0u8; 3];
+        flash.read(0x1000, &mut data).unwrap();
+        // programming only clears bits
+        assert_eq!(data, [0x0c, 0x30, 0xff]);
+
+        // the address wraps within the page
+        flash.write_bytes(0x10fe, &mut [1, 2, 3, 4]).unwrap();
+        flash.read(0x10fe, &mut data[..2]).unwrap();
+        assert_eq!(data[..2], [1, 2]);
+        flash.read(0x1000, &mut data[..2]).unwrap();
+        assert_eq!(data[..2], [0x0c & 3, 0x30 & 4]);
+
+        flash.erase_sectors(0x1000, 1).unwrap();
+        flash.read(0x1000, &mut data).unwrap();
+        assert_eq!(data, [0xff; 3]);
+        assert_eq!(chip.erases()[1], 1);
+
+        flash.write_bytes(0x2000, &mut [0]).unwrap();
+        flash.erase_all().unwrap();
+        assert!(chip.memory().iter().all(|&b| b == 0xff));
+    }
+
+    #[test]
+    fn flash_storage_works() {
+        let chip = SimulatedFlash::new(CAPACITY as usize);
+        let content: Vec<u8> = (0..10_000).map(|i| (i % 253) as u8).collect();
+        {
+            let flash = Flash::init(chip.spi(), chip.cs()).unwrap();
+            let mut storage = storage::mount(flash).unwrap();
+
+            storage.start_filename();
+            b"HELLO".iter().for_each(|&b| storage.next_filename_byte(b));
+            storage.fname_done();
+            storage.start_save().unwrap();
+            for (i, &b) in content.iter().enumerate() {
+                storage.save_data_byte(i, b).unwrap();
+            }
+            storage.end_save().unwrap();
+
+            storage.open(2, &OpenName::parse(b"DATA,S,W")).unwrap();
+            storage.write(2, 42).unwrap();
+            storage.close(2).unwrap();
+            storage.file_system().unwrap().rename(b"DATA", b"INFO").unwrap();
+        }
+
+        // everything is still there after a reset
+        let flash = Flash::init(chip.spi(), chip.cs()).unwrap();
+        let mut storage = storage::mount(flash).unwrap();
+        storage.start_filename();
+        b"HEL*".iter().for_each(|&b| storage.next_filename_byte(b));
+        storage.fname_done();
+        let len = storage.load_data_len().unwrap();
+        let loaded: Vec<u8> = (0..len).map(|i| storage.load_data_byte(i).unwrap()).collect();
+        assert_eq!(loaded, content);
+
+        storage.open(2, &OpenName::parse(b"INFO,S,R")).unwrap();
+        assert_eq!(storage.read(2), Some((42, true)));
+        assert_eq!(
+            storage.open(3, &OpenName::parse(b"DATA,S,R")),
+            Err(pet::dos::DosError::FileNotFound)
+        );
+        assert_eq!(chip.erases().iter().sum::<u32>(), 5);
+    }
+}
@@ -3,17 +3,19 @@
 #![feature(asm)]
 
 use panic_halt as _;
-use rtt_target::{rprintln, rtt_init_print};
+use rtt_target::{rprintln, rtt_init, set_print_channel};
 
 use cortex_m_rt::entry;
 use mos6502::Cpu;
+use pet::boot::{self, Autoboot};
+use pet::io::RetraceSource;
 use pet::Ram;
 
 mod keyboard;
 mod storage;
+mod transfer;
 mod video;
 
-use storage::FlashStorage;
 
 use stm32f1xx_hal::spi::{Mode, Phase, Polarity, Spi};
 use stm32f1xx_hal::{pac, prelude::*};
@@ -21,11 +23,38 @@ use stm32f1xx_hal::{pac, prelude::*};
 use embedded_hal::digital::v2::OutputPin;
 use embedded_hal::spi::MODE_0;
 
+#[cfg(not(feature = "sdcard"))]
 use spi_memory::series25::Flash;
 
 #[entry]
 fn main() -> ! {
-    rtt_init_print!();
+    // channel 0 is the terminal, channel 1 carries file transfers
+    let channels = rtt_init! {
+        up: {
+            0: {
+                size: 1024
+                mode: NoBlockSkip
+                name: "Terminal"
+            }
+            1: {
+                size: 1024
+                mode: BlockIfFull
+                name: "Transfer"
+            }
+        }
+        down: {
+            0: {
+                size: 64
+                name: "Terminal"
+            }
+            1: {
+                size: 1024
+                name: "Transfer"
+            }
+        }
+    };
+    set_print_channel(channels.up.0);
+    let mut terminal = transfer::Terminal::new(channels.down.0, channels.up.1, channels.down.1);
 
     // Get access to the core peripherals from the cortex-m crate
     let mut cp = cortex_m::Peripherals::take().unwrap();
@@ -90,20 +119,45 @@ fn main() -> ! {
         gpiob.pb15.into_alternate_push_pull(&mut gpiob.crh),
     );
 
-    let spi = Spi::spi2(dp.SPI2, pins, MODE_0, 4.mhz(), clocks, &mut rcc.apb1);
-
     // for testing use the onboard led
     let mut gpioc = dp.GPIOC.split(&mut rcc.apb2);
     let mut led = gpioc.pc13.into_push_pull_output(&mut gpioc.crh);
 
-    let spi_flash = Flash::init(spi, cs).unwrap();
+    #[cfg(not(feature = "sdcard"))]
+    let mut file_storage = {
+        let spi = Spi::spi2(dp.SPI2, pins, MODE_0, 4.mhz(), clocks, &mut rcc.apb1);
+        let spi_flash = Flash::init(spi, cs).unwrap();
 
-    //spi_flash.erase_all();
-    let mut file_storage = FlashStorage::new(spi_flash);
+        //spi_flash.erase_all();
+        storage::mount(spi_flash).unwrap()
+    };
+
+    // the card shares the bus, the flash stays deselected
+    #[cfg(feature = "sdcard")]
+    let mut file_storage = {
+        let _flash_cs = cs;
+        let card_cs = {
+            let mut card_cs = gpiob.pb12.into_push_pull_output(&mut gpiob.crh);
+            card_cs.set_high().unwrap(); // deselect
+            card_cs
+        };
+
+        // the card starts up slowly
+        let spi = Spi::spi2(dp.SPI2, pins, MODE_0, 400.khz(), clocks, &mut rcc.apb1);
+        let card = storage::sdcard::SdCard::init(spi, card_cs).unwrap();
+        let card = card.map_spi(|spi| {
+            let (spi2, pins) = spi.release();
+            Spi::spi2(spi2, pins, MODE_0, 4.mhz(), clocks, &mut rcc.apb1)
+        });
+        storage::sdcard::mount(card).unwrap()
+    };
 
     // prepare video stuff
     video::init_video(&mut cp, dp.TIM4, dp.TIM1);
 
+    // a program named BOOT is loaded and run after power-up
+    let mut autoboot = Autoboot::find(&mut file_storage, boot::BOOT_NAME);
+
     // init the emulator stuff
     let mut ram = [0u8; 8192];
 
@@ -111,6 +165,9 @@ fn main() -> ! {
     let mut cpu = Cpu::new(&mem);
     cpu.reset();
 
+    // the system interrupt is driven by the vsync of the composite video
+    mem.io.borrow_mut().set_retrace_source(RetraceSource::External);
+
     // emulation
     let mut cycle_cnt: u64 = 0;
     let mut tick_cntr = 0u32;
@@ -125,6 +182,14 @@ fn main() -> ! {
     loop {
         if cycle_cnt == 0 {
             cycle_cnt = cpu.step();
+            let mut trigger_irq = mem.io.borrow_mut().step(cycle_cnt as u16);
+            // the retrace edges are signalled by the video interrupt when they happen
+            if let Some(retrace) = video::take_retrace_edge() {
+                trigger_irq |= mem.io.borrow_mut().set_retrace(retrace);
+            }
+            if trigger_irq {
+                cpu.trigger_irq();
+            }
             cycle_cnt -= 1;
         } else {
             cycle_cnt -= 1;
@@ -133,10 +198,12 @@ fn main() -> ! {
         tick_cntr += 1;
         if tick_cntr >= 1000 {
             tick_cntr = 0;
-            let trigger_irq = mem.io.borrow_mut().tick();
-            if trigger_irq {
+            if mem.io.borrow_mut().tick() {
                 cpu.trigger_irq();
             }
+
+            video::select_lowercase_charset(mem.io.borrow().lowercase_charset());
+            video::set_screen_blank(mem.io.borrow().screen_blanked());
         }
 
         keyboard_cnt += 1;
@@ -164,6 +231,14 @@ fn main() -> ! {
                 &pb4,
                 &mut (mem.io.borrow_mut().keyboard),
             );
+
+            if let Some(boot) = &mut autoboot {
+                if boot.step(&mem) {
+                    autoboot = None;
+                }
+            }
+
+            terminal.poll(mem.io.borrow_mut().ieee.drive_storage());
         }
     }
 }
@@ -0,0 +1,311 @@
+// An SD card in SPI mode, for the files of the PET in `pet::fatfs`
+//
+// The card has to be initialized with a clock of at most 400 kHz, afterwards it runs at
+// up to 25 MHz, see `SdCard::map_spi`. Cards up to 2 GB (SDSC) address bytes, larger
+// ones (SDHC/SDXC) sectors, the driver takes care of it.
+
+use embedded_hal::blocking::spi::Transfer;
+use embedded_hal::digital::v2::OutputPin;
+
+use pet::dos::DosError;
+use pet::fatfs::{FatFs, SectorDevice, SectorError, SECTOR_SIZE};
+
+const GO_IDLE_STATE: u8 = 0;
+const SEND_IF_COND: u8 = 8;
+const SEND_CSD: u8 = 9;
+const SET_BLOCKLEN: u8 = 16;
+const READ_SINGLE_BLOCK: u8 = 17;
+const WRITE_BLOCK: u8 = 24;
+const APP_CMD: u8 = 55;
+const READ_OCR: u8 = 58;
+const SD_SEND_OP_COND: u8 = 41;
+
+const R1_IDLE: u8 = 0x01;
+const R1_ILLEGAL_COMMAND: u8 = 0x04;
+
+const DATA_TOKEN: u8 = 0xfe;
+const DATA_ACCEPTED: u8 = 0x05;
+
+// the high capacity bit in SD_SEND_OP_COND and the OCR
+const HIGH_CAPACITY: u32 = 0x4000_0000;
+
+// bytes to wait for a response, a data token or the end of busy
+const RESPONSE_TRIES: usize = 16;
+const TOKEN_TRIES: usize = 20_000;
+const BUSY_TRIES: usize = 200_000;
+const INIT_TRIES: usize = 20_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SdError {
+    Spi,
+    // no card or it doesn't answer
+    NoResponse,
+    // the card answered with an error
+    Command(u8, u8),
+    Timeout,
+    Unsupported,
+}
+
+/// The card on an SPI bus with its own chip select
+pub struct SdCard<SPI: Transfer<u8>, CS: OutputPin> {
+    spi: SPI,
+    cs: CS,
+    sectors: u32,
+    // SDSC cards address bytes
+    block_addressing: bool,
+}
+
+impl<SPI: Transfer<u8>, CS: OutputPin> SdCard<SPI, CS> {
+    /// Wakes the card up, the SPI clock must be 400 kHz at most
+    pub fn init(spi: SPI, cs: CS) -> Result<SdCard<SPI, CS>, SdError> {
+        let mut card = SdCard {
+            spi,
+            cs,
+            sectors: 0,
+            block_addressing: false,
+        };
+
+        // at least 74 clocks with CS high
+        card.deselect()?;
+        for _ in 0..10 {
+            card.transfer(0xff)?;
+        }
+
+        card.select()?;
+        let result = card.start();
+        card.deselect()?;
+        result?;
+        Ok(card)
+    }
+
+    /// Replaces the SPI bus, e.g. by the same one with a faster clock
+    pub fn map_spi(self, f: impl FnOnce(SPI) -> SPI) -> SdCard<SPI, CS> {
+        SdCard {
+            spi: f(self.spi),
+            ..self
+        }
+    }
+
+    fn start(&mut self) -> Result<(), SdError> {
+        if self.command(GO_IDLE_STATE, 0)? != R1_IDLE {
+            return Err(SdError::NoResponse);
+        }
+
+        // only version 2 cards know SEND_IF_COND, they echo the check pattern
+        let version_2 = match self.command(SEND_IF_COND, 0x1aa)? {
+            r1 if r1 & R1_ILLEGAL_COMMAND != 0 => false,
+            R1_IDLE => {
+                let echo = self.read_u32()?;
+                if echo & 0xfff != 0x1aa {
+                    return Err(SdError::Unsupported);
+                }
+                true
+            }
+            r1 => return Err(SdError::Command(SEND_IF_COND, r1)),
+        };
+
+        let argument = if version_2 { HIGH_CAPACITY } else { 0 };
+        let mut ready = false;
+        for _ in 0..INIT_TRIES {
+            self.command(APP_CMD, 0)?;
+            match self.command(SD_SEND_OP_COND, argument)? {
+                0 => {
+                    ready = true;
+                    break;
+                }
+                R1_IDLE => {}
+                r1 => return Err(SdError::Command(SD_SEND_OP_COND, r1)),
+            }
+        }
+        if !ready {
+            return Err(SdError::Timeout);
+        }
+
+        if version_2 {
+            match self.command(READ_OCR, 0)? {
+                0 => self.block_addressing = self.read_u32()? & HIGH_CAPACITY != 0,
+                r1 => return Err(SdError::Command(READ_OCR, r1)),
+            }
+        }
+        if !self.block_addressing {
+            match self.command(SET_BLOCKLEN, SECTOR_SIZE as u32)? {
+                0 => {}
+                r1 => return Err(SdError::Command(SET_BLOCKLEN, r1)),
+            }
+        }
+
+        let mut csd = [0u8; 16];
+        match self.command(SEND_CSD, 0)? {
+            0 => self.read_data(&mut csd)?,
+            r1 => return Err(SdError::Command(SEND_CSD, r1)),
+        }
+        self.sectors = capacity(&csd).ok_or(SdError::Unsupported)?;
+        Ok(())
+    }
+
+    fn select(&mut self) -> Result<(), SdError> {
+        self.cs.set_low().map_err(|_| SdError::Spi)
+    }
+
+    // an extra byte lets the card release the data line
+    fn deselect(&mut self) -> Result<(), SdError> {
+        self.cs.set_high().map_err(|_| SdError::Spi)?;
+        self.transfer(0xff)?;
+        Ok(())
+    }
+
+    fn transfer(&mut self, value: u8) -> Result<u8, SdError> {
+        let mut data = [value];
+        self.spi.transfer(&mut data).map_err(|_| SdError::Spi)?;
+        Ok(data[0])
+    }
+
+    fn read_u32(&mut self) -> Result<u32, SdError> {
+        let mut value = 0;
+        for _ in 0..4 {
+            value = value << 8 | self.transfer(0xff)? as u32;
+        }
+        Ok(value)
+    }
+
+    // sends a command and returns its R1 response, the rest is left to the caller
+    fn command(&mut self, command: u8, argument: u32) -> Result<u8, SdError> {
+        self.wait_not_busy()?;
+
+        // only the first two commands are checked in SPI mode
+        let crc = match command {
+            GO_IDLE_STATE => 0x95,
+            SEND_IF_COND => 0x87,
+            _ => 0x01,
+        };
+        self.transfer(0x40 | command)?;
+        for &byte in argument.to_be_bytes().iter() {
+            self.transfer(byte)?;
+        }
+        self.transfer(crc)?;
+
+        for _ in 0..RESPONSE_TRIES {
+            let r1 = self.transfer(0xff)?;
+            if r1 & 0x80 == 0 {
+                return Ok(r1);
+            }
+        }
+        Err(SdError::NoResponse)
+    }
+
+    fn wait_not_busy(&mut self) -> Result<(), SdError> {
+        for _ in 0..BUSY_TRIES {
+            if self.transfer(0xff)? == 0xff {
+                return Ok(());
+            }
+        }
+        Err(SdError::Timeout)
+    }
+
+    // a data block after the data token, its CRC is ignored
+    fn read_data(&mut self, data: &mut [u8]) -> Result<(), SdError> {
+        let mut token = 0xff;
+        for _ in 0..TOKEN_TRIES {
+            token = self.transfer(0xff)?;
+            if token != 0xff {
+                break;
+            }
+        }
+        if token != DATA_TOKEN {
+            return Err(SdError::Timeout);
+        }
+
+        data.iter_mut().for_each(|b| *b = 0xff);
+        self.spi.transfer(data).map_err(|_| SdError::Spi)?;
+        self.transfer(0xff)?;
+        self.transfer(0xff)?;
+        Ok(())
+    }
+
+    fn address(&self, sector: u32) -> u32 {
+        match self.block_addressing {
+            true => sector,
+            false => sector * SECTOR_SIZE as u32,
+        }
+    }
+
+    fn read_block(&mut self, sector: u32, data: &mut [u8; SECTOR_SIZE]) -> Result<(), SdError> {
+        match self.command(READ_SINGLE_BLOCK, self.address(sector))? {
+            0 => self.read_data(data),
+            r1 => Err(SdError::Command(READ_SINGLE_BLOCK, r1)),
+        }
+    }
+
+    fn write_block(&mut self, sector: u32, data: &[u8; SECTOR_SIZE]) -> Result<(), SdError> {
+        match self.command(WRITE_BLOCK, self.address(sector))? {
+            0 => {}
+            r1 => return Err(SdError::Command(WRITE_BLOCK, r1)),
+        }
+
+        self.transfer(0xff)?;
+        self.transfer(DATA_TOKEN)?;
+        // the driver wants a mutable buffer
+        let mut block = *data;
+        self.spi.transfer(&mut block).map_err(|_| SdError::Spi)?;
+        self.transfer(0xff)?;
+        self.transfer(0xff)?;
+
+        match self.transfer(0xff)? & 0x1f {
+            DATA_ACCEPTED => self.wait_not_busy(),
+            response => Err(SdError::Command(WRITE_BLOCK, response)),
+        }
+    }
+}
+
+// the number of sectors from the card specific data register
+fn capacity(csd: &[u8; 16]) -> Option<u32> {
+    match csd[0] >> 6 {
+        // SDHC and SDXC: C_SIZE counts 512 KB
+        1 => {
+            let c_size = (csd[7] as u32 & 0x3f) << 16 | (csd[8] as u32) << 8 | csd[9] as u32;
+            Some((c_size + 1) * 1024)
+        }
+        // SDSC: (C_SIZE + 1) * 2^(C_SIZE_MULT + 2) blocks of 2^READ_BL_LEN bytes
+        0 => {
+            let read_bl_len = (csd[5] & 0x0f) as u32;
+            let c_size = (csd[6] as u32 & 0x03) << 10 | (csd[7] as u32) << 2 | (csd[8] as u32) >> 6;
+            let c_size_mult = ((csd[9] & 0x03) << 1 | csd[10] >> 7) as u32;
+            let bytes = (c_size + 1) << (c_size_mult + 2 + read_bl_len);
+            Some(bytes / SECTOR_SIZE as u32)
+        }
+        _ => None,
+    }
+}
+
+impl<SPI: Transfer<u8>, CS: OutputPin> SectorDevice for SdCard<SPI, CS> {
+    fn sectors(&self) -> u32 {
+        self.sectors
+    }
+
+    fn read_sector(
+        &mut self,
+        sector: u32,
+        data: &mut [u8; SECTOR_SIZE],
+    ) -> Result<(), SectorError> {
+        self.select().map_err(|_| SectorError)?;
+        let result = self.read_block(sector, data);
+        self.deselect().map_err(|_| SectorError)?;
+        result.map_err(|_| SectorError)
+    }
+
+    fn write_sector(&mut self, sector: u32, data: &[u8; SECTOR_SIZE]) -> Result<(), SectorError> {
+        self.select().map_err(|_| SectorError)?;
+        let result = self.write_block(sector, data);
+        self.deselect().map_err(|_| SectorError)?;
+        result.map_err(|_| SectorError)
+    }
+}
+
+pub type CardStorage<SPI, CS> = FatFs<SdCard<SPI, CS>>;
+
+/// Mounts the FAT volume of the card
+pub fn mount<SPI: Transfer<u8>, CS: OutputPin>(
+    card: SdCard<SPI, CS>,
+) -> Result<CardStorage<SPI, CS>, DosError> {
+    FatFs::new(card)
+}
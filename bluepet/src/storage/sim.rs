@@ -0,0 +1,231 @@
+// A series-25 SPI NOR flash on the host, for testing the storage without the board
+//
+// It answers the commands `spi_memory::series25::Flash` sends: read (0x03), page program
+// (0x02), sector erase (0x20), chip erase (0xc7), write enable (0x06), read status (0x05)
+// and the JEDEC id (0x9f). Like the real chip it only programs or erases after a write
+// enable, executes the command when CS goes high and is busy for a few status reads
+// afterwards. Programming clears bits only and wraps around within the 256 byte page.
+//
+// The SPI bus and the CS pin share the chip, so `Flash::init(chip.spi(), chip.cs())`
+// talks to it. The firmware target is the default, tests run with
+// `cargo test --lib --target x86_64-unknown-linux-gnu` (or the host triple).
+
+use core::cell::RefCell;
+use core::convert::Infallible;
+
+use embedded_hal::blocking::spi::Transfer;
+use embedded_hal::digital::v2::OutputPin;
+
+use std::vec::Vec;
+
+pub const PAGE_SIZE: usize = 256;
+pub const SECTOR_SIZE: usize = 4096;
+
+// GigaDevice GD25Q64
+const JEDEC_ID: [u8; 3] = [0xc8, 0x40, 0x17];
+
+const READ: u8 = 0x03;
+const PAGE_PROGRAM: u8 = 0x02;
+const SECTOR_ERASE: u8 = 0x20;
+const CHIP_ERASE: u8 = 0xc7;
+const WRITE_ENABLE: u8 = 0x06;
+const READ_STATUS: u8 = 0x05;
+const READ_JEDEC_ID: u8 = 0x9f;
+
+const STATUS_BUSY: u8 = 0x01;
+const STATUS_WEL: u8 = 0x02;
+
+// status reads a program or erase stays busy for
+const BUSY_READS: usize = 2;
+
+#[derive(Debug)]
+struct State {
+    memory: Vec<u8>,
+    erases: Vec<u32>,
+
+    selected: bool,
+    // the bytes of the current command so far
+    position: usize,
+    opcode: u8,
+    address: u32,
+    page: [Option<u8>; PAGE_SIZE],
+
+    write_enabled: bool,
+    busy: usize,
+}
+
+impl State {
+    fn exchange(&mut self, value: u8) -> u8 {
+        if !self.selected {
+            return 0xff;
+        }
+
+        let position = self.position;
+        self.position += 1;
+        if position == 0 {
+            self.opcode = value;
+            self.address = 0;
+            self.page = [None; PAGE_SIZE];
+            return 0xff;
+        }
+
+        match self.opcode {
+            READ_STATUS => {
+                let status = match self.busy {
+                    0 => 0,
+                    _ => STATUS_BUSY,
+                } | match self.write_enabled {
+                    true => STATUS_WEL,
+                    false => 0,
+                };
+                self.busy = self.busy.saturating_sub(1);
+                status
+            }
+            READ_JEDEC_ID => JEDEC_ID.get(position - 1).copied().unwrap_or(0),
+            _ if self.busy > 0 => 0xff,
+            READ | PAGE_PROGRAM | SECTOR_ERASE if position < 4 => {
+                self.address = self.address << 8 | value as u32;
+                0xff
+            }
+            READ => {
+                let address = (self.address as usize + position - 4) % self.memory.len();
+                self.memory[address]
+            }
+            PAGE_PROGRAM => {
+                // the address counter wraps within the page
+                let offset = (self.address as usize + position - 4) % PAGE_SIZE;
+                self.page[offset] = Some(value);
+                0xff
+            }
+            _ => 0xff,
+        }
+    }
+
+    // commands are executed when the chip is deselected
+    fn deselect(&mut self) {
+        if !self.selected || self.position == 0 {
+            self.selected = false;
+            return;
+        }
+        self.selected = false;
+
+        if self.busy > 0 {
+            return;
+        }
+        match self.opcode {
+            WRITE_ENABLE => self.write_enabled = true,
+            PAGE_PROGRAM if self.write_enabled && self.position > 4 => {
+                let base = self.address as usize / PAGE_SIZE * PAGE_SIZE % self.memory.len();
+                for (i, value) in self.page.iter().enumerate() {
+                    if let Some(value) = value {
+                        self.memory[base + i] &= value;
+                    }
+                }
+                self.done();
+            }
+            SECTOR_ERASE if self.write_enabled && self.position == 4 => {
+                let sector = self.address as usize % self.memory.len() / SECTOR_SIZE;
+                self.erase(sector);
+                self.done();
+            }
+            CHIP_ERASE if self.write_enabled && self.position == 1 => {
+                for sector in 0..self.memory.len() / SECTOR_SIZE {
+                    self.erase(sector);
+                }
+                self.done();
+            }
+            _ => {}
+        }
+    }
+
+    fn erase(&mut self, sector: usize) {
+        let start = sector * SECTOR_SIZE;
+        self.memory[start..start + SECTOR_SIZE]
+            .iter_mut()
+            .for_each(|b| *b = 0xff);
+        self.erases[sector] += 1;
+    }
+
+    fn done(&mut self) {
+        self.write_enabled = false;
+        self.busy = BUSY_READS;
+    }
+}
+
+/// The simulated chip, erased when created
+#[derive(Debug)]
+pub struct SimulatedFlash {
+    state: RefCell<State>,
+}
+
+impl SimulatedFlash {
+    pub fn new(capacity: usize) -> SimulatedFlash {
+        SimulatedFlash {
+            state: RefCell::new(State {
+                memory: std::vec![0xff; capacity],
+                erases: std::vec![0; capacity / SECTOR_SIZE],
+                selected: false,
+                position: 0,
+                opcode: 0,
+                address: 0,
+                page: [None; PAGE_SIZE],
+                write_enabled: false,
+                busy: 0,
+            }),
+        }
+    }
+
+    pub fn spi(&self) -> SimulatedSpi<'_> {
+        SimulatedSpi(&self.state)
+    }
+
+    pub fn cs(&self) -> SimulatedCs<'_> {
+        SimulatedCs(&self.state)
+    }
+
+    /// A copy of the content
+    pub fn memory(&self) -> Vec<u8> {
+        self.state.borrow().memory.clone()
+    }
+
+    /// How often each sector was erased
+    pub fn erases(&self) -> Vec<u32> {
+        self.state.borrow().erases.clone()
+    }
+}
+
+/// The SPI bus to the chip
+#[derive(Debug)]
+pub struct SimulatedSpi<'a>(&'a RefCell<State>);
+
+impl Transfer<u8> for SimulatedSpi<'_> {
+    type Error = Infallible;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Infallible> {
+        let mut state = self.0.borrow_mut();
+        for word in words.iter_mut() {
+            *word = state.exchange(*word);
+        }
+        Ok(words)
+    }
+}
+
+/// The chip select pin, active low
+#[derive(Debug)]
+pub struct SimulatedCs<'a>(&'a RefCell<State>);
+
+impl OutputPin for SimulatedCs<'_> {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        let mut state = self.0.borrow_mut();
+        state.selected = true;
+        state.position = 0;
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        self.0.borrow_mut().deselect();
+        Ok(())
+    }
+}
@@ -0,0 +1,160 @@
+// File transfers over the debug probe, see pet::xmodem
+//
+// RTT channel 0 is the terminal: "rx" receives files sent with YMODEM (`sb`), "rx NAME" a
+// single file sent with XMODEM (`sx`) and "sx NAME" sends a file with YMODEM (`rb`).
+// The data goes over RTT channel 1. The emulation stops while a transfer runs.
+// "disks" lists the disks in the flash and "cd NAME" mounts one, "cd" the first.
+
+use pet::dos::{hostname, DosError, MAX_NAME_LEN};
+use pet::io::Storage;
+use pet::xmodem::{self, Transport};
+use rtt_target::{rprintln, DownChannel, UpChannel};
+
+// cycles of the 72 MHz core
+const CYCLES_PER_MS: u32 = 72_000;
+const POLL_CYCLES: u32 = 7_200;
+
+const MAX_LINE_LEN: usize = 40;
+
+pub struct RttTransport {
+    up: UpChannel,
+    down: DownChannel,
+}
+
+impl Transport for RttTransport {
+    fn read(&mut self, timeout: u32) -> Option<u8> {
+        let mut value = [0u8];
+        for _ in 0..timeout * (CYCLES_PER_MS / POLL_CYCLES) {
+            if self.down.read(&mut value) == 1 {
+                return Some(value[0]);
+            }
+            cortex_m::asm::delay(POLL_CYCLES);
+        }
+        None
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        let mut written = 0;
+        while written < data.len() {
+            written += self.up.write(&data[written..]);
+        }
+    }
+}
+
+/// Reads commands from the terminal and runs them
+pub struct Terminal {
+    down: DownChannel,
+    transport: RttTransport,
+    line: [u8; MAX_LINE_LEN],
+    len: usize,
+}
+
+impl Terminal {
+    pub fn new(down: DownChannel, transport_up: UpChannel, transport_down: DownChannel) -> Terminal {
+        Terminal {
+            down,
+            transport: RttTransport {
+                up: transport_up,
+                down: transport_down,
+            },
+            line: [0u8; MAX_LINE_LEN],
+            len: 0,
+        }
+    }
+
+    /// Runs a command if one was typed
+    pub fn poll(&mut self, storage: &mut dyn Storage) {
+        let mut value = [0u8];
+        while self.down.read(&mut value) == 1 {
+            match value[0] {
+                b'\r' | b'\n' => {
+                    let len = core::mem::replace(&mut self.len, 0);
+                    let line = self.line;
+                    self.command(&line[..len], storage);
+                }
+                c if self.len < MAX_LINE_LEN => {
+                    self.line[self.len] = c;
+                    self.len += 1;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn command(&mut self, line: &[u8], storage: &mut dyn Storage) {
+        let mut words = line.split(|&c| c == b' ').filter(|word| !word.is_empty());
+        let command = words.next();
+
+        // names are typed like host filenames
+        let mut name = [0u8; MAX_NAME_LEN];
+        let mut len = 0;
+        if let Some(word) = words.next() {
+            hostname::pet_name(word, |c| {
+                if len < MAX_NAME_LEN {
+                    name[len] = c;
+                    len += 1;
+                }
+            });
+        }
+        let name = match len {
+            0 => None,
+            _ => Some(&name[..len]),
+        };
+
+        match (command, name) {
+            (Some(b"rx"), name) => {
+                rprintln!("receiving, start the upload");
+                match xmodem::receive(&mut self.transport, storage, name) {
+                    Ok(files) => rprintln!("{} files received", files),
+                    Err(error) => rprintln!("failed: {:?}", error),
+                }
+            }
+            (Some(b"sx"), Some(name)) => {
+                rprintln!("sending, start the download");
+                match xmodem::send(&mut self.transport, storage, name) {
+                    Ok(()) => rprintln!("sent"),
+                    Err(error) => rprintln!("failed: {:?}", error),
+                }
+            }
+            (Some(b"disks"), None) => {
+                if let Err(error) = print_disks(storage) {
+                    rprintln!("failed: {:?}", error);
+                }
+            }
+            (Some(b"cd"), name) => {
+                let result = match storage.file_system() {
+                    Some(fs) => fs.change_disk(name.unwrap_or(b"")),
+                    None => Err(DosError::InvalidCommand),
+                };
+                match result {
+                    Ok(()) => rprintln!("mounted"),
+                    Err(error) => rprintln!("failed: {:?}", error),
+                }
+            }
+            (None, _) => {}
+            _ => rprintln!("commands: rx, rx NAME, sx NAME, disks, cd, cd NAME"),
+        }
+    }
+}
+
+// one disk a line, the mounted one marked with "*"
+fn print_disks(storage: &mut dyn Storage) -> Result<(), DosError> {
+    let fs = storage.file_system().ok_or(DosError::InvalidCommand)?;
+    let mut cursor = 0;
+    while let Some((header, mounted)) = fs.next_disk(&mut cursor)? {
+        let name_len = header
+            .name
+            .iter()
+            .rposition(|&c| c != b' ')
+            .map_or(0, |i| i + 1);
+        let mut name = [0u8; 3 * MAX_NAME_LEN];
+        let mut len = 0;
+        hostname::host_name(&header.name[..name_len], |c| {
+            name[len] = c;
+            len += 1;
+        });
+        let name = core::str::from_utf8(&name[..len]).unwrap_or("?");
+        rprintln!("{} {}", if mounted { '*' } else { ' ' }, name);
+    }
+    Ok(())
+}
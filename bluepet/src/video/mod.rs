@@ -3,6 +3,7 @@ use stm32f1xx_hal::pac::{interrupt, Interrupt};
 use stm32f1xx_hal::{pac};
 
 use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicU8, Ordering};
 
 static mut TIMER_TIM4: MaybeUninit<pac::TIM4> = MaybeUninit::uninit();
 static mut TIMER_TIM1: MaybeUninit<pac::TIM1> = MaybeUninit::uninit();
@@ -13,6 +14,18 @@ static mut SCANLINE_PIXELS: [u8; 40] = [0u8; 40];
 
 static mut CHAR_ROM_RAM: [u8; 2048] = [0u8; 2048];
 
+// 0 for the graphics character set, 1024 for the lowercase character set
+static mut CHARSET_OFFSET: isize = 0;
+
+// no pixels are output while the screen is blanked
+static mut SCREEN_BLANK: bool = false;
+
+// the last change of the vertical retrace, set by the isr and taken by `take_retrace_edge`
+static RETRACE_EDGE: AtomicU8 = AtomicU8::new(NO_EDGE);
+const NO_EDGE: u8 = 0;
+const RETRACE_STARTED: u8 = 1;
+const RETRACE_ENDED: u8 = 2;
+
 const ISR_OVERHEAD_CORRECTION: u16 = 110;
 
 pub fn init_video(cp: &mut Peripherals, tim4: pac::TIM4, tim1: pac::TIM1) {
@@ -56,6 +69,20 @@ pub fn init_video(cp: &mut Peripherals, tim4: pac::TIM4, tim1: pac::TIM1) {
     }
 }
 
+/// selects the half of the character ROM used for the following frames
+pub fn select_lowercase_charset(lowercase: bool) {
+    unsafe {
+        CHARSET_OFFSET = if lowercase { 1024 } else { 0 };
+    }
+}
+
+/// blanks the screen (black scanlines) for the following frames
+pub fn set_screen_blank(blank: bool) {
+    unsafe {
+        SCREEN_BLANK = blank;
+    }
+}
+
 pub fn start_video() {
     schedule(HALF_SCANLINE_ARR, SHORT_SYNC_CRR);
 }
@@ -160,6 +187,16 @@ static mut IDX: usize = 0usize;
 const START_AT_SCANLINE: usize = 80;
 const STOP_AT_SCANLINE: usize = START_AT_SCANLINE + 200;
 
+/// The vertical retrace of the PET is while the composite output is outside of the visible
+/// lines. Returns Some(true) if it started since the last call, Some(false) if it ended.
+pub fn take_retrace_edge() -> Option<bool> {
+    match RETRACE_EDGE.swap(NO_EDGE, Ordering::Relaxed) {
+        RETRACE_STARTED => Some(true),
+        RETRACE_ENDED => Some(false),
+        _ => None,
+    }
+}
+
 #[interrupt]
 fn TIM4() {
     unsafe {
@@ -172,7 +209,7 @@ fn TIM4() {
         let new_crr = DATA[IDX].ccr;
         schedule(new_arr, new_crr);
 
-        if has_pixels && IDX >= START_AT_SCANLINE && IDX < STOP_AT_SCANLINE {
+        if has_pixels && !SCREEN_BLANK && IDX >= START_AT_SCANLINE && IDX < STOP_AT_SCANLINE {
             // copy pixel data (1 bit = 1 pixel) to prepare it to be shown on screen
             let y = IDX - START_AT_SCANLINE;
             let line = y / 8;
@@ -207,7 +244,7 @@ fn TIM4() {
                 chr_data_ptr = in(reg) VID_RAM.as_ptr().offset(vid_ram_idx),
                 pxl_data_ptr = inout(reg)  SCANLINE_PIXELS.as_mut_ptr() => _,
                 pxl_count = in(reg) 40,
-                char_rom_base = in(reg) CHAR_ROM_RAM.as_ptr(),
+                char_rom_base = in(reg) CHAR_ROM_RAM.as_ptr().offset(CHARSET_OFFSET),
                 scanline_mod = in(reg) scanline_mod,
                 char_rom_ptr = out(reg) _,
                 chr_data = out(reg) _,
@@ -220,6 +257,12 @@ fn TIM4() {
         if IDX >= DATA.len() {
             IDX = 0;
         }
+
+        if IDX == STOP_AT_SCANLINE {
+            RETRACE_EDGE.store(RETRACE_STARTED, Ordering::Relaxed);
+        } else if IDX == START_AT_SCANLINE {
+            RETRACE_EDGE.store(RETRACE_ENDED, Ordering::Relaxed);
+        }
     };
 }
 
@@ -0,0 +1,347 @@
+// BASIC programs as text
+//
+// `tokenize` turns a listing into a program as BASIC stores it, `detokenize` lists one.
+// The text is written like host filenames (see `dos::hostname`): lowercase letters are
+// unshifted ones, so "10 print" is the usual 10 PRINT and uppercase letters are shifted.
+// Other PETSCII characters are escapes in braces, "{clr}" or "{rvs on}" for the control
+// codes and "{$a0}" for any byte.
+//
+// Keywords are crunched like the ROMs do when a line is entered: wherever a keyword
+// starts outside quotes, even within a variable name, "?" is PRINT and a shifted letter
+// after the first one ends an abbreviation ("pO" is POKE). The rest of a REM and DATA up
+// to the next ":" stay as they are. Lines are linked from the start of BASIC at 0x0401.
+
+use crate::prg::BasicVersion;
+
+/// Where BASIC programs start on every PET
+pub const BASIC_START: u16 = 0x0401;
+
+const MAX_LINE_NUMBER: u16 = 63999;
+// a crunched line without its link and number, what the ROMs take is shorter
+const MAX_LINE_LEN: usize = 250;
+
+const FIRST_TOKEN: u8 = 0x80;
+const TOKEN_DATA: u8 = 0x83;
+const TOKEN_REM: u8 = 0x8f;
+const TOKEN_PRINT: u8 = 0x99;
+
+// in the order of their tokens, from 0x80 on
+#[rustfmt::skip]
+const KEYWORDS: [&[u8]; 91] = [
+    b"END", b"FOR", b"NEXT", b"DATA", b"INPUT#", b"INPUT", b"DIM", b"READ", b"LET", b"GOTO",
+    b"RUN", b"IF", b"RESTORE", b"GOSUB", b"RETURN", b"REM", b"STOP", b"ON", b"WAIT", b"LOAD",
+    b"SAVE", b"VERIFY", b"DEF", b"POKE", b"PRINT#", b"PRINT", b"CONT", b"LIST", b"CLR", b"CMD",
+    b"SYS", b"OPEN", b"CLOSE", b"GET", b"NEW", b"TAB(", b"TO", b"FN", b"SPC(", b"THEN",
+    b"NOT", b"STEP", b"+", b"-", b"*", b"/", b"^", b"AND", b"OR", b">", b"=", b"<", b"SGN",
+    b"INT", b"ABS", b"USR", b"FRE", b"POS", b"SQR", b"RND", b"LOG", b"EXP", b"COS", b"SIN",
+    b"TAN", b"ATN", b"PEEK", b"LEN", b"STR$", b"VAL", b"ASC", b"CHR$", b"LEFT$", b"RIGHT$",
+    b"MID$", // the last one of BASIC 1
+    b"GO", // the last one of BASIC 2
+    b"CONCAT", b"DOPEN", b"DCLOSE", b"RECORD", b"HEADER", b"COLLECT", b"BACKUP", b"COPY",
+    b"APPEND", b"DSAVE", b"DLOAD", b"CATALOG", b"RENAME", b"SCRATCH", b"DIRECTORY",
+];
+
+// the names of the control codes, the first one of a code is used when listing
+const ESCAPES: &[(&[u8], u8)] = &[
+    (b"clr", 0x93),
+    (b"home", 0x13),
+    (b"down", 0x11),
+    (b"up", 0x91),
+    (b"left", 0x9d),
+    (b"right", 0x1d),
+    (b"rght", 0x1d),
+    (b"rvs on", 0x12),
+    (b"rvon", 0x12),
+    (b"rvs off", 0x92),
+    (b"rvof", 0x92),
+    (b"del", 0x14),
+    (b"inst", 0x94),
+];
+
+#[derive(Debug, PartialEq)]
+pub enum BasicError {
+    /// A line of the text (counted from 1) without a line number or one above 63999
+    LineNumber(usize),
+    /// A line of the text with a number not above the one of the line before
+    LineOrder(usize),
+    /// A line of the text with an escape that isn't known or not closed
+    Escape(usize),
+    /// A line of the text too long for BASIC
+    LineTooLong(usize),
+    /// The program ends in the middle of a line or isn't there at all
+    Truncated,
+}
+
+fn keywords(version: BasicVersion) -> &'static [&'static [u8]] {
+    match version {
+        BasicVersion::Basic1 => &KEYWORDS[..0xcb - FIRST_TOKEN as usize],
+        BasicVersion::Basic2 => &KEYWORDS[..0xcc - FIRST_TOKEN as usize],
+        BasicVersion::Basic4 => &KEYWORDS,
+    }
+}
+
+/// Builds the program from its listing, with the load address in front like a PRG file.
+/// Empty lines are left out.
+pub fn tokenize(
+    text: &[u8],
+    version: BasicVersion,
+    mut put: impl FnMut(u8),
+) -> Result<(), BasicError> {
+    BASIC_START.to_le_bytes().iter().for_each(|&b| put(b));
+
+    let mut address = BASIC_START;
+    let mut last_number = None;
+    for (i, line) in text.split(|&c| c == b'\n').enumerate() {
+        let number = i + 1;
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.iter().all(|&c| c == b' ') {
+            continue;
+        }
+
+        let (line_number, rest) = line_number(line).ok_or(BasicError::LineNumber(number))?;
+        if last_number.map_or(false, |last| line_number <= last) {
+            return Err(BasicError::LineOrder(number));
+        }
+        last_number = Some(line_number);
+
+        let mut petscii = [0u8; MAX_LINE_LEN];
+        let len = to_petscii(rest, &mut petscii, number)?;
+        let mut crunched = [0u8; MAX_LINE_LEN];
+        let len = crunch(&petscii[..len], version, &mut crunched)
+            .ok_or(BasicError::LineTooLong(number))?;
+
+        // the link, the line number, the line and its end
+        address = address.wrapping_add(2 + 2 + len as u16 + 1);
+        address.to_le_bytes().iter().for_each(|&b| put(b));
+        line_number.to_le_bytes().iter().for_each(|&b| put(b));
+        crunched[..len].iter().for_each(|&b| put(b));
+        put(0);
+    }
+
+    put(0);
+    put(0);
+    Ok(())
+}
+
+// the line number and the text after it without the spaces in between
+fn line_number(line: &[u8]) -> Option<(u16, &[u8])> {
+    let line = &line[line.iter().position(|&c| c != b' ')?..];
+    let digits = line.iter().take_while(|c| c.is_ascii_digit()).count();
+    if digits == 0 {
+        return None;
+    }
+
+    let mut number = 0u32;
+    for &digit in &line[..digits] {
+        number = number * 10 + (digit - b'0') as u32;
+        if number > MAX_LINE_NUMBER as u32 {
+            return None;
+        }
+    }
+    let rest = &line[digits..];
+    let spaces = rest.iter().take_while(|&&c| c == b' ').count();
+    Some((number as u16, &rest[spaces..]))
+}
+
+// the text of the line `number` in PETSCII with the escapes replaced
+fn to_petscii(text: &[u8], out: &mut [u8], number: usize) -> Result<usize, BasicError> {
+    let mut len = 0;
+    let mut i = 0;
+    while i < text.len() {
+        let c = text[i];
+        i += 1;
+        let value = match c {
+            b'{' => {
+                let end = text[i..].iter().position(|&c| c == b'}');
+                let end = i + end.ok_or(BasicError::Escape(number))?;
+                let name = &text[i..end];
+                i = end + 1;
+                escape_code(name).ok_or(BasicError::Escape(number))?
+            }
+            b'a'..=b'z' => c - 0x20,
+            b'A'..=b'Z' => c + 0x80,
+            _ => c,
+        };
+        *out.get_mut(len).ok_or(BasicError::LineTooLong(number))? = value;
+        len += 1;
+    }
+    Ok(len)
+}
+
+fn escape_code(name: &[u8]) -> Option<u8> {
+    if let Some(hex) = name.strip_prefix(b"$") {
+        let hex = core::str::from_utf8(hex).ok()?;
+        return match hex.len() {
+            2 => u8::from_str_radix(hex, 16).ok(),
+            _ => None,
+        };
+    }
+    ESCAPES
+        .iter()
+        .find(|(escape, _)| escape.eq_ignore_ascii_case(name))
+        .map(|&(_, code)| code)
+}
+
+// the keyword starting the text and how long it is there
+fn keyword(text: &[u8], version: BasicVersion) -> Option<(u8, usize)> {
+    for (token, keyword) in keywords(version).iter().enumerate() {
+        for (i, &k) in keyword.iter().enumerate() {
+            match text.get(i) {
+                Some(&c) if c == k && i + 1 == keyword.len() => {
+                    return Some((FIRST_TOKEN + token as u8, i + 1))
+                }
+                Some(&c) if c == k => {}
+                // shifted, it's abbreviated
+                Some(&c) if i > 0 && c == k | 0x80 => {
+                    return Some((FIRST_TOKEN + token as u8, i + 1))
+                }
+                _ => break,
+            }
+        }
+    }
+    None
+}
+
+// replaces the keywords by their tokens, None if the line gets too long
+fn crunch(line: &[u8], version: BasicVersion, out: &mut [u8]) -> Option<usize> {
+    let mut len = 0;
+    let mut quoted = false;
+    let mut data = false;
+    let mut i = 0;
+    while i < line.len() {
+        let c = line[i];
+        let value = if c == b'"' {
+            quoted = !quoted;
+            i += 1;
+            c
+        } else if quoted || c == b' ' || (data && c != b':') {
+            i += 1;
+            c
+        } else if c == b'?' {
+            i += 1;
+            TOKEN_PRINT
+        } else {
+            data = false;
+            match keyword(&line[i..], version) {
+                Some((TOKEN_REM, len_of)) => {
+                    // the rest of the line as it is
+                    i += len_of;
+                    *out.get_mut(len)? = TOKEN_REM;
+                    len += 1;
+                    for &c in &line[i..] {
+                        *out.get_mut(len)? = c;
+                        len += 1;
+                    }
+                    return Some(len);
+                }
+                Some((token, len_of)) => {
+                    data = token == TOKEN_DATA;
+                    i += len_of;
+                    token
+                }
+                None => {
+                    i += 1;
+                    c
+                }
+            }
+        };
+        *out.get_mut(len)? = value;
+        len += 1;
+    }
+    Some(len)
+}
+
+/// Lists the program, given with its load address like a PRG file, as text with a line
+/// ending in "\n" for each line
+pub fn detokenize(
+    prg: &[u8],
+    version: BasicVersion,
+    mut put: impl FnMut(u8),
+) -> Result<(), BasicError> {
+    let mut rest = prg.get(2..).ok_or(BasicError::Truncated)?;
+    loop {
+        // a link with a zero high byte ends the program
+        match rest {
+            [_, 0, ..] => return Ok(()),
+            [_, _, low, high, line @ ..] => {
+                let end = line
+                    .iter()
+                    .position(|&c| c == 0)
+                    .ok_or(BasicError::Truncated)?;
+                let number = u16::from_le_bytes([*low, *high]);
+                put_number(number, &mut put);
+                put(b' ');
+                list_line(&line[..end], version, &mut put);
+                put(b'\n');
+                rest = &line[end + 1..];
+            }
+            _ => return Err(BasicError::Truncated),
+        }
+    }
+}
+
+fn put_number(number: u16, put: &mut impl FnMut(u8)) {
+    let mut digits = [0u8; 5];
+    let mut len = 0;
+    let mut number = number;
+    loop {
+        digits[len] = b'0' + (number % 10) as u8;
+        len += 1;
+        number /= 10;
+        if number == 0 {
+            break;
+        }
+    }
+    digits[..len].iter().rev().for_each(|&c| put(c));
+}
+
+fn list_line(line: &[u8], version: BasicVersion, put: &mut impl FnMut(u8)) {
+    let keywords = keywords(version);
+    let mut quoted = false;
+    let mut remark = false;
+    let mut data = false;
+    for &c in line {
+        match c {
+            b'"' => quoted = !quoted,
+            b':' if !quoted => data = false,
+            _ => {}
+        }
+        let token = match c.checked_sub(FIRST_TOKEN) {
+            Some(token) if !quoted && !remark && !data => keywords.get(token as usize),
+            _ => None,
+        };
+        match token {
+            Some(keyword) => {
+                keyword.iter().for_each(|c| put(c.to_ascii_lowercase()));
+                remark = c == TOKEN_REM;
+                data = c == TOKEN_DATA;
+            }
+            None => put_char(c, put),
+        }
+    }
+}
+
+// a PETSCII character as text
+fn put_char(c: u8, put: &mut impl FnMut(u8)) {
+    match c {
+        b'A'..=b'Z' => put(c + 0x20),
+        0xc1..=0xda => put(c - 0x80),
+        b' '..=b']' | b'^' | b'_' => put(c),
+        _ => match ESCAPES.iter().find(|&&(_, code)| code == c) {
+            Some((name, _)) => put_escape(name, put),
+            None => {
+                const HEX: &[u8; 16] = b"0123456789abcdef";
+                put_escape(
+                    &[b'$', HEX[(c >> 4) as usize], HEX[(c & 0x0f) as usize]],
+                    put,
+                )
+            }
+        },
+    }
+}
+
+fn put_escape(name: &[u8], put: &mut impl FnMut(u8)) {
+    put(b'{');
+    name.iter().for_each(|&c| put(c));
+    put(b'}');
+}
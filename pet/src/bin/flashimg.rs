@@ -0,0 +1,334 @@
+// Builds and inspects images of the SPI flash of the board, see pet::flashfs
+//
+//   flashimg create IMAGE FOLDER [SIZE]   a new image with the files of a folder, 8 MB by default
+//   flashimg add IMAGE FILE...            adds files, existing ones are replaced
+//   flashimg list IMAGE
+//   flashimg extract IMAGE NAME [FILE]
+//   flashimg delete IMAGE PATTERN         locked files are kept
+//   flashimg lock IMAGE PATTERN
+//   flashimg unlock IMAGE PATTERN
+//   flashimg check IMAGE                  reads all files, fails if one is damaged
+//   flashimg disks IMAGE
+//   flashimg mkdisk IMAGE NAME
+//   flashimg rmdisk IMAGE NAME            only empty disks
+//
+// The commands work on the first disk, "-d NAME" before the command chooses another one.
+// Files and names are mapped like in host folders (see pet::hostdir): lowercase letters are
+// unshifted ones and the extension of a file is its type (.prg, .seq or .usr).
+
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+use pet::dos::listing::{Directory, DirectoryEntry};
+use pet::dos::{self, hostname, DosError, FileSystem};
+use pet::flashfs::{FlashFs, SECTOR_SIZE};
+use pet::hostdir;
+use pet::io::{FileMode, FileType, OpenName, Storage};
+
+// the GD25Q64 on the board
+const DEFAULT_SIZE: usize = 8 * 1024 * 1024;
+
+const USAGE: &str = "usage: flashimg [-d DISK] create IMAGE FOLDER [SIZE]
+       flashimg [-d DISK] add IMAGE FILE...
+       flashimg [-d DISK] list IMAGE
+       flashimg [-d DISK] extract IMAGE NAME [FILE]
+       flashimg [-d DISK] delete IMAGE PATTERN
+       flashimg [-d DISK] lock IMAGE PATTERN
+       flashimg [-d DISK] unlock IMAGE PATTERN
+       flashimg [-d DISK] check IMAGE
+       flashimg disks IMAGE
+       flashimg mkdisk IMAGE NAME
+       flashimg rmdisk IMAGE NAME";
+
+type Image<'a> = FlashFs<&'a mut [u8]>;
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let (disk, args) = match args.as_slice() {
+        ["-d", disk, args @ ..] => (Some(*disk), args),
+        args => (None, args),
+    };
+
+    let result = match args {
+        ["create", image, folder] => create(image, folder, DEFAULT_SIZE, disk),
+        ["create", image, folder, size] => match size.parse() {
+            Ok(size) => create(image, folder, size, disk),
+            Err(_) => Err(format!("invalid size {}", size)),
+        },
+        ["add", image, files @ ..] if !files.is_empty() => change(image, disk, |fs| {
+            files.iter().try_for_each(|file| add(fs, Path::new(file)))
+        }),
+        ["list", image] => inspect(image, disk, list),
+        ["extract", image, name] => inspect(image, disk, |fs| extract(fs, name, None)),
+        ["extract", image, name, file] => inspect(image, disk, |fs| extract(fs, name, Some(file))),
+        ["delete", image, pattern] => change(image, disk, |fs| delete(fs, pattern)),
+        ["lock", image, pattern] => change(image, disk, |fs| lock(fs, pattern, true)),
+        ["unlock", image, pattern] => change(image, disk, |fs| lock(fs, pattern, false)),
+        ["check", image] => inspect(image, disk, check),
+        ["disks", image] if disk.is_none() => inspect(image, None, disks),
+        ["mkdisk", image, name] if disk.is_none() => change(image, None, |fs| {
+            fs.make_disk(&hostdir::pet_name(name)).map_err(dos_error)
+        }),
+        ["rmdisk", image, name] if disk.is_none() => change(image, None, |fs| {
+            fs.remove_disk(&hostdir::pet_name(name)).map_err(dos_error)
+        }),
+        _ => {
+            eprintln!("{}", USAGE);
+            exit(2);
+        }
+    };
+
+    if let Err(error) = result {
+        eprintln!("flashimg: {}", error);
+        exit(1);
+    }
+}
+
+fn dos_error(error: DosError) -> String {
+    error.message().to_lowercase()
+}
+
+// PETSCII names as far as they are printable
+fn display(name: &[u8]) -> String {
+    name.iter()
+        .map(|&c| match c {
+            0x20..=0x7e => c as char,
+            _ => '?',
+        })
+        .collect()
+}
+
+fn read_image(image: &str) -> Result<Vec<u8>, String> {
+    std::fs::read(image).map_err(|error| format!("{}: {}", image, error))
+}
+
+fn mount<'a>(data: &'a mut [u8], disk: Option<&str>) -> Result<Image<'a>, String> {
+    let mut fs = FlashFs::new(data).map_err(dos_error)?;
+    if let Some(disk) = disk {
+        fs.change_disk(&hostdir::pet_name(disk))
+            .map_err(|error| format!("disk {}: {}", disk, dos_error(error)))?;
+    }
+    Ok(fs)
+}
+
+fn inspect(
+    image: &str,
+    disk: Option<&str>,
+    f: impl FnOnce(&mut Image) -> Result<(), String>,
+) -> Result<(), String> {
+    let mut data = read_image(image)?;
+    f(&mut mount(&mut data, disk)?)
+}
+
+// the image is only written if everything worked
+fn change(
+    image: &str,
+    disk: Option<&str>,
+    f: impl FnOnce(&mut Image) -> Result<(), String>,
+) -> Result<(), String> {
+    let mut data = read_image(image)?;
+    f(&mut mount(&mut data, disk)?)?;
+    std::fs::write(image, &data).map_err(|error| format!("{}: {}", image, error))
+}
+
+// the files go to a new disk of that name if one is given
+fn create(image: &str, folder: &str, size: usize, disk: Option<&str>) -> Result<(), String> {
+    if size == 0 || size % SECTOR_SIZE != 0 {
+        return Err(format!("the size has to be a multiple of {}", SECTOR_SIZE));
+    }
+
+    let mut files: Vec<PathBuf> = std::fs::read_dir(folder)
+        .and_then(|entries| entries.map(|entry| entry.map(|e| e.path())).collect())
+        .map_err(|error| format!("{}: {}", folder, error))?;
+    files.retain(|path| file_type(path).is_some());
+    files.sort();
+
+    let mut data = vec![0xffu8; size];
+    {
+        let mut fs = FlashFs::new(&mut data[..]).map_err(dos_error)?;
+        if let Some(disk) = disk {
+            let name = hostdir::pet_name(disk);
+            fs.make_disk(&name)
+                .and_then(|()| fs.change_disk(&name))
+                .map_err(|error| format!("disk {}: {}", disk, dos_error(error)))?;
+        }
+        for file in &files {
+            add(&mut fs, file)?;
+        }
+    }
+    std::fs::write(image, &data).map_err(|error| format!("{}: {}", image, error))
+}
+
+// relative files need a record length, they aren't added
+fn file_type(path: &Path) -> Option<FileType> {
+    let extension = path.extension()?.to_str()?;
+    hostname::file_type(extension.as_bytes()).filter(|&file_type| file_type != FileType::Rel)
+}
+
+fn add(fs: &mut Image, path: &Path) -> Result<(), String> {
+    let file_type = file_type(path)
+        .ok_or_else(|| format!("{}: not a .prg, .seq or .usr file", path.display()))?;
+    let stem = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("");
+    let name = hostdir::pet_name(stem);
+    let content = std::fs::read(path).map_err(|error| format!("{}: {}", path.display(), error))?;
+
+    let open_name = OpenName {
+        name: &name,
+        file_type: Some(file_type),
+        mode: FileMode::Write,
+        replace: true,
+    };
+    let failed = |error| format!("{}: {}", path.display(), dos_error(error));
+    fs.open(2, &open_name).map_err(failed)?;
+    content
+        .iter()
+        .try_for_each(|&b| fs.write(2, b))
+        .map_err(failed)?;
+    fs.close(2).map_err(failed)?;
+
+    println!(
+        "{:<18} {}",
+        format!("\"{}\"", display(&name)),
+        path.display()
+    );
+    Ok(())
+}
+
+fn entries(fs: &mut Image) -> Result<Vec<DirectoryEntry>, String> {
+    let mut entries = Vec::new();
+    let mut cursor = 0;
+    while let Some(entry) = fs.next_entry(&mut cursor).map_err(dos_error)? {
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+fn type_name(file_type: FileType) -> &'static str {
+    match file_type {
+        FileType::Prg => "PRG",
+        FileType::Seq => "SEQ",
+        FileType::Usr => "USR",
+        FileType::Rel => "REL",
+    }
+}
+
+fn list(fs: &mut Image) -> Result<(), String> {
+    for entry in entries(fs)? {
+        println!(
+            "{:<5} {:<18} {}{}",
+            entry.blocks,
+            format!("\"{}\"", display(entry.name())),
+            type_name(entry.file_type),
+            if entry.locked { "<" } else { "" }
+        );
+    }
+    println!("{} BLOCKS FREE.", fs.blocks_free().map_err(dos_error)?);
+
+    let erases = (0..fs.sectors())
+        .map(|sector| fs.erase_count(sector).map(|count| count.unwrap_or(0)))
+        .collect::<Result<Vec<u32>, DosError>>()
+        .map_err(dos_error)?;
+    if let (Some(min), Some(max)) = (erases.iter().min(), erases.iter().max()) {
+        println!("sectors erased {} to {} times", min, max);
+    }
+    Ok(())
+}
+
+fn read_file(fs: &mut Image, name: &[u8]) -> Result<Vec<u8>, String> {
+    let open_name = OpenName {
+        name,
+        file_type: None,
+        mode: FileMode::Read,
+        replace: false,
+    };
+    fs.open(2, &open_name).map_err(dos_error)?;
+    let mut content = Vec::new();
+    while let Some((value, _)) = fs.read(2) {
+        content.push(value);
+    }
+    fs.close(2).map_err(dos_error)?;
+
+    match fs.verify(name).map_err(dos_error)? {
+        true => Ok(content),
+        false => Err(format!("\"{}\" is damaged", display(name))),
+    }
+}
+
+fn extract(fs: &mut Image, pattern: &str, file: Option<&str>) -> Result<(), String> {
+    let pattern = hostdir::pet_name(pattern);
+    let entry = entries(fs)?
+        .into_iter()
+        .find(|entry| dos::matches(&pattern, entry.name()))
+        .ok_or_else(|| dos_error(DosError::FileNotFound))?;
+
+    let content = read_file(fs, entry.name())?;
+    let file = match file {
+        Some(file) => PathBuf::from(file),
+        None => PathBuf::from(format!(
+            "{}.{}",
+            hostdir::host_name(entry.name()),
+            hostname::extension(entry.file_type)
+        )),
+    };
+    std::fs::write(&file, content).map_err(|error| format!("{}: {}", file.display(), error))?;
+    println!("{}", file.display());
+    Ok(())
+}
+
+fn delete(fs: &mut Image, pattern: &str) -> Result<(), String> {
+    let scratched = fs.scratch(&hostdir::pet_name(pattern)).map_err(dos_error)?;
+    println!("{} files scratched", scratched);
+    Ok(())
+}
+
+fn lock(fs: &mut Image, pattern: &str, locked: bool) -> Result<(), String> {
+    match fs
+        .lock(&hostdir::pet_name(pattern), Some(locked))
+        .map_err(dos_error)?
+    {
+        0 => Err(dos_error(DosError::FileNotFound)),
+        files => {
+            println!(
+                "{} files {}",
+                files,
+                if locked { "locked" } else { "unlocked" }
+            );
+            Ok(())
+        }
+    }
+}
+
+// with their numbers, the first disk is always there
+fn disks(fs: &mut Image) -> Result<(), String> {
+    let mut cursor = 0;
+    while let Some((disk, header)) = fs.next_numbered_disk(&mut cursor).map_err(dos_error)? {
+        println!(
+            "{:<3} \"{}\" {}",
+            disk,
+            display(&header.name).trim_end(),
+            display(&header.id)
+        );
+    }
+    Ok(())
+}
+
+fn check(fs: &mut Image) -> Result<(), String> {
+    let entries = entries(fs)?;
+    let mut damaged = 0;
+    for entry in &entries {
+        if let Err(error) = read_file(fs, entry.name()) {
+            println!("{}", error);
+            damaged += 1;
+        }
+    }
+
+    println!("{} files, {} damaged", entries.len(), damaged);
+    match damaged {
+        0 => Ok(()),
+        _ => Err(format!("{} damaged files", damaged)),
+    }
+}
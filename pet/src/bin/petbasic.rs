@@ -0,0 +1,83 @@
+// Turns BASIC listings into programs and back, see pet::basic
+//
+//   petbasic build TEXT [PRG]    the program goes next to the text as .prg by default
+//   petbasic list PRG [TEXT]     the listing goes to stdout by default
+//
+// "-v 1" or "-v 4" before the command chooses the keywords of BASIC 1 or 4, BASIC 2 is
+// the one of the ROMs in the emulator.
+
+use std::io::Write;
+use std::path::Path;
+use std::process::exit;
+
+use pet::basic::{self, BasicError};
+use pet::prg::BasicVersion;
+
+const USAGE: &str = "usage: petbasic [-v 1|2|4] build TEXT [PRG]
+       petbasic [-v 1|2|4] list PRG [TEXT]";
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let (version, args) = match args.as_slice() {
+        ["-v", "1", args @ ..] => (BasicVersion::Basic1, args),
+        ["-v", "2", args @ ..] => (BasicVersion::Basic2, args),
+        ["-v", "4", args @ ..] => (BasicVersion::Basic4, args),
+        ["-v", ..] => usage(),
+        args => (BasicVersion::Basic2, args),
+    };
+
+    let result = match args {
+        ["build", text] => {
+            let prg = Path::new(text).with_extension("prg");
+            build(text, &prg.to_string_lossy(), version)
+        }
+        ["build", text, prg] => build(text, prg, version),
+        ["list", prg] => list(prg, None, version),
+        ["list", prg, text] => list(prg, Some(text), version),
+        _ => usage(),
+    };
+
+    if let Err(error) = result {
+        eprintln!("petbasic: {}", error);
+        exit(1);
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    exit(2);
+}
+
+fn basic_error(file: &str, error: BasicError) -> String {
+    match error {
+        BasicError::LineNumber(line) => format!("{}:{}: no line number or above 63999", file, line),
+        BasicError::LineOrder(line) => format!("{}:{}: the line numbers have to go up", file, line),
+        BasicError::Escape(line) => format!("{}:{}: unknown escape", file, line),
+        BasicError::LineTooLong(line) => format!("{}:{}: line too long", file, line),
+        BasicError::Truncated => format!("{}: not a complete BASIC program", file),
+    }
+}
+
+fn build(text: &str, prg: &str, version: BasicVersion) -> Result<(), String> {
+    let source = std::fs::read(text).map_err(|error| format!("{}: {}", text, error))?;
+    let mut program = Vec::new();
+    basic::tokenize(&source, version, |b| program.push(b))
+        .map_err(|error| basic_error(text, error))?;
+    std::fs::write(prg, &program).map_err(|error| format!("{}: {}", prg, error))
+}
+
+fn list(prg: &str, text: Option<&str>, version: BasicVersion) -> Result<(), String> {
+    let program = std::fs::read(prg).map_err(|error| format!("{}: {}", prg, error))?;
+    let mut listing = Vec::new();
+    basic::detokenize(&program, version, |b| listing.push(b))
+        .map_err(|error| basic_error(prg, error))?;
+    match text {
+        Some(text) => {
+            std::fs::write(text, &listing).map_err(|error| format!("{}: {}", text, error))
+        }
+        None => std::io::stdout()
+            .write_all(&listing)
+            .map_err(|error| error.to_string()),
+    }
+}
@@ -0,0 +1,370 @@
+// Runs a PET program without a display and prints the screen, e.g. to test programs in CI
+//
+//   petrun [OPTIONS] [PROGRAM]
+//
+// PROGRAM is a .prg file or a BASIC listing (.bas, see pet::basic). It's put into memory
+// once BASIC is ready and RUN. The run stops at the first of
+//
+//   --until-ready               BASIC is back in direct mode after the program, the default
+//   --until-text TEXT           the text shows on the screen
+//   --until-write ADDR[=VALUE]  the address is written (with that value), e.g. 0x8000=1
+//   --cycles N                  N cycles ran, 50000000 (50 seconds) by default
+//
+// and the screen is printed as text, or as JSON with --json. Drive 8 serves a folder, the
+// current one or the one given with --disk DIR. --boot NAME loads and runs a program from
+// it like the board does after power-up, instead of PROGRAM.
+//
+// The exit code is 0 if the run stopped as asked, 1 if the cycles ran out first, 3 if it
+// stopped with a BASIC error on the screen and 2 for wrong arguments or files.
+
+use std::cell::Cell;
+use std::path::Path;
+use std::process::exit;
+
+use mos6502::{Cpu, Memory};
+use pet::basic;
+use pet::boot::Autoboot;
+use pet::hostdir::{self, HostDirectory};
+use pet::screen::{self, COLUMNS, READY, ROWS, SCREEN};
+use pet::Ram;
+
+const USAGE: &str = "usage: petrun [--until-ready] [--until-text TEXT] [--until-write ADDR[=VALUE]]
+              [--cycles N] [--disk DIR] [--boot NAME] [--json] [PROGRAM]";
+
+const DEFAULT_CYCLES: u64 = 50_000_000;
+
+// how often the conditions are checked and the keyboard buffer is filled
+const CHECK_CYCLES: u64 = 1000;
+const TYPE_CYCLES: u64 = 20_000;
+
+#[derive(Default)]
+struct Options {
+    until_ready: bool,
+    until_text: Option<String>,
+    until_write: Option<(u16, Option<u8>)>,
+    cycles: u64,
+    disk: String,
+    boot: Option<String>,
+    json: bool,
+    program: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Stop {
+    Ready,
+    Text,
+    Write,
+    Cycles,
+}
+
+impl Stop {
+    fn name(self) -> &'static str {
+        match self {
+            Stop::Ready => "ready",
+            Stop::Text => "text",
+            Stop::Write => "write",
+            Stop::Cycles => "cycles",
+        }
+    }
+}
+
+// the memory as the CPU sees it, noting the writes the run waits for
+struct Watched<'m, 'a> {
+    ram: &'m Ram<'a>,
+    address: Option<(u16, Option<u8>)>,
+    written: Cell<bool>,
+    // the line running, to tell when the program started
+    line_high: u16,
+    typed: Cell<bool>,
+    ran: Cell<bool>,
+    // how much of READY. was just printed, and where, to tell when the program ended
+    ready_len: Cell<usize>,
+    ready_addr: Cell<u16>,
+    finished: Cell<bool>,
+}
+
+impl<'m, 'a> Memory for Watched<'m, 'a> {
+    fn get(&self, addr: u16) -> u8 {
+        self.ram.get(addr)
+    }
+
+    fn set(&self, addr: u16, v: u8) {
+        self.ram.set(addr, v);
+        match self.address {
+            Some((address, value)) if address == addr && value.map_or(true, |value| value == v) => {
+                self.written.set(true)
+            }
+            _ => {}
+        }
+        if addr == self.line_high && v != 0xff && self.typed.get() {
+            self.ran.set(true);
+        }
+        if (SCREEN..SCREEN + (ROWS * COLUMNS) as u16).contains(&addr) {
+            self.printed(addr, v);
+        }
+    }
+}
+
+impl<'m, 'a> Watched<'m, 'a> {
+    fn printed(&self, addr: u16, v: u8) {
+        let len = self.ready_len.get();
+        let len = if v == READY[len] && (len == 0 || addr == self.ready_addr.get() + 1) {
+            len + 1
+        } else {
+            (v == READY[0]) as usize
+        };
+        self.ready_addr.set(addr);
+        if len == READY.len() {
+            self.finished.set(self.ran.get());
+            self.ready_len.set(0);
+        } else {
+            self.ready_len.set(len);
+        }
+    }
+}
+
+fn main() {
+    let options = match parse(std::env::args().skip(1).collect()) {
+        Some(options) => options,
+        None => {
+            eprintln!("{}", USAGE);
+            exit(2);
+        }
+    };
+
+    match run(&options) {
+        Ok((stop, cycles, lines)) => {
+            if options.json {
+                print_json(stop, cycles, &lines);
+            } else {
+                lines.iter().for_each(|line| println!("{}", line));
+            }
+
+            let error = lines
+                .iter()
+                .any(|line| line.starts_with('?') && line.contains(" ERROR"));
+            exit(match stop {
+                Stop::Cycles => 1,
+                Stop::Ready if error => 3,
+                _ => 0,
+            });
+        }
+        Err(error) => {
+            eprintln!("petrun: {}", error);
+            exit(2);
+        }
+    }
+}
+
+fn parse(args: Vec<String>) -> Option<Options> {
+    let mut options = Options {
+        cycles: DEFAULT_CYCLES,
+        disk: String::from("."),
+        ..Default::default()
+    };
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--until-ready" => options.until_ready = true,
+            "--until-text" => options.until_text = Some(args.next()?),
+            "--until-write" => {
+                let arg = args.next()?;
+                options.until_write = Some(match arg.split_once('=') {
+                    Some((address, value)) => {
+                        let value = number(value).filter(|&value| value <= 0xff)?;
+                        (number(address)? as u16, Some(value as u8))
+                    }
+                    None => (number(&arg)? as u16, None),
+                });
+            }
+            "--cycles" => options.cycles = args.next()?.parse().ok()?,
+            "--disk" => options.disk = args.next()?,
+            "--boot" => options.boot = Some(args.next()?),
+            "--json" => options.json = true,
+            arg if arg.starts_with("--") || options.program.is_some() => return None,
+            _ => options.program = Some(arg),
+        }
+    }
+
+    if options.boot.is_some() && options.program.is_some() {
+        return None;
+    }
+    if options.until_text.is_none() && options.until_write.is_none() {
+        options.until_ready = true;
+    }
+    Some(options)
+}
+
+// decimal, or hex with "0x" or "$" in front, up to 0xffff
+fn number(text: &str) -> Option<u32> {
+    let number = match text.strip_prefix("0x").or_else(|| text.strip_prefix('$')) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+        None => text.parse().ok()?,
+    };
+    Some(number).filter(|&number| number <= 0xffff)
+}
+
+// the program as a PRG file, listings are tokenized
+fn read_program(path: &str, version: pet::prg::BasicVersion) -> Result<Vec<u8>, String> {
+    let data = std::fs::read(path).map_err(|error| format!("{}: {}", path, error))?;
+    let listing = Path::new(path)
+        .extension()
+        .map_or(false, |extension| extension.eq_ignore_ascii_case("bas"));
+    if !listing {
+        return Ok(data);
+    }
+
+    let mut program = Vec::new();
+    basic::tokenize(&data, version, |b| program.push(b))
+        .map_err(|error| format!("{}: {:?}", path, error))?;
+    Ok(program)
+}
+
+fn run(options: &Options) -> Result<(Stop, u64, Vec<String>), String> {
+    let mut storage = HostDirectory::new(&options.disk);
+    let mut typist = match &options.boot {
+        Some(name) => Some(
+            Autoboot::find(&mut storage, &hostdir::pet_name(name))
+                .ok_or_else(|| format!("{} isn't in {}", name, options.disk))?,
+        ),
+        None => None,
+    };
+
+    let mut ram = [0u8; 8192];
+    let mut vid_ram = [0u8; 2048];
+    let mem = Ram::new(&mut ram, &mut vid_ram, &mut storage);
+    mem.io.borrow_mut().ieee.set_warp(true);
+    let mut program = match &options.program {
+        Some(path) => Some(read_program(path, mem.basic_version())?),
+        None => None,
+    };
+
+    let watched = Watched {
+        ram: &mem,
+        address: options.until_write,
+        written: Cell::new(false),
+        line_high: mem.basic_version().line_high(),
+        typed: Cell::new(false),
+        ran: Cell::new(false),
+        ready_len: Cell::new(0),
+        ready_addr: Cell::new(0),
+        finished: Cell::new(false),
+    };
+    let mut cpu = Cpu::new(&watched);
+    cpu.reset();
+
+    let mut cycle_cnt: u64 = 0;
+    let mut cycles: u64 = 0;
+    let mut tick_cntr = 0u64;
+    let mut type_cntr = 0u64;
+    let stop = loop {
+        if cycle_cnt == 0 {
+            cycle_cnt = cpu.step();
+            if mem.io.borrow_mut().step(cycle_cnt as u16) {
+                cpu.trigger_irq();
+            }
+        }
+        cycle_cnt -= 1;
+        cycles += 1;
+
+        tick_cntr += 1;
+        if tick_cntr < CHECK_CYCLES {
+            continue;
+        }
+        tick_cntr = 0;
+        if mem.io.borrow_mut().tick() {
+            cpu.trigger_irq();
+        }
+
+        type_cntr += CHECK_CYCLES;
+        if type_cntr >= TYPE_CYCLES {
+            type_cntr = 0;
+            // the program is put into memory once BASIC cleared it
+            if let Some(prg) = &program {
+                if screen::shows_ready(&mem) {
+                    mem.load_prg(prg).map_err(|error| format!("{:?}", error))?;
+                    program = None;
+                    typist = Some(Autoboot::run());
+                }
+            }
+            if let Some(typing) = &mut typist {
+                if typing.step(&mem) {
+                    typist = None;
+                    watched.typed.set(true);
+                }
+            }
+        }
+
+        if watched.written.get() {
+            break Stop::Write;
+        }
+        if let Some(text) = &options.until_text {
+            if screen_lines(&mem)
+                .iter()
+                .any(|line| line.contains(text.as_str()))
+            {
+                break Stop::Text;
+            }
+        }
+        let starting = program.is_some() || typist.is_some();
+        if options.until_ready && ready(&mem, &watched, starting) {
+            break Stop::Ready;
+        }
+        if cycles >= options.cycles {
+            break Stop::Cycles;
+        }
+    };
+
+    let lines = screen_lines(&mem);
+    Ok((stop, cycles, lines))
+}
+
+// BASIC printed READY. after the program ran, or is at the prompt if nothing runs
+fn ready(mem: &Ram, watched: &Watched, starting: bool) -> bool {
+    if watched.finished.get() {
+        true
+    } else if starting || watched.typed.get() {
+        false
+    } else {
+        screen::shows_ready(mem)
+    }
+}
+
+// without the spaces at their ends
+fn screen_lines(mem: &Ram) -> Vec<String> {
+    let lowercase = mem.io.borrow().lowercase_charset();
+    (0..ROWS)
+        .map(|row| {
+            let line: String = (0..COLUMNS)
+                .map(|column| {
+                    let code = mem.get(SCREEN + (row * COLUMNS + column) as u16);
+                    screen::to_char(code, lowercase)
+                })
+                .collect();
+            line.trim_end().to_string()
+        })
+        .collect()
+}
+
+fn print_json(stop: Stop, cycles: u64, lines: &[String]) {
+    let lines: Vec<String> = lines.iter().map(|line| json_string(line)).collect();
+    println!(
+        "{{\"stop\":\"{}\",\"cycles\":{},\"screen\":[{}]}}",
+        stop.name(),
+        cycles,
+        lines.join(",")
+    );
+}
+
+fn json_string(text: &str) -> String {
+    let mut json = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
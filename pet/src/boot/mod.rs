@@ -0,0 +1,104 @@
+// Loading and running a program after power-up
+//
+// Once BASIC shows its READY. prompt `LOAD"NAME",8` and `RUN` are put into the keyboard
+// buffer of the editor, as if they were typed. The buffer only holds 10 keys, the command
+// is given in parts whenever it's empty. A key held down when the prompt shows up skips
+// it, so a broken boot program can't lock the user out. On the host, `petrun --boot NAME`
+// boots a program from a folder the same way.
+
+use mos6502::Memory;
+
+use crate::dos::MAX_NAME_LEN;
+use crate::io::Storage;
+use crate::screen;
+use crate::Ram;
+
+/// The program run after power-up if there is one
+pub const BOOT_NAME: &[u8] = b"BOOT";
+
+// LOAD"",8 and RUN with their returns
+const MAX_COMMAND_LEN: usize = MAX_NAME_LEN + 15;
+
+// the keyboard buffer of the editor in BASIC 2
+const KEYBOARD_BUFFER: u16 = 0x026f;
+const KEYBOARD_BUFFER_LEN: u16 = 0x9e;
+const KEYBOARD_BUFFER_SIZE: usize = 10;
+
+/// Types the commands loading and running a program once BASIC is ready.
+#[derive(Debug)]
+pub struct Autoboot {
+    command: [u8; MAX_COMMAND_LEN],
+    len: usize,
+    typed: usize,
+}
+
+impl Autoboot {
+    /// Boots the program from the drive with the given address
+    pub fn new(name: &[u8], device: u8) -> Autoboot {
+        let mut boot = Autoboot {
+            command: [0u8; MAX_COMMAND_LEN],
+            len: 0,
+            typed: 0,
+        };
+        boot.push(b"LOAD\"");
+        boot.push(&name[..name.len().min(MAX_NAME_LEN)]);
+        boot.push(b"\",");
+        if device >= 10 {
+            boot.push(&[b'0' + device / 10]);
+        }
+        boot.push(&[b'0' + device % 10, b'\r']);
+        boot.push(b"RUN\r");
+        boot
+    }
+
+    /// Only types RUN, for a program put into memory already
+    pub fn run() -> Autoboot {
+        let mut boot = Autoboot {
+            command: [0u8; MAX_COMMAND_LEN],
+            len: 0,
+            typed: 0,
+        };
+        boot.push(b"RUN\r");
+        boot
+    }
+
+    /// Boots the program from drive 8 if the storage has it
+    pub fn find(storage: &mut dyn Storage, name: &[u8]) -> Option<Autoboot> {
+        storage.start_filename();
+        name.iter().for_each(|&c| storage.next_filename_byte(c));
+        storage.fname_done();
+        match storage.load_data_len() {
+            Ok(_) => Some(Autoboot::new(name, 8)),
+            Err(_) => None,
+        }
+    }
+
+    fn push(&mut self, text: &[u8]) {
+        self.command[self.len..self.len + text.len()].copy_from_slice(text);
+        self.len += text.len();
+    }
+
+    /// Types the next part of the command when BASIC waits for it, to be called regularly
+    /// after the keyboard was scanned. True once everything was typed or a key skipped it.
+    pub fn step(&mut self, mem: &Ram) -> bool {
+        if self.typed == 0 {
+            if !screen::shows_ready(mem) {
+                return false;
+            }
+            if mem.io.borrow().keyboard.any_key_down() {
+                return true;
+            }
+        }
+
+        if mem.get(KEYBOARD_BUFFER_LEN) == 0 {
+            let part = &self.command[self.typed..self.len];
+            let part = &part[..part.len().min(KEYBOARD_BUFFER_SIZE)];
+            for (i, &c) in part.iter().enumerate() {
+                mem.set(KEYBOARD_BUFFER + i as u16, c);
+            }
+            mem.set(KEYBOARD_BUFFER_LEN, part.len() as u8);
+            self.typed += part.len();
+        }
+        self.typed == self.len
+    }
+}
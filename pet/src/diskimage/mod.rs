@@ -0,0 +1,992 @@
+// Commodore disk images (.d64 for the 2040/4040, .d80 for the 8050, .d82 for the 8250)
+//
+// An image is the sequence of the 256 byte blocks of a disk, ordered by track and sector.
+// Files are chains of blocks, the first two bytes of a block point to the next one. The
+// last block of a chain has track 0 and the index of its last used byte instead.
+// The BAM (block availability map) has a bit for every sector, set if the sector is free.
+
+use crate::dos::listing::{Directory, DirectoryEntry, DiskHeader};
+use crate::dos::volume::{Channels, Volume};
+use crate::dos::{self, new_name, valid_name, DosError, FileSystem, MAX_NAME_LEN};
+use crate::io::{FileMode, FileType};
+
+pub const BLOCK_SIZE: usize = 256;
+
+const PADDING: u8 = 0xa0;
+
+const ENTRY_SIZE: usize = 32;
+const ENTRIES_PER_BLOCK: u8 = 8;
+const ENTRY_TYPE: usize = 2;
+const ENTRY_FIRST_BLOCK: usize = 3;
+const ENTRY_NAME: usize = 5;
+const ENTRY_BLOCKS: usize = 30;
+
+const TYPE_MASK: u8 = 0x07;
+const TYPE_CLOSED: u8 = 0x80;
+const TYPE_LOCKED: u8 = 0x40;
+const TYPE_SEQ: u8 = 1;
+const TYPE_PRG: u8 = 2;
+const TYPE_USR: u8 = 3;
+const TYPE_REL: u8 = 4;
+
+const DIRECTORY_INTERLEAVE: u8 = 3;
+
+/// Reading or writing a block failed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlockError;
+
+/// Storage made of 256 byte blocks, e.g. an image file on the host, SPI flash or an SD card
+pub trait BlockDevice {
+    /// The number of blocks
+    fn blocks(&self) -> u32;
+
+    fn read_block(&mut self, block: u32, data: &mut [u8; BLOCK_SIZE]) -> Result<(), BlockError>;
+
+    fn write_block(&mut self, block: u32, data: &[u8; BLOCK_SIZE]) -> Result<(), BlockError>;
+}
+
+impl BlockDevice for &mut [u8] {
+    fn blocks(&self) -> u32 {
+        (self.len() / BLOCK_SIZE) as u32
+    }
+
+    fn read_block(&mut self, block: u32, data: &mut [u8; BLOCK_SIZE]) -> Result<(), BlockError> {
+        let start = block as usize * BLOCK_SIZE;
+        let source = self.get(start..start + BLOCK_SIZE).ok_or(BlockError)?;
+        data.copy_from_slice(source);
+        Ok(())
+    }
+
+    fn write_block(&mut self, block: u32, data: &[u8; BLOCK_SIZE]) -> Result<(), BlockError> {
+        let start = block as usize * BLOCK_SIZE;
+        let destination = self.get_mut(start..start + BLOCK_SIZE).ok_or(BlockError)?;
+        destination.copy_from_slice(data);
+        Ok(())
+    }
+}
+
+/// The disk formats of the drives
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// 2040/4040, 35 tracks
+    D64,
+    /// 8050, 77 tracks
+    D80,
+    /// 8250, 77 tracks on both sides
+    D82,
+}
+
+const D64_BAM: [(u8, u8); 1] = [(18, 0)];
+const D80_BAM: [(u8, u8); 2] = [(38, 0), (38, 3)];
+const D82_BAM: [(u8, u8); 4] = [(38, 0), (38, 3), (38, 6), (38, 9)];
+
+// tracks covered by one BAM block of the 8050 and 8250
+const TRACKS_PER_BAM_BLOCK: u8 = 50;
+
+impl Format {
+    /// Guesses the format from the size of an image, images with error information
+    /// appended are accepted too.
+    pub fn from_blocks(blocks: u32) -> Option<Format> {
+        match blocks {
+            683..=685 => Some(Format::D64),
+            2083..=2091 => Some(Format::D80),
+            4166..=4182 => Some(Format::D82),
+            _ => None,
+        }
+    }
+
+    pub fn tracks(self) -> u8 {
+        match self {
+            Format::D64 => 35,
+            Format::D80 => 77,
+            Format::D82 => 154,
+        }
+    }
+
+    pub fn sectors(self, track: u8) -> u8 {
+        match self {
+            Format::D64 => match track {
+                1..=17 => 21,
+                18..=24 => 19,
+                25..=30 => 18,
+                _ => 17,
+            },
+            Format::D80 | Format::D82 => match (track - 1) % 77 + 1 {
+                1..=39 => 29,
+                40..=53 => 27,
+                54..=64 => 25,
+                _ => 23,
+            },
+        }
+    }
+
+    /// The number of blocks of an image in this format
+    pub fn blocks(self) -> u32 {
+        (1..=self.tracks())
+            .map(|track| self.sectors(track) as u32)
+            .sum()
+    }
+
+    // index of a block in the image
+    fn block(self, (track, sector): (u8, u8)) -> Option<u32> {
+        if track == 0 || track > self.tracks() || sector >= self.sectors(track) {
+            return None;
+        }
+        let before: u32 = (1..track).map(|track| self.sectors(track) as u32).sum();
+        Some(before + sector as u32)
+    }
+
+    fn directory_track(self) -> u8 {
+        match self {
+            Format::D64 => 18,
+            Format::D80 | Format::D82 => 39,
+        }
+    }
+
+    fn header(self) -> (u8, u8) {
+        (self.directory_track(), 0)
+    }
+
+    fn first_directory_block(self) -> (u8, u8) {
+        (self.directory_track(), 1)
+    }
+
+    fn bam_blocks(self) -> &'static [(u8, u8)] {
+        match self {
+            Format::D64 => &D64_BAM,
+            Format::D80 => &D80_BAM,
+            Format::D82 => &D82_BAM,
+        }
+    }
+
+    // block and offset of the free count of a track, the bitmap follows it
+    fn bam_entry(self, track: u8) -> ((u8, u8), usize) {
+        match self {
+            Format::D64 => (D64_BAM[0], 4 + 4 * (track as usize - 1)),
+            Format::D80 | Format::D82 => {
+                let index = (track - 1) / TRACKS_PER_BAM_BLOCK;
+                let offset = 6 + 5 * ((track - 1) % TRACKS_PER_BAM_BLOCK) as usize;
+                (self.bam_blocks()[index as usize], offset)
+            }
+        }
+    }
+
+    fn interleave(self) -> u8 {
+        match self {
+            Format::D64 => 10,
+            Format::D80 | Format::D82 => 6,
+        }
+    }
+
+    fn dos_version(self) -> u8 {
+        match self {
+            Format::D64 => b'A',
+            Format::D80 | Format::D82 => b'C',
+        }
+    }
+
+    // offsets of the disk name and id in the header block
+    fn name_offset(self) -> usize {
+        match self {
+            Format::D64 => 0x90,
+            Format::D80 | Format::D82 => 0x06,
+        }
+    }
+
+    fn id_offset(self) -> usize {
+        self.name_offset() + 0x12
+    }
+}
+
+// a directory entry: the directory block and the index of the entry in it
+pub(crate) type Slot = ((u8, u8), u8);
+
+// listings walk the directory with a cursor, 0 is before the first slot
+fn slot_cursor(((track, sector), index): Slot) -> usize {
+    ((track as usize) << 16 | (sector as usize) << 8 | index as usize) + 1
+}
+
+fn cursor_slot(cursor: usize) -> Option<Slot> {
+    let value = cursor.checked_sub(1)?;
+    Some((((value >> 16) as u8, (value >> 8) as u8), value as u8))
+}
+
+// a file opened on a channel
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Channel {
+    slot: Slot,
+    block: (u8, u8),
+    position: usize,
+    blocks: u16,
+    writing: bool,
+}
+
+/// A disk image on a `BlockDevice`
+pub struct DiskImage<D: BlockDevice> {
+    device: D,
+    format: Format,
+
+    buffer: [u8; BLOCK_SIZE],
+    buffer_block: Option<(u8, u8)>,
+    dirty: bool,
+
+    channels: Channels<Channel>,
+}
+
+impl<D: BlockDevice> core::fmt::Debug for DiskImage<D> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "DiskImage {:?}", self.format)
+    }
+}
+
+fn type_byte(file_type: FileType) -> u8 {
+    match file_type {
+        FileType::Seq => TYPE_SEQ,
+        FileType::Prg => TYPE_PRG,
+        FileType::Usr => TYPE_USR,
+        FileType::Rel => TYPE_REL,
+    }
+}
+
+fn stored_type(value: u8) -> Option<FileType> {
+    match value & TYPE_MASK {
+        TYPE_SEQ => Some(FileType::Seq),
+        TYPE_PRG => Some(FileType::Prg),
+        TYPE_USR => Some(FileType::Usr),
+        TYPE_REL => Some(FileType::Rel),
+        _ => None,
+    }
+}
+
+fn entry_matches(pattern: &[u8], entry: &[u8]) -> bool {
+    let stored = &entry[ENTRY_NAME..ENTRY_NAME + MAX_NAME_LEN];
+    let len = stored
+        .iter()
+        .position(|&c| c == PADDING)
+        .unwrap_or(MAX_NAME_LEN);
+    entry[ENTRY_TYPE] != 0 && dos::matches(pattern, &stored[..len])
+}
+
+impl<D: BlockDevice> DiskImage<D> {
+    /// Opens an image, the format is guessed from its size
+    pub fn new(device: D) -> Option<DiskImage<D>> {
+        let format = Format::from_blocks(device.blocks())?;
+        Some(DiskImage::with_format(device, format))
+    }
+
+    pub fn with_format(device: D, format: Format) -> DiskImage<D> {
+        DiskImage {
+            device,
+            format,
+            buffer: [0u8; BLOCK_SIZE],
+            buffer_block: None,
+            dirty: false,
+            channels: Channels::new(),
+        }
+    }
+
+    pub fn disk_format(&self) -> Format {
+        self.format
+    }
+
+    /// Gives back the block device, everything written is flushed first
+    pub fn into_device(mut self) -> Result<D, DosError> {
+        self.flush()?;
+        Ok(self.device)
+    }
+
+    /// Writes the block in the buffer back to the device
+    pub fn flush(&mut self) -> Result<(), DosError> {
+        if let (true, Some(block)) = (self.dirty, self.buffer_block) {
+            let index = self
+                .format
+                .block(block)
+                .ok_or(DosError::IllegalTrackOrSector)?;
+            self.device
+                .write_block(index, &self.buffer)
+                .map_err(|_| DosError::WriteError)?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+
+    /// The number of free blocks as shown in the directory
+    pub fn free_blocks(&mut self) -> Result<u16, DosError> {
+        let mut free = 0u16;
+        for track in 1..=self.format.tracks() {
+            if track != self.format.directory_track() {
+                let (block, offset) = self.format.bam_entry(track);
+                free += self.block(block)?[offset] as u16;
+            }
+        }
+        Ok(free)
+    }
+
+    fn block(&mut self, block: (u8, u8)) -> Result<&[u8; BLOCK_SIZE], DosError> {
+        self.fetch(block)?;
+        Ok(&self.buffer)
+    }
+
+    fn block_mut(&mut self, block: (u8, u8)) -> Result<&mut [u8; BLOCK_SIZE], DosError> {
+        self.fetch(block)?;
+        self.dirty = true;
+        Ok(&mut self.buffer)
+    }
+
+    // a block whose old content doesn't matter
+    fn new_block(&mut self, block: (u8, u8)) -> Result<&mut [u8; BLOCK_SIZE], DosError> {
+        self.format
+            .block(block)
+            .ok_or(DosError::IllegalTrackOrSector)?;
+        self.flush()?;
+        self.buffer = [0u8; BLOCK_SIZE];
+        self.buffer_block = Some(block);
+        self.dirty = true;
+        Ok(&mut self.buffer)
+    }
+
+    fn fetch(&mut self, block: (u8, u8)) -> Result<(), DosError> {
+        if self.buffer_block == Some(block) {
+            return Ok(());
+        }
+
+        let index = self
+            .format
+            .block(block)
+            .ok_or(DosError::IllegalTrackOrSector)?;
+        self.flush()?;
+        self.buffer_block = None;
+        self.device
+            .read_block(index, &mut self.buffer)
+            .map_err(|_| DosError::ReadError)?;
+        self.buffer_block = Some(block);
+        Ok(())
+    }
+
+    fn link(&mut self, block: (u8, u8)) -> Result<(u8, u8), DosError> {
+        let data = self.block(block)?;
+        Ok((data[0], data[1]))
+    }
+
+    // BAM
+
+    fn is_free(&mut self, (track, sector): (u8, u8)) -> Result<bool, DosError> {
+        let (block, offset) = self.format.bam_entry(track);
+        let bits = self.block(block)?[offset + 1 + sector as usize / 8];
+        Ok(bits & (1 << (sector % 8)) != 0)
+    }
+
+    fn set_free(&mut self, (track, sector): (u8, u8), free: bool) -> Result<(), DosError> {
+        if self.format.block((track, sector)).is_none() {
+            return Err(DosError::IllegalTrackOrSector);
+        }
+        if self.is_free((track, sector))? == free {
+            return Ok(());
+        }
+
+        let (block, offset) = self.format.bam_entry(track);
+        let data = self.block_mut(block)?;
+        let bit = 1 << (sector % 8);
+        // the count doesn't match the bits on a broken BAM
+        if free {
+            data[offset] = data[offset].checked_add(1).ok_or(DosError::DirError)?;
+            data[offset + 1 + sector as usize / 8] |= bit;
+        } else {
+            data[offset] = data[offset].checked_sub(1).ok_or(DosError::DirError)?;
+            data[offset + 1 + sector as usize / 8] &= !bit;
+        }
+        Ok(())
+    }
+
+    fn set_track_free(&mut self, track: u8) -> Result<(), DosError> {
+        let sectors = self.format.sectors(track);
+        let bytes = match self.format {
+            Format::D64 => 3,
+            Format::D80 | Format::D82 => 4,
+        };
+        let (block, offset) = self.format.bam_entry(track);
+        let data = self.block_mut(block)?;
+        data[offset] = sectors;
+        for i in 0..bytes {
+            let first = i as u8 * 8;
+            data[offset + 1 + i] = match sectors.saturating_sub(first) {
+                0 => 0,
+                n if n >= 8 => 0xff,
+                n => (1 << n) - 1,
+            };
+        }
+        Ok(())
+    }
+
+    // the first free sector of the track, starting the search at the given sector
+    fn allocate_on(&mut self, track: u8, start: u8) -> Result<Option<(u8, u8)>, DosError> {
+        let sectors = self.format.sectors(track);
+        let (block, offset) = self.format.bam_entry(track);
+        if self.block(block)?[offset] == 0 {
+            return Ok(None);
+        }
+
+        for i in 0..sectors {
+            let sector = (start + i) % sectors;
+            if self.is_free((track, sector))? {
+                self.set_free((track, sector), false)?;
+                return Ok(Some((track, sector)));
+            }
+        }
+        Ok(None)
+    }
+
+    // a block for file data, near to the previous one or else close to the directory
+    fn allocate(&mut self, previous: Option<(u8, u8)>) -> Result<(u8, u8), DosError> {
+        if let Some((track, sector)) = previous {
+            let start = (sector + self.format.interleave()) % self.format.sectors(track);
+            if let Some(block) = self.allocate_on(track, start)? {
+                return Ok(block);
+            }
+        }
+
+        let directory = self.format.directory_track();
+        for distance in 1..self.format.tracks() {
+            let tracks = [
+                directory.checked_sub(distance),
+                directory.checked_add(distance),
+            ];
+            for track in tracks.iter().flatten() {
+                if *track >= 1 && *track <= self.format.tracks() {
+                    if let Some(block) = self.allocate_on(*track, 0)? {
+                        return Ok(block);
+                    }
+                }
+            }
+        }
+        Err(DosError::DiskFull)
+    }
+
+    fn free_chain(&mut self, first: (u8, u8)) -> Result<(), DosError> {
+        let mut block = first;
+        for _ in 0..self.format.blocks() {
+            if block.0 == 0 {
+                return Ok(());
+            }
+            let next = self.link(block)?;
+            self.set_free(block, true)?;
+            block = next;
+        }
+        Err(DosError::IllegalTrackOrSector)
+    }
+
+    // directory
+
+    fn entry(&mut self, (block, index): Slot) -> Result<&[u8], DosError> {
+        let offset = index as usize * ENTRY_SIZE;
+        Ok(&self.block(block)?[offset..offset + ENTRY_SIZE])
+    }
+
+    fn entry_mut(&mut self, (block, index): Slot) -> Result<&mut [u8], DosError> {
+        let offset = index as usize * ENTRY_SIZE;
+        Ok(&mut self.block_mut(block)?[offset..offset + ENTRY_SIZE])
+    }
+
+    fn first_block(&mut self, slot: Slot) -> Result<(u8, u8), DosError> {
+        let entry = self.entry(slot)?;
+        Ok((entry[ENTRY_FIRST_BLOCK], entry[ENTRY_FIRST_BLOCK + 1]))
+    }
+
+    // the slot after the given one, the first one for None
+    fn next_slot(&mut self, slot: Option<Slot>) -> Result<Option<Slot>, DosError> {
+        match slot {
+            None => Ok(Some((self.format.first_directory_block(), 0))),
+            Some((block, index)) if index + 1 < ENTRIES_PER_BLOCK => Ok(Some((block, index + 1))),
+            Some((block, _)) => match self.link(block)? {
+                (0, _) => Ok(None),
+                next => Ok(Some((next, 0))),
+            },
+        }
+    }
+
+    // calls `f` for every slot until it returns true
+    fn find_slot(&mut self, mut f: impl FnMut(&[u8]) -> bool) -> Result<Option<Slot>, DosError> {
+        let mut slot = None;
+        for _ in 0..self.format.sectors(self.format.directory_track()) as usize * 8 {
+            slot = match self.next_slot(slot)? {
+                Some(slot) => Some(slot),
+                None => return Ok(None),
+            };
+            if let Some(slot) = slot {
+                if f(self.entry(slot)?) {
+                    return Ok(Some(slot));
+                }
+            }
+        }
+        Err(DosError::DirError)
+    }
+
+    fn set_name(&mut self, slot: Slot, name: &[u8]) -> Result<(), DosError> {
+        let entry = self.entry_mut(slot)?;
+        for i in 0..MAX_NAME_LEN {
+            entry[ENTRY_NAME + i] = name.get(i).copied().unwrap_or(PADDING);
+        }
+        Ok(())
+    }
+
+    // a free directory entry, the directory grows if there is none
+    fn free_slot(&mut self) -> Result<Slot, DosError> {
+        if let Some(slot) = self.find_slot(|entry| entry[ENTRY_TYPE] == 0)? {
+            return Ok(slot);
+        }
+
+        let mut last = self.format.first_directory_block();
+        for _ in 0..self.format.sectors(self.format.directory_track()) {
+            match self.link(last)? {
+                (0, _) => break,
+                next => last = next,
+            }
+        }
+
+        let track = self.format.directory_track();
+        let start = (last.1 + DIRECTORY_INTERLEAVE) % self.format.sectors(track);
+        let block = self.allocate_on(track, start)?.ok_or(DosError::DiskFull)?;
+        let data = self.block_mut(last)?;
+        data[0] = block.0;
+        data[1] = block.1;
+        let data = self.new_block(block)?;
+        data[1] = 0xff;
+        Ok((block, 0))
+    }
+
+    // files
+
+    fn scratch_slot(&mut self, slot: Slot) -> Result<(), DosError> {
+        let entry = self.entry(slot)?;
+        let closed = entry[ENTRY_TYPE] & TYPE_CLOSED != 0;
+        let first = (entry[ENTRY_FIRST_BLOCK], entry[ENTRY_FIRST_BLOCK + 1]);
+        if closed {
+            self.free_chain(first)?;
+        }
+        self.entry_mut(slot)?[ENTRY_TYPE] = 0;
+        Ok(())
+    }
+
+    fn create_file(
+        &mut self,
+        name: &[u8],
+        file_type: u8,
+        replace: bool,
+    ) -> Result<Channel, DosError> {
+        let name = new_name(name)?;
+        if let Some(slot) = self.find(name)? {
+            if !replace {
+                return Err(DosError::FileExists);
+            }
+            if self.entry(slot)?[ENTRY_TYPE] & TYPE_LOCKED != 0 {
+                return Err(DosError::WriteProtectOn);
+            }
+            self.scratch_slot(slot)?;
+        }
+
+        let slot = self.free_slot()?;
+        let first = self.allocate(None)?;
+
+        let entry = self.entry_mut(slot)?;
+        entry[ENTRY_TYPE] = file_type;
+        entry[ENTRY_FIRST_BLOCK] = first.0;
+        entry[ENTRY_FIRST_BLOCK + 1] = first.1;
+        for b in entry[ENTRY_NAME + MAX_NAME_LEN..].iter_mut() {
+            *b = 0;
+        }
+        self.set_name(slot, name)?;
+
+        self.new_block(first)?[1] = 1;
+        Ok(Channel {
+            slot,
+            block: first,
+            position: 2,
+            blocks: 1,
+            writing: true,
+        })
+    }
+
+    fn open_read(&mut self, slot: Slot) -> Result<Channel, DosError> {
+        Ok(Channel {
+            slot,
+            block: self.first_block(slot)?,
+            position: 2,
+            blocks: 0,
+            writing: false,
+        })
+    }
+
+    fn open_append(&mut self, slot: Slot) -> Result<Channel, DosError> {
+        if self.entry(slot)?[ENTRY_TYPE] & TYPE_LOCKED != 0 {
+            return Err(DosError::WriteProtectOn);
+        }
+        let mut block = self.first_block(slot)?;
+        let mut blocks = 1;
+        for _ in 0..self.format.blocks() {
+            match self.link(block)? {
+                (0, last) => {
+                    self.entry_mut(slot)?[ENTRY_TYPE] &= !TYPE_CLOSED;
+                    return Ok(Channel {
+                        slot,
+                        block,
+                        position: last as usize + 1,
+                        blocks,
+                        writing: true,
+                    });
+                }
+                next => {
+                    block = next;
+                    blocks += 1;
+                }
+            }
+        }
+        Err(DosError::IllegalTrackOrSector)
+    }
+
+    fn close_channel(&mut self, channel: &Channel) -> Result<(), DosError> {
+        if channel.writing {
+            let data = self.block_mut(channel.block)?;
+            data[0] = 0;
+            data[1] = (channel.position - 1) as u8;
+
+            let entry = self.entry_mut(channel.slot)?;
+            entry[ENTRY_TYPE] |= TYPE_CLOSED;
+            entry[ENTRY_BLOCKS] = channel.blocks as u8;
+            entry[ENTRY_BLOCKS + 1] = (channel.blocks >> 8) as u8;
+        }
+        self.flush()
+    }
+
+    // the number of bytes in the file of the slot
+    fn file_len(&mut self, slot: Slot) -> Result<usize, DosError> {
+        let mut block = self.first_block(slot)?;
+        let mut len = 0;
+        for _ in 0..self.format.blocks() {
+            match self.link(block)? {
+                (0, last) => return Ok(len + (last as usize).saturating_sub(1)),
+                next => {
+                    len += BLOCK_SIZE - 2;
+                    block = next;
+                }
+            }
+        }
+        Err(DosError::IllegalTrackOrSector)
+    }
+}
+
+impl<D: BlockDevice> Volume for DiskImage<D> {
+    type File = Slot;
+    type Channel = Channel;
+
+    fn channels(&mut self) -> &mut Channels<Channel> {
+        &mut self.channels
+    }
+
+    // the first file matching the pattern
+    fn find(&mut self, name: &[u8]) -> Result<Option<Slot>, DosError> {
+        let name = valid_name(name)?;
+        self.find_slot(|entry| entry_matches(name, entry))
+    }
+
+    fn file_type(&mut self, &slot: &Slot) -> Result<FileType, DosError> {
+        stored_type(self.entry(slot)?[ENTRY_TYPE]).ok_or(DosError::FileTypeMismatch)
+    }
+
+    fn create(
+        &mut self,
+        name: &[u8],
+        file_type: FileType,
+        replace: bool,
+    ) -> Result<Channel, DosError> {
+        self.create_file(name, type_byte(file_type), replace)
+    }
+
+    fn open_file(&mut self, &slot: &Slot, mode: FileMode) -> Result<Channel, DosError> {
+        match mode {
+            FileMode::Append => self.open_append(slot),
+            _ => self.open_read(slot),
+        }
+    }
+
+    fn rewind(&mut self, channel: &mut Channel) -> Result<(), DosError> {
+        *channel = self.open_read(channel.slot)?;
+        Ok(())
+    }
+
+    fn read_len(&mut self, channel: &Channel) -> Result<usize, DosError> {
+        self.file_len(channel.slot)
+    }
+
+    fn is_writing(channel: &Channel) -> bool {
+        channel.writing
+    }
+
+    fn read_byte(&mut self, channel: &mut Channel) -> Result<Option<(u8, bool)>, DosError> {
+        for _ in 0..self.format.blocks() {
+            let data = self.block(channel.block)?;
+            let end = match data[0] {
+                0 => data[1] as usize + 1,
+                _ => BLOCK_SIZE,
+            };
+
+            if channel.position < end {
+                let value = data[channel.position];
+                let last = data[0] == 0 && channel.position + 1 == end;
+                channel.position += 1;
+                return Ok(Some((value, last)));
+            }
+
+            match data[0] {
+                0 => return Ok(None),
+                track => {
+                    channel.block = (track, data[1]);
+                    channel.position = 2;
+                }
+            }
+        }
+        Err(DosError::IllegalTrackOrSector)
+    }
+
+    fn write_byte(&mut self, channel: &mut Channel, value: u8) -> Result<(), DosError> {
+        if channel.position == BLOCK_SIZE {
+            let next = self.allocate(Some(channel.block))?;
+            let data = self.block_mut(channel.block)?;
+            data[0] = next.0;
+            data[1] = next.1;
+            self.new_block(next)?;
+            channel.block = next;
+            channel.position = 2;
+            channel.blocks += 1;
+        }
+
+        self.block_mut(channel.block)?[channel.position] = value;
+        channel.position += 1;
+        Ok(())
+    }
+
+    fn close_file(&mut self, channel: Channel) -> Result<(), DosError> {
+        self.close_channel(&channel)
+    }
+}
+
+impl<D: BlockDevice> Directory for DiskImage<D> {
+    fn disk_header(&mut self) -> Result<DiskHeader, DosError> {
+        let format = self.format;
+        let header = self.block(format.header())?;
+        Ok(DiskHeader::new(
+            &header[format.name_offset()..format.name_offset() + MAX_NAME_LEN],
+            &header[format.id_offset()..format.id_offset() + 5],
+        ))
+    }
+
+    fn next_entry(&mut self, cursor: &mut usize) -> Result<Option<DirectoryEntry>, DosError> {
+        let mut slot = cursor_slot(*cursor);
+        for _ in 0..self.format.sectors(self.format.directory_track()) as usize * 8 {
+            slot = match self.next_slot(slot)? {
+                Some(slot) => Some(slot),
+                None => return Ok(None),
+            };
+            let current = slot.ok_or(DosError::DirError)?;
+            *cursor = slot_cursor(current);
+
+            let entry = self.entry(current)?;
+            let file_type = match stored_type(entry[ENTRY_TYPE]) {
+                Some(file_type) => file_type,
+                // scratched entries and DEL files
+                None => continue,
+            };
+
+            let stored = &entry[ENTRY_NAME..ENTRY_NAME + MAX_NAME_LEN];
+            let len = stored
+                .iter()
+                .position(|&c| c == PADDING)
+                .unwrap_or(MAX_NAME_LEN);
+            let blocks = entry[ENTRY_BLOCKS] as u16 | (entry[ENTRY_BLOCKS + 1] as u16) << 8;
+            let mut listed = DirectoryEntry::new(&stored[..len], file_type, blocks);
+            listed.closed = entry[ENTRY_TYPE] & TYPE_CLOSED != 0;
+            listed.locked = entry[ENTRY_TYPE] & TYPE_LOCKED != 0;
+            return Ok(Some(listed));
+        }
+        Err(DosError::DirError)
+    }
+
+    fn blocks_free(&mut self) -> Result<u16, DosError> {
+        self.free_blocks()
+    }
+}
+
+impl<D: BlockDevice> FileSystem for DiskImage<D> {
+    // locked files are skipped
+    fn scratch(&mut self, name: &[u8]) -> Result<u8, DosError> {
+        let name = valid_name(name)?;
+        let mut scratched = 0u8;
+        while let Some(slot) = self
+            .find_slot(|entry| entry_matches(name, entry) && entry[ENTRY_TYPE] & TYPE_LOCKED == 0)?
+        {
+            self.scratch_slot(slot)?;
+            scratched = scratched.saturating_add(1);
+        }
+        self.flush()?;
+        Ok(scratched)
+    }
+
+    fn rename(&mut self, old: &[u8], new: &[u8]) -> Result<(), DosError> {
+        let new = new_name(new)?;
+        if self.find(new)?.is_some() {
+            return Err(DosError::FileExists);
+        }
+        let slot = self.find(old)?.ok_or(DosError::FileNotFound)?;
+        self.set_name(slot, new)?;
+        self.flush()
+    }
+
+    fn copy(&mut self, source: &[u8], destination: &[u8]) -> Result<(), DosError> {
+        let slot = self.find(source)?.ok_or(DosError::FileNotFound)?;
+        let file_type = self.entry(slot)?[ENTRY_TYPE] & TYPE_MASK;
+        let mut from = self.open_read(slot)?;
+        let mut to = self.create_file(destination, file_type, false)?;
+
+        while let Some((value, _)) = self.read_byte(&mut from)? {
+            self.write_byte(&mut to, value)?;
+        }
+        self.close_channel(&to)
+    }
+
+    fn lock(&mut self, name: &[u8], locked: Option<bool>) -> Result<u8, DosError> {
+        let name = valid_name(name)?;
+        let mut matched = 0u8;
+        let mut slot = None;
+        for _ in 0..self.format.sectors(self.format.directory_track()) as usize * 8 {
+            slot = match self.next_slot(slot)? {
+                Some(slot) => Some(slot),
+                None => break,
+            };
+            if let Some(slot) = slot {
+                let entry = self.entry(slot)?;
+                if !entry_matches(name, entry) {
+                    continue;
+                }
+                matched = matched.saturating_add(1);
+                let entry_type = entry[ENTRY_TYPE];
+                self.entry_mut(slot)?[ENTRY_TYPE] =
+                    match locked.unwrap_or(entry_type & TYPE_LOCKED == 0) {
+                        true => entry_type | TYPE_LOCKED,
+                        false => entry_type & !TYPE_LOCKED,
+                    };
+            }
+        }
+        self.flush()?;
+        Ok(matched)
+    }
+
+    fn initialize(&mut self) -> Result<(), DosError> {
+        self.flush()?;
+        self.buffer_block = None;
+        Ok(())
+    }
+
+    fn validate(&mut self) -> Result<(), DosError> {
+        for track in 1..=self.format.tracks() {
+            self.set_track_free(track)?;
+        }
+        self.set_free(self.format.header(), false)?;
+        for block in self.format.bam_blocks() {
+            self.set_free(*block, false)?;
+        }
+
+        let mut block = self.format.first_directory_block();
+        for _ in 0..self.format.sectors(self.format.directory_track()) {
+            self.set_free(block, false)?;
+            match self.link(block)? {
+                (0, _) => break,
+                next => block = next,
+            }
+        }
+
+        // files which were never closed get deleted, the others keep their blocks
+        let mut slot = None;
+        for _ in 0..self.format.sectors(self.format.directory_track()) as usize * 8 {
+            let current = match self.next_slot(slot)? {
+                Some(current) => current,
+                None => break,
+            };
+            slot = Some(current);
+            let file_type = self.entry(current)?[ENTRY_TYPE];
+            if file_type == 0 {
+                continue;
+            }
+            if file_type & TYPE_CLOSED == 0 {
+                self.entry_mut(current)?[ENTRY_TYPE] = 0;
+                continue;
+            }
+
+            let mut block = self.first_block(current)?;
+            for _ in 0..self.format.blocks() {
+                if block.0 == 0 {
+                    break;
+                }
+                self.set_free(block, false)?;
+                block = self.link(block)?;
+            }
+        }
+        self.flush()
+    }
+
+    fn format(&mut self, name: &[u8], id: Option<&[u8]>) -> Result<(), DosError> {
+        let format = self.format;
+        let name = new_name(name)?;
+        let mut new_id = [b'0'; 2];
+        match id {
+            Some(b"") => return Err(DosError::SyntaxError),
+            Some(id) => {
+                for (i, b) in id.iter().take(2).enumerate() {
+                    new_id[i] = *b;
+                }
+            }
+            None => {
+                let header = self.block(format.header())?;
+                new_id.copy_from_slice(&header[format.id_offset()..format.id_offset() + 2]);
+            }
+        }
+
+        // the header and the BAM share a block on the 2040/4040
+        let header = self.new_block(format.header())?;
+        let (link, dos_type) = match format {
+            Format::D64 => (format.first_directory_block(), 0xa5),
+            Format::D80 | Format::D82 => (format.bam_blocks()[0], 0x1b),
+        };
+        header[0] = link.0;
+        header[1] = link.1;
+        header[2] = format.dos_version();
+        let name_offset = format.name_offset();
+        for b in header[name_offset..dos_type + 6].iter_mut() {
+            *b = PADDING;
+        }
+        header[name_offset..name_offset + name.len()].copy_from_slice(name);
+        header[format.id_offset()..format.id_offset() + 2].copy_from_slice(&new_id);
+        header[dos_type] = b'2';
+        header[dos_type + 1] = format.dos_version();
+
+        let bam_blocks = format.bam_blocks();
+        for (i, block) in bam_blocks.iter().enumerate() {
+            if format == Format::D64 {
+                break;
+            }
+            let next = bam_blocks
+                .get(i + 1)
+                .copied()
+                .unwrap_or_else(|| format.first_directory_block());
+            let first_track = i as u8 * TRACKS_PER_BAM_BLOCK + 1;
+            let data = self.new_block(*block)?;
+            data[0] = next.0;
+            data[1] = next.1;
+            data[2] = format.dos_version();
+            data[4] = first_track;
+            data[5] = (first_track + TRACKS_PER_BAM_BLOCK).min(format.tracks() + 1);
+        }
+
+        self.new_block(format.first_directory_block())?[1] = 0xff;
+        self.channels = Channels::new();
+        self.validate()
+    }
+}
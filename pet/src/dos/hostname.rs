@@ -0,0 +1,130 @@
+// PETSCII names as filenames on other computers
+//
+// Unshifted letters become lowercase, shifted letters uppercase and everything a host
+// filesystem might not accept is written as "%xx", so "GAME" is "game" and ".DATA " is
+// "%2edata%20". Host folders and file transfers name files this way. The type of a file
+// is its extension, ".prg", ".seq", ".usr" or ".rel".
+
+use crate::io::FileType;
+
+const FILE_TYPES: [FileType; 4] = [FileType::Prg, FileType::Seq, FileType::Usr, FileType::Rel];
+
+/// The host filename for a PETSCII name, without extension, a byte at a time
+pub fn host_name(name: &[u8], mut put: impl FnMut(u8)) {
+    for (i, &c) in name.iter().enumerate() {
+        let last = i + 1 == name.len();
+        match c {
+            b'.' if i == 0 || last => escape(&mut put, c),
+            b' ' if last => escape(&mut put, c),
+            b'A'..=b'Z' => put(c + 0x20),
+            0xc1..=0xda => put(c - 0x80),
+            b'0'..=b'9'
+            | b' '
+            | b'!'
+            | b'#'
+            | b'$'
+            | b'&'
+            | b'\''
+            | b'('
+            | b')'
+            | b'+'
+            | b','
+            | b'-'
+            | b'.'
+            | b';'
+            | b'='
+            | b'@'
+            | b'['
+            | b']'
+            | b'^'
+            | b'_' => put(c),
+            _ => escape(&mut put, c),
+        }
+    }
+}
+
+// shifted letters are the same as unshifted ones on most hosts
+fn fold(c: u8) -> u8 {
+    match c {
+        0xc1..=0xda => c - 0x80,
+        _ => c,
+    }
+}
+
+/// Matches a name against a pattern like `dos::matches`, but ignoring the letter case
+/// like most hosts do.
+pub fn matches_ignoring_case(pattern: &[u8], name: &[u8]) -> bool {
+    let mut name = name.iter().map(|&c| fold(c));
+    for &p in pattern {
+        match fold(p) {
+            b'*' => return true,
+            b'?' if name.next().is_some() => {}
+            p if name.next() == Some(p) => {}
+            _ => return false,
+        }
+    }
+    name.next().is_none()
+}
+
+fn escape(put: &mut impl FnMut(u8), c: u8) {
+    const HEX: &[u8; 16] = b"0123456789abcdef";
+    put(b'%');
+    put(HEX[(c >> 4) as usize]);
+    put(HEX[(c & 0x0f) as usize]);
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// The PETSCII name for a UTF-8 host filename without extension, a byte at a time.
+/// Characters other than ASCII become "?".
+pub fn pet_name(host: &[u8], mut put: impl FnMut(u8)) {
+    let mut i = 0;
+    while i < host.len() {
+        let c = host[i];
+        i += 1;
+        let value = match c {
+            b'%' => match (
+                host.get(i).copied().and_then(hex_digit),
+                host.get(i + 1).copied().and_then(hex_digit),
+            ) {
+                (Some(high), Some(low)) => {
+                    i += 2;
+                    high << 4 | low
+                }
+                _ => b'%',
+            },
+            b'a'..=b'z' => c - 0x20,
+            b'A'..=b'Z' => c + 0x80,
+            b' '..=b'~' => c,
+            // the rest of a multi-byte character
+            0x80..=0xbf => continue,
+            _ => b'?',
+        };
+        put(value);
+    }
+}
+
+/// The host filename extension for files of that type, without the dot
+pub fn extension(file_type: FileType) -> &'static str {
+    match file_type {
+        FileType::Prg => "prg",
+        FileType::Seq => "seq",
+        FileType::Usr => "usr",
+        FileType::Rel => "rel",
+    }
+}
+
+/// The type of a host file by its extension, ignoring the letter case
+pub fn file_type(extension: &[u8]) -> Option<FileType> {
+    FILE_TYPES
+        .iter()
+        .copied()
+        .find(|&file_type| extension.eq_ignore_ascii_case(self::extension(file_type).as_bytes()))
+}
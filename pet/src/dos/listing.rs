@@ -0,0 +1,306 @@
+// Directory listings as a drive sends them for LOAD"$",8
+//
+// The listing is a BASIC program. The header line shows the disk name and id in reverse,
+// every file gets a line numbered with its size in blocks and the last line tells how
+// many blocks are free:
+//
+//   0 "DISK NAME       " 01 2A
+//   3    "GAME"            PRG
+//   1    "DATA"           *SEQ<
+//   661 BLOCKS FREE.
+//
+// A "*" marks a file that wasn't closed, a "<" a locked one. The program is generated a
+// line at a time, so no buffer for all of it is needed.
+
+use crate::dos::{self, DosError, MAX_NAME_LEN};
+use crate::io::FileType;
+
+const ID_LEN: usize = 5;
+const SHIFTED_SPACE: u8 = 0xa0;
+const REVERSE_ON: u8 = 0x12;
+
+const LOAD_ADDRESS: u16 = 0x0401;
+// link, line number and the terminating zero
+const LINE_OVERHEAD: usize = 5;
+const HEADER_TEXT_LEN: usize = 2 + MAX_NAME_LEN + 2 + ID_LEN;
+// all file lines have the same length, the text is padded with spaces
+const ENTRY_TEXT_LEN: usize = 27;
+const FOOTER_TEXT_LEN: usize = 25;
+const MAX_LINE_LEN: usize = ENTRY_TEXT_LEN + LINE_OVERHEAD;
+const BLOCKS_FREE: &[u8] = b"BLOCKS FREE.";
+
+// bytes of a file in a block, the other two link to the next one
+const BLOCK_DATA_LEN: usize = 254;
+
+/// The blocks a file of `len` bytes takes on a disk
+pub fn blocks(len: usize) -> u16 {
+    ((len + BLOCK_DATA_LEN - 1) / BLOCK_DATA_LEN).min(u16::MAX as usize) as u16
+}
+
+fn pad(target: &mut [u8], source: &[u8]) {
+    for (i, b) in target.iter_mut().enumerate() {
+        *b = match source.get(i) {
+            Some(&SHIFTED_SPACE) | None => b' ',
+            Some(&c) => c,
+        };
+    }
+}
+
+/// The disk name and id shown in the header line
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DiskHeader {
+    pub name: [u8; MAX_NAME_LEN],
+    pub id: [u8; ID_LEN],
+}
+
+impl DiskHeader {
+    /// Both are padded with spaces, shifted spaces (the padding on disk) become spaces too.
+    /// The id includes the DOS type, e.g. "01 2A".
+    pub fn new(name: &[u8], id: &[u8]) -> DiskHeader {
+        let mut header = DiskHeader {
+            name: [b' '; MAX_NAME_LEN],
+            id: [b' '; ID_LEN],
+        };
+        pad(&mut header.name, name);
+        pad(&mut header.id, id);
+        header
+    }
+}
+
+/// A file as shown in the listing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DirectoryEntry {
+    name: [u8; MAX_NAME_LEN],
+    name_len: usize,
+    pub file_type: FileType,
+    pub blocks: u16,
+    pub closed: bool,
+    pub locked: bool,
+}
+
+impl DirectoryEntry {
+    /// A closed, unlocked file. Names longer than 16 bytes are cut.
+    pub fn new(name: &[u8], file_type: FileType, blocks: u16) -> DirectoryEntry {
+        let name_len = name.len().min(MAX_NAME_LEN);
+        let mut entry = DirectoryEntry {
+            name: [0u8; MAX_NAME_LEN],
+            name_len,
+            file_type,
+            blocks,
+            closed: true,
+            locked: false,
+        };
+        entry.name[..name_len].copy_from_slice(&name[..name_len]);
+        entry
+    }
+
+    pub fn name(&self) -> &[u8] {
+        &self.name[..self.name_len]
+    }
+}
+
+/// A storage that can list its files
+pub trait Directory {
+    fn disk_header(&mut self) -> Result<DiskHeader, DosError>;
+
+    /// The file at the cursor or the next one after it, None after the last one.
+    /// The cursor starts at 0 and is moved past the file returned.
+    fn next_entry(&mut self, cursor: &mut usize) -> Result<Option<DirectoryEntry>, DosError>;
+
+    fn blocks_free(&mut self) -> Result<u16, DosError>;
+
+    /// Whether a file is listed for the pattern, storages ignoring the letter case
+    /// for their names do so here as well.
+    fn name_matches(&self, pattern: &[u8], name: &[u8]) -> bool {
+        dos::matches(pattern, name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Part {
+    Header,
+    Entries,
+    Footer,
+    End,
+    Done,
+}
+
+/// Generates the listing of a `Directory` byte by byte
+#[derive(Debug, Clone, Copy)]
+pub struct Listing {
+    pattern: [u8; MAX_NAME_LEN],
+    pattern_len: usize,
+
+    part: Part,
+    cursor: usize,
+    address: u16,
+
+    line: [u8; MAX_LINE_LEN],
+    line_len: usize,
+    line_pos: usize,
+    // the number of bytes generated so far
+    index: usize,
+}
+
+impl Listing {
+    /// A listing of the files matching the pattern, see `dos::directory_pattern`
+    pub fn new(pattern: &[u8]) -> Listing {
+        let pattern_len = pattern.len().min(MAX_NAME_LEN);
+        let mut listing = Listing {
+            pattern: [0u8; MAX_NAME_LEN],
+            pattern_len,
+
+            part: Part::Header,
+            cursor: 0,
+            address: LOAD_ADDRESS,
+
+            line: [0u8; MAX_LINE_LEN],
+            line_len: 2,
+            line_pos: 0,
+            index: 0,
+        };
+        listing.pattern[..pattern_len].copy_from_slice(&pattern[..pattern_len]);
+        listing.line[..2].copy_from_slice(&LOAD_ADDRESS.to_le_bytes());
+        listing
+    }
+
+    fn pattern(&self) -> &[u8] {
+        &self.pattern[..self.pattern_len]
+    }
+
+    /// The size of the whole listing in bytes
+    pub fn len(&self, directory: &mut dyn Directory) -> Result<usize, DosError> {
+        let mut files = 0;
+        let mut cursor = 0;
+        while let Some(entry) = directory.next_entry(&mut cursor)? {
+            if directory.name_matches(self.pattern(), entry.name()) {
+                files += 1;
+            }
+        }
+
+        // the load address, the lines and the zero link at the end
+        Ok(2 + (HEADER_TEXT_LEN + LINE_OVERHEAD)
+            + files * (ENTRY_TEXT_LEN + LINE_OVERHEAD)
+            + (FOOTER_TEXT_LEN + LINE_OVERHEAD)
+            + 2)
+    }
+
+    /// The byte at the index. The listing starts over if an earlier byte is asked for.
+    pub fn byte(&mut self, index: usize, directory: &mut dyn Directory) -> Result<u8, DosError> {
+        if index < self.index {
+            *self = Listing::new(self.pattern());
+        }
+
+        loop {
+            let value = self.next_byte(directory)?.ok_or(DosError::ReadError)?;
+            if self.index > index {
+                return Ok(value);
+            }
+        }
+    }
+
+    /// The next byte, None after the end of the program
+    pub fn next_byte(&mut self, directory: &mut dyn Directory) -> Result<Option<u8>, DosError> {
+        while self.line_pos == self.line_len {
+            if !self.next_line(directory)? {
+                return Ok(None);
+            }
+        }
+
+        let value = self.line[self.line_pos];
+        self.line_pos += 1;
+        self.index += 1;
+        Ok(Some(value))
+    }
+
+    // false after the last line
+    fn next_line(&mut self, directory: &mut dyn Directory) -> Result<bool, DosError> {
+        let mut text = [b' '; ENTRY_TEXT_LEN];
+        let (number, len) = match self.part {
+            Part::Header => {
+                let header = directory.disk_header()?;
+                text[0] = REVERSE_ON;
+                text[1] = b'"';
+                text[2..2 + MAX_NAME_LEN].copy_from_slice(&header.name);
+                text[2 + MAX_NAME_LEN] = b'"';
+                text[4 + MAX_NAME_LEN..HEADER_TEXT_LEN].copy_from_slice(&header.id);
+                self.part = Part::Entries;
+                (0, HEADER_TEXT_LEN)
+            }
+            Part::Entries => match self.next_listed(directory)? {
+                Some(entry) => {
+                    entry_text(&entry, &mut text);
+                    (entry.blocks, ENTRY_TEXT_LEN)
+                }
+                None => {
+                    self.part = Part::Footer;
+                    return Ok(true);
+                }
+            },
+            Part::Footer => {
+                text[..BLOCKS_FREE.len()].copy_from_slice(BLOCKS_FREE);
+                self.part = Part::End;
+                (directory.blocks_free()?, FOOTER_TEXT_LEN)
+            }
+            Part::End => {
+                // a zero link ends the program
+                self.line[..2].copy_from_slice(&[0, 0]);
+                self.line_len = 2;
+                self.line_pos = 0;
+                self.part = Part::Done;
+                return Ok(true);
+            }
+            Part::Done => return Ok(false),
+        };
+
+        self.address = self.address.wrapping_add((len + LINE_OVERHEAD) as u16);
+        self.line[..2].copy_from_slice(&self.address.to_le_bytes());
+        self.line[2..4].copy_from_slice(&number.to_le_bytes());
+        self.line[4..4 + len].copy_from_slice(&text[..len]);
+        self.line[4 + len] = 0;
+        self.line_len = len + LINE_OVERHEAD;
+        self.line_pos = 0;
+        Ok(true)
+    }
+
+    fn next_listed(
+        &mut self,
+        directory: &mut dyn Directory,
+    ) -> Result<Option<DirectoryEntry>, DosError> {
+        while let Some(entry) = directory.next_entry(&mut self.cursor)? {
+            if directory.name_matches(self.pattern(), entry.name()) {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+}
+
+// `  "NAME"            PRG ` - the names line up whatever the number of blocks
+fn entry_text(entry: &DirectoryEntry, text: &mut [u8; ENTRY_TEXT_LEN]) {
+    let start = match entry.blocks {
+        0..=9 => 3,
+        10..=99 => 2,
+        100..=999 => 1,
+        _ => 0,
+    };
+
+    let name = entry.name();
+    text[start] = b'"';
+    text[start + 1..start + 1 + name.len()].copy_from_slice(name);
+    text[start + 1 + name.len()] = b'"';
+
+    let file_type = start + MAX_NAME_LEN + 3;
+    if !entry.closed {
+        text[file_type - 1] = b'*';
+    }
+    text[file_type..file_type + 3].copy_from_slice(match entry.file_type {
+        FileType::Prg => b"PRG",
+        FileType::Seq => b"SEQ",
+        FileType::Usr => b"USR",
+        FileType::Rel => b"REL",
+    });
+    if entry.locked {
+        text[file_type + 3] = b'<';
+    }
+}
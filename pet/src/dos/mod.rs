@@ -0,0 +1,482 @@
+// CBM DOS command channel (secondary address 15)
+//
+// Commands are collected from the bytes sent to the channel and executed when the PET
+// unlistens. Reading the channel returns the status of the last command, e.g.
+// "62,FILE NOT FOUND,00,00". The status is reset to "00, OK,00,00" once it was read.
+
+pub mod hostname;
+pub mod listing;
+pub mod volume;
+
+/// Longer filenames are cut off, like the drives do
+pub const MAX_NAME_LEN: usize = 16;
+
+/// The channels of a drive, numbered by their secondary address
+pub const CHANNELS: usize = 16;
+/// LOAD and SAVE use the channels of their secondary address
+pub const LOAD_CHANNEL: usize = 0;
+pub const SAVE_CHANNEL: usize = 1;
+/// Takes the commands and reports the status
+pub const COMMAND_CHANNEL: usize = 15;
+
+const MAX_COMMAND_LEN: usize = 41;
+const MAX_STATUS_LEN: usize = 48;
+
+/// Errors as reported on the command channel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DosError {
+    ReadError,
+    WriteError,
+    WriteProtectOn,
+    SyntaxError,
+    InvalidCommand,
+    LongLine,
+    InvalidFilename,
+    NoFileGiven,
+    WriteFileOpen,
+    FileNotOpen,
+    FileNotFound,
+    FileExists,
+    FileTypeMismatch,
+    IllegalTrackOrSector,
+    NoChannel,
+    DirError,
+    DiskFull,
+    DriveNotReady,
+}
+
+impl DosError {
+    pub fn code(&self) -> u8 {
+        match self {
+            DosError::ReadError => 20,
+            DosError::WriteError => 25,
+            DosError::WriteProtectOn => 26,
+            DosError::SyntaxError => 30,
+            DosError::InvalidCommand => 31,
+            DosError::LongLine => 32,
+            DosError::InvalidFilename => 33,
+            DosError::NoFileGiven => 34,
+            DosError::WriteFileOpen => 60,
+            DosError::FileNotOpen => 61,
+            DosError::FileNotFound => 62,
+            DosError::FileExists => 63,
+            DosError::FileTypeMismatch => 64,
+            DosError::IllegalTrackOrSector => 66,
+            DosError::NoChannel => 70,
+            DosError::DirError => 71,
+            DosError::DiskFull => 72,
+            DosError::DriveNotReady => 74,
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            DosError::ReadError => "READ ERROR",
+            DosError::WriteError => "WRITE ERROR",
+            DosError::WriteProtectOn => "WRITE PROTECT ON",
+            DosError::SyntaxError
+            | DosError::InvalidCommand
+            | DosError::LongLine
+            | DosError::InvalidFilename
+            | DosError::NoFileGiven => "SYNTAX ERROR",
+            DosError::WriteFileOpen => "WRITE FILE OPEN",
+            DosError::FileNotOpen => "FILE NOT OPEN",
+            DosError::FileNotFound => "FILE NOT FOUND",
+            DosError::FileExists => "FILE EXISTS",
+            DosError::FileTypeMismatch => "FILE TYPE MISMATCH",
+            DosError::IllegalTrackOrSector => "ILLEGAL TRACK OR SECTOR",
+            DosError::NoChannel => "NO CHANNEL",
+            DosError::DirError => "DIR ERROR",
+            DosError::DiskFull => "DISK FULL",
+            DosError::DriveNotReady => "DRIVE NOT READY",
+        }
+    }
+}
+
+/// What the command channel reports
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Status {
+    pub code: u8,
+    pub message: &'static str,
+    pub track: u8,
+    pub sector: u8,
+}
+
+impl Status {
+    pub const OK: Status = Status {
+        code: 0,
+        message: " OK",
+        track: 0,
+        sector: 0,
+    };
+
+    /// The number of files scratched is reported as the track
+    pub fn scratched(files: u8) -> Status {
+        Status {
+            code: 1,
+            message: "FILES SCRATCHED",
+            track: files,
+            sector: 0,
+        }
+    }
+}
+
+impl From<DosError> for Status {
+    fn from(error: DosError) -> Status {
+        Status {
+            code: error.code(),
+            message: error.message(),
+            track: 0,
+            sector: 0,
+        }
+    }
+}
+
+impl From<Result<(), DosError>> for Status {
+    fn from(result: Result<(), DosError>) -> Status {
+        match result {
+            Ok(()) => Status::OK,
+            Err(error) => error.into(),
+        }
+    }
+}
+
+/// The operations of the DOS commands a storage backend has to provide.
+/// Names are given in PETSCII without the drive number.
+pub trait FileSystem {
+    /// Deletes the file, returns how many files got deleted.
+    fn scratch(&mut self, name: &[u8]) -> Result<u8, DosError>;
+
+    fn rename(&mut self, old: &[u8], new: &[u8]) -> Result<(), DosError>;
+
+    fn copy(&mut self, source: &[u8], destination: &[u8]) -> Result<(), DosError>;
+
+    /// Locks (write protects) or unlocks the files, None toggles each of them.
+    /// Locked files can't be scratched, replaced or appended to. Returns how many matched.
+    fn lock(&mut self, name: &[u8], locked: Option<bool>) -> Result<u8, DosError>;
+
+    /// Re-reads the directory
+    fn initialize(&mut self) -> Result<(), DosError>;
+
+    /// Cleans up the directory and frees everything not belonging to a file
+    fn validate(&mut self) -> Result<(), DosError>;
+
+    /// Deletes all files and names the disk. The id is only given for a full format.
+    fn format(&mut self, name: &[u8], id: Option<&[u8]>) -> Result<(), DosError>;
+
+    // storages holding several disks, one of them mounted at a time
+
+    /// Mounts the first disk matching the pattern, an empty name mounts the first disk
+    fn change_disk(&mut self, _name: &[u8]) -> Result<(), DosError> {
+        Err(DosError::InvalidCommand)
+    }
+
+    /// Creates an empty disk, it isn't mounted
+    fn make_disk(&mut self, _name: &[u8]) -> Result<(), DosError> {
+        Err(DosError::InvalidCommand)
+    }
+
+    /// Removes a disk without files
+    fn remove_disk(&mut self, _name: &[u8]) -> Result<(), DosError> {
+        Err(DosError::InvalidCommand)
+    }
+
+    /// The disk at the cursor or the next one, true for the one mounted.
+    /// The cursor starts at 0 and is moved past the disk returned.
+    fn next_disk(
+        &mut self,
+        _cursor: &mut usize,
+    ) -> Result<Option<(listing::DiskHeader, bool)>, DosError> {
+        Ok(None)
+    }
+}
+
+impl<'a> core::fmt::Debug for dyn FileSystem + 'a {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "FileSystem")
+    }
+}
+
+/// Matches a name against a CBM DOS pattern. "?" matches any character, "*" the rest of
+/// the name. Without "*" the pattern has to cover the whole name.
+pub fn matches(pattern: &[u8], name: &[u8]) -> bool {
+    let mut name = name.iter();
+    for &p in pattern {
+        match p {
+            b'*' => return true,
+            b'?' if name.next().is_some() => {}
+            _ if name.next() == Some(&p) => {}
+            _ => return false,
+        }
+    }
+    name.next().is_none()
+}
+
+/// True if the name contains wildcards, so it can't be used for a new file
+pub fn is_pattern(name: &[u8]) -> bool {
+    name.iter().any(|&c| c == b'*' || c == b'?')
+}
+
+/// The name of a file to look for, cut off after MAX_NAME_LEN characters
+pub fn valid_name(name: &[u8]) -> Result<&[u8], DosError> {
+    match name.len() {
+        0 => Err(DosError::NoFileGiven),
+        len => Ok(&name[..len.min(MAX_NAME_LEN)]),
+    }
+}
+
+/// The name for a new file. Wildcards only find files, they can't name one.
+pub fn new_name(name: &[u8]) -> Result<&[u8], DosError> {
+    match valid_name(name)? {
+        name if is_pattern(name) => Err(DosError::InvalidFilename),
+        name => Ok(name),
+    }
+}
+
+/// The pattern of a directory request like "$", "$0" or "$0:A*",
+/// None if the name doesn't ask for the directory.
+pub fn directory_pattern(name: &[u8]) -> Option<&[u8]> {
+    if name.first() != Some(&b'$') {
+        return None;
+    }
+    match argument(name) {
+        Some(pattern) if !pattern.is_empty() => Some(pattern),
+        _ => Some(b"*"),
+    }
+}
+
+// "S0:NAME" -> "NAME", None if there is no colon
+fn argument(command: &[u8]) -> Option<&[u8]> {
+    let colon = command.iter().position(|&c| c == b':')?;
+    Some(&command[colon + 1..])
+}
+
+// "NEW=OLD" -> ("NEW", "OLD") with the drive number stripped from the old name
+fn assignment(argument: &[u8]) -> Result<(&[u8], &[u8]), DosError> {
+    let equals = argument
+        .iter()
+        .position(|&c| c == b'=')
+        .ok_or(DosError::SyntaxError)?;
+    let new = &argument[..equals];
+    let old = &argument[equals + 1..];
+    let old = argument_or_name(old);
+
+    if new.is_empty() || old.is_empty() {
+        return Err(DosError::NoFileGiven);
+    }
+    Ok((new, old))
+}
+
+fn argument_or_name(name: &[u8]) -> &[u8] {
+    argument(name).unwrap_or(name)
+}
+
+/// Executes a DOS command like "S0:NAME", "R:NEW=OLD", "C:NEW=OLD", "I", "V" or "N:NAME,ID".
+/// "L:NAME" toggles the lock of the files, as on an SD2IEC. The directory commands of
+/// CMD drives switch disks: "CD:NAME" mounts one, "CD←" the first, "MD:NAME" creates one
+/// and "RD:NAME" removes it.
+pub fn execute(command: &[u8], fs: &mut dyn FileSystem) -> Status {
+    let end = command
+        .iter()
+        .rposition(|&c| c != b'\r' && c != b'\n')
+        .map_or(0, |last| last + 1);
+    let command = &command[..end];
+
+    let first = match command.first() {
+        Some(first) => *first,
+        None => return Status::OK,
+    };
+
+    match command.get(..2) {
+        Some(b"CD") => {
+            return match argument(command).unwrap_or(&command[2..]) {
+                // up with the left arrow or to the root
+                b"\x5f" | b"//" => fs.change_disk(b"").into(),
+                b"" => DosError::NoFileGiven.into(),
+                name => fs.change_disk(name).into(),
+            };
+        }
+        Some(b"MD") | Some(b"RD") => {
+            let name = match argument(command) {
+                Some(name) if !name.is_empty() => name,
+                _ => return DosError::NoFileGiven.into(),
+            };
+            return match first {
+                b'M' => fs.make_disk(name),
+                _ => fs.remove_disk(name),
+            }
+            .into();
+        }
+        _ => {}
+    }
+
+    match first {
+        b'I' => fs.initialize().into(),
+        b'V' => fs.validate().into(),
+        b'S' => {
+            let names = match argument(command) {
+                Some(names) if !names.is_empty() => names,
+                _ => return DosError::NoFileGiven.into(),
+            };
+
+            let mut scratched = 0u8;
+            for name in names.split(|&c| c == b',') {
+                match fs.scratch(argument_or_name(name)) {
+                    Ok(count) => scratched = scratched.saturating_add(count),
+                    Err(error) => return error.into(),
+                }
+            }
+            Status::scratched(scratched)
+        }
+        b'R' => match argument(command)
+            .ok_or(DosError::NoFileGiven)
+            .and_then(assignment)
+        {
+            Ok((new, old)) => fs.rename(old, new).into(),
+            Err(error) => error.into(),
+        },
+        b'C' => match argument(command)
+            .ok_or(DosError::NoFileGiven)
+            .and_then(assignment)
+        {
+            Ok((_, old)) if old.contains(&b',') => DosError::SyntaxError.into(),
+            Ok((new, old)) => fs.copy(old, new).into(),
+            Err(error) => error.into(),
+        },
+        b'L' => match argument(command) {
+            Some(name) if !name.is_empty() => match fs.lock(name, None) {
+                Ok(0) => DosError::FileNotFound.into(),
+                Ok(_) => Status::OK,
+                Err(error) => error.into(),
+            },
+            _ => DosError::NoFileGiven.into(),
+        },
+        b'N' => {
+            let argument = match argument(command) {
+                Some(argument) if !argument.is_empty() => argument,
+                _ => return DosError::NoFileGiven.into(),
+            };
+
+            let mut parts = argument.splitn(2, |&c| c == b',');
+            let name = parts.next().unwrap_or(&[]);
+            let id = parts.next();
+            fs.format(name, id).into()
+        }
+        _ => DosError::InvalidCommand.into(),
+    }
+}
+
+/// The command channel of a drive
+#[derive(Debug)]
+pub struct CommandChannel {
+    command: [u8; MAX_COMMAND_LEN],
+    command_len: usize,
+    overflow: bool,
+
+    status: Status,
+    status_text: [u8; MAX_STATUS_LEN],
+    status_len: usize,
+    status_pos: usize,
+}
+
+impl Default for CommandChannel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CommandChannel {
+    pub fn new() -> CommandChannel {
+        CommandChannel {
+            command: [0u8; MAX_COMMAND_LEN],
+            command_len: 0,
+            overflow: false,
+
+            status: Status::OK,
+            status_text: [0u8; MAX_STATUS_LEN],
+            status_len: 0,
+            status_pos: 0,
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        self.status
+    }
+
+    pub fn set_status(&mut self, status: Status) {
+        self.status = status;
+        self.status_len = 0;
+        self.status_pos = 0;
+    }
+
+    /// A byte of a command
+    pub fn receive(&mut self, value: u8) {
+        if self.command_len < MAX_COMMAND_LEN {
+            self.command[self.command_len] = value;
+            self.command_len += 1;
+        } else {
+            self.overflow = true;
+        }
+    }
+
+    /// Executes the command received so far
+    pub fn execute(&mut self, fs: Option<&mut dyn FileSystem>) {
+        if self.command_len == 0 && !self.overflow {
+            return;
+        }
+
+        let status = match fs {
+            _ if self.overflow => DosError::LongLine.into(),
+            Some(fs) => execute(&self.command[..self.command_len], fs),
+            None => DosError::InvalidCommand.into(),
+        };
+        self.set_status(status);
+
+        self.command_len = 0;
+        self.overflow = false;
+    }
+
+    /// The next byte of the status and true if it's the last one.
+    /// The status goes back to OK once it was read completely.
+    pub fn read(&mut self) -> Option<(u8, bool)> {
+        if self.status_len == 0 {
+            self.format_status();
+        }
+
+        let value = self.status_text[self.status_pos];
+        self.status_pos += 1;
+
+        let last = self.status_pos == self.status_len;
+        if last {
+            self.set_status(Status::OK);
+        }
+        Some((value, last))
+    }
+
+    fn format_status(&mut self) {
+        let status = self.status;
+        let mut text = [0u8; MAX_STATUS_LEN];
+        let mut len = 0;
+        let mut put = |value: u8| {
+            if len < MAX_STATUS_LEN {
+                text[len] = value;
+                len += 1;
+            }
+        };
+
+        let two_digits = |value: u8| [b'0' + value / 10 % 10, b'0' + value % 10];
+
+        two_digits(status.code).iter().for_each(|&c| put(c));
+        put(b',');
+        status.message.bytes().for_each(&mut put);
+        put(b',');
+        two_digits(status.track).iter().for_each(|&c| put(c));
+        put(b',');
+        two_digits(status.sector).iter().for_each(|&c| put(c));
+        put(b'\r');
+
+        self.status_text = text;
+        self.status_len = len;
+        self.status_pos = 0;
+    }
+}
@@ -0,0 +1,249 @@
+// The drive side of the filesystems
+//
+// A `Volume` stores files, `Storage` is implemented on top of it for all of them: the name
+// sent for LOAD and SAVE is collected, files are opened on the channels by their name,
+// read and written a byte at a time and closed, and LOAD"$" gets the `Listing` of the
+// volume. Opening a channel that is in use closes the file open on it first.
+
+use crate::dos::listing::{Directory, Listing};
+use crate::dos::{self, DosError, FileSystem};
+use crate::dos::{CHANNELS, LOAD_CHANNEL, SAVE_CHANNEL};
+use crate::io::{FileMode, FileType, OpenName, Storage};
+
+// the longest name sent for LOAD or SAVE, "@0:" and the name with ",P,W"
+const NAME_BUFFER_LEN: usize = 41;
+
+/// The files open on a volume and the name of the last LOAD or SAVE
+#[derive(Debug)]
+pub(crate) struct Channels<C> {
+    name: [u8; NAME_BUFFER_LEN],
+    name_len: usize,
+
+    open: [Option<C>; CHANNELS],
+    // the bytes read on the load channel, LOAD may go back
+    loaded: usize,
+    // LOAD"$" reads this instead of a file
+    listing: Option<Listing>,
+}
+
+impl<C> Default for Channels<C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C> Channels<C> {
+    pub fn new() -> Channels<C> {
+        Channels {
+            name: [0u8; NAME_BUFFER_LEN],
+            name_len: 0,
+            open: Default::default(),
+            loaded: 0,
+            listing: None,
+        }
+    }
+}
+
+/// Files stored by name, read and written a byte at a time
+pub(crate) trait Volume: FileSystem + Directory {
+    /// A file found by its name
+    type File;
+    /// A file opened for reading or writing
+    type Channel;
+
+    fn channels(&mut self) -> &mut Channels<Self::Channel>;
+
+    fn find(&mut self, name: &[u8]) -> Result<Option<Self::File>, DosError>;
+
+    fn file_type(&mut self, file: &Self::File) -> Result<FileType, DosError>;
+
+    /// A new file, replacing the one of the same name if `replace` is set
+    fn create(
+        &mut self,
+        name: &[u8],
+        file_type: FileType,
+        replace: bool,
+    ) -> Result<Self::Channel, DosError>;
+
+    /// Opens the file for `FileMode::Read` or `FileMode::Append`
+    fn open_file(&mut self, file: &Self::File, mode: FileMode) -> Result<Self::Channel, DosError>;
+
+    /// Reading starts over at the first byte
+    fn rewind(&mut self, channel: &mut Self::Channel) -> Result<(), DosError>;
+
+    /// The length of a file opened for reading
+    fn read_len(&mut self, channel: &Self::Channel) -> Result<usize, DosError>;
+
+    fn is_writing(channel: &Self::Channel) -> bool;
+
+    /// The next byte and true if it's the last one, None at the end
+    fn read_byte(&mut self, channel: &mut Self::Channel) -> Result<Option<(u8, bool)>, DosError>;
+
+    fn write_byte(&mut self, channel: &mut Self::Channel, value: u8) -> Result<(), DosError>;
+
+    /// A file written is complete afterwards
+    fn close_file(&mut self, channel: Self::Channel) -> Result<(), DosError>;
+}
+
+fn channel_result<V: Volume, T>(
+    volume: &mut V,
+    channel: usize,
+    f: impl FnOnce(&mut V, &mut V::Channel) -> Result<T, DosError>,
+) -> Result<T, DosError> {
+    let mut open = volume.channels().open[channel]
+        .take()
+        .ok_or(DosError::FileNotOpen)?;
+    let result = f(volume, &mut open);
+    volume.channels().open[channel] = Some(open);
+    result
+}
+
+fn close_channel<V: Volume>(volume: &mut V, channel: usize) -> Result<(), DosError> {
+    match volume.channels().open[channel].take() {
+        Some(open) => volume.close_file(open),
+        None => Ok(()),
+    }
+}
+
+// the name of the last LOAD or SAVE
+fn name<V: Volume>(volume: &mut V) -> ([u8; NAME_BUFFER_LEN], usize) {
+    let channels = volume.channels();
+    (channels.name, channels.name_len)
+}
+
+impl<V: Volume> Storage for V {
+    fn start_filename(&mut self) {
+        self.channels().name_len = 0;
+    }
+
+    fn next_filename_byte(&mut self, value: u8) {
+        let channels = self.channels();
+        if channels.name_len < NAME_BUFFER_LEN {
+            channels.name[channels.name_len] = value;
+            channels.name_len += 1;
+        }
+    }
+
+    fn fname_done(&mut self) {}
+
+    fn start_save(&mut self) -> Result<(), DosError> {
+        close_channel(self, SAVE_CHANNEL)?;
+
+        let (name, len) = name(self);
+        let parsed = OpenName::parse(&name[..len]);
+        let file_type = parsed.file_type.unwrap_or(FileType::Prg);
+        let channel = self.create(parsed.name, file_type, parsed.replace)?;
+        self.channels().open[SAVE_CHANNEL] = Some(channel);
+        Ok(())
+    }
+
+    fn end_save(&mut self) -> Result<(), DosError> {
+        let channel = self.channels().open[SAVE_CHANNEL]
+            .take()
+            .ok_or(DosError::FileNotOpen)?;
+        self.close_file(channel)
+    }
+
+    fn load_data_byte(&mut self, index: usize) -> Result<u8, DosError> {
+        if let Some(mut listing) = self.channels().listing {
+            let result = listing.byte(index, self);
+            self.channels().listing = Some(listing);
+            return result;
+        }
+
+        let mut loaded = self.channels().loaded;
+        let result = channel_result(self, LOAD_CHANNEL, |volume, channel| {
+            if index < loaded {
+                volume.rewind(channel)?;
+                loaded = 0;
+            }
+            loop {
+                let byte = volume.read_byte(channel)?.ok_or(DosError::ReadError)?;
+                loaded += 1;
+                if loaded > index {
+                    return Ok(byte.0);
+                }
+            }
+        });
+        self.channels().loaded = loaded;
+        result
+    }
+
+    fn save_data_byte(&mut self, _index: usize, value: u8) -> Result<(), DosError> {
+        channel_result(self, SAVE_CHANNEL, |volume, channel| {
+            volume.write_byte(channel, value)
+        })
+    }
+
+    fn load_data_len(&mut self) -> Result<usize, DosError> {
+        close_channel(self, LOAD_CHANNEL)?;
+        self.channels().listing = None;
+        self.channels().loaded = 0;
+
+        let (name, len) = name(self);
+        if let Some(pattern) = dos::directory_pattern(&name[..len]) {
+            let listing = Listing::new(pattern);
+            let len = listing.len(self)?;
+            self.channels().listing = Some(listing);
+            return Ok(len);
+        }
+
+        let parsed = OpenName::parse(&name[..len]);
+        let file = self.find(parsed.name)?.ok_or(DosError::FileNotFound)?;
+        let channel = self.open_file(&file, FileMode::Read)?;
+        let len = self.read_len(&channel);
+        self.channels().open[LOAD_CHANNEL] = Some(channel);
+        len
+    }
+
+    fn open(&mut self, channel: u8, name: &OpenName) -> Result<(), DosError> {
+        close_channel(self, channel as usize)?;
+
+        let opened = match name.mode {
+            FileMode::Write => {
+                let file_type = name.file_type.unwrap_or(FileType::Seq);
+                self.create(name.name, file_type, name.replace)?
+            }
+            FileMode::Read | FileMode::Append => {
+                let file = self.find(name.name)?.ok_or(DosError::FileNotFound)?;
+                if let Some(file_type) = name.file_type {
+                    if file_type != self.file_type(&file)? {
+                        return Err(DosError::FileTypeMismatch);
+                    }
+                }
+                self.open_file(&file, name.mode)?
+            }
+        };
+
+        self.channels().open[channel as usize] = Some(opened);
+        Ok(())
+    }
+
+    fn read(&mut self, channel: u8) -> Option<(u8, bool)> {
+        channel_result(self, channel as usize, |volume, open| {
+            match V::is_writing(open) {
+                true => Err(DosError::FileNotOpen),
+                false => volume.read_byte(open),
+            }
+        })
+        .ok()
+        .flatten()
+    }
+
+    fn write(&mut self, channel: u8, value: u8) -> Result<(), DosError> {
+        channel_result(self, channel as usize, |volume, open| {
+            match V::is_writing(open) {
+                true => volume.write_byte(open, value),
+                false => Err(DosError::FileNotOpen),
+            }
+        })
+    }
+
+    fn close(&mut self, channel: u8) -> Result<(), DosError> {
+        close_channel(self, channel as usize)
+    }
+
+    fn file_system(&mut self) -> Option<&mut dyn FileSystem> {
+        Some(self)
+    }
+}
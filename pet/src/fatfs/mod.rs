@@ -0,0 +1,1477 @@
+// FAT16 and FAT32 volumes, e.g. on an SD card prepared on a PC
+//
+// The volume is either the whole device or the first FAT partition of its MBR. Only the
+// files in the root directory with a PET extension are used, "game.prg" is the program
+// "GAME" (see `dos::hostname`), like in host folders. Names are compared ignoring the
+// letter case. Long names are read and written, a name fitting 8.3 in lowercase is stored
+// as a short name only. Read-only files are locked ones.
+//
+// All sectors go through a single buffer, it's written back when another sector is needed,
+// on close and on flush. Clusters are allocated as a file grows, the directory entry gets
+// the first cluster and the length on close.
+
+use crate::dos::listing::{self, Directory, DirectoryEntry, DiskHeader};
+use crate::dos::volume::{Channels, Volume};
+use crate::dos::{hostname, new_name, valid_name, DosError, FileSystem, MAX_NAME_LEN};
+use crate::io::{FileMode, FileType};
+
+pub const SECTOR_SIZE: usize = 512;
+
+// boot sector
+const BOOT_JUMP: usize = 0;
+const BOOT_OEM: usize = 3;
+const BOOT_BYTES_PER_SECTOR: usize = 11;
+const BOOT_SECTORS_PER_CLUSTER: usize = 13;
+const BOOT_RESERVED_SECTORS: usize = 14;
+const BOOT_FATS: usize = 16;
+const BOOT_ROOT_ENTRIES: usize = 17;
+const BOOT_SECTORS_16: usize = 19;
+const BOOT_MEDIA: usize = 21;
+const BOOT_FAT_SECTORS_16: usize = 22;
+const BOOT_SECTORS_PER_TRACK: usize = 24;
+const BOOT_HEADS: usize = 26;
+const BOOT_HIDDEN_SECTORS: usize = 28;
+const BOOT_SECTORS_32: usize = 32;
+const BOOT_FAT_SECTORS_32: usize = 36;
+const BOOT_ROOT_CLUSTER: usize = 44;
+const BOOT_FS_INFO: usize = 48;
+const BOOT_BACKUP: usize = 50;
+// the extended boot record starts here for FAT16 and 28 bytes later for FAT32
+const BOOT_EXTENDED_16: usize = 36;
+const BOOT_EXTENDED_32: usize = 64;
+const EXTENDED_DRIVE: usize = 0;
+const EXTENDED_SIGNATURE: usize = 2;
+const EXTENDED_VOLUME_ID: usize = 3;
+const EXTENDED_LABEL: usize = 7;
+const EXTENDED_FS_TYPE: usize = 18;
+const BOOT_SIGNATURE: usize = 510;
+
+// FAT32 FSInfo sector
+const FS_INFO_MAGIC: usize = 0;
+const FS_INFO_MAGIC_2: usize = 484;
+const FS_INFO_FREE: usize = 488;
+const FS_INFO_NEXT_FREE: usize = 492;
+
+// MBR
+const PARTITIONS: usize = 446;
+const PARTITION_LEN: usize = 16;
+const PARTITION_TYPE: usize = 4;
+const PARTITION_START: usize = 8;
+const PARTITION_SECTORS: usize = 12;
+const FAT_PARTITION_TYPES: &[u8] = &[0x04, 0x06, 0x0b, 0x0c, 0x0e];
+
+// directory entries
+const ENTRY_SIZE: usize = 32;
+const ENTRIES_PER_SECTOR: u32 = (SECTOR_SIZE / ENTRY_SIZE) as u32;
+const ENTRY_NAME: usize = 0;
+const ENTRY_ATTRIBUTES: usize = 11;
+const ENTRY_CASE: usize = 12;
+const ENTRY_CLUSTER_HIGH: usize = 20;
+const ENTRY_TIME: usize = 22;
+const ENTRY_DATE: usize = 24;
+const ENTRY_CLUSTER_LOW: usize = 26;
+const ENTRY_LENGTH: usize = 28;
+const SHORT_NAME_LEN: usize = 11;
+
+const ENTRY_END: u8 = 0x00;
+const ENTRY_DELETED: u8 = 0xe5;
+
+const ATTRIBUTE_READ_ONLY: u8 = 0x01;
+const ATTRIBUTE_VOLUME: u8 = 0x08;
+const ATTRIBUTE_DIRECTORY: u8 = 0x10;
+const ATTRIBUTE_ARCHIVE: u8 = 0x20;
+const ATTRIBUTE_LONG_NAME: u8 = 0x0f;
+
+// the base name or the extension of a short name are shown in lowercase
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXTENSION: u8 = 0x10;
+
+// long name entries, 13 UTF-16 characters each
+const LONG_SEQUENCE: usize = 0;
+const LONG_CHECKSUM: usize = 13;
+const LONG_LAST: u8 = 0x40;
+const LONG_CHARS: [usize; 13] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const LONG_NAME_LEN: usize = 13 * MAX_LONG_ENTRIES;
+// enough for 16 PETSCII characters written as "%xx" and the extension
+const MAX_LONG_ENTRIES: usize = 4;
+
+// there is no clock, files are dated 1980-01-01
+const DATE: u16 = 0x0021;
+
+const DISK_NAME: &[u8] = b"SD CARD";
+
+/// Reading or writing a sector failed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SectorError;
+
+/// Storage made of 512 byte sectors like an SD card or an image file of one
+pub trait SectorDevice {
+    /// The number of sectors
+    fn sectors(&self) -> u32;
+
+    fn read_sector(&mut self, sector: u32, data: &mut [u8; SECTOR_SIZE])
+        -> Result<(), SectorError>;
+
+    fn write_sector(&mut self, sector: u32, data: &[u8; SECTOR_SIZE]) -> Result<(), SectorError>;
+}
+
+impl SectorDevice for &mut [u8] {
+    fn sectors(&self) -> u32 {
+        (self.len() / SECTOR_SIZE) as u32
+    }
+
+    fn read_sector(
+        &mut self,
+        sector: u32,
+        data: &mut [u8; SECTOR_SIZE],
+    ) -> Result<(), SectorError> {
+        let start = sector as usize * SECTOR_SIZE;
+        let source = self.get(start..start + SECTOR_SIZE).ok_or(SectorError)?;
+        data.copy_from_slice(source);
+        Ok(())
+    }
+
+    fn write_sector(&mut self, sector: u32, data: &[u8; SECTOR_SIZE]) -> Result<(), SectorError> {
+        let start = sector as usize * SECTOR_SIZE;
+        let destination = self
+            .get_mut(start..start + SECTOR_SIZE)
+            .ok_or(SectorError)?;
+        destination.copy_from_slice(data);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FatType {
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    fn end_of_chain(self) -> u32 {
+        match self {
+            FatType::Fat16 => 0xfff8,
+            FatType::Fat32 => 0x0fff_fff8,
+        }
+    }
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+fn put_u16(data: &mut [u8], offset: usize, value: u16) {
+    data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+// characters allowed in short names besides letters and digits
+fn is_short_name_char(c: u8) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || b"!#$%&'()-@^_`{}~".contains(&c)
+}
+
+fn short_name_checksum(short: &[u8]) -> u8 {
+    short
+        .iter()
+        .fold(0u8, |sum, &c| sum.rotate_right(1).wrapping_add(c))
+}
+
+/// A host filename with its extension
+#[derive(Clone, Copy)]
+struct LongName {
+    name: [u8; LONG_NAME_LEN],
+    len: usize,
+}
+
+impl LongName {
+    const EMPTY: LongName = LongName {
+        name: [0u8; LONG_NAME_LEN],
+        len: 0,
+    };
+
+    fn push(&mut self, c: u8) {
+        if self.len < LONG_NAME_LEN {
+            self.name[self.len] = c;
+            self.len += 1;
+        }
+    }
+
+    // "GAME" as a program is "game.prg"
+    fn from_pet(name: &[u8], file_type: FileType) -> LongName {
+        let mut long = LongName::EMPTY;
+        hostname::host_name(name, |c| long.push(c));
+        long.push(b'.');
+        hostname::extension(file_type)
+            .bytes()
+            .for_each(|c| long.push(c));
+        long
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.name[..self.len]
+    }
+
+    // the stem and the extension without the dot
+    fn split(&self) -> (&[u8], &[u8]) {
+        let name = self.as_bytes();
+        match name.iter().rposition(|&c| c == b'.') {
+            Some(dot) if dot > 0 => (&name[..dot], &name[dot + 1..]),
+            _ => (name, &[]),
+        }
+    }
+
+    // "game.prg" as a short name, None if it needs a long one. Uppercase letters would get
+    // lost, so only names in lowercase are stored as short names.
+    fn short_name(&self) -> Option<[u8; SHORT_NAME_LEN]> {
+        let (stem, extension) = self.split();
+        if stem.is_empty() || stem.len() > 8 || extension.len() > 3 {
+            return None;
+        }
+
+        let mut short = [b' '; SHORT_NAME_LEN];
+        for (i, &c) in stem.iter().enumerate() {
+            match c.to_ascii_uppercase() {
+                u if is_short_name_char(u) && !c.is_ascii_uppercase() => short[i] = u,
+                _ => return None,
+            }
+        }
+        for (i, &c) in extension.iter().enumerate() {
+            short[8 + i] = c.to_ascii_uppercase();
+        }
+        Some(short)
+    }
+}
+
+/// A file found in the directory
+#[derive(Clone, Copy)]
+pub(crate) struct Found {
+    // its long name entries come before the short one
+    first_entry: u32,
+    entry: u32,
+    name: [u8; MAX_NAME_LEN],
+    name_len: usize,
+    file_type: FileType,
+    first_cluster: u32,
+    len: u32,
+    attributes: u8,
+}
+
+impl Found {
+    fn name(&self) -> &[u8] {
+        &self.name[..self.name_len]
+    }
+
+    fn locked(&self) -> bool {
+        self.attributes & ATTRIBUTE_READ_ONLY != 0
+    }
+}
+
+// the long name collected from the entries before a short one
+#[derive(Clone, Copy)]
+struct LongEntries {
+    name: LongName,
+    first_entry: u32,
+    // the sequence number the next entry needs, 0 if there is no valid long name
+    expected: u8,
+    checksum: u8,
+}
+
+impl LongEntries {
+    const NONE: LongEntries = LongEntries {
+        name: LongName::EMPTY,
+        first_entry: 0,
+        expected: 0,
+        checksum: 0,
+    };
+
+    fn add(&mut self, index: u32, entry: &[u8]) {
+        let sequence = entry[LONG_SEQUENCE];
+        let number = sequence & !LONG_LAST;
+        if sequence & LONG_LAST != 0 {
+            *self = LongEntries::NONE;
+            if number as usize > MAX_LONG_ENTRIES {
+                return;
+            }
+            self.first_entry = index;
+            self.expected = number;
+            self.checksum = entry[LONG_CHECKSUM];
+        } else if number != self.expected || entry[LONG_CHECKSUM] != self.checksum {
+            self.expected = 0;
+        }
+        if self.expected == 0 || number != self.expected {
+            return;
+        }
+
+        for (i, &offset) in LONG_CHARS.iter().enumerate() {
+            let c = u16_at(entry, offset);
+            if c == 0 || c == 0xffff {
+                break;
+            }
+            let position = (number as usize - 1) * 13 + i;
+            // other characters than ASCII become "?" later
+            self.name.name[position] = match c {
+                0x20..=0x7e => c as u8,
+                _ => 0xff,
+            };
+            self.name.len = self.name.len.max(position + 1);
+        }
+        self.expected -= 1;
+    }
+
+    // the long name if it belongs to the short entry
+    fn name_for(&self, short: &[u8]) -> Option<LongName> {
+        match self.expected == 0 && self.name.len > 0 {
+            true if short_name_checksum(short) == self.checksum => Some(self.name),
+            _ => None,
+        }
+    }
+}
+
+// a file opened on a channel
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Channel {
+    entry: u32,
+    first_cluster: u32,
+    // the cluster of the byte before the position
+    cluster: u32,
+    position: u32,
+    len: u32,
+    writing: bool,
+}
+
+/// A FAT volume on a `SectorDevice`
+pub struct FatFs<D: SectorDevice> {
+    device: D,
+    fat_type: FatType,
+
+    // in sectors from the start of the device
+    volume_start: u32,
+    volume_sectors: u32,
+    fat_start: u32,
+    fat_sectors: u32,
+    fats: u32,
+    // FAT16 has a fixed root directory, FAT32 a chain of clusters
+    root_start: u32,
+    root_entries: u32,
+    root_cluster: u32,
+    data_start: u32,
+    sectors_per_cluster: u32,
+    clusters: u32,
+    fs_info: Option<u32>,
+
+    free_clusters: Option<u32>,
+    // where the search for a free cluster starts
+    next_free: u32,
+
+    buffer: [u8; SECTOR_SIZE],
+    buffer_sector: Option<u32>,
+    dirty: bool,
+
+    channels: Channels<Channel>,
+}
+
+impl<D: SectorDevice> core::fmt::Debug for FatFs<D> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "FatFs {:?} {} clusters", self.fat_type, self.clusters)
+    }
+}
+
+// the first FAT volume: the whole device or a partition
+fn find_volume<D: SectorDevice>(device: &mut D) -> Result<(u32, u32), DosError> {
+    let mut sector = [0u8; SECTOR_SIZE];
+    device
+        .read_sector(0, &mut sector)
+        .map_err(|_| DosError::DriveNotReady)?;
+    if u16_at(&sector, BOOT_SIGNATURE) != 0xaa55 {
+        return Err(DosError::DriveNotReady);
+    }
+
+    let is_boot_sector = matches!(sector[BOOT_JUMP], 0xeb | 0xe9)
+        && u16_at(&sector, BOOT_BYTES_PER_SECTOR) as usize == SECTOR_SIZE;
+    if is_boot_sector {
+        return Ok((0, device.sectors()));
+    }
+
+    (0..4)
+        .map(|i| &sector[PARTITIONS + i * PARTITION_LEN..][..PARTITION_LEN])
+        .find(|partition| FAT_PARTITION_TYPES.contains(&partition[PARTITION_TYPE]))
+        .map(|partition| {
+            (
+                u32_at(partition, PARTITION_START),
+                u32_at(partition, PARTITION_SECTORS),
+            )
+        })
+        .ok_or(DosError::DriveNotReady)
+}
+
+// sectors per cluster as Windows chooses them, None if the size doesn't suit the type
+fn sectors_per_cluster(sectors: u32, fat_type: FatType) -> Option<u32> {
+    let table: &[(u32, u32)] = match fat_type {
+        FatType::Fat16 => &[
+            (8400, 0),
+            (32680, 2),
+            (262_144, 4),
+            (524_288, 8),
+            (1_048_576, 16),
+            (2_097_152, 32),
+            (4_194_304, 64),
+        ],
+        FatType::Fat32 => &[
+            (66600, 0),
+            (532_480, 1),
+            (16_777_216, 8),
+            (33_554_432, 16),
+            (67_108_864, 32),
+            (u32::MAX, 64),
+        ],
+    };
+    match table.iter().find(|(limit, _)| sectors <= *limit)? {
+        (_, 0) => None,
+        (_, sectors_per_cluster) => Some(*sectors_per_cluster),
+    }
+}
+
+/// Formats the whole device as one FAT volume, FAT16 up to 2 GB and FAT32 beyond
+pub fn format<D: SectorDevice>(device: &mut D, label: &[u8]) -> Result<(), DosError> {
+    let sectors = device.sectors();
+    let fat_type = match sectors_per_cluster(sectors, FatType::Fat16) {
+        Some(_) => FatType::Fat16,
+        None => FatType::Fat32,
+    };
+    format_volume(device, 0, sectors, label, fat_type, 0x2a2a_2a2a)
+}
+
+/// Formats the whole device with the given FAT type
+pub fn format_as<D: SectorDevice>(
+    device: &mut D,
+    label: &[u8],
+    fat_type: FatType,
+) -> Result<(), DosError> {
+    let sectors = device.sectors();
+    format_volume(device, 0, sectors, label, fat_type, 0x2a2a_2a2a)
+}
+
+// the label is a PETSCII name
+fn volume_label(name: &[u8]) -> [u8; SHORT_NAME_LEN] {
+    let mut label = [b' '; SHORT_NAME_LEN];
+    let mut len = 0;
+    hostname::host_name(name, |c| {
+        if len < SHORT_NAME_LEN {
+            label[len] = match c.to_ascii_uppercase() {
+                c if is_short_name_char(c) || c == b' ' => c,
+                _ => b'_',
+            };
+            len += 1;
+        }
+    });
+    if len == 0 {
+        label[..7].copy_from_slice(b"NO NAME");
+    }
+    label
+}
+
+fn format_volume<D: SectorDevice>(
+    device: &mut D,
+    start: u32,
+    sectors: u32,
+    name: &[u8],
+    fat_type: FatType,
+    volume_id: u32,
+) -> Result<(), DosError> {
+    let sectors_per_cluster = sectors_per_cluster(sectors, fat_type).ok_or(DosError::DiskFull)?;
+    let (reserved, root_entries) = match fat_type {
+        FatType::Fat16 => (1u32, 512u32),
+        FatType::Fat32 => (32, 0),
+    };
+    let fats = 2u32;
+    let root_sectors = root_entries / ENTRIES_PER_SECTOR;
+
+    // as Microsoft calculates it, a few sectors too many rather than too few
+    let rest = sectors - reserved - root_sectors;
+    let per_fat_sector = match fat_type {
+        FatType::Fat16 => 256 * sectors_per_cluster + fats,
+        FatType::Fat32 => (256 * sectors_per_cluster + fats) / 2,
+    };
+    let fat_sectors = (rest + per_fat_sector - 1) / per_fat_sector;
+    let data_start = reserved + fats * fat_sectors + root_sectors;
+    let clusters = (sectors - data_start) / sectors_per_cluster;
+
+    let write = |device: &mut D, sector: u32, data: &[u8; SECTOR_SIZE]| {
+        device
+            .write_sector(start + sector, data)
+            .map_err(|_| DosError::WriteError)
+    };
+
+    // the FATs and the root directory start empty
+    let zero = [0u8; SECTOR_SIZE];
+    let first_cluster = data_start + sectors_per_cluster;
+    for sector in 0..first_cluster {
+        write(device, sector, &zero)?;
+    }
+
+    let label = volume_label(name);
+    let mut boot = [0u8; SECTOR_SIZE];
+    boot[BOOT_JUMP..BOOT_JUMP + 3].copy_from_slice(&[0xeb, 0x58, 0x90]);
+    boot[BOOT_OEM..BOOT_OEM + 8].copy_from_slice(b"BLUEPET ");
+    put_u16(&mut boot, BOOT_BYTES_PER_SECTOR, SECTOR_SIZE as u16);
+    boot[BOOT_SECTORS_PER_CLUSTER] = sectors_per_cluster as u8;
+    put_u16(&mut boot, BOOT_RESERVED_SECTORS, reserved as u16);
+    boot[BOOT_FATS] = fats as u8;
+    put_u16(&mut boot, BOOT_ROOT_ENTRIES, root_entries as u16);
+    match sectors {
+        0..=0xffff if fat_type == FatType::Fat16 => {
+            put_u16(&mut boot, BOOT_SECTORS_16, sectors as u16)
+        }
+        _ => put_u32(&mut boot, BOOT_SECTORS_32, sectors),
+    }
+    boot[BOOT_MEDIA] = 0xf8;
+    put_u16(&mut boot, BOOT_SECTORS_PER_TRACK, 63);
+    put_u16(&mut boot, BOOT_HEADS, 255);
+    put_u32(&mut boot, BOOT_HIDDEN_SECTORS, start);
+    let (extended, fs_type) = match fat_type {
+        FatType::Fat16 => {
+            boot[BOOT_JUMP + 1] = 0x3c;
+            put_u16(&mut boot, BOOT_FAT_SECTORS_16, fat_sectors as u16);
+            (BOOT_EXTENDED_16, b"FAT16   ")
+        }
+        FatType::Fat32 => {
+            put_u32(&mut boot, BOOT_FAT_SECTORS_32, fat_sectors);
+            put_u32(&mut boot, BOOT_ROOT_CLUSTER, 2);
+            put_u16(&mut boot, BOOT_FS_INFO, 1);
+            put_u16(&mut boot, BOOT_BACKUP, 6);
+            (BOOT_EXTENDED_32, b"FAT32   ")
+        }
+    };
+    boot[extended + EXTENDED_DRIVE] = 0x80;
+    boot[extended + EXTENDED_SIGNATURE] = 0x29;
+    put_u32(&mut boot, extended + EXTENDED_VOLUME_ID, volume_id);
+    boot[extended + EXTENDED_LABEL..][..SHORT_NAME_LEN].copy_from_slice(&label);
+    boot[extended + EXTENDED_FS_TYPE..][..8].copy_from_slice(fs_type);
+    put_u16(&mut boot, BOOT_SIGNATURE, 0xaa55);
+    write(device, 0, &boot)?;
+
+    // the first two FAT entries are taken, for FAT32 the third one is the root directory
+    let mut fat = [0u8; SECTOR_SIZE];
+    match fat_type {
+        FatType::Fat16 => {
+            put_u16(&mut fat, 0, 0xfff8);
+            put_u16(&mut fat, 2, 0xffff);
+        }
+        FatType::Fat32 => {
+            put_u32(&mut fat, 0, 0x0fff_fff8);
+            put_u32(&mut fat, 4, 0x0fff_ffff);
+            put_u32(&mut fat, 8, 0x0fff_ffff);
+
+            let mut fs_info = [0u8; SECTOR_SIZE];
+            put_u32(&mut fs_info, FS_INFO_MAGIC, 0x4161_5252);
+            put_u32(&mut fs_info, FS_INFO_MAGIC_2, 0x6141_7272);
+            put_u32(&mut fs_info, FS_INFO_FREE, clusters - 1);
+            put_u32(&mut fs_info, FS_INFO_NEXT_FREE, 3);
+            put_u16(&mut fs_info, BOOT_SIGNATURE, 0xaa55);
+            write(device, 1, &fs_info)?;
+            write(device, 6, &boot)?;
+            write(device, 7, &fs_info)?;
+        }
+    }
+    for i in 0..fats {
+        write(device, reserved + i * fat_sectors, &fat)?;
+    }
+
+    let mut root = [0u8; SECTOR_SIZE];
+    root[ENTRY_NAME..ENTRY_NAME + SHORT_NAME_LEN].copy_from_slice(&label);
+    root[ENTRY_ATTRIBUTES] = ATTRIBUTE_VOLUME;
+    put_u16(&mut root, ENTRY_DATE, DATE);
+    write(device, reserved + fats * fat_sectors, &root)
+}
+
+impl<D: SectorDevice> FatFs<D> {
+    /// Mounts the first FAT volume of the device
+    pub fn new(mut device: D) -> Result<FatFs<D>, DosError> {
+        let (volume_start, volume_sectors) = find_volume(&mut device)?;
+        let mut fs = FatFs {
+            device,
+            fat_type: FatType::Fat16,
+            volume_start,
+            volume_sectors,
+            fat_start: 0,
+            fat_sectors: 0,
+            fats: 0,
+            root_start: 0,
+            root_entries: 0,
+            root_cluster: 0,
+            data_start: 0,
+            sectors_per_cluster: 1,
+            clusters: 0,
+            fs_info: None,
+            free_clusters: None,
+            next_free: 2,
+            buffer: [0u8; SECTOR_SIZE],
+            buffer_sector: None,
+            dirty: false,
+            channels: Channels::new(),
+        };
+        fs.mount()?;
+        Ok(fs)
+    }
+
+    /// Gives back the device, everything written is flushed first
+    pub fn into_device(mut self) -> Result<D, DosError> {
+        self.flush()?;
+        Ok(self.device)
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.fat_type
+    }
+
+    /// The bytes of a cluster
+    pub fn cluster_size(&self) -> usize {
+        self.sectors_per_cluster as usize * SECTOR_SIZE
+    }
+
+    /// The number of free clusters, counted once if the volume doesn't tell
+    pub fn free_clusters(&mut self) -> Result<u32, DosError> {
+        if let Some(free) = self.free_clusters {
+            return Ok(free);
+        }
+        let mut free = 0;
+        for cluster in 2..self.clusters + 2 {
+            if self.fat_entry(cluster)? == 0 {
+                free += 1;
+            }
+        }
+        self.free_clusters = Some(free);
+        Ok(free)
+    }
+
+    /// Writes the sector in the buffer back to the device
+    pub fn flush(&mut self) -> Result<(), DosError> {
+        if let (true, Some(sector)) = (self.dirty, self.buffer_sector) {
+            self.device
+                .write_sector(sector, &self.buffer)
+                .map_err(|_| DosError::WriteError)?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+
+    fn mount(&mut self) -> Result<(), DosError> {
+        self.flush()?;
+        self.buffer_sector = None;
+        let boot = *self.sector(self.volume_start)?;
+
+        let sectors_per_cluster = boot[BOOT_SECTORS_PER_CLUSTER] as u32;
+        let reserved = u16_at(&boot, BOOT_RESERVED_SECTORS) as u32;
+        let fats = boot[BOOT_FATS] as u32;
+        let root_entries = u16_at(&boot, BOOT_ROOT_ENTRIES) as u32;
+        let sectors = match u16_at(&boot, BOOT_SECTORS_16) {
+            0 => u32_at(&boot, BOOT_SECTORS_32),
+            sectors => sectors as u32,
+        };
+        let fat_sectors = match u16_at(&boot, BOOT_FAT_SECTORS_16) {
+            0 => u32_at(&boot, BOOT_FAT_SECTORS_32),
+            sectors => sectors as u32,
+        };
+        if u16_at(&boot, BOOT_BYTES_PER_SECTOR) as usize != SECTOR_SIZE
+            || !sectors_per_cluster.is_power_of_two()
+            || fats == 0
+        {
+            return Err(DosError::DriveNotReady);
+        }
+
+        let root_sectors = (root_entries + ENTRIES_PER_SECTOR - 1) / ENTRIES_PER_SECTOR;
+        let data_start = reserved + fats * fat_sectors + root_sectors;
+        let clusters = sectors.saturating_sub(data_start) / sectors_per_cluster;
+        self.fat_type = match clusters {
+            0..=4084 => return Err(DosError::DriveNotReady),
+            4085..=65524 => FatType::Fat16,
+            _ => FatType::Fat32,
+        };
+
+        self.volume_sectors = sectors;
+        self.fat_start = self.volume_start + reserved;
+        self.fat_sectors = fat_sectors;
+        self.fats = fats;
+        self.root_start = self.fat_start + fats * fat_sectors;
+        self.root_entries = root_entries;
+        self.data_start = self.volume_start + data_start;
+        self.sectors_per_cluster = sectors_per_cluster;
+        self.clusters = clusters;
+        self.free_clusters = None;
+        self.next_free = 2;
+        self.fs_info = None;
+
+        if self.fat_type == FatType::Fat32 {
+            self.root_cluster = u32_at(&boot, BOOT_ROOT_CLUSTER);
+            let fs_info = self.volume_start + u16_at(&boot, BOOT_FS_INFO) as u32;
+            let info = *self.sector(fs_info)?;
+            if u32_at(&info, FS_INFO_MAGIC) == 0x4161_5252 {
+                self.fs_info = Some(fs_info);
+                let free = u32_at(&info, FS_INFO_FREE);
+                let next_free = u32_at(&info, FS_INFO_NEXT_FREE);
+                self.free_clusters = Some(free).filter(|&free| free <= clusters);
+                if (2..clusters + 2).contains(&next_free) {
+                    self.next_free = next_free;
+                }
+            }
+        } else {
+            self.free_clusters()?;
+        }
+        Ok(())
+    }
+
+    // sectors
+
+    fn sector(&mut self, sector: u32) -> Result<&[u8; SECTOR_SIZE], DosError> {
+        self.fetch(sector)?;
+        Ok(&self.buffer)
+    }
+
+    fn sector_mut(&mut self, sector: u32) -> Result<&mut [u8; SECTOR_SIZE], DosError> {
+        self.fetch(sector)?;
+        self.dirty = true;
+        Ok(&mut self.buffer)
+    }
+
+    fn fetch(&mut self, sector: u32) -> Result<(), DosError> {
+        if self.buffer_sector == Some(sector) {
+            return Ok(());
+        }
+        self.flush()?;
+        self.buffer_sector = None;
+        self.device
+            .read_sector(sector, &mut self.buffer)
+            .map_err(|_| DosError::ReadError)?;
+        self.buffer_sector = Some(sector);
+        Ok(())
+    }
+
+    // clusters
+
+    fn cluster_sector(&self, cluster: u32) -> Result<u32, DosError> {
+        if !(2..self.clusters + 2).contains(&cluster) {
+            return Err(DosError::IllegalTrackOrSector);
+        }
+        Ok(self.data_start + (cluster - 2) * self.sectors_per_cluster)
+    }
+
+    // the sector and the offset of the entry of a cluster in the first FAT
+    fn fat_position(&self, cluster: u32) -> (u32, usize) {
+        let offset = match self.fat_type {
+            FatType::Fat16 => cluster as usize * 2,
+            FatType::Fat32 => cluster as usize * 4,
+        };
+        (
+            self.fat_start + (offset / SECTOR_SIZE) as u32,
+            offset % SECTOR_SIZE,
+        )
+    }
+
+    fn fat_entry(&mut self, cluster: u32) -> Result<u32, DosError> {
+        let (sector, offset) = self.fat_position(cluster);
+        let fat_type = self.fat_type;
+        let data = self.sector(sector)?;
+        Ok(match fat_type {
+            FatType::Fat16 => u16_at(data, offset) as u32,
+            FatType::Fat32 => u32_at(data, offset) & 0x0fff_ffff,
+        })
+    }
+
+    // all FATs are kept the same
+    fn set_fat_entry(&mut self, cluster: u32, value: u32) -> Result<(), DosError> {
+        let (sector, offset) = self.fat_position(cluster);
+        for i in 0..self.fats {
+            let fat_type = self.fat_type;
+            let data = self.sector_mut(sector + i * self.fat_sectors)?;
+            match fat_type {
+                FatType::Fat16 => put_u16(data, offset, value as u16),
+                FatType::Fat32 => {
+                    let reserved = u32_at(data, offset) & 0xf000_0000;
+                    put_u32(data, offset, reserved | value);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // None at the end of the chain
+    fn next_cluster(&mut self, cluster: u32) -> Result<Option<u32>, DosError> {
+        match self.fat_entry(cluster)? {
+            next if next >= self.fat_type.end_of_chain() => Ok(None),
+            next if (2..self.clusters + 2).contains(&next) => Ok(Some(next)),
+            _ => Err(DosError::ReadError),
+        }
+    }
+
+    // a free cluster at the end of the chain of `previous`
+    fn allocate(&mut self, previous: Option<u32>) -> Result<u32, DosError> {
+        for i in 0..self.clusters {
+            let cluster = 2 + (self.next_free - 2 + i) % self.clusters;
+            if self.fat_entry(cluster)? == 0 {
+                self.set_fat_entry(cluster, self.fat_type.end_of_chain() | 0x07)?;
+                if let Some(previous) = previous {
+                    self.set_fat_entry(previous, cluster)?;
+                }
+                self.next_free = 2 + (cluster - 1) % self.clusters;
+                self.free_clusters = self.free_clusters.map(|free| free.saturating_sub(1));
+                return Ok(cluster);
+            }
+        }
+        Err(DosError::DiskFull)
+    }
+
+    fn free_chain(&mut self, first: u32) -> Result<(), DosError> {
+        let mut cluster = match first {
+            0 => return Ok(()),
+            first => first,
+        };
+        for _ in 0..self.clusters {
+            let next = self.next_cluster(cluster)?;
+            self.set_fat_entry(cluster, 0)?;
+            self.free_clusters = self.free_clusters.map(|free| free + 1);
+            match next {
+                Some(next) => cluster = next,
+                None => return Ok(()),
+            }
+        }
+        Err(DosError::ReadError)
+    }
+
+    // the FSInfo sector keeps the free clusters of FAT32, it's only a hint
+    fn write_fs_info(&mut self) -> Result<(), DosError> {
+        if let (Some(sector), Some(free)) = (self.fs_info, self.free_clusters) {
+            let next_free = self.next_free;
+            let info = self.sector_mut(sector)?;
+            put_u32(info, FS_INFO_FREE, free);
+            put_u32(info, FS_INFO_NEXT_FREE, next_free);
+        }
+        self.flush()
+    }
+
+    // directory
+
+    // the sector and the offset of an entry of the root directory,
+    // None after the last one
+    fn entry_position(&mut self, index: u32) -> Result<Option<(u32, usize)>, DosError> {
+        let offset = (index % ENTRIES_PER_SECTOR) as usize * ENTRY_SIZE;
+        if self.fat_type == FatType::Fat16 {
+            return Ok(match index < self.root_entries {
+                true => Some((self.root_start + index / ENTRIES_PER_SECTOR, offset)),
+                false => None,
+            });
+        }
+
+        let per_cluster = ENTRIES_PER_SECTOR * self.sectors_per_cluster;
+        let mut cluster = self.root_cluster;
+        for _ in 0..index / per_cluster {
+            cluster = match self.next_cluster(cluster)? {
+                Some(next) => next,
+                None => return Ok(None),
+            };
+        }
+        let sector = (index % per_cluster) / ENTRIES_PER_SECTOR;
+        Ok(Some((self.cluster_sector(cluster)? + sector, offset)))
+    }
+
+    fn entry(&mut self, index: u32) -> Result<Option<[u8; ENTRY_SIZE]>, DosError> {
+        match self.entry_position(index)? {
+            Some((sector, offset)) => {
+                let mut entry = [0u8; ENTRY_SIZE];
+                entry.copy_from_slice(&self.sector(sector)?[offset..offset + ENTRY_SIZE]);
+                Ok(Some(entry))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn entry_mut(&mut self, index: u32) -> Result<&mut [u8], DosError> {
+        let (sector, offset) = self
+            .entry_position(index)?
+            .ok_or(DosError::IllegalTrackOrSector)?;
+        Ok(&mut self.sector_mut(sector)?[offset..offset + ENTRY_SIZE])
+    }
+
+    // the file at the cursor or the next one, the cursor is the next entry to look at
+    fn next_file(&mut self, cursor: &mut u32) -> Result<Option<Found>, DosError> {
+        let mut long = LongEntries::NONE;
+        loop {
+            let index = *cursor;
+            let entry = match self.entry(index)? {
+                Some(entry) if entry[ENTRY_NAME] != ENTRY_END => entry,
+                _ => return Ok(None),
+            };
+            *cursor += 1;
+
+            let attributes = entry[ENTRY_ATTRIBUTES];
+            if entry[ENTRY_NAME] == ENTRY_DELETED {
+                long = LongEntries::NONE;
+                continue;
+            }
+            if attributes & ATTRIBUTE_LONG_NAME == ATTRIBUTE_LONG_NAME {
+                long.add(index, &entry);
+                continue;
+            }
+            if attributes & (ATTRIBUTE_VOLUME | ATTRIBUTE_DIRECTORY) != 0 {
+                long = LongEntries::NONE;
+                continue;
+            }
+
+            let short = &entry[ENTRY_NAME..ENTRY_NAME + SHORT_NAME_LEN];
+            let (host, first_entry) = match long.name_for(short) {
+                Some(name) => (name, long.first_entry),
+                None => (short_host_name(short), index),
+            };
+            long = LongEntries::NONE;
+
+            let (stem, extension) = host.split();
+            let file_type = match hostname::file_type(extension) {
+                Some(file_type) => file_type,
+                None => continue,
+            };
+            let mut found = Found {
+                first_entry,
+                entry: index,
+                name: [0u8; MAX_NAME_LEN],
+                name_len: 0,
+                file_type,
+                first_cluster: (u16_at(&entry, ENTRY_CLUSTER_HIGH) as u32) << 16
+                    | u16_at(&entry, ENTRY_CLUSTER_LOW) as u32,
+                len: u32_at(&entry, ENTRY_LENGTH),
+                attributes,
+            };
+            hostname::pet_name(stem, |c| {
+                if found.name_len < MAX_NAME_LEN {
+                    found.name[found.name_len] = c;
+                    found.name_len += 1;
+                }
+            });
+            return Ok(Some(found));
+        }
+    }
+
+    fn short_name_exists(&mut self, short: &[u8]) -> Result<bool, DosError> {
+        let mut index = 0;
+        while let Some(entry) = self.entry(index)? {
+            match entry[ENTRY_NAME] {
+                ENTRY_END => return Ok(false),
+                _ if &entry[ENTRY_NAME..ENTRY_NAME + SHORT_NAME_LEN] == short => return Ok(true),
+                _ => index += 1,
+            }
+        }
+        Ok(false)
+    }
+
+    // "GAME~1  PRG" for a name needing a long one
+    fn unique_short_name(&mut self, long: &LongName) -> Result<[u8; SHORT_NAME_LEN], DosError> {
+        let (stem, extension) = long.split();
+        let mut base = [b'_'; 6];
+        let mut base_len = 0;
+        for c in stem.iter().map(|c| c.to_ascii_uppercase()) {
+            if base_len < base.len() && is_short_name_char(c) {
+                base[base_len] = c;
+                base_len += 1;
+            }
+        }
+        let base_len = base_len.max(1);
+
+        let mut short = [b' '; SHORT_NAME_LEN];
+        for (i, c) in extension.iter().take(3).enumerate() {
+            short[8 + i] = c.to_ascii_uppercase();
+        }
+        for number in 1..1_000_000u32 {
+            let mut digits = [0u8; 7];
+            let mut len = 0;
+            let mut rest = number;
+            while rest > 0 {
+                digits[6 - len] = b'0' + (rest % 10) as u8;
+                len += 1;
+                rest /= 10;
+            }
+            let keep = base_len.min(7 - len);
+            short[..8].iter_mut().for_each(|c| *c = b' ');
+            short[..keep].copy_from_slice(&base[..keep]);
+            short[keep] = b'~';
+            short[keep + 1..keep + 1 + len].copy_from_slice(&digits[7 - len..]);
+            if !self.short_name_exists(&short)? {
+                return Ok(short);
+            }
+        }
+        Err(DosError::FileExists)
+    }
+
+    // the index of the first of `count` free entries in a row, FAT32 directories grow
+    fn free_entries(&mut self, count: u32) -> Result<u32, DosError> {
+        let mut run = 0;
+        let mut index = 0;
+        loop {
+            match self.entry(index)? {
+                Some(entry) if matches!(entry[ENTRY_NAME], ENTRY_END | ENTRY_DELETED) => {
+                    run += 1;
+                    if run == count {
+                        return Ok(index + 1 - count);
+                    }
+                }
+                Some(_) => run = 0,
+                None if self.fat_type == FatType::Fat32 => {
+                    self.grow_root()?;
+                    continue;
+                }
+                None => return Err(DosError::DiskFull),
+            }
+            index += 1;
+        }
+    }
+
+    fn grow_root(&mut self) -> Result<(), DosError> {
+        let mut last = self.root_cluster;
+        while let Some(next) = self.next_cluster(last)? {
+            last = next;
+        }
+        let cluster = self.allocate(Some(last))?;
+        let first = self.cluster_sector(cluster)?;
+        for sector in first..first + self.sectors_per_cluster {
+            *self.sector_mut(sector)? = [0u8; SECTOR_SIZE];
+        }
+        Ok(())
+    }
+
+    // writes the entries of a file, returns the index of its short entry
+    fn add_entries(
+        &mut self,
+        name: &[u8],
+        file_type: FileType,
+        attributes: u8,
+        first_cluster: u32,
+        len: u32,
+    ) -> Result<u32, DosError> {
+        let long = LongName::from_pet(name, file_type);
+        let (short, long_entries, case) = match long.short_name() {
+            Some(short) => (short, 0, CASE_LOWER_BASE | CASE_LOWER_EXTENSION),
+            None => {
+                let short = self.unique_short_name(&long)?;
+                (short, ((long.len + 12) / 13) as u32, 0)
+            }
+        };
+
+        let first = self.free_entries(long_entries + 1)?;
+        let checksum = short_name_checksum(&short);
+        for number in 1..=long_entries {
+            let entry = self.entry_mut(first + long_entries - number)?;
+            entry.iter_mut().for_each(|b| *b = 0);
+            entry[LONG_SEQUENCE] = match number == long_entries {
+                true => number as u8 | LONG_LAST,
+                false => number as u8,
+            };
+            entry[ENTRY_ATTRIBUTES] = ATTRIBUTE_LONG_NAME;
+            entry[LONG_CHECKSUM] = checksum;
+            for (i, &offset) in LONG_CHARS.iter().enumerate() {
+                let position = (number as usize - 1) * 13 + i;
+                let c = match long.as_bytes().get(position) {
+                    Some(&c) => c as u16,
+                    None if position == long.len => 0,
+                    None => 0xffff,
+                };
+                put_u16(entry, offset, c);
+            }
+        }
+
+        let index = first + long_entries;
+        let entry = self.entry_mut(index)?;
+        entry.iter_mut().for_each(|b| *b = 0);
+        entry[ENTRY_NAME..ENTRY_NAME + SHORT_NAME_LEN].copy_from_slice(&short);
+        entry[ENTRY_ATTRIBUTES] = attributes;
+        entry[ENTRY_CASE] = case;
+        put_u16(entry, ENTRY_TIME, 0);
+        put_u16(entry, ENTRY_DATE, DATE);
+        put_u16(entry, ENTRY_CLUSTER_HIGH, (first_cluster >> 16) as u16);
+        put_u16(entry, ENTRY_CLUSTER_LOW, first_cluster as u16);
+        put_u32(entry, ENTRY_LENGTH, len);
+        Ok(index)
+    }
+
+    fn remove_entries(&mut self, found: &Found) -> Result<(), DosError> {
+        for index in found.first_entry..=found.entry {
+            self.entry_mut(index)?[ENTRY_NAME] = ENTRY_DELETED;
+        }
+        Ok(())
+    }
+
+    // files
+
+    fn open_read(&mut self, found: &Found) -> Channel {
+        Channel {
+            entry: found.entry,
+            first_cluster: found.first_cluster,
+            cluster: found.first_cluster,
+            position: 0,
+            len: found.len,
+            writing: false,
+        }
+    }
+
+    // the writing goes on after the last cluster
+    fn open_append(&mut self, found: &Found) -> Result<Channel, DosError> {
+        if found.locked() {
+            return Err(DosError::WriteProtectOn);
+        }
+
+        let mut cluster = found.first_cluster;
+        if cluster != 0 {
+            for _ in 0..self.clusters {
+                match self.next_cluster(cluster)? {
+                    Some(next) => cluster = next,
+                    None => break,
+                }
+            }
+        }
+        Ok(Channel {
+            entry: found.entry,
+            first_cluster: found.first_cluster,
+            cluster,
+            position: found.len,
+            len: found.len,
+            writing: true,
+        })
+    }
+
+    // the entry gets the first cluster and the length
+    fn close_channel(&mut self, channel: &Channel) -> Result<(), DosError> {
+        if !channel.writing {
+            return Ok(());
+        }
+
+        let entry = self.entry_mut(channel.entry)?;
+        put_u16(
+            entry,
+            ENTRY_CLUSTER_HIGH,
+            (channel.first_cluster >> 16) as u16,
+        );
+        put_u16(entry, ENTRY_CLUSTER_LOW, channel.first_cluster as u16);
+        put_u32(entry, ENTRY_LENGTH, channel.len);
+        self.write_fs_info()
+    }
+
+    // the volume label in the root directory, else the one of the boot sector
+    fn label(&mut self) -> Result<[u8; SHORT_NAME_LEN], DosError> {
+        let mut index = 0;
+        while let Some(entry) = self.entry(index)? {
+            if entry[ENTRY_NAME] == ENTRY_END {
+                break;
+            }
+            let attributes = entry[ENTRY_ATTRIBUTES];
+            if entry[ENTRY_NAME] != ENTRY_DELETED
+                && attributes & ATTRIBUTE_LONG_NAME == ATTRIBUTE_VOLUME
+            {
+                let mut label = [0u8; SHORT_NAME_LEN];
+                label.copy_from_slice(&entry[ENTRY_NAME..ENTRY_NAME + SHORT_NAME_LEN]);
+                return Ok(label);
+            }
+            index += 1;
+        }
+
+        let extended = match self.fat_type {
+            FatType::Fat16 => BOOT_EXTENDED_16,
+            FatType::Fat32 => BOOT_EXTENDED_32,
+        };
+        let volume_start = self.volume_start;
+        let boot = self.sector(volume_start)?;
+        let mut label = [0u8; SHORT_NAME_LEN];
+        label.copy_from_slice(&boot[extended + EXTENDED_LABEL..][..SHORT_NAME_LEN]);
+        Ok(label)
+    }
+}
+
+// short names are shown in lowercase, the letter case doesn't matter for them
+fn short_host_name(short: &[u8]) -> LongName {
+    let mut host = LongName::EMPTY;
+    let trimmed = |part: &[u8]| part.len() - part.iter().rev().take_while(|&&c| c == b' ').count();
+    let (stem, extension) = short.split_at(8);
+    let stem = &stem[..trimmed(stem)];
+    let extension = &extension[..trimmed(extension)];
+
+    for (i, &c) in stem.iter().enumerate() {
+        // 0x05 stands for a name starting with 0xe5
+        let c = match (i, c) {
+            (0, 0x05) => 0xe5,
+            _ => c,
+        };
+        host.push(c.to_ascii_lowercase());
+    }
+    if !extension.is_empty() {
+        host.push(b'.');
+        extension
+            .iter()
+            .for_each(|&c| host.push(c.to_ascii_lowercase()));
+    }
+    host
+}
+
+impl<D: SectorDevice> Volume for FatFs<D> {
+    type File = Found;
+    type Channel = Channel;
+
+    fn channels(&mut self) -> &mut Channels<Channel> {
+        &mut self.channels
+    }
+
+    // the first file matching the pattern
+    fn find(&mut self, name: &[u8]) -> Result<Option<Found>, DosError> {
+        let name = valid_name(name)?;
+        let mut cursor = 0;
+        while let Some(found) = self.next_file(&mut cursor)? {
+            if hostname::matches_ignoring_case(name, found.name()) {
+                return Ok(Some(found));
+            }
+        }
+        Ok(None)
+    }
+
+    fn file_type(&mut self, found: &Found) -> Result<FileType, DosError> {
+        Ok(found.file_type)
+    }
+
+    fn create(
+        &mut self,
+        name: &[u8],
+        file_type: FileType,
+        replace: bool,
+    ) -> Result<Channel, DosError> {
+        let name = new_name(name)?;
+        if let Some(existing) = self.find(name)? {
+            if !replace {
+                return Err(DosError::FileExists);
+            }
+            if existing.locked() {
+                return Err(DosError::WriteProtectOn);
+            }
+            self.remove_entries(&existing)?;
+            self.free_chain(existing.first_cluster)?;
+        }
+
+        let entry = self.add_entries(name, file_type, ATTRIBUTE_ARCHIVE, 0, 0)?;
+        self.flush()?;
+        Ok(Channel {
+            entry,
+            first_cluster: 0,
+            cluster: 0,
+            position: 0,
+            len: 0,
+            writing: true,
+        })
+    }
+
+    fn open_file(&mut self, found: &Found, mode: FileMode) -> Result<Channel, DosError> {
+        match mode {
+            FileMode::Append => self.open_append(found),
+            _ => Ok(self.open_read(found)),
+        }
+    }
+
+    fn rewind(&mut self, channel: &mut Channel) -> Result<(), DosError> {
+        channel.position = 0;
+        channel.cluster = channel.first_cluster;
+        Ok(())
+    }
+
+    fn read_len(&mut self, channel: &Channel) -> Result<usize, DosError> {
+        Ok(channel.len as usize)
+    }
+
+    fn is_writing(channel: &Channel) -> bool {
+        channel.writing
+    }
+
+    fn read_byte(&mut self, channel: &mut Channel) -> Result<Option<(u8, bool)>, DosError> {
+        if channel.position >= channel.len {
+            return Ok(None);
+        }
+
+        let offset = channel.position as usize % self.cluster_size();
+        if offset == 0 && channel.position > 0 {
+            channel.cluster = self
+                .next_cluster(channel.cluster)?
+                .ok_or(DosError::ReadError)?;
+        }
+        let sector = self.cluster_sector(channel.cluster)? + (offset / SECTOR_SIZE) as u32;
+        let value = self.sector(sector)?[offset % SECTOR_SIZE];
+        channel.position += 1;
+        Ok(Some((value, channel.position == channel.len)))
+    }
+
+    fn write_byte(&mut self, channel: &mut Channel, value: u8) -> Result<(), DosError> {
+        let offset = channel.len as usize % self.cluster_size();
+        if offset == 0 {
+            let previous = match channel.first_cluster {
+                0 => None,
+                _ => Some(channel.cluster),
+            };
+            channel.cluster = self.allocate(previous)?;
+            if previous.is_none() {
+                channel.first_cluster = channel.cluster;
+            }
+        }
+
+        let sector = self.cluster_sector(channel.cluster)? + (offset / SECTOR_SIZE) as u32;
+        self.sector_mut(sector)?[offset % SECTOR_SIZE] = value;
+        channel.len += 1;
+        channel.position = channel.len;
+        Ok(())
+    }
+
+    fn close_file(&mut self, channel: Channel) -> Result<(), DosError> {
+        self.close_channel(&channel)
+    }
+}
+
+impl<D: SectorDevice> Directory for FatFs<D> {
+    fn disk_header(&mut self) -> Result<DiskHeader, DosError> {
+        let label = self.label()?;
+        let len = label.len() - label.iter().rev().take_while(|&&c| c == b' ').count();
+        let mut name = [0u8; MAX_NAME_LEN];
+        let mut name_len = 0;
+        let lowercase = label.map(|c| c.to_ascii_lowercase());
+        hostname::pet_name(&lowercase[..len], |c| {
+            if name_len < MAX_NAME_LEN {
+                name[name_len] = c;
+                name_len += 1;
+            }
+        });
+
+        let id: &[u8] = match self.fat_type {
+            FatType::Fat16 => b"FAT16",
+            FatType::Fat32 => b"FAT32",
+        };
+        match (name_len, &label[..len]) {
+            (0, _) | (_, b"NO NAME") => Ok(DiskHeader::new(DISK_NAME, id)),
+            _ => Ok(DiskHeader::new(&name[..name_len], id)),
+        }
+    }
+
+    // the cursor is the next directory entry to look at
+    fn next_entry(&mut self, cursor: &mut usize) -> Result<Option<DirectoryEntry>, DosError> {
+        let mut index = *cursor as u32;
+        let found = self.next_file(&mut index)?;
+        *cursor = index as usize;
+        Ok(found.map(|found| {
+            let blocks = listing::blocks(found.len as usize);
+            let mut entry = DirectoryEntry::new(found.name(), found.file_type, blocks);
+            entry.locked = found.locked();
+            entry
+        }))
+    }
+
+    fn blocks_free(&mut self) -> Result<u16, DosError> {
+        let bytes = self.free_clusters()? as u64 * self.cluster_size() as u64;
+        Ok((bytes / 254).min(u16::MAX as u64) as u16)
+    }
+
+    fn name_matches(&self, pattern: &[u8], name: &[u8]) -> bool {
+        hostname::matches_ignoring_case(pattern, name)
+    }
+}
+
+impl<D: SectorDevice> FileSystem for FatFs<D> {
+    // locked files are skipped
+    fn scratch(&mut self, name: &[u8]) -> Result<u8, DosError> {
+        let name = valid_name(name)?;
+        let mut scratched = 0u8;
+        let mut cursor = 0;
+        while let Some(found) = self.next_file(&mut cursor)? {
+            if hostname::matches_ignoring_case(name, found.name()) && !found.locked() {
+                self.remove_entries(&found)?;
+                self.free_chain(found.first_cluster)?;
+                scratched = scratched.saturating_add(1);
+            }
+        }
+        self.write_fs_info()?;
+        Ok(scratched)
+    }
+
+    // the entries are written again, the new name might need more of them
+    fn rename(&mut self, old: &[u8], new: &[u8]) -> Result<(), DosError> {
+        let new = new_name(new)?;
+        if self.find(new)?.is_some() {
+            return Err(DosError::FileExists);
+        }
+        let found = self.find(old)?.ok_or(DosError::FileNotFound)?;
+        self.remove_entries(&found)?;
+        self.add_entries(
+            new,
+            found.file_type,
+            found.attributes,
+            found.first_cluster,
+            found.len,
+        )?;
+        self.flush()
+    }
+
+    fn copy(&mut self, source: &[u8], destination: &[u8]) -> Result<(), DosError> {
+        let found = self.find(source)?.ok_or(DosError::FileNotFound)?;
+        let mut from = self.open_read(&found);
+        let mut to = self.create(destination, found.file_type, false)?;
+        while let Some((value, _)) = self.read_byte(&mut from)? {
+            self.write_byte(&mut to, value)?;
+        }
+        self.close_channel(&to)
+    }
+
+    fn lock(&mut self, name: &[u8], locked: Option<bool>) -> Result<u8, DosError> {
+        let name = valid_name(name)?;
+        let mut matched = 0u8;
+        let mut cursor = 0;
+        while let Some(found) = self.next_file(&mut cursor)? {
+            if hostname::matches_ignoring_case(name, found.name()) {
+                let entry = self.entry_mut(found.entry)?;
+                match locked.unwrap_or(!found.locked()) {
+                    true => entry[ENTRY_ATTRIBUTES] |= ATTRIBUTE_READ_ONLY,
+                    false => entry[ENTRY_ATTRIBUTES] &= !ATTRIBUTE_READ_ONLY,
+                }
+                matched = matched.saturating_add(1);
+            }
+        }
+        self.flush()?;
+        Ok(matched)
+    }
+
+    fn initialize(&mut self) -> Result<(), DosError> {
+        self.mount()
+    }
+
+    // checking the volume is left to the PC
+    fn validate(&mut self) -> Result<(), DosError> {
+        self.flush()
+    }
+
+    // a quick format of the volume, the id becomes its serial number
+    fn format(&mut self, name: &[u8], id: Option<&[u8]>) -> Result<(), DosError> {
+        let name = new_name(name)?;
+        if id == Some(b"") {
+            return Err(DosError::SyntaxError);
+        }
+        self.flush()?;
+        self.buffer_sector = None;
+        self.channels = Channels::new();
+
+        let mut volume_id = 0x2a2a_2a2a;
+        if let Some(id) = id {
+            for (i, &c) in id.iter().take(4).enumerate() {
+                volume_id = volume_id & !(0xff << (i * 8)) | (c as u32) << (i * 8);
+            }
+        }
+        let (start, sectors, fat_type) = (self.volume_start, self.volume_sectors, self.fat_type);
+        format_volume(&mut self.device, start, sectors, name, fat_type, volume_id)?;
+        self.mount()
+    }
+}
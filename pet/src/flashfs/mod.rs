@@ -0,0 +1,1156 @@
+// A log-structured filesystem for NOR flash like the SPI flash of the board
+//
+// The flash is used in sectors of 4 KB, every file takes one or more of them. The first
+// sector of a file (its head) starts with the name, type and length of the file, every
+// further sector with a short header. A sector header links to the next sector of the file.
+//
+// Nothing is ever rewritten in place. A file is written to freshly erased sectors and only
+// becomes visible when the state byte of its head is programmed to "committed" on close.
+// Replacing, renaming or appending writes a new head naming the one it replaces, the old
+// head is marked deleted afterwards. Since programming can only clear bits, the state,
+// the length and the links are left erased (0xff) until they are known.
+//
+// Flags like the lock of a file are bits of the head cleared when set, so an erased head
+// has none. Locking also writes a new head.
+//
+// The head keeps a CRC of the file content and one of its own fields, both written on
+// close. Heads with a wrong CRC or a broken chain of sectors are skipped when mounting,
+// the content is checked when the file is read to its end and by validate.
+//
+// The flash holds several disks, each with its own files. A head names the disk of its file,
+// disks besides the first are created by a head of their own giving their name and id.
+// The sectors are shared by all of them.
+//
+// There is no directory or allocation table on the flash, mounting scans the sector
+// headers. Free sectors are taken round-robin starting after the newest file, so erases
+// are spread over the whole chip. Every header keeps the number of times its sector was
+// erased.
+
+use crate::dos::listing::{self, Directory, DirectoryEntry, DiskHeader};
+use crate::dos::volume::{Channels, Volume};
+use crate::dos::{self, new_name, valid_name, DosError, FileSystem, MAX_NAME_LEN};
+use crate::io::{FileMode, FileType};
+
+pub const SECTOR_SIZE: usize = 4096;
+pub const PAGE_SIZE: usize = 256;
+/// Flash beyond this many sectors (8 MB) is not used
+pub const MAX_SECTORS: usize = 2048;
+
+const MAGIC: &[u8; 4] = b"BPFS";
+
+// header of every sector
+const HEADER_MAGIC: usize = 0;
+const HEADER_ERASES: usize = 4;
+const HEADER_KIND: usize = 8;
+const HEADER_STATE: usize = 9;
+const HEADER_NEXT: usize = 10;
+const DATA_START: usize = 16;
+
+// header of the first sector of a file
+const HEAD_SEQUENCE: usize = 16;
+const HEAD_REPLACES: usize = 20;
+const HEAD_TYPE: usize = 22;
+const HEAD_FLAGS: usize = 23;
+const HEAD_NAME: usize = 24;
+const HEAD_LENGTH: usize = 40;
+const HEAD_DATA_CRC: usize = 44;
+const HEAD_CRC: usize = 48;
+// the inverted number of the disk, so an erased byte is the first one
+const HEAD_DISK: usize = 52;
+const HEAD_DATA_START: usize = 64;
+
+const KIND_HEAD: u8 = 0x01;
+const KIND_DATA: u8 = 0x02;
+
+const STATE_WRITING: u8 = 0xff;
+const STATE_COMMITTED: u8 = 0x0f;
+const STATE_DELETED: u8 = 0x00;
+
+// bits of HEAD_FLAGS, cleared when set
+const FLAG_LOCKED: u8 = 0x01;
+
+const NO_SECTOR: u16 = 0xffff;
+const UNKNOWN_LENGTH: u32 = 0xffff_ffff;
+
+const PADDING: u8 = 0xa0;
+
+const TYPE_SEQ: u8 = 1;
+const TYPE_PRG: u8 = 2;
+const TYPE_USR: u8 = 3;
+const TYPE_REL: u8 = 4;
+// the head of a disk, its content is the id
+const TYPE_DISK: u8 = 0x10;
+
+const DISK_NAME: &[u8] = b"BLUEPET";
+const DISK_ID: &[u8] = b"00";
+const DOS_TYPE: &[u8] = b"2A";
+const ID_LEN: usize = 2;
+
+/// Disks are numbered from 0, the first one always exists
+pub const MAX_DISKS: usize = 255;
+
+// blocks of 254 bytes, as the listing counts them, fitting into the data of a sector
+const BLOCKS_PER_SECTOR: usize = (SECTOR_SIZE - DATA_START) / 254;
+
+const CACHE_LEN: usize = 32;
+// bytes moved at once when a head is copied, a divider of PAGE_SIZE
+const COPY_CHUNK_LEN: usize = 64;
+
+/// Reading, programming or erasing the flash failed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FlashError;
+
+/// A NOR flash: erasing sets a whole sector to 0xff, programming only clears bits.
+pub trait NorFlash {
+    /// The size in bytes
+    fn capacity(&self) -> u32;
+
+    fn read(&mut self, address: u32, data: &mut [u8]) -> Result<(), FlashError>;
+
+    /// Programs bytes within one page, bits already cleared stay cleared.
+    fn program(&mut self, address: u32, data: &[u8]) -> Result<(), FlashError>;
+
+    /// Erases the sector the address is in
+    fn erase_sector(&mut self, address: u32) -> Result<(), FlashError>;
+}
+
+impl NorFlash for &mut [u8] {
+    fn capacity(&self) -> u32 {
+        self.len() as u32
+    }
+
+    fn read(&mut self, address: u32, data: &mut [u8]) -> Result<(), FlashError> {
+        let start = address as usize;
+        let bytes = self.get(start..start + data.len()).ok_or(FlashError)?;
+        data.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn program(&mut self, address: u32, data: &[u8]) -> Result<(), FlashError> {
+        let start = address as usize;
+        if start % PAGE_SIZE + data.len() > PAGE_SIZE {
+            return Err(FlashError);
+        }
+        let bytes = self.get_mut(start..start + data.len()).ok_or(FlashError)?;
+        for (b, value) in bytes.iter_mut().zip(data) {
+            *b &= value;
+        }
+        Ok(())
+    }
+
+    fn erase_sector(&mut self, address: u32) -> Result<(), FlashError> {
+        let start = address as usize / SECTOR_SIZE * SECTOR_SIZE;
+        let bytes = self.get_mut(start..start + SECTOR_SIZE).ok_or(FlashError)?;
+        bytes.iter_mut().for_each(|b| *b = 0xff);
+        Ok(())
+    }
+}
+
+fn type_byte(file_type: FileType) -> u8 {
+    match file_type {
+        FileType::Seq => TYPE_SEQ,
+        FileType::Prg => TYPE_PRG,
+        FileType::Usr => TYPE_USR,
+        FileType::Rel => TYPE_REL,
+    }
+}
+
+fn file_type(value: u8) -> FileType {
+    match value {
+        TYPE_SEQ => FileType::Seq,
+        TYPE_USR => FileType::Usr,
+        TYPE_REL => FileType::Rel,
+        _ => FileType::Prg,
+    }
+}
+
+/// The CRC-32 (as used by zip) of the data, continuing one of earlier bytes
+pub fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                1 => crc >> 1 ^ 0xedb8_8320,
+                _ => crc >> 1,
+            };
+        }
+    }
+    !crc
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+// the header of a sector, only the first DATA_START bytes for other sectors than heads
+type Header = [u8; HEAD_DATA_START];
+
+fn stored_name(header: &Header) -> &[u8] {
+    let name = &header[HEAD_NAME..HEAD_NAME + MAX_NAME_LEN];
+    let len = name
+        .iter()
+        .position(|&c| c == PADDING)
+        .unwrap_or(MAX_NAME_LEN);
+    &name[..len]
+}
+
+fn is_locked(header: &Header) -> bool {
+    header[HEAD_FLAGS] & FLAG_LOCKED == 0
+}
+
+fn disk_of(header: &Header) -> u8 {
+    !header[HEAD_DISK]
+}
+
+// the disk byte is only included when it's programmed, so heads written before there
+// were disks stay valid
+fn head_crc(header: &Header) -> u32 {
+    let crc = crc32(0, &header[HEAD_SEQUENCE..HEAD_CRC]);
+    match header[HEAD_DISK] {
+        0xff => crc,
+        disk => crc32(crc, &[disk]),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Bitmap([u32; MAX_SECTORS / 32]);
+
+impl Bitmap {
+    const EMPTY: Bitmap = Bitmap([0; MAX_SECTORS / 32]);
+
+    fn get(&self, sector: u16) -> bool {
+        self.0[sector as usize / 32] & 1 << (sector % 32) != 0
+    }
+
+    fn set(&mut self, sector: u16, value: bool) {
+        let bit = 1 << (sector % 32);
+        match value {
+            true => self.0[sector as usize / 32] |= bit,
+            false => self.0[sector as usize / 32] &= !bit,
+        }
+    }
+}
+
+// a file opened on a channel
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Channel {
+    head: u16,
+    sector: u16,
+    // the next byte in the sector
+    offset: usize,
+    // the next byte in the file
+    position: u32,
+    len: u32,
+    // of the bytes read or written so far
+    crc: u32,
+    writing: bool,
+}
+
+/// The filesystem on a `NorFlash`
+pub struct FlashFs<F: NorFlash> {
+    flash: F,
+    sectors: u16,
+
+    // committed heads and all sectors belonging to a file or being written
+    heads: Bitmap,
+    used: Bitmap,
+    next_sequence: u32,
+    // where the search for a free sector starts
+    cursor: u16,
+    // the disk mounted
+    disk: u8,
+
+    cache: [u8; CACHE_LEN],
+    cache_address: Option<u32>,
+
+    // there is one writer at a time, its bytes are collected up to a page
+    page: [u8; PAGE_SIZE],
+    page_from: usize,
+    writing: bool,
+
+    channels: Channels<Channel>,
+}
+
+impl<F: NorFlash> core::fmt::Debug for FlashFs<F> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "FlashFs {} sectors", self.sectors)
+    }
+}
+
+impl<F: NorFlash> FlashFs<F> {
+    /// Mounts the filesystem, a fully erased flash is an empty one
+    pub fn new(flash: F) -> Result<FlashFs<F>, DosError> {
+        let sectors = (flash.capacity() as usize / SECTOR_SIZE).min(MAX_SECTORS) as u16;
+        let mut fs = FlashFs {
+            flash,
+            sectors,
+            heads: Bitmap::EMPTY,
+            used: Bitmap::EMPTY,
+            next_sequence: 0,
+            cursor: 0,
+            disk: 0,
+            cache: [0u8; CACHE_LEN],
+            cache_address: None,
+            page: [0xff; PAGE_SIZE],
+            page_from: 0,
+            writing: false,
+            channels: Channels::new(),
+        };
+        fs.mount()?;
+        Ok(fs)
+    }
+
+    pub fn into_flash(self) -> F {
+        self.flash
+    }
+
+    /// The number of sectors the filesystem uses
+    pub fn sectors(&self) -> u16 {
+        self.sectors
+    }
+
+    /// The number of sectors not used by any file
+    pub fn free_sectors(&self) -> usize {
+        (0..self.sectors).filter(|&s| !self.used.get(s)).count()
+    }
+
+    /// How often the sector was erased, None if it never was
+    pub fn erase_count(&mut self, sector: u16) -> Result<Option<u32>, DosError> {
+        let header = self.header(sector)?;
+        match &header[HEADER_MAGIC..HEADER_MAGIC + 4] == MAGIC {
+            true => Ok(Some(u32_at(&header, HEADER_ERASES))),
+            false => Ok(None),
+        }
+    }
+
+    /// Reads the whole file, true if its content has the CRC written with it
+    pub fn verify(&mut self, name: &[u8]) -> Result<bool, DosError> {
+        let head = self.find(name)?.ok_or(DosError::FileNotFound)?;
+        self.verify_head(head)
+    }
+
+    // finds the files by scanning all sector headers
+    fn mount(&mut self) -> Result<(), DosError> {
+        self.heads = Bitmap::EMPTY;
+        self.used = Bitmap::EMPTY;
+        self.channels = Channels::new();
+        self.writing = false;
+        self.cache_address = None;
+
+        let mut newest = None;
+        for sector in 0..self.sectors {
+            let header = self.header(sector)?;
+            if self.is_committed_head(&header) {
+                self.heads.set(sector, true);
+                let sequence = u32_at(&header, HEAD_SEQUENCE);
+                if newest.map_or(true, |(_, newest)| sequence >= newest) {
+                    newest = Some((sector, sequence));
+                }
+            }
+        }
+
+        // finish replacing files a reset interrupted
+        for sector in 0..self.sectors {
+            if !self.heads.get(sector) {
+                continue;
+            }
+            let header = self.header(sector)?;
+            let replaces = u16_at(&header, HEAD_REPLACES);
+            if replaces < self.sectors && replaces != sector && self.heads.get(replaces) {
+                let old = self.header(replaces)?;
+                if u32_at(&old, HEAD_SEQUENCE) < u32_at(&header, HEAD_SEQUENCE) {
+                    self.set_state(replaces, STATE_DELETED)?;
+                    self.heads.set(replaces, false);
+                }
+            }
+        }
+
+        for sector in 0..self.sectors {
+            if self.heads.get(sector) {
+                let chain = self.chain(sector)?;
+                match chain {
+                    Some(sectors) => self.mark_chain(sector, sectors)?,
+                    None => self.heads.set(sector, false),
+                }
+            }
+        }
+
+        if let Some((sector, sequence)) = newest {
+            self.next_sequence = sequence.wrapping_add(1);
+            self.cursor = (sector + 1) % self.sectors.max(1);
+        }
+        Ok(())
+    }
+
+    fn is_committed_head(&self, header: &Header) -> bool {
+        &header[HEADER_MAGIC..HEADER_MAGIC + 4] == MAGIC
+            && header[HEADER_KIND] == KIND_HEAD
+            && header[HEADER_STATE] == STATE_COMMITTED
+            && u32_at(header, HEAD_LENGTH) != UNKNOWN_LENGTH
+            && u32_at(header, HEAD_CRC) == head_crc(header)
+    }
+
+    // the number of sectors of a file, None if its chain is broken or runs into another file
+    fn chain(&mut self, head: u16) -> Result<Option<usize>, DosError> {
+        let len = u32_at(&self.header(head)?, HEAD_LENGTH) as usize;
+        let sectors = match len.checked_sub(SECTOR_SIZE - HEAD_DATA_START) {
+            None | Some(0) => 1,
+            Some(rest) => 1 + (rest + SECTOR_SIZE - DATA_START - 1) / (SECTOR_SIZE - DATA_START),
+        };
+        if sectors > self.sectors as usize {
+            return Ok(None);
+        }
+
+        let mut sector = head;
+        for _ in 1..sectors {
+            let next = u16_at(&self.header(sector)?, HEADER_NEXT);
+            if next >= self.sectors || next == head || self.used.get(next) {
+                return Ok(None);
+            }
+            let header = self.header(next)?;
+            if &header[HEADER_MAGIC..HEADER_MAGIC + 4] != MAGIC || header[HEADER_KIND] != KIND_DATA
+            {
+                return Ok(None);
+            }
+            sector = next;
+        }
+        Ok(Some(sectors))
+    }
+
+    // marks the sectors of a file as used
+    fn mark_chain(&mut self, head: u16, sectors: usize) -> Result<(), DosError> {
+        let mut sector = head;
+        self.used.set(sector, true);
+        for _ in 1..sectors {
+            sector = u16_at(&self.header(sector)?, HEADER_NEXT);
+            self.used.set(sector, true);
+        }
+        Ok(())
+    }
+
+    // reads the whole file, true if it has the CRC of its head
+    fn verify_head(&mut self, head: u16) -> Result<bool, DosError> {
+        let mut channel = self.open_read(head)?;
+        loop {
+            match self.read_byte(&mut channel) {
+                Ok(Some(_)) => {}
+                Ok(None) => return Ok(true),
+                Err(DosError::ReadError) => return Ok(false),
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    fn free_chain(&mut self, head: u16) -> Result<(), DosError> {
+        let mut sector = head;
+        for _ in 0..self.sectors {
+            self.used.set(sector, false);
+            let next = u16_at(&self.header(sector)?, HEADER_NEXT);
+            if next >= self.sectors || !self.used.get(next) {
+                break;
+            }
+            sector = next;
+        }
+        Ok(())
+    }
+
+    // flash access
+
+    fn address(sector: u16, offset: usize) -> u32 {
+        (sector as usize * SECTOR_SIZE + offset) as u32
+    }
+
+    fn header(&mut self, sector: u16) -> Result<Header, DosError> {
+        let mut header = [0u8; HEAD_DATA_START];
+        self.flash
+            .read(Self::address(sector, 0), &mut header)
+            .map_err(|_| DosError::ReadError)?;
+        Ok(header)
+    }
+
+    fn program(&mut self, sector: u16, offset: usize, data: &[u8]) -> Result<(), DosError> {
+        self.cache_address = None;
+        self.flash
+            .program(Self::address(sector, offset), data)
+            .map_err(|_| DosError::WriteError)
+    }
+
+    fn set_state(&mut self, sector: u16, state: u8) -> Result<(), DosError> {
+        self.program(sector, HEADER_STATE, &[state])
+    }
+
+    fn read_byte_at(&mut self, sector: u16, offset: usize) -> Result<u8, DosError> {
+        let address = Self::address(sector, offset);
+        match self.cache_address {
+            Some(start) if address >= start && address < start + CACHE_LEN as u32 => {}
+            _ => {
+                // the cache doesn't cross the end of the sector
+                let start = address.min(Self::address(sector, SECTOR_SIZE - CACHE_LEN));
+                self.flash
+                    .read(start, &mut self.cache)
+                    .map_err(|_| DosError::ReadError)?;
+                self.cache_address = Some(start);
+            }
+        }
+        let start = self.cache_address.unwrap_or(address);
+        Ok(self.cache[(address - start) as usize])
+    }
+
+    // erases the next free sector and writes its header
+    fn allocate(&mut self, kind: u8) -> Result<u16, DosError> {
+        let sector = (0..self.sectors)
+            .map(|i| (self.cursor + i) % self.sectors)
+            .find(|&sector| !self.used.get(sector))
+            .ok_or(DosError::DiskFull)?;
+
+        let erases = self.erase_count(sector)?.unwrap_or(0);
+        self.cache_address = None;
+        self.flash
+            .erase_sector(Self::address(sector, 0))
+            .map_err(|_| DosError::WriteError)?;
+
+        let mut header = [0xff; DATA_START];
+        header[HEADER_MAGIC..HEADER_MAGIC + 4].copy_from_slice(MAGIC);
+        header[HEADER_ERASES..HEADER_ERASES + 4]
+            .copy_from_slice(&erases.wrapping_add(1).to_le_bytes());
+        header[HEADER_KIND] = kind;
+        header[HEADER_STATE] = STATE_WRITING;
+        self.program(sector, 0, &header)?;
+
+        self.used.set(sector, true);
+        self.cursor = (sector + 1) % self.sectors;
+        Ok(sector)
+    }
+
+    fn allocate_head(
+        &mut self,
+        name: &[u8],
+        file_type: u8,
+        disk: u8,
+        replaces: Option<u16>,
+    ) -> Result<u16, DosError> {
+        let sector = self.allocate(KIND_HEAD)?;
+
+        let mut head = [0xff; HEAD_DATA_START - DATA_START];
+        let field = |offset: usize| offset - DATA_START;
+        head[field(HEAD_SEQUENCE)..field(HEAD_SEQUENCE) + 4]
+            .copy_from_slice(&self.next_sequence.to_le_bytes());
+        head[field(HEAD_REPLACES)..field(HEAD_REPLACES) + 2]
+            .copy_from_slice(&replaces.unwrap_or(NO_SECTOR).to_le_bytes());
+        head[field(HEAD_TYPE)] = file_type;
+        head[field(HEAD_DISK)] = !disk;
+        for i in 0..MAX_NAME_LEN {
+            head[field(HEAD_NAME) + i] = name.get(i).copied().unwrap_or(PADDING);
+        }
+        self.program(sector, DATA_START, &head)?;
+
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+        Ok(sector)
+    }
+
+    // marks a head deleted, the sectors of the file are free again
+    fn retire(&mut self, head: u16, free_chain: bool) -> Result<(), DosError> {
+        self.set_state(head, STATE_DELETED)?;
+        self.heads.set(head, false);
+        match free_chain {
+            true => self.free_chain(head),
+            false => {
+                self.used.set(head, false);
+                Ok(())
+            }
+        }
+    }
+
+    // directory
+
+    // the head of a file on the disk mounted, None for other sectors
+    fn file_head(&mut self, sector: u16) -> Result<Option<Header>, DosError> {
+        if !self.heads.get(sector) {
+            return Ok(None);
+        }
+        let header = self.header(sector)?;
+        match header[HEAD_TYPE] != TYPE_DISK && disk_of(&header) == self.disk {
+            true => Ok(Some(header)),
+            false => Ok(None),
+        }
+    }
+
+    // disks
+
+    /// The disk mounted
+    pub fn disk(&self) -> u8 {
+        self.disk
+    }
+
+    /// The disk at the cursor or the next one with its name and id, the first disk comes
+    /// first. The cursor starts at 0.
+    pub fn next_numbered_disk(
+        &mut self,
+        cursor: &mut usize,
+    ) -> Result<Option<(u8, DiskHeader)>, DosError> {
+        if *cursor == 0 {
+            *cursor = 1;
+            return Ok(Some((0, self.disk_header_of(0)?)));
+        }
+
+        // then the heads of the others, the cursor is the sector after the last one
+        while *cursor <= self.sectors as usize {
+            let sector = (*cursor - 1) as u16;
+            *cursor += 1;
+            match self.disk_head(sector)? {
+                Some(header) if disk_of(&header) != 0 => {
+                    let disk = disk_of(&header);
+                    return Ok(Some((disk, self.disk_header_of(disk)?)));
+                }
+                _ => {}
+            }
+        }
+        Ok(None)
+    }
+
+    /// Mounts the disk, its files are the only ones seen afterwards
+    pub fn mount_disk(&mut self, disk: u8) -> Result<(), DosError> {
+        if disk != 0 && self.disk_record(disk)?.is_none() {
+            return Err(DosError::DriveNotReady);
+        }
+        self.disk = disk;
+        Ok(())
+    }
+
+    fn disk_head(&mut self, sector: u16) -> Result<Option<Header>, DosError> {
+        if !self.heads.get(sector) {
+            return Ok(None);
+        }
+        let header = self.header(sector)?;
+        match header[HEAD_TYPE] {
+            TYPE_DISK => Ok(Some(header)),
+            _ => Ok(None),
+        }
+    }
+
+    // the head naming the disk, the first disk only has one once it was formatted
+    fn disk_record(&mut self, disk: u8) -> Result<Option<u16>, DosError> {
+        for sector in 0..self.sectors {
+            match self.disk_head(sector)? {
+                Some(header) if disk_of(&header) == disk => return Ok(Some(sector)),
+                _ => {}
+            }
+        }
+        Ok(None)
+    }
+
+    fn disk_header_of(&mut self, disk: u8) -> Result<DiskHeader, DosError> {
+        let mut id = [b' '; ID_LEN + 1 + DOS_TYPE.len()];
+        id[ID_LEN + 1..].copy_from_slice(DOS_TYPE);
+        match self.disk_record(disk)? {
+            Some(head) => {
+                let header = self.header(head)?;
+                let len = (u32_at(&header, HEAD_LENGTH) as usize).min(ID_LEN);
+                for (i, c) in id[..len].iter_mut().enumerate() {
+                    *c = self.read_byte_at(head, HEAD_DATA_START + i)?;
+                }
+                Ok(DiskHeader::new(stored_name(&header), &id))
+            }
+            None => {
+                id[..ID_LEN].copy_from_slice(DISK_ID);
+                Ok(DiskHeader::new(DISK_NAME, &id))
+            }
+        }
+    }
+
+    // the first disk matching the pattern
+    fn find_disk(&mut self, name: &[u8]) -> Result<Option<u8>, DosError> {
+        let name = valid_name(name)?;
+        let mut cursor = 0;
+        while let Some((disk, header)) = self.next_numbered_disk(&mut cursor)? {
+            let len = header
+                .name
+                .iter()
+                .rposition(|&c| c != b' ')
+                .map_or(0, |last| last + 1);
+            if dos::matches(name, &header.name[..len]) {
+                return Ok(Some(disk));
+            }
+        }
+        Ok(None)
+    }
+
+    // names the disk, a new head replaces the one it had
+    fn write_disk_record(&mut self, disk: u8, name: &[u8], id: &[u8]) -> Result<(), DosError> {
+        if self.writing {
+            return Err(DosError::NoChannel);
+        }
+
+        let replaces = self.disk_record(disk)?;
+        let head = self.allocate_head(name, TYPE_DISK, disk, replaces)?;
+        let mut channel = Channel {
+            head,
+            sector: head,
+            offset: HEAD_DATA_START,
+            position: 0,
+            len: 0,
+            crc: 0,
+            writing: true,
+        };
+        for &value in id.iter().take(ID_LEN) {
+            self.write_byte(&mut channel, value)?;
+        }
+        self.close_channel(&channel)
+    }
+
+    // files
+
+    fn create_file(
+        &mut self,
+        name: &[u8],
+        file_type: u8,
+        replace: bool,
+    ) -> Result<Channel, DosError> {
+        if self.writing {
+            return Err(DosError::NoChannel);
+        }
+
+        let name = new_name(name)?;
+        let replaces = match self.find(name)? {
+            Some(_) if !replace => return Err(DosError::FileExists),
+            Some(head) if is_locked(&self.header(head)?) => return Err(DosError::WriteProtectOn),
+            existing => existing,
+        };
+
+        let head = self.allocate_head(name, file_type, self.disk, replaces)?;
+        Ok(Channel {
+            head,
+            sector: head,
+            offset: HEAD_DATA_START,
+            position: 0,
+            len: 0,
+            crc: 0,
+            writing: true,
+        })
+    }
+
+    fn open_read(&mut self, head: u16) -> Result<Channel, DosError> {
+        let header = self.header(head)?;
+        Ok(Channel {
+            head,
+            sector: head,
+            offset: HEAD_DATA_START,
+            position: 0,
+            len: u32_at(&header, HEAD_LENGTH),
+            crc: 0,
+            writing: false,
+        })
+    }
+
+    // a new version of the file gets the old content, then more is written to it
+    fn open_append(&mut self, head: u16) -> Result<Channel, DosError> {
+        let header = self.header(head)?;
+        let mut from = self.open_read(head)?;
+        let mut to = self.create_file(stored_name(&header), header[HEAD_TYPE], true)?;
+        self.writing = true;
+        let copied = self.copy_content(&mut from, &mut to);
+        self.writing = false;
+        copied.map(|_| to)
+    }
+
+    fn copy_content(&mut self, from: &mut Channel, to: &mut Channel) -> Result<(), DosError> {
+        while let Some((value, _)) = self.read_byte(from)? {
+            self.write_byte(to, value)?;
+        }
+        Ok(())
+    }
+
+    // programs the bytes collected since the last flush
+    fn flush_page(&mut self, channel: &Channel) -> Result<(), DosError> {
+        if self.page_from >= channel.offset {
+            return Ok(());
+        }
+
+        let base = self.page_from / PAGE_SIZE * PAGE_SIZE;
+        let page = self.page;
+        self.program(
+            channel.sector,
+            self.page_from,
+            &page[self.page_from - base..channel.offset - base],
+        )?;
+        self.page_from = channel.offset;
+        Ok(())
+    }
+
+    // the length and the CRCs are written, then the state makes the file visible
+    fn commit(&mut self, head: u16, len: u32, crc: u32) -> Result<(), DosError> {
+        self.program(head, HEAD_LENGTH, &len.to_le_bytes())?;
+        self.program(head, HEAD_DATA_CRC, &crc.to_le_bytes())?;
+        let header = self.header(head)?;
+        self.program(head, HEAD_CRC, &head_crc(&header).to_le_bytes())?;
+        self.set_state(head, STATE_COMMITTED)?;
+        self.heads.set(head, true);
+        Ok(())
+    }
+
+    fn close_channel(&mut self, channel: &Channel) -> Result<(), DosError> {
+        if !channel.writing {
+            return Ok(());
+        }
+
+        self.flush_page(channel)?;
+        self.commit(channel.head, channel.len, channel.crc)?;
+
+        let replaces = u16_at(&self.header(channel.head)?, HEAD_REPLACES);
+        if replaces < self.sectors && self.heads.get(replaces) {
+            self.retire(replaces, true)?;
+        }
+        Ok(())
+    }
+
+    // a new head with another name or flags, the rest of the file stays where it is
+    fn rewrite_head(&mut self, head: u16, name: &[u8], flags: u8) -> Result<(), DosError> {
+        let old = self.header(head)?;
+        let new = self.allocate_head(name, old[HEAD_TYPE], disk_of(&old), Some(head))?;
+        self.program(new, HEAD_FLAGS, &[flags])?;
+
+        let len = u32_at(&old, HEAD_LENGTH) as usize;
+        let end = (HEAD_DATA_START + len).min(SECTOR_SIZE);
+        let mut chunk = [0u8; COPY_CHUNK_LEN];
+        for offset in (HEAD_DATA_START..end).step_by(COPY_CHUNK_LEN) {
+            self.flash
+                .read(Self::address(head, offset), &mut chunk)
+                .map_err(|_| DosError::ReadError)?;
+            self.program(new, offset, &chunk)?;
+        }
+
+        self.program(new, HEADER_NEXT, &old[HEADER_NEXT..HEADER_NEXT + 2])?;
+        self.commit(new, len as u32, u32_at(&old, HEAD_DATA_CRC))?;
+        self.retire(head, false)
+    }
+}
+
+impl<F: NorFlash> Volume for FlashFs<F> {
+    type File = u16;
+    type Channel = Channel;
+
+    fn channels(&mut self) -> &mut Channels<Channel> {
+        &mut self.channels
+    }
+
+    // the first file matching the pattern
+    fn find(&mut self, name: &[u8]) -> Result<Option<u16>, DosError> {
+        let name = valid_name(name)?;
+        for sector in 0..self.sectors {
+            match self.file_head(sector)? {
+                Some(header) if dos::matches(name, stored_name(&header)) => {
+                    return Ok(Some(sector))
+                }
+                _ => {}
+            }
+        }
+        Ok(None)
+    }
+
+    fn file_type(&mut self, &head: &u16) -> Result<FileType, DosError> {
+        Ok(file_type(self.header(head)?[HEAD_TYPE]))
+    }
+
+    fn create(
+        &mut self,
+        name: &[u8],
+        file_type: FileType,
+        replace: bool,
+    ) -> Result<Channel, DosError> {
+        let channel = self.create_file(name, type_byte(file_type), replace)?;
+        self.writing = true;
+        Ok(channel)
+    }
+
+    fn open_file(&mut self, &head: &u16, mode: FileMode) -> Result<Channel, DosError> {
+        match mode {
+            FileMode::Append if self.writing => Err(DosError::NoChannel),
+            FileMode::Append => {
+                let channel = self.open_append(head)?;
+                self.writing = true;
+                Ok(channel)
+            }
+            _ => self.open_read(head),
+        }
+    }
+
+    fn rewind(&mut self, channel: &mut Channel) -> Result<(), DosError> {
+        *channel = self.open_read(channel.head)?;
+        Ok(())
+    }
+
+    fn read_len(&mut self, channel: &Channel) -> Result<usize, DosError> {
+        Ok(channel.len as usize)
+    }
+
+    fn is_writing(channel: &Channel) -> bool {
+        channel.writing
+    }
+
+    fn read_byte(&mut self, channel: &mut Channel) -> Result<Option<(u8, bool)>, DosError> {
+        if channel.position >= channel.len {
+            return Ok(None);
+        }
+
+        if channel.offset == SECTOR_SIZE {
+            let next = u16_at(&self.header(channel.sector)?, HEADER_NEXT);
+            if next >= self.sectors {
+                return Err(DosError::ReadError);
+            }
+            channel.sector = next;
+            channel.offset = DATA_START;
+        }
+
+        let value = self.read_byte_at(channel.sector, channel.offset)?;
+        channel.offset += 1;
+        channel.position += 1;
+        channel.crc = crc32(channel.crc, &[value]);
+
+        let last = channel.position == channel.len;
+        if last && channel.crc != u32_at(&self.header(channel.head)?, HEAD_DATA_CRC) {
+            return Err(DosError::ReadError);
+        }
+        Ok(Some((value, last)))
+    }
+
+    fn write_byte(&mut self, channel: &mut Channel, value: u8) -> Result<(), DosError> {
+        if channel.offset == SECTOR_SIZE {
+            let next = self.allocate(KIND_DATA)?;
+            self.program(channel.sector, HEADER_NEXT, &next.to_le_bytes())?;
+            channel.sector = next;
+            channel.offset = DATA_START;
+            self.page_from = DATA_START;
+        }
+        if channel.position == 0 && channel.sector == channel.head {
+            self.page_from = channel.offset;
+        }
+
+        self.page[channel.offset % PAGE_SIZE] = value;
+        channel.offset += 1;
+        channel.position += 1;
+        channel.len += 1;
+        channel.crc = crc32(channel.crc, &[value]);
+
+        if channel.offset % PAGE_SIZE == 0 {
+            self.flush_page(channel)?;
+        }
+        Ok(())
+    }
+
+    fn close_file(&mut self, channel: Channel) -> Result<(), DosError> {
+        if channel.writing {
+            self.writing = false;
+        }
+        self.close_channel(&channel)
+    }
+}
+
+impl<F: NorFlash> Directory for FlashFs<F> {
+    fn disk_header(&mut self) -> Result<DiskHeader, DosError> {
+        self.disk_header_of(self.disk)
+    }
+
+    // the cursor is the next sector to look at
+    fn next_entry(&mut self, cursor: &mut usize) -> Result<Option<DirectoryEntry>, DosError> {
+        while *cursor < self.sectors as usize {
+            let sector = *cursor as u16;
+            *cursor += 1;
+
+            if let Some(header) = self.file_head(sector)? {
+                let blocks = listing::blocks(u32_at(&header, HEAD_LENGTH) as usize);
+                let mut entry =
+                    DirectoryEntry::new(stored_name(&header), file_type(header[HEAD_TYPE]), blocks);
+                entry.locked = is_locked(&header);
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+
+    fn blocks_free(&mut self) -> Result<u16, DosError> {
+        Ok((self.free_sectors() * BLOCKS_PER_SECTOR).min(u16::MAX as usize) as u16)
+    }
+}
+
+impl<F: NorFlash> FileSystem for FlashFs<F> {
+    // locked files are skipped
+    fn scratch(&mut self, name: &[u8]) -> Result<u8, DosError> {
+        let name = valid_name(name)?;
+        let mut scratched = 0u8;
+        for sector in 0..self.sectors {
+            let header = match self.file_head(sector)? {
+                Some(header) => header,
+                None => continue,
+            };
+            if dos::matches(name, stored_name(&header)) && !is_locked(&header) {
+                self.retire(sector, true)?;
+                scratched = scratched.saturating_add(1);
+            }
+        }
+        Ok(scratched)
+    }
+
+    fn rename(&mut self, old: &[u8], new: &[u8]) -> Result<(), DosError> {
+        let new = new_name(new)?;
+        if self.find(new)?.is_some() {
+            return Err(DosError::FileExists);
+        }
+        let head = self.find(old)?.ok_or(DosError::FileNotFound)?;
+        let flags = self.header(head)?[HEAD_FLAGS];
+        self.rewrite_head(head, new, flags)
+    }
+
+    fn copy(&mut self, source: &[u8], destination: &[u8]) -> Result<(), DosError> {
+        let head = self.find(source)?.ok_or(DosError::FileNotFound)?;
+        let file_type = self.header(head)?[HEAD_TYPE];
+        let mut from = self.open_read(head)?;
+        let mut to = self.create_file(destination, file_type, false)?;
+
+        self.writing = true;
+        let copied = self.copy_content(&mut from, &mut to);
+        self.writing = false;
+        copied?;
+        self.close_channel(&to)
+    }
+
+    // the new heads get newer sequence numbers than all files there were before
+    fn lock(&mut self, name: &[u8], locked: Option<bool>) -> Result<u8, DosError> {
+        let name = valid_name(name)?;
+        let first_new = self.next_sequence;
+        let mut matched = 0u8;
+        for sector in 0..self.sectors {
+            let header = match self.file_head(sector)? {
+                Some(header) => header,
+                None => continue,
+            };
+            let sequence = u32_at(&header, HEAD_SEQUENCE);
+            if sequence.wrapping_sub(first_new) < u32::MAX / 2
+                || !dos::matches(name, stored_name(&header))
+            {
+                continue;
+            }
+
+            matched = matched.saturating_add(1);
+            let lock = locked.unwrap_or(!is_locked(&header));
+            if lock != is_locked(&header) {
+                let flags = header[HEAD_FLAGS] ^ FLAG_LOCKED;
+                self.rewrite_head(sector, stored_name(&header), flags)?;
+            }
+        }
+        Ok(matched)
+    }
+
+    fn initialize(&mut self) -> Result<(), DosError> {
+        self.mount()
+    }
+
+    // the sectors not belonging to a committed file are found again,
+    // files with a wrong CRC are deleted
+    fn validate(&mut self) -> Result<(), DosError> {
+        self.mount()?;
+        for sector in 0..self.sectors {
+            if self.heads.get(sector) && !self.verify_head(sector)? {
+                self.retire(sector, true)?;
+            }
+        }
+        Ok(())
+    }
+
+    // deleting the heads of the files on the disk is enough, sectors are erased when they
+    // are used again. The disk keeps its id unless a new one is given.
+    // nothing is deleted before the name and the id turned out fine
+    fn format(&mut self, name: &[u8], id: Option<&[u8]>) -> Result<(), DosError> {
+        let header;
+        let record = match (name, id) {
+            (_, Some(b"")) => return Err(DosError::SyntaxError),
+            (b"", _) => None,
+            (name, Some(id)) => Some((new_name(name)?, id)),
+            (name, None) => {
+                header = self.disk_header_of(self.disk)?;
+                Some((new_name(name)?, &header.id[..ID_LEN]))
+            }
+        };
+
+        for sector in 0..self.sectors {
+            if self.file_head(sector)?.is_some() {
+                self.set_state(sector, STATE_DELETED)?;
+            }
+        }
+        self.mount()?;
+
+        if let Some((name, id)) = record {
+            self.write_disk_record(self.disk, name, id)?;
+        }
+        Ok(())
+    }
+
+    fn change_disk(&mut self, name: &[u8]) -> Result<(), DosError> {
+        let disk = match name {
+            b"" => 0,
+            name => self.find_disk(name)?.ok_or(DosError::FileNotFound)?,
+        };
+        self.mount_disk(disk)
+    }
+
+    // the lowest number not taken
+    fn make_disk(&mut self, name: &[u8]) -> Result<(), DosError> {
+        let name = new_name(name)?;
+        if self.find_disk(name)?.is_some() {
+            return Err(DosError::FileExists);
+        }
+
+        let mut taken = [false; MAX_DISKS];
+        taken[0] = true;
+        let mut cursor = 0;
+        while let Some((disk, _)) = self.next_numbered_disk(&mut cursor)? {
+            if let Some(taken) = taken.get_mut(disk as usize) {
+                *taken = true;
+            }
+        }
+        let disk = taken
+            .iter()
+            .position(|&taken| !taken)
+            .ok_or(DosError::DiskFull)?;
+        self.write_disk_record(disk as u8, name, DISK_ID)
+    }
+
+    // only empty disks, the first one stays
+    fn remove_disk(&mut self, name: &[u8]) -> Result<(), DosError> {
+        let disk = self.find_disk(name)?.ok_or(DosError::FileNotFound)?;
+        if disk == 0 {
+            return Err(DosError::WriteProtectOn);
+        }
+
+        let mounted = self.disk;
+        self.disk = disk;
+        let mut cursor = 0;
+        let empty = self.next_entry(&mut cursor).map(|entry| entry.is_none());
+        self.disk = mounted;
+        if !empty? {
+            return Err(DosError::FileExists);
+        }
+
+        if let Some(head) = self.disk_record(disk)? {
+            self.retire(head, true)?;
+        }
+        if self.disk == disk {
+            self.mount_disk(0)?;
+        }
+        Ok(())
+    }
+
+    fn next_disk(&mut self, cursor: &mut usize) -> Result<Option<(DiskHeader, bool)>, DosError> {
+        let disk = self.next_numbered_disk(cursor)?;
+        Ok(disk.map(|(disk, header)| (header, disk == self.disk)))
+    }
+}
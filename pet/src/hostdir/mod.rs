@@ -0,0 +1,420 @@
+// A folder on the host as a disk drive (needs the "std" feature)
+//
+// Programs are kept as plain files: "GAME" is stored as "game.prg", sequential files get
+// ".seq", user files ".usr". PC64 files (".p00", ".s00", ...) are read as well, their
+// PETSCII name is taken from the header instead of the host filename.
+//
+// PETSCII names and host names are converted with `host_name` and `pet_name`, see
+// `dos::hostname`. Names are compared ignoring the letter case, as most hosts do.
+// Read-only files are locked ones.
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::string::String;
+use std::vec::Vec;
+
+use crate::dos::listing::{self, Directory, DirectoryEntry, DiskHeader};
+use crate::dos::volume::{Channels, Volume};
+use crate::dos::{hostname, new_name, DosError, FileSystem, MAX_NAME_LEN};
+use crate::io::{FileMode, FileType};
+
+// PC64 header: "C64File", 0, the name padded with zeros, the REL record length
+const PC64_MAGIC: &[u8] = b"C64File\0";
+const PC64_NAME: usize = 8;
+const PC64_HEADER_LEN: usize = 26;
+
+// there is no sensible free space to report for a host folder
+const BLOCKS_FREE: u16 = 65535;
+const DISK_ID: &[u8] = b"00 2A";
+
+/// The host filename for a PETSCII name, without extension
+pub fn host_name(name: &[u8]) -> String {
+    let mut host = String::new();
+    hostname::host_name(name, |c| host.push(c as char));
+    host
+}
+
+/// The PETSCII name for a host filename without extension
+pub fn pet_name(host: &str) -> Vec<u8> {
+    let mut name = Vec::new();
+    hostname::pet_name(host.as_bytes(), |c| name.push(c));
+    name
+}
+
+fn name_matches(pattern: &[u8], name: &[u8]) -> bool {
+    hostname::matches_ignoring_case(pattern, name)
+}
+
+fn type_from_letter(letter: char) -> Option<FileType> {
+    match letter.to_ascii_lowercase() {
+        'p' => Some(FileType::Prg),
+        's' => Some(FileType::Seq),
+        'u' => Some(FileType::Usr),
+        'r' => Some(FileType::Rel),
+        _ => None,
+    }
+}
+
+fn read_error(error: std::io::Error) -> DosError {
+    match error.kind() {
+        ErrorKind::NotFound => DosError::FileNotFound,
+        _ => DosError::ReadError,
+    }
+}
+
+// the file is read-only
+fn is_locked(path: &Path) -> bool {
+    fs::metadata(path).map_or(false, |metadata| metadata.permissions().readonly())
+}
+
+fn write_error(error: std::io::Error) -> DosError {
+    match error.kind() {
+        ErrorKind::PermissionDenied => DosError::WriteProtectOn,
+        _ => DosError::WriteError,
+    }
+}
+
+/// A file in the folder
+#[derive(Debug, Clone)]
+pub(crate) struct Entry {
+    name: Vec<u8>,
+    file_type: FileType,
+    path: PathBuf,
+    // the PC64 header of .p00 files
+    header: usize,
+}
+
+impl Entry {
+    fn from_path(path: PathBuf) -> Option<Entry> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        let stem = path.file_stem()?.to_string_lossy();
+
+        let file_type = match hostname::file_type(extension.as_bytes()) {
+            Some(file_type) => file_type,
+            None => {
+                // .p00, .s01, ...
+                let mut letters = extension.chars();
+                let file_type = type_from_letter(letters.next()?)?;
+                let number = letters.as_str();
+                if number.len() != 2 || !number.chars().all(|c| c.is_ascii_digit()) {
+                    return None;
+                }
+
+                let name = pc64_name(&path)?;
+                return Some(Entry {
+                    name,
+                    file_type,
+                    path,
+                    header: PC64_HEADER_LEN,
+                });
+            }
+        };
+
+        Some(Entry {
+            name: pet_name(&stem),
+            file_type,
+            path,
+            header: 0,
+        })
+    }
+}
+
+fn pc64_name(path: &Path) -> Option<Vec<u8>> {
+    use std::io::Read;
+    let mut header = [0u8; PC64_HEADER_LEN];
+    fs::File::open(path).ok()?.read_exact(&mut header).ok()?;
+    if !header.starts_with(PC64_MAGIC) {
+        return None;
+    }
+
+    let name = &header[PC64_NAME..PC64_NAME + MAX_NAME_LEN];
+    let len = name.iter().position(|&c| c == 0).unwrap_or(MAX_NAME_LEN);
+    Some(name[..len].to_vec())
+}
+
+#[derive(Debug)]
+pub(crate) struct Channel {
+    path: PathBuf,
+    // kept when appending to a .p00 file
+    header: Vec<u8>,
+    data: Vec<u8>,
+    position: usize,
+    writing: bool,
+    // the file written replaces this one on close
+    replaces: Option<PathBuf>,
+}
+
+impl Channel {
+    fn read(entry: &Entry) -> Result<Channel, DosError> {
+        let mut data = fs::read(&entry.path).map_err(read_error)?;
+        let header = data.drain(..entry.header.min(data.len())).collect();
+        Ok(Channel {
+            path: entry.path.clone(),
+            header,
+            data,
+            position: 0,
+            writing: false,
+            replaces: None,
+        })
+    }
+}
+
+/// Serves the files of a host folder
+#[derive(Debug)]
+pub struct HostDirectory {
+    path: PathBuf,
+    // the files of the folder at the time the listing was asked for
+    listed: Vec<Entry>,
+    channels: Channels<Channel>,
+}
+
+impl HostDirectory {
+    pub fn new<P: Into<PathBuf>>(path: P) -> HostDirectory {
+        HostDirectory {
+            path: path.into(),
+            listed: Vec::new(),
+            channels: Channels::new(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // the files sorted by their host name
+    fn entries(&self) -> Result<Vec<Entry>, DosError> {
+        let mut entries: Vec<Entry> = fs::read_dir(&self.path)
+            .map_err(|_| DosError::DriveNotReady)?
+            .filter_map(|dir_entry| dir_entry.ok())
+            .filter(|dir_entry| dir_entry.file_type().map_or(false, |t| t.is_file()))
+            .filter_map(|dir_entry| Entry::from_path(dir_entry.path()))
+            .collect();
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(entries)
+    }
+
+    fn new_path(&self, name: &[u8], file_type: FileType) -> PathBuf {
+        let mut host = host_name(name);
+        host.push('.');
+        host.push_str(hostname::extension(file_type));
+        self.path.join(host)
+    }
+
+    fn close_channel(channel: Channel) -> Result<(), DosError> {
+        if !channel.writing {
+            return Ok(());
+        }
+
+        let mut content = channel.header;
+        content.extend_from_slice(&channel.data);
+        fs::write(&channel.path, &content).map_err(write_error)?;
+
+        match channel.replaces {
+            Some(old) if old != channel.path => fs::remove_file(old).map_err(write_error),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Directory for HostDirectory {
+    fn disk_header(&mut self) -> Result<DiskHeader, DosError> {
+        let name = self
+            .path
+            .file_name()
+            .map_or(Vec::new(), |name| pet_name(&name.to_string_lossy()));
+        Ok(DiskHeader::new(&name, DISK_ID))
+    }
+
+    fn next_entry(&mut self, cursor: &mut usize) -> Result<Option<DirectoryEntry>, DosError> {
+        // the files are read once per listing
+        if *cursor == 0 {
+            self.listed = self.entries()?;
+        }
+        let entry = match self.listed.get(*cursor) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        *cursor += 1;
+
+        let len = fs::metadata(&entry.path).map_err(read_error)?.len() as usize;
+        let blocks = listing::blocks(len.saturating_sub(entry.header)).max(1);
+        let mut listed = DirectoryEntry::new(&entry.name, entry.file_type, blocks);
+        listed.locked = is_locked(&entry.path);
+        Ok(Some(listed))
+    }
+
+    fn blocks_free(&mut self) -> Result<u16, DosError> {
+        Ok(BLOCKS_FREE)
+    }
+
+    fn name_matches(&self, pattern: &[u8], name: &[u8]) -> bool {
+        name_matches(pattern, name)
+    }
+}
+
+impl Volume for HostDirectory {
+    type File = Entry;
+    type Channel = Channel;
+
+    fn channels(&mut self) -> &mut Channels<Channel> {
+        &mut self.channels
+    }
+
+    // the first file matching the pattern
+    fn find(&mut self, name: &[u8]) -> Result<Option<Entry>, DosError> {
+        Ok(self
+            .entries()?
+            .into_iter()
+            .find(|entry| name_matches(name, &entry.name)))
+    }
+
+    fn file_type(&mut self, entry: &Entry) -> Result<FileType, DosError> {
+        Ok(entry.file_type)
+    }
+
+    fn create(
+        &mut self,
+        name: &[u8],
+        file_type: FileType,
+        replace: bool,
+    ) -> Result<Channel, DosError> {
+        let name = new_name(name)?;
+        let mut path = self.new_path(name, file_type);
+        let replaces = match self.find(name)? {
+            Some(existing) if replace && is_locked(&existing.path) => {
+                return Err(DosError::WriteProtectOn)
+            }
+            // written over, as its name may differ from the new path only in case
+            Some(existing)
+                if replace && existing.header == 0 && existing.file_type == file_type =>
+            {
+                path = existing.path;
+                None
+            }
+            Some(existing) if replace => Some(existing.path),
+            Some(_) => return Err(DosError::FileExists),
+            None => None,
+        };
+
+        Ok(Channel {
+            path,
+            header: Vec::new(),
+            data: Vec::new(),
+            position: 0,
+            writing: true,
+            replaces,
+        })
+    }
+
+    fn open_file(&mut self, entry: &Entry, mode: FileMode) -> Result<Channel, DosError> {
+        if mode == FileMode::Append && is_locked(&entry.path) {
+            return Err(DosError::WriteProtectOn);
+        }
+
+        let mut opened = Channel::read(entry)?;
+        opened.writing = mode == FileMode::Append;
+        Ok(opened)
+    }
+
+    fn rewind(&mut self, channel: &mut Channel) -> Result<(), DosError> {
+        channel.position = 0;
+        Ok(())
+    }
+
+    fn read_len(&mut self, channel: &Channel) -> Result<usize, DosError> {
+        Ok(channel.data.len())
+    }
+
+    fn is_writing(channel: &Channel) -> bool {
+        channel.writing
+    }
+
+    fn read_byte(&mut self, channel: &mut Channel) -> Result<Option<(u8, bool)>, DosError> {
+        let value = match channel.data.get(channel.position) {
+            Some(&value) => value,
+            None => return Ok(None),
+        };
+        channel.position += 1;
+        Ok(Some((value, channel.position == channel.data.len())))
+    }
+
+    fn write_byte(&mut self, channel: &mut Channel, value: u8) -> Result<(), DosError> {
+        channel.data.push(value);
+        Ok(())
+    }
+
+    fn close_file(&mut self, channel: Channel) -> Result<(), DosError> {
+        HostDirectory::close_channel(channel)
+    }
+}
+
+impl FileSystem for HostDirectory {
+    // locked files are skipped
+    fn scratch(&mut self, name: &[u8]) -> Result<u8, DosError> {
+        let mut scratched = 0u8;
+        for entry in self.entries()? {
+            if name_matches(name, &entry.name) && !is_locked(&entry.path) {
+                fs::remove_file(&entry.path).map_err(write_error)?;
+                scratched = scratched.saturating_add(1);
+            }
+        }
+        Ok(scratched)
+    }
+
+    fn rename(&mut self, old: &[u8], new: &[u8]) -> Result<(), DosError> {
+        let new = new_name(new)?;
+        let entry = self.find(old)?.ok_or(DosError::FileNotFound)?;
+        if self.find(new)?.is_some() {
+            return Err(DosError::FileExists);
+        }
+
+        // a PC64 file keeps its name in the header, it's converted to a plain file
+        let path = self.new_path(new, entry.file_type);
+        if entry.header == 0 {
+            fs::rename(&entry.path, &path).map_err(write_error)
+        } else {
+            let mut channel = Channel::read(&entry)?;
+            channel.header.clear();
+            channel.path = path;
+            channel.writing = true;
+            channel.replaces = Some(entry.path);
+            HostDirectory::close_channel(channel)
+        }
+    }
+
+    fn copy(&mut self, source: &[u8], destination: &[u8]) -> Result<(), DosError> {
+        let entry = self.find(source)?.ok_or(DosError::FileNotFound)?;
+        let mut channel = self.create(destination, entry.file_type, false)?;
+        channel.data = Channel::read(&entry)?.data;
+        HostDirectory::close_channel(channel)
+    }
+
+    fn lock(&mut self, name: &[u8], locked: Option<bool>) -> Result<u8, DosError> {
+        let mut matched = 0u8;
+        for entry in self.entries()? {
+            if name_matches(name, &entry.name) {
+                let mut permissions = fs::metadata(&entry.path).map_err(read_error)?.permissions();
+                let lock = locked.unwrap_or(!permissions.readonly());
+                permissions.set_readonly(lock);
+                fs::set_permissions(&entry.path, permissions).map_err(write_error)?;
+                matched = matched.saturating_add(1);
+            }
+        }
+        Ok(matched)
+    }
+
+    // the folder is read again for every access anyway
+    fn initialize(&mut self) -> Result<(), DosError> {
+        Ok(())
+    }
+
+    fn validate(&mut self) -> Result<(), DosError> {
+        Ok(())
+    }
+
+    // a host folder isn't wiped by a stray "N:"
+    fn format(&mut self, _name: &[u8], _id: Option<&[u8]>) -> Result<(), DosError> {
+        Err(DosError::WriteProtectOn)
+    }
+}
@@ -1,3 +1,7 @@
+use crate::dos::{CommandChannel, DosError, FileSystem, Status};
+use crate::dos::{CHANNELS, COMMAND_CHANNEL, LOAD_CHANNEL, SAVE_CHANNEL};
+use crate::tape::Datasette;
+
 // base is 0xe800
 const PIA1: u16 = 0x0010;
 const PIA2: u16 = 0x0020;
@@ -23,7 +27,6 @@ const PCR: u16 = 0x0c;
 const IFR: u16 = 0x0d;
 const IER: u16 = 0x0e;
 
-const IER_MASTER: u8 = 0x80;
 const IER_TIMER1: u8 = 0x40;
 const IER_TIMER2: u8 = 0x20;
 const IER_CB1_ACTIVE: u8 = 0x10;
@@ -32,15 +35,24 @@ const IER_SHIFT_REG: u8 = 0x04;
 const IER_CA1_ACTIVE: u8 = 0x02;
 const IER_CA2_ACTIVE: u8 = 0x01;
 
+const PIA_CR_C1_IRQ_ENABLE: u8 = 0x01;
+const PIA_CR_C1_POSITIVE_EDGE: u8 = 0x02;
+const PIA_CR_C1_IRQ_FLAG: u8 = 0x80;
+
+const PIA1_PA_CASSETTE1_SENSE: u8 = 0x10;
+
 const VIA_VIDEO_RETRACE: u8 = 0x20;
+const VIA_PB_CASSETTE_WRITE: u8 = 0x08;
+const VIA_PCR_CA2_MASK: u8 = 0x0e;
+const VIA_PCR_CA2_HIGH: u8 = 0x0e;
 const VIA_ACR_SHIFT_MASK: u8 = 0x1c;
 const VIA_ACR_T1_CONTINUOUS: u8 = 0x40;
 
 // 1ms internal clock tick
 const TICK_FREQ: u16 = 1000;
 
-// 50Hz system interrupt frequency
-const SYS_TICKS: u16 = 20;
+// length of the vertical retrace in ticks (roughly the 62 blank lines of a 262 line frame)
+const RETRACE_TICKS: u16 = 4;
 
 const PIA1_PORTB: u16 = PIA1 + PORTB;
 const PIA1_PORTA: u16 = PIA1 + PORTA;
@@ -70,21 +82,52 @@ const VIA_DDRB: u16 = VIA + DDRB;
 
 const VIA_ANH: u16 = 0x4f;
 
+/// The emulated machine model. It decides the frame rate of the video
+/// and so the rate of the system interrupt (the jiffy clock).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Model {
+    /// 60Hz machines
+    Pet2001,
+    /// 50Hz machines as sold in Europe
+    Pet2001Europe,
+}
+
+impl Model {
+    pub fn frame_rate(&self) -> u16 {
+        match self {
+            Model::Pet2001 => 60,
+            Model::Pet2001Europe => 50,
+        }
+    }
+}
+
+/// Where the vertical retrace signal (PIA1 CB1 and VIA PB5) comes from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RetraceSource {
+    /// Generated by `Io::tick` at the frame rate of the given model
+    Internal(Model),
+    /// Driven by the host via `Io::set_retrace`, e.g. from a real video output
+    External,
+}
+
 #[derive(Debug)]
 pub struct Io<'a> {
-    ticks: u16,
+    retrace_source: RetraceSource,
+    frame_ticks: u16,
+    retrace_ticks: u16,
+    retrace: bool,
+
     timer1: bool,
     timer2: bool,
     t1: u16,
     t2: u16,
     t1_latch: u16,
+    t2_latch: u8,
     acr: u8,
     ier: u8,
     ifr: u8,
     ddra: u8,
     ddrb: u8,
-    porta: u8,
-    portb: u8,
 
     pia1_pa_in: u8,
     pia1_pa_out: u8,
@@ -123,24 +166,28 @@ pub struct Io<'a> {
 
     pub keyboard: Keyboard,
     pub ieee: Ieee<'a>,
+    pub datasette: Datasette<'a>,
 }
 
 impl<'a> Io<'a> {
     pub fn new(keyboard: Keyboard, storage: &'a mut dyn Storage) -> Io {
         Io {
-            ticks: 0,
+            retrace_source: RetraceSource::Internal(Model::Pet2001),
+            frame_ticks: 0,
+            retrace_ticks: 0,
+            retrace: false,
+
             timer1: false,
             timer2: false,
             t1: 0,
             t2: 0,
             t1_latch: 0,
+            t2_latch: 0,
             acr: 0,
             ier: 0x80,
             ifr: 0,
             ddra: 0,
             ddrb: 0,
-            porta: 0,
-            portb: 0,
 
             pia1_pa_in: 0xf0,
             pia1_pa_out: 0,
@@ -150,7 +197,8 @@ impl<'a> Io<'a> {
             pia1_pb_out: 0,
             pia1_ddrb: 0,
             pia1_crb: 0,
-            pia1_ca2: 0,
+            // an input after reset, pulled high: EOI inactive and the screen on
+            pia1_ca2: 1,
 
             pia2_pa_in: 0,
             pia2_pa_out: 0,
@@ -161,7 +209,7 @@ impl<'a> Io<'a> {
             pia2_ddrb: 0,
             pia2_crb: 0,
 
-            via_drb_in: 0,
+            via_drb_in: VIA_VIDEO_RETRACE,
             via_drb_out: 0,
             via_dra_in: 0,
             via_dra_out: 0,
@@ -179,6 +227,7 @@ impl<'a> Io<'a> {
             keyboard: keyboard,
 
             ieee: Ieee::new(storage),
+            datasette: Datasette::new(),
         }
     }
 
@@ -196,63 +245,129 @@ impl<'a> Io<'a> {
         self.keyboard.reset();
     }
 
+    pub fn set_retrace_source(&mut self, source: RetraceSource) {
+        self.retrace_source = source;
+        self.frame_ticks = 0;
+        self.retrace_ticks = 0;
+    }
+
+    /// Drives the vertical retrace signal which is connected to PIA1 CB1 and VIA PB5.
+    /// The signal is low while the retrace is active.
+    /// true -> trigger IRQ, false otherwise
+    pub fn set_retrace(&mut self, retrace: bool) -> bool {
+        if retrace == self.retrace {
+            return false;
+        }
+        self.retrace = retrace;
+
+        if retrace {
+            self.via_drb_in &= !VIA_VIDEO_RETRACE;
+        } else {
+            self.via_drb_in |= VIA_VIDEO_RETRACE;
+        }
+
+        // CRB bit 1 selects the active edge of CB1
+        let positive_edge = !retrace;
+        if positive_edge == ((self.pia1_crb & PIA_CR_C1_POSITIVE_EDGE) != 0) {
+            self.pia1_crb |= PIA_CR_C1_IRQ_FLAG;
+            return (self.pia1_crb & PIA_CR_C1_IRQ_ENABLE) != 0;
+        }
+
+        false
+    }
+
+    /// true if VIA CA2 selects the lowercase / business character set (`POKE 59468,14`),
+    /// false for the graphics character set (`POKE 59468,12`)
+    pub fn lowercase_charset(&self) -> bool {
+        (self.via_pcr & VIA_PCR_CA2_MASK) == VIA_PCR_CA2_HIGH
+    }
+
+    /// true while PIA1 CA2 blanks the display. Nothing but black should be shown then.
+    pub fn screen_blanked(&self) -> bool {
+        self.pia1_ca2 == 0
+    }
+
     // true -> trigger IRQ, false otherwise
     pub fn tick(&mut self) -> bool {
         let mut raise_irq = false;
 
-        if self.ticks == SYS_TICKS {
-            self.ticks = 0;
-            self.portb |= VIA_VIDEO_RETRACE;
+        if let RetraceSource::Internal(model) = self.retrace_source {
+            // count in frames per second to get the right average rate from the 1ms tick
+            self.frame_ticks += model.frame_rate();
+            if self.frame_ticks >= TICK_FREQ {
+                self.frame_ticks -= TICK_FREQ;
+                self.retrace_ticks = RETRACE_TICKS;
+                raise_irq |= self.set_retrace(true);
+            } else if self.retrace_ticks > 0 {
+                self.retrace_ticks -= 1;
+                if self.retrace_ticks == 0 {
+                    raise_irq |= self.set_retrace(false);
+                }
+            }
+        }
+
+        if self.irq_line() {
             raise_irq = true;
-        } else {
-            self.portb = self.portb & !VIA_VIDEO_RETRACE;
-            self.ticks += 1;
         }
 
+        raise_irq
+    }
+
+    /// Advances the VIA timers and the datasette by the cycles the last `Cpu::step` took.
+    /// true -> trigger IRQ, false otherwise
+    pub fn step(&mut self, cycles: u16) -> bool {
         if self.timer1 {
-            if self.t1 < TICK_FREQ {
+            if self.t1 < cycles {
                 self.t1 = 0;
                 self.timer1 = false;
                 self.ifr |= IER_TIMER1;
-
-                if (self.ier & IER_MASTER) != 0 && (self.ier & IER_TIMER1) != 0 {
-                    raise_irq = true;
-                }
             } else {
-                self.t1 -= TICK_FREQ;
+                self.t1 -= cycles;
             }
         }
 
-        if self.timer2 {
-            if self.t2 < TICK_FREQ {
-                self.t2 = 0;
-                self.timer2 = false;
-                self.ifr |= IER_TIMER2;
+        // T2 keeps counting down after it timed out, the tape routines rely on that
+        if self.timer2 && self.t2 < cycles {
+            self.timer2 = false;
+            self.ifr |= IER_TIMER2;
+        }
+        self.t2 = self.t2.wrapping_sub(cycles);
 
-                if (self.ier & IER_MASTER) != 0 && (self.ier & IER_TIMER2) != 0 {
-                    raise_irq = true;
-                }
-            } else {
-                self.t2 -= TICK_FREQ;
-            }
+        if let Some(level) = self.datasette.step(cycles as u32) {
+            self.set_cassette_read(level);
         }
 
+        self.irq_line()
+    }
+
+    // state of the IRQ line - any of the PIAs or the VIA requesting an interrupt
+    fn irq_line(&mut self) -> bool {
+        let mut irq = false;
+
         if (self.pia1_cra & 0x81) == 0x81
             || (self.pia1_cra & 0x48) == 0x48
             || (self.pia1_crb & 0x81) == 0x81
             || (self.pia1_crb & 0x48) == 0x48
         {
-            raise_irq = true;
+            irq = true;
         }
 
         if (self.ifr & self.ier & 0x7f) != 0 {
             self.ifr |= 0x80;
-            raise_irq = true;
+            irq = true;
         } else {
             self.ifr &= !0x80;
         }
 
-        raise_irq
+        irq
+    }
+
+    // the read line of cassette #1 is connected to PIA1 CA1
+    fn set_cassette_read(&mut self, level: bool) {
+        // CRA bit 1 selects the active edge of CA1
+        if level == ((self.pia1_cra & PIA_CR_C1_POSITIVE_EDGE) != 0) {
+            self.pia1_cra |= PIA_CR_C1_IRQ_FLAG;
+        }
     }
 
     pub fn read(&mut self, offset: u16) -> u8 {
@@ -271,6 +386,12 @@ impl<'a> Io<'a> {
                             self.pia1_pa_in &= 0xbf;
                         }
                     }
+                    // cassette #1 sense, low while a button is pressed
+                    if self.datasette.sense() {
+                        self.pia1_pa_in &= !PIA1_PA_CASSETTE1_SENSE;
+                    } else {
+                        self.pia1_pa_in |= PIA1_PA_CASSETTE1_SENSE;
+                    }
 
                     r = (self.pia1_pa_in & !self.pia1_ddra) | (self.pia1_pa_out & self.pia1_ddra);
                 } else {
@@ -286,9 +407,10 @@ impl<'a> Io<'a> {
                     if (self.pia1_crb & 0xC0) != 0 {
                         self.pia1_crb &= 0x3F;
                     }
+                    self.pia1_pb_in = self.keyboard.read();
                     r = (self.pia1_pb_in & !self.pia1_ddrb) | (self.pia1_pb_out & self.pia1_ddrb);
                 } else {
-                    r = self.keyboard.read();
+                    r = self.pia1_ddrb;
                 }
             }
             PIA1_CRB => {
@@ -469,7 +591,6 @@ impl<'a> Io<'a> {
                     // Which keyrow are we accessing?
                     if (self.pia1_pa_out & 15) < 10 {
                         self.keyboard.write(v & 0x0f);
-                        self.pia1_pb_in = self.keyboard.row(); // ???? keyrow[pia1_pa_out & 15];
                     }
                 } else {
                     self.pia1_ddra = v;
@@ -477,44 +598,47 @@ impl<'a> Io<'a> {
             }
             PIA1_CRA => {
                 self.pia1_cra = (self.pia1_cra & 0xc0) | (v & 0x3f);
-                // Change in CA2? (screen blank)
+                // Change in CA2? It drives both the IEEE EOI output and the screen blank.
                 if (self.pia1_cra & 0x38) == 0x38 && self.pia1_ca2 == 0 {
-                    // CA2 transitioning high. (Screen On)
+                    // CA2 transitioning high.
                     self.pia1_ca2 = 1;
                     self.ieee.eoi_out(true);
                 } else if (self.pia1_cra & 0x38) == 0x30 && self.pia1_ca2 != 0 {
-                    // CA2 transitioning low. (Screen Blank)
+                    // CA2 transitioning low.
                     self.pia1_ca2 = 0;
                     self.ieee.eoi_out(false);
                 }
             }
 
             PIA1_CRB => {
-                // ???
+                self.pia1_crb = (self.pia1_crb & 0xc0) | (v & 0x3f);
+                // CB2 is the motor of cassette #1 - it's running while CB2 is low
+                self.datasette.set_motor((self.pia1_crb & 0x38) == 0x30);
             }
 
             VIA_T1LLO => {
-                self.t1_latch = (self.t1 & 0xff00) | v as u16;
+                self.t1_latch = (self.t1_latch & 0xff00) | v as u16;
             }
             VIA_T1LO => {
-                self.t1_latch = (self.t1 & 0xff00) | v as u16;
+                self.t1_latch = (self.t1_latch & 0xff00) | v as u16;
             }
             VIA_T1LHI => {
                 self.t1_latch = (self.t1_latch & 0xff) | (v as u16) << 8;
                 self.ifr &= !IER_TIMER1;
             }
             VIA_T1HI => {
+                // writing the high byte loads the counter from the latch
+                self.t1_latch = (self.t1_latch & 0xff) | (v as u16) << 8;
                 self.t1 = self.t1_latch;
                 self.ifr &= !IER_TIMER1;
                 self.timer1 = true;
             }
             VIA_T2LO => {
-                self.t2 = v as u16;
-                self.timer2 = false;
-                self.ifr &= !IER_TIMER2;
+                self.t2_latch = v;
             }
             VIA_T2HI => {
-                self.t2 += (v as u16) << 8;
+                // writing the high byte loads the counter
+                self.t2 = self.t2_latch as u16 | (v as u16) << 8;
                 self.ifr &= !IER_TIMER2;
                 self.timer2 = true;
             }
@@ -563,6 +687,11 @@ impl<'a> Io<'a> {
                 }
                 self.via_drb_out = v;
 
+                // cassette write line
+                if (self.ddrb & VIA_PB_CASSETTE_WRITE) != 0 {
+                    self.datasette.write((self.via_drb_out & VIA_PB_CASSETTE_WRITE) != 0);
+                }
+
                 // IEEE outputs
                 if (self.ddrb & 0x04) != 0 {
                     self.ieee.atn_out((self.via_drb_out & 0x04) != 0x00);
@@ -611,13 +740,7 @@ impl<'a> Io<'a> {
                 self.acr = v;
             }
             VIA_PCR => {
-                /* Did we change CA2 output? */
-                if (self.via_pcr & 0x0c) == 0x0c
-                    && (v & 0x0c) == 0x0c
-                    && ((self.via_pcr ^ v) & 0x02) != 0
-                {
-                    // nothing
-                }
+                // CA2 in manual output mode selects the character set - see `lowercase_charset`
                 self.via_pcr = v;
             }
             VIA_IER => {
@@ -669,6 +792,11 @@ impl Keyboard {
         self.row = v;
     }
 
+    /// True while any key is held down
+    pub fn any_key_down(&self) -> bool {
+        self.rows.iter().any(|&row| row != 0)
+    }
+
     /// Sets a key represented by the parameter to down.
     /// the high nibble is the row, the low nibble is the column
     pub fn key_down(&mut self, k: u8) {
@@ -682,16 +810,139 @@ impl Keyboard {
     }
 }
 
-const MY_ADDRESS: u8 = 8;
+// primary address of the drive built into the bus, the one backed by the `Storage` given to `Io::new`
+const DEFAULT_DRIVE_ADDRESS: u8 = 8;
+
+// devices which can be attached in addition to the built-in drive
+const MAX_DEVICES: usize = 8;
+
+// commands sent under ATN
+const IEEE_LISTEN: u8 = 0x20;
+const IEEE_UNLISTEN: u8 = 0x3f;
+const IEEE_TALK: u8 = 0x40;
+const IEEE_UNTALK: u8 = 0x5f;
+const IEEE_SECONDARY: u8 = 0x60;
+const IEEE_ADDRESS_MASK: u8 = 0x1f;
+
+// old PET ROMs send this instead of TALK when loading
+const IEEE_OLD_ROM_LOAD: u8 = 0x7f;
+
+// old ROMs don't read the load address of a program, they store it from here on
+const OLD_ROM_LOAD_START: u16 = 0x0400;
 
 #[derive(Debug)]
 enum IeeeState {
-    IDLE,
-    LISTEN,
-    FNAME,
-    LOAD,
-    SAVE,
-    SAVE1,
+    Idle,
+    Listen,
+    Talk,
+    // old ROMs send the data with ATN low
+    OldRomSave,
+}
+
+/// A device on the IEEE-488 bus.
+///
+/// The bus does the handshaking and decodes the commands sent under ATN,
+/// a device only sees the commands meant for its primary address.
+pub trait IeeeDevice {
+    /// The primary address (0-30) the device listens and talks on.
+    fn address(&self) -> u8;
+
+    /// The device got addressed to listen.
+    /// `secondary` is 0x60-0x6f for data, 0xe0-0xef for close and 0xf0-0xff for open
+    /// with the channel in the low nibble.
+    fn listen(&mut self, secondary: u8);
+
+    /// A data byte received while listening.
+    fn receive(&mut self, value: u8);
+
+    fn unlisten(&mut self);
+
+    /// The device got addressed to talk, `secondary` is 0x60-0x6f.
+    fn talk(&mut self, secondary: u8);
+
+    /// The byte to send next while talking and true if it's the last one.
+    /// None if there is nothing to send.
+    fn peek(&mut self) -> Option<(u8, bool)>;
+
+    /// The byte returned by `peek` got accepted.
+    fn advance(&mut self);
+
+    fn untalk(&mut self);
+}
+
+impl<'a> core::fmt::Debug for dyn IeeeDevice + 'a {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "IeeeDevice {}", self.address())
+    }
+}
+
+#[derive(Debug)]
+pub enum AttachError {
+    AddressInUse,
+    BusFull,
+}
+
+/// The file type given with the name when opening a file, e.g. "DATA,S,W"
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileType {
+    Prg,
+    Seq,
+    Usr,
+    Rel,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileMode {
+    Read,
+    Write,
+    Append,
+}
+
+/// A filename as sent with OPEN split into its parts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OpenName<'n> {
+    pub name: &'n [u8],
+    pub file_type: Option<FileType>,
+    pub mode: FileMode,
+    /// "@0:NAME" overwrites an existing file
+    pub replace: bool,
+}
+
+impl<'n> OpenName<'n> {
+    /// Parses names like "0:DATA,S,W" - the drive number is ignored,
+    /// the mode defaults to read.
+    pub fn parse(raw: &'n [u8]) -> OpenName<'n> {
+        let replace = raw.first() == Some(&b'@');
+        let raw = match raw.iter().position(|&c| c == b':') {
+            Some(colon) => &raw[colon + 1..],
+            None => raw,
+        };
+
+        let mut parts = raw.split(|&c| c == b',');
+        let name = parts.next().unwrap_or(&[]);
+
+        let mut file_type = None;
+        let mut mode = FileMode::Read;
+        for part in parts {
+            match part.first() {
+                Some(b'P') => file_type = Some(FileType::Prg),
+                Some(b'S') => file_type = Some(FileType::Seq),
+                Some(b'U') => file_type = Some(FileType::Usr),
+                Some(b'L') => file_type = Some(FileType::Rel),
+                Some(b'R') => mode = FileMode::Read,
+                Some(b'W') => mode = FileMode::Write,
+                Some(b'A') => mode = FileMode::Append,
+                _ => {}
+            }
+        }
+
+        OpenName {
+            name,
+            file_type,
+            mode,
+            replace,
+        }
+    }
 }
 
 pub trait Storage {
@@ -699,27 +950,287 @@ pub trait Storage {
 
     fn next_filename_byte(&mut self, value: u8);
 
-    fn start_save(&mut self);
+    fn start_save(&mut self) -> Result<(), DosError>;
 
-    fn end_save(&mut self);
+    fn end_save(&mut self) -> Result<(), DosError>;
 
     fn fname_done(&mut self);
 
-    fn has_data_to_load(&mut self) -> bool;
+    fn load_data_byte(&mut self, index: usize) -> Result<u8, DosError>;
+
+    fn save_data_byte(&mut self, index: usize, value: u8) -> Result<(), DosError>;
+
+    /// The length of the file named last, `DosError::FileNotFound` if there is none.
+    fn load_data_len(&mut self) -> Result<usize, DosError>;
+
+    /// Opens a file on a data channel (secondary address 2-14).
+    fn open(&mut self, channel: u8, name: &OpenName) -> Result<(), DosError>;
 
-    fn load_data_byte(&mut self, index: usize) -> u8;
+    /// The next byte of the file open on the channel and true if it's the last one.
+    /// None if there is nothing (more) to read.
+    fn read(&mut self, channel: u8) -> Option<(u8, bool)>;
 
-    fn save_data_byte(&mut self, index: usize, value: u8);
+    fn write(&mut self, channel: u8, value: u8) -> Result<(), DosError>;
 
-    fn load_data_len(&mut self) -> usize;
+    fn close(&mut self, channel: u8) -> Result<(), DosError>;
+
+    /// What the commands sent to the command channel work on, None if they aren't supported.
+    fn file_system(&mut self) -> Option<&mut dyn FileSystem>;
 }
 
-impl core::fmt::Debug for dyn Storage {
+impl<'a> core::fmt::Debug for dyn Storage + 'a {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "Storage")
     }
 }
 
+const MAX_OPEN_NAME_LEN: usize = 40;
+
+const SECONDARY_DATA: u8 = IEEE_SECONDARY;
+const SECONDARY_CLOSE: u8 = 0xe0;
+const SECONDARY_OPEN: u8 = 0xf0;
+const SECONDARY_COMMAND_MASK: u8 = 0xf0;
+const SECONDARY_CHANNEL_MASK: u8 = 0x0f;
+
+#[derive(Debug)]
+enum DriveState {
+    Idle,
+    Filename,
+    Load,
+    Save,
+    OpenName(u8),
+    Write(u8),
+    Read(u8),
+    Command,
+}
+
+/// A disk drive on the IEEE bus backed by a `Storage`.
+#[derive(Debug)]
+pub struct DiskDrive<'a> {
+    address: u8,
+    state: DriveState,
+    data_index: usize,
+    data_len: usize,
+
+    open_name: [u8; MAX_OPEN_NAME_LEN],
+    open_name_len: usize,
+
+    // a byte read from a channel which wasn't accepted by the PET yet
+    pending: [Option<(u8, bool)>; CHANNELS],
+
+    command: CommandChannel,
+
+    storage: &'a mut dyn Storage,
+}
+
+impl<'a> DiskDrive<'a> {
+    pub fn new(address: u8, storage: &'a mut dyn Storage) -> DiskDrive<'a> {
+        DiskDrive {
+            address,
+            state: DriveState::Idle,
+            data_index: 0,
+            data_len: 0,
+            open_name: [0u8; MAX_OPEN_NAME_LEN],
+            open_name_len: 0,
+            pending: [None; CHANNELS],
+            command: CommandChannel::new(),
+            storage,
+        }
+    }
+
+    pub fn set_address(&mut self, address: u8) {
+        self.address = address;
+    }
+
+    /// What reading the command channel would return
+    pub fn status(&self) -> Status {
+        self.command.status()
+    }
+
+    /// The storage of the drive, e.g. to copy files to it from outside the emulation
+    pub fn storage(&mut self) -> &mut dyn Storage {
+        self.storage
+    }
+
+    fn report(&mut self, result: Result<(), DosError>) {
+        self.command.set_status(result.into());
+    }
+
+    // the position in the program being loaded
+    fn load_index(&self) -> Option<usize> {
+        match self.state {
+            DriveState::Load => Some(self.data_index),
+            _ => None,
+        }
+    }
+
+    // hands the program loaded over up to its last byte, returns how many bytes it were
+    fn skip_load(&mut self, mut store: impl FnMut(u8)) -> usize {
+        let mut stored = 0;
+        while self.load_index().is_some() && self.data_index + 1 < self.data_len {
+            match self.storage.load_data_byte(self.data_index) {
+                Ok(value) => store(value),
+                Err(error) => {
+                    self.command.set_status(error.into());
+                    break;
+                }
+            }
+            self.data_index += 1;
+            stored += 1;
+        }
+        stored
+    }
+}
+
+impl<'a> IeeeDevice for DiskDrive<'a> {
+    fn address(&self) -> u8 {
+        self.address
+    }
+
+    fn listen(&mut self, secondary: u8) {
+        let channel = (secondary & SECONDARY_CHANNEL_MASK) as usize;
+        match (secondary & SECONDARY_COMMAND_MASK, channel) {
+            (SECONDARY_OPEN, LOAD_CHANNEL) | (SECONDARY_OPEN, SAVE_CHANNEL) => {
+                // load or save
+                self.storage.start_filename();
+                self.state = DriveState::Filename;
+            }
+            (SECONDARY_OPEN, COMMAND_CHANNEL) | (SECONDARY_DATA, COMMAND_CHANNEL) => {
+                self.state = DriveState::Command;
+            }
+            (SECONDARY_OPEN, _) => {
+                self.open_name_len = 0;
+                self.state = DriveState::OpenName(channel as u8);
+            }
+            (SECONDARY_CLOSE, LOAD_CHANNEL)
+            | (SECONDARY_CLOSE, SAVE_CHANNEL)
+            | (SECONDARY_CLOSE, COMMAND_CHANNEL) => {}
+            (SECONDARY_CLOSE, _) => {
+                self.pending[channel] = None;
+                let result = self.storage.close(channel as u8);
+                self.report(result);
+            }
+            (SECONDARY_DATA, SAVE_CHANNEL) => {
+                let result = self.storage.start_save();
+                if result.is_ok() {
+                    self.data_index = 0;
+                    self.state = DriveState::Save;
+                }
+                self.report(result);
+            }
+            (SECONDARY_DATA, LOAD_CHANNEL) => {}
+            (SECONDARY_DATA, _) => {
+                self.state = DriveState::Write(channel as u8);
+            }
+            _ => {}
+        }
+    }
+
+    fn receive(&mut self, value: u8) {
+        match self.state {
+            DriveState::Filename => {
+                self.storage.next_filename_byte(value);
+            }
+            DriveState::Save => match self.storage.save_data_byte(self.data_index, value) {
+                Ok(()) => self.data_index += 1,
+                Err(error) => {
+                    self.command.set_status(error.into());
+                    self.state = DriveState::Idle;
+                }
+            },
+            DriveState::OpenName(_) if self.open_name_len < MAX_OPEN_NAME_LEN => {
+                self.open_name[self.open_name_len] = value;
+                self.open_name_len += 1;
+            }
+            DriveState::Write(channel) => {
+                if let Err(error) = self.storage.write(channel, value) {
+                    self.command.set_status(error.into());
+                }
+            }
+            DriveState::Command => self.command.receive(value),
+            _ => {}
+        }
+    }
+
+    fn unlisten(&mut self) {
+        match self.state {
+            DriveState::Filename => self.storage.fname_done(),
+            DriveState::Save => {
+                let result = self.storage.end_save();
+                self.report(result);
+            }
+            DriveState::OpenName(channel) => {
+                self.pending[channel as usize] = None;
+                let name = OpenName::parse(&self.open_name[..self.open_name_len]);
+                let result = self.storage.open(channel, &name);
+                self.report(result);
+            }
+            DriveState::Command => self.command.execute(self.storage.file_system()),
+            _ => {}
+        }
+        self.state = DriveState::Idle;
+    }
+
+    fn talk(&mut self, secondary: u8) {
+        match (secondary & SECONDARY_CHANNEL_MASK) as usize {
+            // a missing file leaves the drive silent, the PET times out and reports FILE NOT FOUND
+            LOAD_CHANNEL => match self.storage.load_data_len() {
+                Ok(len) => {
+                    self.data_index = 0;
+                    self.data_len = len;
+                    self.state = DriveState::Load;
+                    self.command.set_status(Status::OK);
+                }
+                Err(error) => {
+                    self.command.set_status(error.into());
+                    self.state = DriveState::Idle;
+                }
+            },
+            channel => {
+                self.state = DriveState::Read(channel as u8);
+            }
+        }
+    }
+
+    fn peek(&mut self) -> Option<(u8, bool)> {
+        match self.state {
+            DriveState::Load if self.data_index < self.data_len => {
+                match self.storage.load_data_byte(self.data_index) {
+                    Ok(value) => Some((value, self.data_index == self.data_len - 1)),
+                    Err(error) => {
+                        self.command.set_status(error.into());
+                        None
+                    }
+                }
+            }
+            DriveState::Read(channel) => {
+                let pending = &mut self.pending[channel as usize];
+                if pending.is_none() {
+                    *pending = if channel as usize == COMMAND_CHANNEL {
+                        self.command.read()
+                    } else {
+                        self.storage.read(channel)
+                    };
+                }
+                *pending
+            }
+            _ => None,
+        }
+    }
+
+    fn advance(&mut self) {
+        match self.state {
+            DriveState::Load => self.data_index += 1,
+            DriveState::Read(channel) => self.pending[channel as usize] = None,
+            _ => {}
+        }
+    }
+
+    fn untalk(&mut self) {
+        self.state = DriveState::Idle;
+    }
+}
+
 pub struct Ieee<'a> {
     state: IeeeState,
     dio: u8,
@@ -735,9 +1246,25 @@ pub struct Ieee<'a> {
     eoi_o: bool,
 
     old_rom: bool,
-    data_index: usize,
+    // a zero byte to send before the program in an old ROM load
+    old_rom_fill: bool,
 
-    storage: &'a mut dyn Storage,
+    // loads from the drive skip the handshake, see `warp_load`
+    warp: bool,
+    warp_pending: bool,
+
+    // the primary address of the current listener or talker and the secondary address
+    // it got addressed with, None until the secondary address was passed on to the device
+    address: u8,
+    secondary: Option<u8>,
+    last_listener: u8,
+
+    // the devices stop taking part in the handshake under ATN when an absent
+    // device got addressed
+    absent_addressed: bool,
+
+    drive: DiskDrive<'a>,
+    devices: [Option<&'a mut dyn IeeeDevice>; MAX_DEVICES],
 }
 
 impl<'a> core::fmt::Debug for Ieee<'a> {
@@ -749,7 +1276,7 @@ impl<'a> core::fmt::Debug for Ieee<'a> {
 impl<'a> Ieee<'a> {
     fn new(storage: &'a mut dyn Storage) -> Ieee<'a> {
         Ieee {
-            state: IeeeState::IDLE,
+            state: IeeeState::Idle,
             dio: 0,
             nrfd_i: true,
             ndac_i: true,
@@ -762,100 +1289,256 @@ impl<'a> Ieee<'a> {
             eoi_i: true,
             eoi_o: true,
             old_rom: false,
-            data_index: 0,
-            storage: storage,
+            old_rom_fill: false,
+            warp: false,
+            warp_pending: false,
+            address: 0,
+            secondary: None,
+            last_listener: DEFAULT_DRIVE_ADDRESS,
+            absent_addressed: false,
+            drive: DiskDrive::new(DEFAULT_DRIVE_ADDRESS, storage),
+            devices: Default::default(),
         }
     }
 
-    fn data_in(&mut self, d8: u8) {
-        if !self.atn {
-            match self.state {
-                IeeeState::IDLE => {
-                    if d8 == 0x20 + MY_ADDRESS {
-                        self.state = IeeeState::LISTEN;
-                    } else if d8 == 0x40 + MY_ADDRESS {
-                        self.old_rom = false;
-                        self.state = IeeeState::LOAD;
-                    } else if d8 == 0x7f && self.storage.has_data_to_load() {
-                        // Old PET ROMs LOAD.
-                        // Assume program starts at either 0x0400 or 0x0401.
-                        self.data_index = if self.storage.load_data_byte(0) == 0 {
-                            2
-                        } else {
-                            1
-                        };
+    /// Changes the primary address of the drive backed by the `Storage` given to `Io::new`.
+    pub fn set_drive_address(&mut self, address: u8) {
+        self.drive.set_address(address);
+    }
 
-                        // Put first data on bus.
-                        self.dio = self.storage.load_data_byte(self.data_index) ^ 0xff;
-                        self.dav_i = false;
+    /// The `Storage` given to `Io::new`
+    pub fn drive_storage(&mut self) -> &mut dyn Storage {
+        self.drive.storage()
+    }
 
-                        self.old_rom = true;
-                        self.state = IeeeState::LOAD;
-                    } else if d8 == 0x3f {
-                        // Old PET ROMs save
-                        self.old_rom = true;
-                        self.storage.start_save();
-                        self.state = IeeeState::SAVE1;
-                    }
+    /// Lets programs loaded from the drive backed by the `Storage` given to `Io::new` go
+    /// straight into memory instead of byte by byte over the bus. Only works for ROMs
+    /// reading the load address themselves and with a memory calling `warp_load`.
+    pub fn set_warp(&mut self, warp: bool) {
+        self.warp = warp;
+        self.warp_pending = false;
+    }
+
+    /// True once a program can be handed over by `warp_load`
+    pub fn warp_pending(&self) -> bool {
+        self.warp_pending
+    }
+
+    /// With warp on and the load address of a program taken by the PET, hands the rest
+    /// of it but the last byte to `store`. The last one is still sent over the bus, so
+    /// the PET ends the load as usual. Returns how many bytes were stored.
+    pub fn warp_load(&mut self, store: impl FnMut(u8)) -> usize {
+        if !self.warp_pending {
+            return 0;
+        }
+        self.warp_pending = false;
+
+        let stored = self.drive.skip_load(store);
+        if stored > 0 {
+            // the byte on the bus is the last one now
+            self.eoi_i = true;
+            self.put_data();
+        }
+        stored
+    }
+
+    /// Plugs a device into the bus.
+    pub fn attach(&mut self, device: &'a mut dyn IeeeDevice) -> Result<(), AttachError> {
+        if self.device(device.address()).is_some() {
+            return Err(AttachError::AddressInUse);
+        }
+
+        match self.devices.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(device);
+                Ok(())
+            }
+            None => Err(AttachError::BusFull),
+        }
+    }
+
+    /// Unplugs the device with the given primary address.
+    pub fn detach(&mut self, address: u8) -> Option<&'a mut dyn IeeeDevice> {
+        self.devices
+            .iter_mut()
+            .find(|slot| matches!(slot, Some(device) if device.address() == address))
+            .and_then(|slot| slot.take())
+    }
+
+    fn device(&mut self, address: u8) -> Option<&mut dyn IeeeDevice> {
+        if self.drive.address() == address {
+            return Some(&mut self.drive);
+        }
+
+        for device in self.devices.iter_mut().flatten() {
+            if device.address() == address {
+                return Some(&mut **device);
+            }
+        }
+        None
+    }
+
+    // the addressed listener or talker
+    fn addressed(&mut self) -> Option<&mut dyn IeeeDevice> {
+        match self.state {
+            IeeeState::Listen | IeeeState::Talk => self.device(self.address),
+            IeeeState::OldRomSave => self.device(self.last_listener),
+            IeeeState::Idle => None,
+        }
+    }
+
+    // passes the secondary address on to the addressed device
+    fn secondary_address(&mut self, secondary: u8) {
+        self.secondary = Some(secondary);
+        match self.state {
+            IeeeState::Listen | IeeeState::OldRomSave => {
+                if let Some(device) = self.addressed() {
+                    device.listen(secondary);
                 }
-                IeeeState::LISTEN => {
-                    // unlisten
-                    if d8 == 0x3f {
-                        self.state = IeeeState::IDLE;
-                    } else if d8 == 0xf0 || d8 == 0xf1 {
-                        // load or save
-                        self.storage.start_filename();
-                        self.data_index = 0;
-                        self.state = IeeeState::FNAME;
-                    } else if d8 == 0x61 {
-                        self.storage.start_save();
-                        self.state = IeeeState::SAVE;
-                    }
+            }
+            IeeeState::Talk => {
+                if let Some(device) = self.addressed() {
+                    device.talk(secondary);
                 }
-                IeeeState::FNAME => {
-                    // unlisten
-                    if d8 == 0x3f {
-                        self.storage.fname_done();
-                        self.state = IeeeState::IDLE;
+            }
+            _ => {}
+        }
+    }
+
+    fn unaddress(&mut self) {
+        if self.secondary.is_some() {
+            match self.state {
+                IeeeState::Listen | IeeeState::OldRomSave => {
+                    if let Some(device) = self.addressed() {
+                        device.unlisten();
                     }
                 }
-                IeeeState::LOAD => {
-                    // untalk
-                    if d8 == 0x5f {
-                        self.state = IeeeState::IDLE;
+                IeeeState::Talk => {
+                    if let Some(device) = self.addressed() {
+                        device.untalk();
                     }
                 }
-                IeeeState::SAVE => {
-                    // unlisten
-                    if d8 == 0x3f {
-                        self.storage.end_save();
-                        self.state = IeeeState::IDLE;
+                _ => {}
+            }
+        }
+        self.secondary = None;
+        self.old_rom = false;
+        self.old_rom_fill = false;
+        self.warp_pending = false;
+        self.state = IeeeState::Idle;
+    }
+
+    // reads the load address in front of the data of the talker
+    fn load_address(&mut self) -> Option<u16> {
+        let device = self.addressed()?;
+        let (low, _) = device.peek()?;
+        device.advance();
+        let (high, _) = device.peek()?;
+        device.advance();
+        Some(u16::from_le_bytes([low, high]))
+    }
+
+    // true if a device takes part in the handshake of the bytes sent by the PET
+    fn acceptor_present(&mut self) -> bool {
+        if !self.atn {
+            // all devices accept commands
+            return !self.absent_addressed;
+        }
+
+        match self.state {
+            IeeeState::Listen => self.addressed().is_some(),
+            _ => false,
+        }
+    }
+
+    // puts the next byte of the talker on the bus
+    fn put_data(&mut self) {
+        if self.old_rom_fill {
+            self.dio = 0xff;
+            self.dav_i = false;
+            return;
+        }
+
+        if let Some((value, last)) = self.addressed().and_then(|device| device.peek()) {
+            self.dio = value ^ 0xff;
+            self.dav_i = false;
+            if last {
+                self.eoi_i = false;
+            }
+        }
+    }
+
+    fn data_in(&mut self, d8: u8) {
+        if !self.atn {
+            if let IeeeState::OldRomSave = self.state {
+                if self.eoi_o {
+                    // Data comes with ATN low in old ROMs.
+                    if let Some(device) = self.addressed() {
+                        device.receive(d8);
                     }
+                } else {
+                    // Ignore last byte.
+                    self.unaddress();
                 }
-                IeeeState::SAVE1 => {
-                    if self.eoi_o {
-                        // Data comes with ATN low in old ROMs.
-                        self.storage.save_data_byte(self.data_index, d8);
-                        self.data_index += 1;
+                return;
+            }
+
+            match d8 {
+                IEEE_UNLISTEN => {
+                    if let IeeeState::Idle = self.state {
+                        // Old PET ROMs save
+                        self.old_rom = true;
+                        self.state = IeeeState::OldRomSave;
+                        self.secondary_address(0x61);
                     } else {
-                        // Ignore last byte.
-                        self.storage.end_save();
-                        self.old_rom = false;
-                        self.state = IeeeState::IDLE;
+                        self.unaddress();
                     }
                 }
-            }
-        } else {
-            match self.state {
-                IeeeState::FNAME => {
-                    self.storage.next_filename_byte(d8);
+                IEEE_UNTALK => {
+                    self.unaddress();
                 }
-                IeeeState::SAVE => {
-                    self.storage.save_data_byte(self.data_index, d8);
-                    self.data_index += 1;
+                IEEE_OLD_ROM_LOAD if matches!(self.state, IeeeState::Idle) => {
+                    // Old PET ROMs LOAD.
+                    self.address = self.last_listener;
+                    self.state = IeeeState::Talk;
+                    self.secondary_address(IEEE_SECONDARY);
+
+                    // Old ROMs load everything to 0x0400, newer ones read the load address
+                    // themselves. A BASIC program from 0x0401 needs the zero byte in front.
+                    match self.load_address() {
+                        Some(address) => self.old_rom_fill = address == OLD_ROM_LOAD_START + 1,
+                        None => {
+                            self.unaddress();
+                            return;
+                        }
+                    }
+
+                    // Put first data on bus.
+                    self.put_data();
+                    self.old_rom = true;
+                }
+                _ if d8 & 0xe0 == IEEE_LISTEN => {
+                    self.unaddress();
+                    self.address = d8 & IEEE_ADDRESS_MASK;
+                    self.last_listener = self.address;
+                    self.state = IeeeState::Listen;
+                    self.absent_addressed = self.addressed().is_none();
+                }
+                _ if d8 & 0xe0 == IEEE_TALK => {
+                    self.unaddress();
+                    self.address = d8 & IEEE_ADDRESS_MASK;
+                    self.state = IeeeState::Talk;
+                    self.absent_addressed = self.addressed().is_none();
+                }
+                _ if d8 >= IEEE_SECONDARY => {
+                    self.secondary_address(d8);
                 }
                 _ => {}
             }
+        } else if let IeeeState::Listen = self.state {
+            if let Some(device) = self.addressed() {
+                device.receive(d8);
+            }
         }
     }
 
@@ -874,12 +1557,16 @@ impl<'a> Ieee<'a> {
     fn ndac_out(&mut self, flag: bool) {
         if !self.ndac_o && flag {
             // Positive transition of NDAC.  Data acknowledged.
-            if let IeeeState::LOAD = self.state {
+            if let IeeeState::Talk = self.state {
                 self.dav_i = true;
                 self.eoi_i = true;
-                self.data_index += 1;
-                if self.old_rom && self.data_index == self.storage.load_data_len() {
-                    self.state = IeeeState::IDLE;
+                if self.old_rom_fill {
+                    self.old_rom_fill = false;
+                } else if let Some(device) = self.addressed() {
+                    device.advance();
+                }
+                if self.old_rom && self.addressed().and_then(|device| device.peek()).is_none() {
+                    self.unaddress();
                 }
             }
         }
@@ -893,14 +1580,14 @@ impl<'a> Ieee<'a> {
     fn nrfd_out(&mut self, flag: bool) {
         if !self.nrfd_o && flag {
             // Positive transition of NRFD.  Put data on bus.
-            if let IeeeState::LOAD = self.state {
-                if self.data_index < self.storage.load_data_len() {
-                    self.dio = self.storage.load_data_byte(self.data_index) ^ 0xff;
-                    self.dav_i = false;
-                    if self.data_index == self.storage.load_data_len() - 1 {
-                        self.eoi_i = false;
-                    }
-                }
+            if let IeeeState::Talk = self.state {
+                self.put_data();
+
+                // the PET is about to take the first byte after the load address
+                self.warp_pending = self.warp
+                    && !self.old_rom
+                    && self.address == self.drive.address()
+                    && self.drive.load_index() == Some(2);
             }
         }
         self.nrfd_o = flag;
@@ -916,13 +1603,27 @@ impl<'a> Ieee<'a> {
 
     fn atn_out(&mut self, flag: bool) {
         if self.atn && !flag {
+            // every device on the bus takes part in the handshake under ATN
+            self.absent_addressed = false;
             self.ndac_i = false;
         } else if !self.atn && flag {
-            if let IeeeState::LOAD = self.state {
+            self.atn = flag;
+
+            // addressed without a secondary address
+            if self.secondary.is_none() {
+                self.secondary_address(IEEE_SECONDARY);
+            }
+
+            // nobody pulls NRFD or NDAC low if the addressed device isn't present
+            if !self.acceptor_present() {
+                self.ndac_i = true;
+                self.nrfd_i = true;
+            }
+
+            if let IeeeState::Talk = self.state {
                 if self.nrfd_o {
                     // put 1st byte on bus
-                    self.dio = self.storage.load_data_byte(0) ^ 0xff;
-                    self.dav_i = false;
+                    self.put_data();
                 }
             }
         }
@@ -932,12 +1633,15 @@ impl<'a> Ieee<'a> {
     fn dav_out(&mut self, flag: bool) {
         if self.dav_o && !flag {
             // Negative transition of DAV.
-            self.ndac_i = true;
-            self.nrfd_i = false;
-            self.data_in(self.dio ^ 0xff);
+            if self.acceptor_present() {
+                self.ndac_i = true;
+                self.nrfd_i = false;
+                self.data_in(self.dio ^ 0xff);
+            }
         } else if !self.dav_o && flag {
             // Positive transition of DAV.
-            self.ndac_i = false;
+            // With NRFD and NDAC both high the PET reports "device not present".
+            self.ndac_i = !self.acceptor_present();
             self.nrfd_i = true;
         }
         self.dav_o = flag;
@@ -1,23 +1,43 @@
 #![no_std]
 
+#[cfg(any(feature = "std", test))]
+extern crate std;
+
 use mos6502::Memory;
 
 use core::cell::RefCell;
 
+pub mod basic;
+pub mod boot;
+pub mod diskimage;
+pub mod dos;
+pub mod fatfs;
+pub mod flashfs;
+#[cfg(any(feature = "std", test))]
+pub mod hostdir;
 pub mod io;
+pub mod prg;
+pub mod screen;
+pub mod tape;
+pub mod xmodem;
 use io::Io;
 use io::Keyboard;
 use io::Storage;
+use prg::{BasicVersion, PrgError};
 
 const ROM_C000: &'static [u8] = include_bytes!("../rom/rom-b-c000.bin");
 const ROM_D000: &'static [u8] = include_bytes!("../rom/rom-b-d000.bin");
 const ROM_E000: &'static [u8] = include_bytes!("../rom/rom-e-e000.bin");
 const ROM_F000: &'static [u8] = include_bytes!("../rom/rom-k-f000.bin");
 
+// graphics character set in the first 1k, lowercase character set in the second 1k
+pub const CHAR_ROM: &[u8] = include_bytes!("../rom/char/characters-2.901447-10.bin");
+
 pub struct Ram<'a> {
     pub ram: RefCell<&'a mut [u8; 8192]>,
     pub vid_ram: RefCell<&'a mut [u8; 2048]>,
     pub io: RefCell<Io<'a>>,
+    basic: BasicVersion,
 }
 
 impl<'a> Ram<'a> {
@@ -33,7 +53,79 @@ impl<'a> Ram<'a> {
             ram: RefCell::new(ram),
             vid_ram: RefCell::new(vid_ram),
             io: RefCell::new(io),
+            basic: BasicVersion::detect(&[ROM_C000, ROM_D000, ROM_E000]),
+        }
+    }
+
+    /// The version of BASIC in the ROMs
+    pub fn basic_version(&self) -> BasicVersion {
+        self.basic
+    }
+
+    /// Puts a program (a PRG file) at its load address without going through LOAD.
+    /// A BASIC program can be RUN afterwards. Returns the addresses it occupies.
+    pub fn load_prg(&self, prg: &[u8]) -> Result<core::ops::Range<u16>, PrgError> {
+        prg::load(self, self.basic, prg)
+    }
+
+    // with warp on the program loaded from the drive is stored at once where the kernal
+    // would put it, see `Ieee::warp_load`
+    fn warp_load(&self) {
+        // called on every I/O write, mostly there is nothing to do
+        if !self.io.borrow().ieee.warp_pending() {
+            return;
+        }
+        let (pointer, verify) = match self.basic.load_pointer() {
+            Some(pointer) => pointer,
+            None => return,
+        };
+        if self.get(verify) != 0 {
+            return;
+        }
+
+        let mut address = u16::from_le_bytes([self.get(pointer), self.get(pointer + 1)]);
+        let stored = self.io.borrow_mut().ieee.warp_load(|value| {
+            if !is_io(address) {
+                self.set(address, value);
+            }
+            address = address.wrapping_add(1);
+        });
+        if stored == 0 {
+            return;
         }
+        let [low, high] = address.to_le_bytes();
+        self.set(pointer, low);
+        self.set(pointer + 1, high);
+    }
+}
+
+pub(crate) fn is_io(addr: u16) -> bool {
+    (0xe800..0xe850).contains(&addr)
+}
+
+impl<'a> Ram<'a> {
+    /// The pixel data (one byte per line, MSB is the leftmost pixel) of a screen code
+    /// in the character set currently selected by the VIA.
+    /// Screen codes >= 0x80 are shown in reverse video.
+    /// All pixels are off while the screen is blanked.
+    pub fn glyph(&self, screen_code: u8) -> [u8; 8] {
+        if self.io.borrow().screen_blanked() {
+            return [0u8; 8];
+        }
+
+        let charset = if self.io.borrow().lowercase_charset() {
+            1024
+        } else {
+            0
+        };
+        let invert = if screen_code & 0x80 != 0 { 0xff } else { 0x00 };
+        let start = charset + (screen_code & 0x7f) as usize * 8;
+
+        let mut glyph = [0u8; 8];
+        for (i, line) in glyph.iter_mut().enumerate() {
+            *line = CHAR_ROM[start + i] ^ invert;
+        }
+        glyph
     }
 }
 
@@ -52,7 +144,8 @@ impl<'a> Memory for Ram<'a> {
         if addr >= 0xd000 && addr < 0xe000 {
             return ROM_D000[(addr - 0xd000) as usize];
         }
-        if addr >= 0xe000 && addr < 0xf000 {
+        // the editor ROM is 2k, 0xe850-0xefff is unconnected
+        if addr >= 0xe000 && addr < 0xe800 {
             return ROM_E000[(addr - 0xe000) as usize];
         }
         if addr >= 0xf000 {
@@ -67,8 +160,9 @@ impl<'a> Memory for Ram<'a> {
     }
 
     fn set(&self, addr: u16, v: u8) {
-        if addr >= 0xe800 && addr < 0xe850 {
+        if is_io(addr) {
             self.io.borrow_mut().write(addr - 0xe800, v);
+            self.warp_load();
             return;
         }
 
@@ -90,7 +184,9 @@ mod tests {
     extern crate std;
     use std::println;
     use std::string::String;
+    use std::vec::Vec;
     use mos6502::Cpu;
+    use tape::Tape;
 
     use super::*;
 
@@ -173,13 +269,68 @@ mod tests {
         res
     }
 
+    // runs the machine for the given number of cycles while typing `enter_str`
+    fn run(mem: &Ram, cpu: &mut Cpu, enter_str: &str, cycles: u32) {
+        let mut str_idx = 0;
+        let mut keyboard_cntr = 50000;
+        let mut cycle_cnt: u64 = 0;
+        let mut tick_cntr = 0u32;
+
+        for _ in 0..cycles {
+            if cycle_cnt == 0 {
+                cycle_cnt = cpu.step();
+                if mem.io.borrow_mut().step(cycle_cnt as u16) {
+                    cpu.trigger_irq();
+                }
+            }
+            cycle_cnt -= 1;
+
+            tick_cntr += 1;
+            if tick_cntr >= 1000 {
+                tick_cntr = 0;
+                if mem.io.borrow_mut().tick() {
+                    cpu.trigger_irq();
+                }
+            }
+
+            if str_idx < enter_str.len() && (keyboard_cntr == 30000 || keyboard_cntr == 0) {
+                let c = enter_str.as_bytes()[str_idx];
+                for i in (0..ASCII2PET.len()).step_by(2) {
+                    if c == ASCII2PET[i] {
+                        if keyboard_cntr == 0 {
+                            mem.io.borrow_mut().keyboard.key_up(ASCII2PET[i + 1]);
+                        } else {
+                            mem.io.borrow_mut().keyboard.key_down(ASCII2PET[i + 1]);
+                        }
+                    }
+                }
+                if keyboard_cntr == 0 {
+                    str_idx += 1;
+                }
+            }
+            if keyboard_cntr == 0 {
+                keyboard_cntr = 50000;
+            }
+            keyboard_cntr -= 1;
+        }
+    }
+
     struct TestStorage {
         filename: [u8; 16],
         load_data_length: usize,
         load_data: [u8; 13],
         save_data: [u8; 256],
+        save_data_length: usize,
 
         filename_index: usize,
+
+        // saved programs by name and the one being loaded
+        programs: Vec<(Vec<u8>, Vec<u8>)>,
+        loading: Option<usize>,
+
+        // sequential files by name and the file and position open on each channel
+        seq_files: Vec<(Vec<u8>, Vec<u8>)>,
+        channels: [Option<(usize, usize)>; 16],
     }
 
     impl TestStorage {
@@ -191,8 +342,15 @@ mod tests {
                     0x01, 0x04, 0x0a, 0x04, 0x64, 0x00, 0x8f, 0x20, 0x48, 0x49, 0x00, 0x00, 0x00,
                 ],
                 save_data: [0u8; 256],
+                save_data_length: 0,
 
                 filename_index: 0usize,
+
+                programs: Vec::new(),
+                loading: None,
+
+                seq_files: Vec::new(),
+                channels: [None; 16],
             }
         }
     }
@@ -205,40 +363,198 @@ mod tests {
 
         fn next_filename_byte(&mut self, value: u8) {
             println!("next filename byte {} = char {}", value, value as char);
-            self.filename[self.filename_index] = value;
+            if self.filename_index < self.filename.len() {
+                self.filename[self.filename_index] = value;
+                self.filename_index += 1;
+            }
         }
 
-        fn start_save(&mut self) {
+        fn start_save(&mut self) -> Result<(), dos::DosError> {
             println!("save start");
+            self.save_data_length = 0;
+            Ok(())
         }
 
-        fn has_data_to_load(&mut self) -> bool {
-            println!("has_data_to_load");
-            self.load_data_length > 0
-        }
-
-        fn load_data_byte(&mut self, index: usize) -> u8 {
+        fn load_data_byte(&mut self, index: usize) -> Result<u8, dos::DosError> {
             println!("load data {}", index);
-            self.load_data[index]
+            match self.loading {
+                Some(program) => Ok(self.programs[program].1[index]),
+                None => Ok(self.load_data[index]),
+            }
         }
 
-        fn save_data_byte(&mut self, index: usize, value: u8) {
+        fn save_data_byte(&mut self, index: usize, value: u8) -> Result<(), dos::DosError> {
             println!("save byte {} {}", index, value);
             self.save_data[index] = value;
+            self.save_data_length = index + 1;
+            Ok(())
         }
 
-        fn load_data_len(&mut self) -> usize {
+        fn load_data_len(&mut self) -> Result<usize, dos::DosError> {
             println!("load data len");
-            self.load_data.len()
+            let name = &self.filename[..self.filename_index];
+            if name == b"MISSING" {
+                return Err(dos::DosError::FileNotFound);
+            }
+            self.loading = self.programs.iter().position(|(n, _)| n == name);
+            match self.loading {
+                Some(program) => Ok(self.programs[program].1.len()),
+                None => Ok(self.load_data_length),
+            }
         }
 
-        fn end_save(&mut self) {
+        fn end_save(&mut self) -> Result<(), dos::DosError> {
             println!("end save");
+            let name = self.filename[..self.filename_index].to_vec();
+            let data = self.save_data[..self.save_data_length].to_vec();
+            self.programs.retain(|(n, _)| *n != name);
+            self.programs.push((name, data));
+            Ok(())
         }
 
         fn fname_done(&mut self) {
             println!("filename done");
         }
+
+        fn open(&mut self, channel: u8, name: &io::OpenName) -> Result<(), dos::DosError> {
+            println!("open {} {:?}", channel, name);
+            let existing = self.seq_files.iter().position(|(n, _)| n == name.name);
+            let file = match (existing, name.mode) {
+                (Some(file), io::FileMode::Read) | (Some(file), io::FileMode::Append) => file,
+                (Some(file), io::FileMode::Write) => {
+                    self.seq_files[file].1.clear();
+                    file
+                }
+                (None, io::FileMode::Read) => return Err(dos::DosError::FileNotFound),
+                (None, _) => {
+                    self.seq_files.push((name.name.to_vec(), Vec::new()));
+                    self.seq_files.len() - 1
+                }
+            };
+            let pos = match name.mode {
+                io::FileMode::Read => 0,
+                _ => self.seq_files[file].1.len(),
+            };
+            self.channels[channel as usize] = Some((file, pos));
+            Ok(())
+        }
+
+        fn read(&mut self, channel: u8) -> Option<(u8, bool)> {
+            let (file, pos) = self.channels[channel as usize].as_mut()?;
+            let data = &self.seq_files[*file].1;
+            let value = *data.get(*pos)?;
+            *pos += 1;
+            Some((value, *pos == data.len()))
+        }
+
+        fn write(&mut self, channel: u8, value: u8) -> Result<(), dos::DosError> {
+            let (file, pos) = self.channels[channel as usize]
+                .as_mut()
+                .ok_or(dos::DosError::FileNotOpen)?;
+            self.seq_files[*file].1.push(value);
+            *pos += 1;
+            Ok(())
+        }
+
+        fn close(&mut self, channel: u8) -> Result<(), dos::DosError> {
+            println!("close {}", channel);
+            self.channels[channel as usize] = None;
+            Ok(())
+        }
+
+        fn file_system(&mut self) -> Option<&mut dyn dos::FileSystem> {
+            Some(self)
+        }
+    }
+
+    impl dos::FileSystem for TestStorage {
+        fn scratch(&mut self, name: &[u8]) -> Result<u8, dos::DosError> {
+            println!("scratch {:?}", name);
+            let before = self.seq_files.len();
+            self.seq_files.retain(|(n, _)| n != name);
+            Ok((before - self.seq_files.len()) as u8)
+        }
+
+        fn rename(&mut self, old: &[u8], new: &[u8]) -> Result<(), dos::DosError> {
+            if self.seq_files.iter().any(|(n, _)| n == new) {
+                return Err(dos::DosError::FileExists);
+            }
+            let file = self
+                .seq_files
+                .iter_mut()
+                .find(|(n, _)| n == old)
+                .ok_or(dos::DosError::FileNotFound)?;
+            file.0 = new.to_vec();
+            Ok(())
+        }
+
+        fn copy(&mut self, source: &[u8], destination: &[u8]) -> Result<(), dos::DosError> {
+            if self.seq_files.iter().any(|(n, _)| n == destination) {
+                return Err(dos::DosError::FileExists);
+            }
+            let data = self
+                .seq_files
+                .iter()
+                .find(|(n, _)| n == source)
+                .ok_or(dos::DosError::FileNotFound)?
+                .1
+                .clone();
+            self.seq_files.push((destination.to_vec(), data));
+            Ok(())
+        }
+
+        // files can't be locked here
+        fn lock(&mut self, name: &[u8], _locked: Option<bool>) -> Result<u8, dos::DosError> {
+            Ok(self.seq_files.iter().filter(|(n, _)| n == name).count() as u8)
+        }
+
+        fn initialize(&mut self) -> Result<(), dos::DosError> {
+            Ok(())
+        }
+
+        fn validate(&mut self) -> Result<(), dos::DosError> {
+            Ok(())
+        }
+
+        fn format(&mut self, _name: &[u8], _id: Option<&[u8]>) -> Result<(), dos::DosError> {
+            self.seq_files.clear();
+            Ok(())
+        }
+    }
+
+    struct TestPrinter {
+        printed: std::vec::Vec<u8>,
+        listening: bool,
+    }
+
+    impl io::IeeeDevice for TestPrinter {
+        fn address(&self) -> u8 {
+            4
+        }
+
+        fn listen(&mut self, _secondary: u8) {
+            self.listening = true;
+        }
+
+        fn receive(&mut self, value: u8) {
+            if self.listening {
+                self.printed.push(value);
+            }
+        }
+
+        fn unlisten(&mut self) {
+            self.listening = false;
+        }
+
+        fn talk(&mut self, _secondary: u8) {}
+
+        fn peek(&mut self) -> Option<(u8, bool)> {
+            None
+        }
+
+        fn advance(&mut self) {}
+
+        fn untalk(&mut self) {}
     }
 
     #[test]
@@ -262,6 +578,9 @@ mod tests {
             if cycle_cnt == 0 {
                 cnt += 1;
                 cycle_cnt = cpu.step();
+                if mem.io.borrow_mut().step(cycle_cnt as u16) {
+                    cpu.trigger_irq();
+                }
                 cycle_cnt -= 1;
             } else {
                 cycle_cnt -= 1;
@@ -318,6 +637,94 @@ mod tests {
         assert!(screen_content.contains("HELLO WORLD 10"));
     }
 
+    #[test]
+    fn jiffy_clock_runs_at_frame_rate() {
+        let mut test_storage = TestStorage::new();
+
+        let mut ram = [0u8; 8192];
+        let mut vid_ram = [0u8; 2048];
+        let mem = Ram::new(&mut ram, &mut vid_ram, &mut test_storage);
+        let mut cpu = Cpu::new(&mem);
+        cpu.reset();
+
+        fn jiffies(mem: &Ram) -> u32 {
+            ((mem.get(0x8d) as u32) << 16) + ((mem.get(0x8e) as u32) << 8) + mem.get(0x8f) as u32
+        }
+
+        let mut cycle_cnt: u64 = 0;
+        let mut tick_cntr = 0u32;
+        let mut start = 0;
+        // boot for one second, then count the interrupts of the next second
+        for i in 0..2000000 {
+            if i == 1000000 {
+                start = jiffies(&mem);
+            }
+
+            if cycle_cnt == 0 {
+                cycle_cnt = cpu.step();
+                if mem.io.borrow_mut().step(cycle_cnt as u16) {
+                    cpu.trigger_irq();
+                }
+            }
+            cycle_cnt -= 1;
+
+            tick_cntr += 1;
+            if tick_cntr >= 1000 {
+                tick_cntr = 0;
+                let trigger_irq = mem.io.borrow_mut().tick();
+                if trigger_irq {
+                    cpu.trigger_irq();
+                }
+            }
+        }
+
+        let elapsed = jiffies(&mem) - start;
+        println!("{} jiffies", elapsed);
+        assert!((59..=61).contains(&elapsed));
+    }
+
+    #[test]
+    fn charset_follows_via_ca2() {
+        let mut test_storage = TestStorage::new();
+        let mut ram = [0u8; 8192];
+        let mut vid_ram = [0u8; 2048];
+        let mem = Ram::new(&mut ram, &mut vid_ram, &mut test_storage);
+
+        // POKE 59468,14
+        mem.set(0xe84c, 14);
+        assert!(mem.io.borrow().lowercase_charset());
+        assert_eq!(mem.glyph(0x01), [0x00, 0x00, 0x38, 0x04, 0x3c, 0x44, 0x3a, 0x00]);
+
+        // POKE 59468,12
+        mem.set(0xe84c, 12);
+        assert!(!mem.io.borrow().lowercase_charset());
+        assert_eq!(mem.glyph(0x01), [0x18, 0x24, 0x42, 0x7e, 0x42, 0x42, 0x42, 0x00]);
+        assert_eq!(mem.glyph(0x81), [0xe7, 0xdb, 0xbd, 0x81, 0xbd, 0xbd, 0xbd, 0xff]);
+    }
+
+    #[test]
+    fn screen_blank_follows_pia1_ca2() {
+        let mut test_storage = TestStorage::new();
+        let mut ram = [0u8; 8192];
+        let mut vid_ram = [0u8; 2048];
+        let mem = Ram::new(&mut ram, &mut vid_ram, &mut test_storage);
+        assert!(!mem.io.borrow().screen_blanked());
+
+        // CA2 high
+        mem.set(0xe811, 0x3c);
+        assert!(!mem.io.borrow().screen_blanked());
+        assert_ne!(mem.glyph(0x01), [0u8; 8]);
+
+        // CA2 low
+        mem.set(0xe811, 0x34);
+        assert!(mem.io.borrow().screen_blanked());
+        assert_eq!(mem.glyph(0x01), [0u8; 8]);
+        assert_eq!(mem.glyph(0xa0), [0u8; 8]);
+
+        mem.set(0xe811, 0x3c);
+        assert!(!mem.io.borrow().screen_blanked());
+    }
+
     #[test]
     fn load_from_disk_works() {
         let mut test_storage = TestStorage::new();
@@ -360,6 +767,9 @@ mod tests {
             if cycle_cnt == 0 {
                 cnt += 1;
                 cycle_cnt = cpu.step();
+                if mem.io.borrow_mut().step(cycle_cnt as u16) {
+                    cpu.trigger_irq();
+                }
                 cycle_cnt -= 1;
             } else {
                 cycle_cnt -= 1;
@@ -448,6 +858,9 @@ mod tests {
             if cycle_cnt == 0 {
                 cnt += 1;
                 cycle_cnt = cpu.step();
+                if mem.io.borrow_mut().step(cycle_cnt as u16) {
+                    cpu.trigger_irq();
+                }
                 cycle_cnt -= 1;
             } else {
                 cycle_cnt -= 1;
@@ -520,5 +933,1686 @@ mod tests {
         assert!(test_storage.save_data[16]==0x52);
         assert!(test_storage.save_data[17]==0x4c);
         assert!(test_storage.save_data[18]==0x44);
+
+        let mut program = Vec::new();
+        let version = prg::BasicVersion::Basic2;
+        basic::tokenize(b"10 rem hello world", version, |b| program.push(b)).unwrap();
+        assert_eq!(test_storage.save_data[..test_storage.save_data_length], program[..]);
+    }
+    #[test]
+    fn load_from_tape_works() {
+        let mut test_storage = TestStorage::new();
+        let prg = [
+            0x01, 0x04, 0x0d, 0x04, 0x0a, 0x00, 0x8f, 0x20, 0x48, 0x45, 0x4c, 0x4c, 0x4f, 0x00,
+            0x00, 0x00,
+        ];
+        let mut tape = tape::PrgTape::new(b"HELLO", &prg);
+
+        let mut ram = [0u8; 8192];
+        let mut vid_ram = [0u8; 2048];
+        let mem = Ram::new(&mut ram, &mut vid_ram, &mut test_storage);
+        mem.io.borrow_mut().datasette.insert(&mut tape);
+        mem.io.borrow_mut().datasette.play();
+        let mut cpu = Cpu::new(&mem);
+        cpu.reset();
+
+        run(&mem, &mut cpu, "load\r", 8_000_000);
+        run(&mem, &mut cpu, "list\r", 1_000_000);
+
+        let screen_content = screen_as_string(&mem);
+        println!("{}\n", screen_content);
+
+        assert!(screen_content.contains("FOUND HELLO"));
+        assert!(screen_content.contains("10 REM HELLO"));
+    }
+
+    #[test]
+    fn tape_pulses_keep_their_length() {
+        struct Square;
+        impl tape::Tape for Square {
+            fn next_pulse(&mut self) -> Option<u32> {
+                Some(100)
+            }
+            fn record_pulse(&mut self, _cycles: u32) {}
+            fn rewind(&mut self) {}
+        }
+
+        let mut square = Square;
+        let mut datasette = tape::Datasette::new();
+        datasette.insert(&mut square);
+        datasette.play();
+        datasette.set_motor(true);
+
+        // steps don't end on the edges, 7 cycles are one instruction or so
+        let edges = (0..10_000 / 7)
+            .filter(|_| datasette.step(7).is_some())
+            .count();
+        assert!((199..=200).contains(&edges), "{} edges", edges);
+    }
+
+    #[test]
+    fn tape_save_and_load_roundtrip() {
+        let mut tap_data = std::vec![0u8; 1_000_000];
+        let mut tap = tape::TapImage::blank(&mut tap_data);
+
+        {
+            let mut test_storage = TestStorage::new();
+            let mut ram = [0u8; 8192];
+            let mut vid_ram = [0u8; 2048];
+            let mem = Ram::new(&mut ram, &mut vid_ram, &mut test_storage);
+            mem.io.borrow_mut().datasette.insert(&mut tap);
+            mem.io.borrow_mut().datasette.record();
+            let mut cpu = Cpu::new(&mem);
+            cpu.reset();
+            run(&mem, &mut cpu, "10 rem hello tape\rsave\"ab\"\r", 20_000_000);
+            assert!(screen_as_string(&mem).contains("WRITING AB"));
+        }
+
+        assert!(!tap.is_empty());
+        tap.rewind();
+
+        let mut test_storage = TestStorage::new();
+        let mut ram = [0u8; 8192];
+        let mut vid_ram = [0u8; 2048];
+        let mem = Ram::new(&mut ram, &mut vid_ram, &mut test_storage);
+        mem.io.borrow_mut().datasette.insert(&mut tap);
+        mem.io.borrow_mut().datasette.play();
+        let mut cpu = Cpu::new(&mem);
+        cpu.reset();
+        run(&mem, &mut cpu, "load\"ab\"\r", 25_000_000);
+        run(&mem, &mut cpu, "list\r", 1_000_000);
+
+        let screen_content = screen_as_string(&mem);
+        println!("{}\n", screen_content);
+
+        assert!(screen_content.contains("FOUND AB"));
+        assert!(screen_content.contains("10 REM HELLO TAPE"));
+    }
+
+    #[test]
+    fn devices_on_the_bus() {
+        let mut drive8 = TestStorage::new();
+        drive8.load_data_length = 0;
+        let mut drive9_storage = TestStorage::new();
+        let mut drive9 = io::DiskDrive::new(9, &mut drive9_storage);
+        let mut printer = TestPrinter {
+            printed: std::vec::Vec::new(),
+            listening: false,
+        };
+
+        let mut ram = [0u8; 8192];
+        let mut vid_ram = [0u8; 2048];
+        let screen_content = {
+            let mem = Ram::new(&mut ram, &mut vid_ram, &mut drive8);
+            mem.io.borrow_mut().ieee.attach(&mut drive9).unwrap();
+            mem.io.borrow_mut().ieee.attach(&mut printer).unwrap();
+            let mut cpu = Cpu::new(&mem);
+            cpu.reset();
+
+            run(&mem, &mut cpu, "load\"test\",9\r", 3_000_000);
+            run(&mem, &mut cpu, "list\r", 1_000_000);
+            run(&mem, &mut cpu, "open 4,4:print#4,\"hi\":close 4\r", 3_000_000);
+            run(&mem, &mut cpu, "load\"test\",5\r", 2_000_000);
+
+            screen_as_string(&mem)
+        };
+        println!("{}\n", screen_content);
+
+        assert!(screen_content.contains("100 REM HI"));
+        assert!(screen_content.contains("?DEVICE NOT PRESENT"));
+        assert_eq!(drive8.filename[0], 0);
+        assert_eq!(drive9_storage.filename[0], b'T');
+        assert_eq!(printer.printed, b"HI\r\n");
+    }
+
+    #[test]
+    fn sequential_files_work() {
+        let mut test_storage = TestStorage::new();
+
+        let mut ram = [0u8; 8192];
+        let mut vid_ram = [0u8; 2048];
+        let screen_content = {
+            let mem = Ram::new(&mut ram, &mut vid_ram, &mut test_storage);
+            let mut cpu = Cpu::new(&mem);
+            cpu.reset();
+
+            run(
+                &mem,
+                &mut cpu,
+                "open 2,8,2,\"data,s,w\":print#2,\"hello,42\":close 2\r",
+                5_000_000,
+            );
+            run(
+                &mem,
+                &mut cpu,
+                "10 open 3,8,3,\"data,s,r\":input#3,a$,a:get#3,b$:close 3\rrun\r",
+                6_000_000,
+            );
+            run(&mem, &mut cpu, "print \"x\"a$;a;len(b$)\r", 2_000_000);
+
+            screen_as_string(&mem)
+        };
+        println!("{}\n", screen_content);
+
+        assert!(screen_content.contains("XHELLO 42  1"));
+        assert_eq!(test_storage.seq_files[0].0, b"DATA");
+        // BASIC 2 ends each line with CR and LF
+        assert_eq!(test_storage.seq_files[0].1, b"HELLO,42\r\n");
+        assert!(test_storage.channels.iter().all(|channel| channel.is_none()));
+    }
+
+    #[test]
+    fn command_channel_works() {
+        let mut test_storage = TestStorage::new();
+
+        let mut ram = [0u8; 8192];
+        let mut vid_ram = [0u8; 2048];
+        let screen_content = {
+            let mem = Ram::new(&mut ram, &mut vid_ram, &mut test_storage);
+            let mut cpu = Cpu::new(&mem);
+            cpu.reset();
+
+            run(
+                &mem,
+                &mut cpu,
+                "open 2,8,2,\"data,s,w\":print#2,\"x\":close 2\r",
+                5_000_000,
+            );
+            run(
+                &mem,
+                &mut cpu,
+                "10 open 15,8,15,\"c:copy=data\":gosub 90\r\
+                 20 print#15,\"r0:new=data\":gosub 90\r\
+                 30 print#15,\"s:new,none\":gosub 90\r\
+                 40 print#15,\"x\":gosub 90\r\
+                 50 open 2,8,2,\"none,s,r\":gosub 90:close 2:close 15:end\r\
+                 90 input#15,e,e$,t,s:print e;e$;t;s:return\r\
+                 run\r",
+                20_000_000,
+            );
+
+            screen_as_string(&mem)
+        };
+        println!("{}\n", screen_content);
+
+        assert!(screen_content.contains(" 0 OK 0  0"));
+        assert!(screen_content.contains(" 1 FILES SCRATCHED 1  0"));
+        assert!(screen_content.contains(" 31 SYNTAX ERROR 0  0"));
+        assert!(screen_content.contains(" 62 FILE NOT FOUND 0  0"));
+        assert_eq!(test_storage.seq_files.len(), 1);
+        assert_eq!(test_storage.seq_files[0].0, b"COPY");
+    }
+
+    #[test]
+    fn loading_a_missing_file_fails() {
+        let mut test_storage = TestStorage::new();
+
+        let mut ram = [0u8; 8192];
+        let mut vid_ram = [0u8; 2048];
+        let screen_content = {
+            let mem = Ram::new(&mut ram, &mut vid_ram, &mut test_storage);
+            let mut cpu = Cpu::new(&mem);
+            cpu.reset();
+
+            // the drive doesn't talk, BASIC 2 keeps waiting for data until STOP is pressed
+            run(&mem, &mut cpu, "load\"missing\",8\r", 3_000_000);
+            assert_eq!(mem.get(0x96) & 0x40, 0);
+            mem.io.borrow_mut().keyboard.key_down(0x94);
+            run(&mem, &mut cpu, "", 100_000);
+            mem.io.borrow_mut().keyboard.key_up(0x94);
+
+            run(
+                &mem,
+                &mut cpu,
+                "10 open 15,8,15:input#15,e,e$:print e;e$:close 15\rrun\r",
+                4_000_000,
+            );
+
+            screen_as_string(&mem)
+        };
+        println!("{}\n", screen_content);
+
+        assert!(screen_content.contains("LOADING                                 \nBREAK"));
+        assert!(screen_content.contains(" 62 FILE NOT FOUND"));
+    }
+
+    #[test]
+    fn machine_code_loads_to_its_address() {
+        // LDA #$01 STA $8000 RTS - puts an "A" in the top left corner
+        let code = [0xa9, 0x01, 0x8d, 0x00, 0x80, 0x60];
+        let mut test_storage = TestStorage::new();
+
+        let mut ram = [0u8; 8192];
+        let mut vid_ram = [0u8; 2048];
+        let screen_content = {
+            let mem = Ram::new(&mut ram, &mut vid_ram, &mut test_storage);
+            let mut cpu = Cpu::new(&mem);
+            cpu.reset();
+            run(&mem, &mut cpu, "", 1_000_000);
+
+            // let SAVE write 0x1c00-0x1c06 instead of the BASIC program
+            for (i, b) in code.iter().enumerate() {
+                mem.set(0x1c00 + i as u16, *b);
+            }
+            let pointers = [mem.get(0x28), mem.get(0x29), mem.get(0x2a), mem.get(0x2b)];
+            mem.set(0x28, 0x00);
+            mem.set(0x29, 0x1c);
+            mem.set(0x2a, 0x06);
+            mem.set(0x2b, 0x1c);
+            run(&mem, &mut cpu, "save\"mc\",8\r", 3_000_000);
+            for (i, p) in pointers.iter().enumerate() {
+                mem.set(0x28 + i as u16, *p);
+            }
+            for i in 0..code.len() {
+                mem.set(0x1c00 + i as u16, 0);
+            }
+
+            run(&mem, &mut cpu, "load\"mc\",8,1\r", 3_000_000);
+            assert_eq!(mem.get(0x8000), b'#');
+            run(&mem, &mut cpu, "sys 7168\r", 1_000_000);
+
+            for (i, b) in code.iter().enumerate() {
+                assert_eq!(mem.get(0x1c00 + i as u16), *b);
+            }
+            screen_as_string(&mem)
+        };
+        println!("{}\n", screen_content);
+
+        assert_eq!(test_storage.programs[0].0, b"MC");
+        assert_eq!(test_storage.programs[0].1[..2], [0x00, 0x1c]);
+        assert_eq!(test_storage.programs[0].1[2..], code);
+        assert!(screen_content.starts_with('A'));
+    }
+
+    #[test]
+    fn autoboot_runs_the_boot_program() {
+        // 10 PRINT"BOOTED"
+        let program = [
+            0x01, 0x04, 0x10, 0x04, 0x0a, 0x00, 0x99, 0x20, 0x22, 0x42, 0x4f, 0x4f, 0x54, 0x45,
+            0x44, 0x22, 0x00, 0x00, 0x00,
+        ];
+        let mut test_storage = TestStorage::new();
+        test_storage
+            .programs
+            .push((boot::BOOT_NAME.to_vec(), program.to_vec()));
+        assert!(boot::Autoboot::find(&mut test_storage, b"MISSING").is_none());
+
+        let boot_with_key = |key: Option<u8>| {
+            let mut test_storage = TestStorage::new();
+            test_storage
+                .programs
+                .push((boot::BOOT_NAME.to_vec(), program.to_vec()));
+            let mut autoboot = boot::Autoboot::find(&mut test_storage, boot::BOOT_NAME).unwrap();
+
+            let mut ram = [0u8; 8192];
+            let mut vid_ram = [0u8; 2048];
+            let mem = Ram::new(&mut ram, &mut vid_ram, &mut test_storage);
+            let mut cpu = Cpu::new(&mem);
+            cpu.reset();
+            if let Some(key) = key {
+                mem.io.borrow_mut().keyboard.key_down(key);
+            }
+
+            let mut booting = true;
+            for _ in 0..200 {
+                run(&mem, &mut cpu, "", 20_000);
+                if booting && autoboot.step(&mem) {
+                    booting = false;
+                    if let Some(key) = key {
+                        mem.io.borrow_mut().keyboard.key_up(key);
+                    }
+                }
+            }
+            screen_as_string(&mem)
+        };
+
+        let screen_content = boot_with_key(None);
+        println!("{}\n", screen_content);
+        assert!(screen_content.contains("LOAD\"BOOT\",8"));
+        assert!(screen_content.contains("\nBOOTED"));
+
+        // a key held down skips it, shift doesn't type anything
+        let screen_content = boot_with_key(Some(0x80));
+        println!("{}\n", screen_content);
+        assert!(!screen_content.contains("LOAD"));
+    }
+
+    #[test]
+    fn prg_runs_without_loading() {
+        // 10 PRINT"HI":20 PRINT"HO" with broken links
+        let program = [
+            0x01, 0x04, 0xff, 0xff, 0x0a, 0x00, 0x99, 0x22, 0x48, 0x49, 0x22, 0x00, 0x01, 0x01,
+            0x14, 0x00, 0x99, 0x22, 0x48, 0x4f, 0x22, 0x00, 0x00, 0x00,
+        ];
+        let mut test_storage = TestStorage::new();
+
+        let mut ram = [0u8; 8192];
+        let mut vid_ram = [0u8; 2048];
+        let screen_content = {
+            let mem = Ram::new(&mut ram, &mut vid_ram, &mut test_storage);
+            let mut cpu = Cpu::new(&mem);
+            cpu.reset();
+            assert_eq!(mem.basic_version(), prg::BasicVersion::Basic2);
+            run(&mem, &mut cpu, "", 1_000_000);
+
+            assert_eq!(mem.load_prg(&program), Ok(0x0401..0x0417));
+            assert_eq!([mem.get(0x0401), mem.get(0x0402)], [0x0b, 0x04]);
+            assert_eq!([mem.get(0x040b), mem.get(0x040c)], [0x15, 0x04]);
+            for pointer in &[0x2a, 0x2c, 0x2e] {
+                assert_eq!([mem.get(*pointer), mem.get(pointer + 1)], [0x17, 0x04]);
+            }
+            run(&mem, &mut cpu, "run\r", 1_000_000);
+
+            // machine code stays as it is
+            assert_eq!(mem.load_prg(&[0x00, 0x80, 0x08, 0x09]), Ok(0x8000..0x8002));
+            assert_eq!(mem.load_prg(&[0x00]), Err(PrgError::TooShort));
+            assert_eq!(mem.load_prg(&[0xff, 0xff, 1, 2]), Err(PrgError::TooLong));
+            assert_eq!(mem.load_prg(&[0xfe, 0xff, 1, 2]), Err(PrgError::TooLong));
+
+            // the I/O registers are left alone, 14 would switch to lowercase
+            assert_eq!(mem.load_prg(&[0x4c, 0xe8, 14]), Ok(0xe84c..0xe84d));
+            assert!(!mem.io.borrow().lowercase_charset());
+            screen_as_string(&mem)
+        };
+        println!("{}\n", screen_content);
+
+        let lines: Vec<&str> = screen_content.lines().map(str::trim_end).collect();
+        assert!(lines[0].starts_with("HI"));
+        assert!(lines.windows(3).any(|lines| lines == ["RUN", "HI", "HO"]));
+    }
+
+    #[test]
+    fn basic_text_round_trip() {
+        let version = prg::BasicVersion::Basic2;
+        let tokenize = |text: &[u8]| {
+            let mut program = Vec::new();
+            basic::tokenize(text, version, |b| program.push(b)).map(|()| program)
+        };
+        let detokenize = |program: &[u8]| {
+            let mut text = Vec::new();
+            basic::detokenize(program, version, |b| text.push(b)).map(|()| text)
+        };
+
+        // crunched like the ROMs do it
+        let line = "10 for i=1to9:?i;:next:go 20\r20 data to,\"a:b\":rem print\r";
+        let mut test_storage = TestStorage::new();
+        let mut ram = [0u8; 8192];
+        let mut vid_ram = [0u8; 2048];
+        {
+            let mem = Ram::new(&mut ram, &mut vid_ram, &mut test_storage);
+            let mut cpu = Cpu::new(&mem);
+            cpu.reset();
+            run(&mem, &mut cpu, line, 3_000_000);
+            run(&mem, &mut cpu, "save\"t\",8\r", 3_000_000);
+        }
+        let text = b"10 for i=1to9:?i;:next:go 20\n20   data to,\"a:b\":rem print\n";
+        let program = tokenize(text).unwrap();
+        assert_eq!(test_storage.programs[0].1, program);
+        assert_eq!(
+            detokenize(&program).unwrap(),
+            b"10 for i=1to9:printi;:next:go 20\n20 data to,\"a:b\":rem print\n".to_vec()
+        );
+
+        // PETSCII in the text, BASIC 4 has more keywords
+        let text = b"1 print\"{clr}{RVS ON}Hi{$a0}\":dload\"x\"\r\n\n2 end\n";
+        let program = tokenize(text).unwrap();
+        assert_eq!(
+            program[6..16],
+            [0x99, 0x22, 0x93, 0x12, 0xc8, 0x49, 0xa0, 0x22, 0x3a, 0x44]
+        );
+        let mut basic4 = Vec::new();
+        basic::tokenize(text, prg::BasicVersion::Basic4, |b| basic4.push(b)).unwrap();
+        assert_eq!(basic4[15], 0xd6);
+        assert_eq!(
+            detokenize(&program).unwrap(),
+            b"1 print\"{clr}{rvs on}Hi{$a0}\":dload\"x\"\n2 end\n".to_vec()
+        );
+
+        // a shifted letter ends an abbreviation
+        assert_eq!(tokenize(b"1 pO1,2").unwrap()[6], 0x97);
+        assert_eq!(tokenize(b"10 a\n5 b"), Err(basic::BasicError::LineOrder(2)));
+        assert_eq!(tokenize(b"print"), Err(basic::BasicError::LineNumber(1)));
+        assert_eq!(tokenize(b"64000 end"), Err(basic::BasicError::LineNumber(1)));
+        assert_eq!(tokenize(b"1 ?\"{bell}\""), Err(basic::BasicError::Escape(1)));
+        let long = [&b"1 rem "[..], &[b'x'; 250]].concat();
+        assert_eq!(tokenize(&long), Err(basic::BasicError::LineTooLong(1)));
+        assert_eq!(detokenize(&program[..10]), Err(basic::BasicError::Truncated));
+    }
+
+    #[test]
+    fn screen_as_text() {
+        let mut test_storage = TestStorage::new();
+        let mut ram = [0u8; 8192];
+        let mut vid_ram = [0u8; 2048];
+        let mem = Ram::new(&mut ram, &mut vid_ram, &mut test_storage);
+        let mut cpu = Cpu::new(&mem);
+        cpu.reset();
+        run(&mem, &mut cpu, "?chr$(193)\r", 3_000_000);
+
+        let line = |row: usize| -> String {
+            (0..screen::COLUMNS)
+                .map(|column| {
+                    let code = mem.get(screen::SCREEN + (row * screen::COLUMNS + column) as u16);
+                    screen::to_char(code, false)
+                })
+                .collect()
+        };
+        assert_eq!(line(0).trim_end(), "### COMMODORE BASIC ###");
+        assert_eq!(line(5).trim_end(), "?CHR$(193)");
+        assert_eq!(line(6).trim_end(), "♠");
+
+        assert_eq!(screen::to_char(0x13, true), 's');
+        assert_eq!(screen::to_char(0x53, true), 'S');
+        assert_eq!(screen::to_char(0x53, false), '♥');
+        assert_eq!(screen::to_char(0x81, false), 'A');
+        assert_eq!(screen::to_char(0xa0, false), '█');
+    }
+
+    #[test]
+    fn warp_load_works() {
+        // 10 PRINT"BOOTED" and some more bytes behind it
+        let mut program = std::vec![
+            0x01, 0x04, 0x10, 0x04, 0x0a, 0x00, 0x99, 0x20, 0x22, 0x42, 0x4f, 0x4f, 0x54, 0x45,
+            0x44, 0x22, 0x00, 0x00, 0x00,
+        ];
+        program.extend((0..5000).map(|i| i as u8));
+
+        // the cycles from typing LOAD to READY. and the memory afterwards
+        let load = |warp: bool| {
+            let mut test_storage = TestStorage::new();
+            test_storage.programs.push((b"PROG".to_vec(), program.clone()));
+
+            let mut ram = [0u8; 8192];
+            let mut vid_ram = [0u8; 2048];
+            let mut cycles = 0;
+            {
+                let mem = Ram::new(&mut ram, &mut vid_ram, &mut test_storage);
+                let mut cpu = Cpu::new(&mem);
+                cpu.reset();
+                mem.io.borrow_mut().ieee.set_warp(warp);
+                run(&mem, &mut cpu, "load\"prog\",8", 1_000_000);
+                run(&mem, &mut cpu, "\r", 50_000);
+                let loaded = |screen: String| {
+                    let lines: Vec<&str> = screen.lines().map(str::trim_end).collect();
+                    lines.windows(2).any(|lines| lines == ["LOADING", "READY."])
+                };
+                while !loaded(screen_as_string(&mem)) {
+                    run(&mem, &mut cpu, "", 10_000);
+                    cycles += 10_000;
+                }
+                run(&mem, &mut cpu, "run\r", 1_000_000);
+                println!("{}\n", screen_as_string(&mem));
+            }
+            (cycles, ram)
+        };
+
+        let (slow, loaded) = load(false);
+        let (fast, warped) = load(true);
+        println!("{} {}", slow, fast);
+        let end = 0x0401 + program.len() - 2;
+        assert_eq!(warped[0x0401..end], program[2..]);
+        assert_eq!(warped[0x28..0x30], loaded[0x28..0x30]);
+        assert_eq!(warped[0x2a..0x2c], (end as u16).to_le_bytes());
+        assert!(fast * 5 < slow);
+    }
+
+    // an image file on the host
+    struct FileDevice(std::fs::File);
+
+    impl diskimage::BlockDevice for FileDevice {
+        fn blocks(&self) -> u32 {
+            (self.0.metadata().unwrap().len() / diskimage::BLOCK_SIZE as u64) as u32
+        }
+
+        fn read_block(
+            &mut self,
+            block: u32,
+            data: &mut [u8; diskimage::BLOCK_SIZE],
+        ) -> Result<(), diskimage::BlockError> {
+            use std::io::{Read, Seek, SeekFrom};
+            let offset = block as u64 * diskimage::BLOCK_SIZE as u64;
+            self.0
+                .seek(SeekFrom::Start(offset))
+                .and_then(|_| self.0.read_exact(data))
+                .map_err(|_| diskimage::BlockError)
+        }
+
+        fn write_block(
+            &mut self,
+            block: u32,
+            data: &[u8; diskimage::BLOCK_SIZE],
+        ) -> Result<(), diskimage::BlockError> {
+            use std::io::{Seek, SeekFrom, Write};
+            let offset = block as u64 * diskimage::BLOCK_SIZE as u64;
+            self.0
+                .seek(SeekFrom::Start(offset))
+                .and_then(|_| self.0.write_all(data))
+                .map_err(|_| diskimage::BlockError)
+        }
+    }
+
+    #[test]
+    fn disk_image_save_and_load() {
+        use dos::FileSystem;
+
+        let path = std::env::temp_dir().join(std::format!("pet-{}.d64", std::process::id()));
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.set_len(diskimage::Format::D64.blocks() as u64 * 256).unwrap();
+
+        let mut image = diskimage::DiskImage::new(FileDevice(file)).unwrap();
+        assert_eq!(image.disk_format(), diskimage::Format::D64);
+        image.format(b"TEST DISK", Some(b"01")).unwrap();
+        assert_eq!(image.free_blocks(), Ok(664));
+
+        let mut ram = [0u8; 8192];
+        let mut vid_ram = [0u8; 2048];
+        let screen_content = {
+            let mem = Ram::new(&mut ram, &mut vid_ram, &mut image);
+            let mut cpu = Cpu::new(&mem);
+            cpu.reset();
+
+            run(&mem, &mut cpu, "10 print \"hi\"\rsave\"prog\",8\r", 3_000_000);
+            run(&mem, &mut cpu, "new\rload\"prog\",8\r", 3_000_000);
+            run(&mem, &mut cpu, "list\r", 1_000_000);
+
+            screen_as_string(&mem)
+        };
+        println!("{}\n", screen_content);
+
+        let lines: Vec<&str> = screen_content.lines().map(|line| line.trim()).collect();
+        assert!(lines.windows(3).any(|w| w == ["LIST", "", "10 PRINT \"HI\""]));
+        assert_eq!(image.free_blocks(), Ok(663));
+
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        // the first directory block is 18/1
+        let entry = &data[(17 * 21 + 1) * 256..][..32];
+        assert_eq!(entry[2], 0x82);
+        assert_eq!(&entry[5..21], b"PROG\xa0\xa0\xa0\xa0\xa0\xa0\xa0\xa0\xa0\xa0\xa0\xa0");
+        assert_eq!(entry[30], 1);
+    }
+
+    #[test]
+    fn disk_image_formats() {
+        use dos::FileSystem;
+
+        let formats = [
+            (diskimage::Format::D64, 664),
+            (diskimage::Format::D80, 2052),
+            (diskimage::Format::D82, 4133),
+        ];
+        for (format, free) in formats.iter() {
+            let mut data = std::vec![0u8; format.blocks() as usize * diskimage::BLOCK_SIZE];
+            assert_eq!(diskimage::Format::from_blocks(data.len() as u32 / 256), Some(*format));
+            let mut image = diskimage::DiskImage::new(&mut data[..]).unwrap();
+            assert_eq!(image.disk_format(), *format);
+            image.format(b"DISK", Some(b"AB")).unwrap();
+            assert_eq!(image.free_blocks(), Ok(*free));
+
+            // three blocks of 254 bytes
+            let content: Vec<u8> = (0..600).map(|i| i as u8).collect();
+            image.open(2, &io::OpenName::parse(b"0:DATA,S,W")).unwrap();
+            for b in content.iter() {
+                image.write(2, *b).unwrap();
+            }
+            image.close(2).unwrap();
+            assert_eq!(image.free_blocks(), Ok(free - 3));
+
+            assert_eq!(
+                image.open(2, &io::OpenName::parse(b"DATA,P,R")),
+                Err(dos::DosError::FileTypeMismatch)
+            );
+            image.open(2, &io::OpenName::parse(b"DATA,S,R")).unwrap();
+            let mut read = Vec::new();
+            while let Some((value, last)) = image.read(2) {
+                read.push(value);
+                assert_eq!(last, read.len() == content.len());
+            }
+            image.close(2).unwrap();
+            assert_eq!(read, content);
+
+            assert_eq!(
+                image.open(3, &io::OpenName::parse(b"DATA,S,W")),
+                Err(dos::DosError::FileExists)
+            );
+            assert_eq!(dos::execute(b"C:COPY=DATA", &mut image), dos::Status::OK);
+            assert_eq!(dos::execute(b"R:MOVED=DATA", &mut image), dos::Status::OK);
+            assert_eq!(image.free_blocks(), Ok(free - 6));
+            assert_eq!(dos::execute(b"S:MOVED", &mut image), dos::Status::scratched(1));
+            assert_eq!(image.free_blocks(), Ok(free - 3));
+            assert_eq!(dos::execute(b"V", &mut image), dos::Status::OK);
+            assert_eq!(image.free_blocks(), Ok(free - 3));
+
+            image.open(2, &io::OpenName::parse(b"COPY,S,R")).unwrap();
+            assert_eq!(image.read(2), Some((0, false)));
+
+            // a new file on every directory entry grows the directory
+            for i in 0..20u8 {
+                let name = [b'F', b'A' + i, b',', b'S', b',', b'W'];
+                image.open(3, &io::OpenName::parse(&name)).unwrap();
+                image.close(3).unwrap();
+            }
+            assert_eq!(image.free_blocks(), Ok(free - 23));
+        }
+    }
+
+    #[test]
+    fn disk_image_damage() {
+        use dos::FileSystem;
+
+        // 18/0 is the header and the BAM, 18/1 the first directory block
+        const BAM: usize = 357 * diskimage::BLOCK_SIZE;
+        const DIRECTORY: usize = 358 * diskimage::BLOCK_SIZE;
+
+        let mut data = std::vec![0u8; diskimage::Format::D64.blocks() as usize * 256];
+        diskimage::DiskImage::new(&mut data[..])
+            .unwrap()
+            .format(b"DAMAGED", Some(b"01"))
+            .unwrap();
+
+        // a directory linked to itself doesn't hang validating
+        data[DIRECTORY] = 18;
+        data[DIRECTORY + 1] = 1;
+        let mut image = diskimage::DiskImage::new(&mut data[..]).unwrap();
+        assert_eq!(image.validate(), Ok(()));
+
+        // more free sectors counted than a track can have
+        let mut data = std::vec![0u8; diskimage::Format::D64.blocks() as usize * 256];
+        {
+            let mut image = diskimage::DiskImage::new(&mut data[..]).unwrap();
+            image.format(b"DAMAGED", Some(b"01")).unwrap();
+            image.open(2, &io::OpenName::parse(b"DATA,S,W")).unwrap();
+            image.write(2, 1).unwrap();
+            image.close(2).unwrap();
+        }
+        for track in 1..=35 {
+            data[BAM + 4 * track] = 255;
+        }
+        let mut image = diskimage::DiskImage::new(&mut data[..]).unwrap();
+        assert_eq!(
+            dos::execute(b"S:DATA", &mut image),
+            dos::DosError::DirError.into()
+        );
+    }
+
+    #[test]
+    fn disk_image_keeps_what_it_was_given() {
+        use dos::listing::Directory;
+        use dos::FileSystem;
+
+        let mut data = std::vec![0u8; diskimage::Format::D64.blocks() as usize * 256];
+        let mut image = diskimage::DiskImage::new(&mut data[..]).unwrap();
+        image.format(b"KEPT", Some(b"01")).unwrap();
+
+        // a bad header leaves the disk as it was
+        assert_eq!(
+            dos::execute(b"N:A*,02", &mut image),
+            dos::DosError::InvalidFilename.into()
+        );
+        assert_eq!(image.format(b"DISK", Some(b"")), Err(dos::DosError::SyntaxError));
+        assert_eq!(&image.disk_header().unwrap().name[..4], b"KEPT");
+
+        // opening a channel again closes the file written on it
+        image.open(2, &io::OpenName::parse(b"FIRST,S,W")).unwrap();
+        image.write(2, b'A').unwrap();
+        image.open(2, &io::OpenName::parse(b"SECOND,S,W")).unwrap();
+        image.close(2).unwrap();
+        image.open(2, &io::OpenName::parse(b"FIRST,S,R")).unwrap();
+        assert_eq!(image.read(2), Some((b'A', true)));
+        assert_eq!(image.validate(), Ok(()));
+    }
+
+    #[test]
+    fn host_directory() {
+        use dos::FileSystem;
+
+        assert_eq!(hostdir::host_name(b"A/B"), "a%2fb");
+        assert_eq!(hostdir::host_name(b".\xc7AME "), "%2eGame%20");
+        assert_eq!(hostdir::pet_name("%2eGame%20"), b".\xc7AME ");
+        assert_eq!(hostdir::pet_name("100%"), b"100%");
+
+        let path = std::env::temp_dir().join(std::format!("pet-host-{}", std::process::id()));
+        std::fs::create_dir_all(&path).unwrap();
+        let hello = [
+            0x01, 0x04, 0x0a, 0x04, 0x64, 0x00, 0x8f, 0x20, 0x48, 0x49, 0x00, 0x00, 0x00,
+        ];
+        std::fs::write(path.join("hello.prg"), &hello[..]).unwrap();
+        let mut p00 = b"C64File\0BAR".to_vec();
+        p00.resize(26, 0);
+        p00.extend_from_slice(&[
+            0x01, 0x04, 0x0b, 0x04, 0x14, 0x00, 0x8f, 0x20, 0x50, 0x30, 0x30, 0x00, 0x00, 0x00,
+        ]);
+        std::fs::write(path.join("bar.p00"), &p00).unwrap();
+        std::fs::write(path.join("Mixed.seq"), b"XY").unwrap();
+
+        let mut host = hostdir::HostDirectory::new(&path);
+
+        // the shifted M on the host matches an unshifted one
+        host.open(2, &io::OpenName::parse(b"MIXED,S,R")).unwrap();
+        assert_eq!(host.read(2), Some((b'X', false)));
+        assert_eq!(host.read(2), Some((b'Y', true)));
+        host.close(2).unwrap();
+
+        let mut ram = [0u8; 8192];
+        let mut vid_ram = [0u8; 2048];
+        let screen_content = {
+            let mem = Ram::new(&mut ram, &mut vid_ram, &mut host);
+            let mut cpu = Cpu::new(&mem);
+            cpu.reset();
+
+            run(&mem, &mut cpu, "load\"hello\",8\r", 3_000_000);
+            run(&mem, &mut cpu, "list\r", 1_000_000);
+            run(&mem, &mut cpu, "load\"bar\",8\r", 3_000_000);
+            run(&mem, &mut cpu, "list\r", 1_000_000);
+            let mut screen_content = screen_as_string(&mem);
+
+            run(&mem, &mut cpu, "save\"a/b\",8\r", 3_000_000);
+            run(&mem, &mut cpu, "load\"$\",8\r", 3_000_000);
+            run(&mem, &mut cpu, "list\r", 1_000_000);
+
+            screen_content.push_str(&screen_as_string(&mem));
+            screen_content
+        };
+        println!("{}\n", screen_content);
+
+        let lines: Vec<String> = screen_content
+            .lines()
+            .map(|line| line.split_whitespace().collect::<Vec<&str>>().join(" "))
+            .collect();
+        assert!(lines.iter().any(|line| line == "100 REM HI"));
+        assert!(lines.iter().any(|line| line == "20 REM P00"));
+        assert!(lines.iter().any(|line| line == "1 \"A/B\" PRG"));
+        assert!(lines.iter().any(|line| line == "1 \"BAR\" PRG"));
+        assert!(lines.iter().any(|line| line == "1 \"HELLO\" PRG"));
+        assert!(lines.iter().any(|line| line == "65535 BLOCKS FREE."));
+
+        let saved = std::fs::read(path.join("a%2fb.prg")).unwrap();
+        assert_eq!(saved, &p00[26..]);
+
+        assert_eq!(dos::execute(b"R:BAZ=BAR", &mut host), dos::Status::OK);
+        assert!(path.join("baz.prg").exists() && !path.join("bar.p00").exists());
+        // replacing keeps the host name, so it can't delete the file on case-insensitive hosts
+        host.open(2, &io::OpenName::parse(b"@0:MIXED,S,W")).unwrap();
+        host.write(2, b'Z').unwrap();
+        host.close(2).unwrap();
+        assert_eq!(std::fs::read(path.join("Mixed.seq")).unwrap(), b"Z");
+        assert!(!path.join("mixed.seq").exists());
+        assert_eq!(dos::execute(b"S:MIXED", &mut host), dos::Status::scratched(1));
+        // opening the channel again writes the file first
+        host.open(2, &io::OpenName::parse(b"KEPT,S,W")).unwrap();
+        host.write(2, b'K').unwrap();
+        host.open(2, &io::OpenName::parse(b"KEPT,S,R")).unwrap();
+        assert_eq!(host.read(2), Some((b'K', true)));
+        host.close(2).unwrap();
+        assert_eq!(
+            host.format(b"DISK", Some(b"01")),
+            Err(dos::DosError::WriteProtectOn)
+        );
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn filename_patterns() {
+        use dos::FileSystem;
+
+        assert!(dos::matches(b"*", b"GAME"));
+        assert!(dos::matches(b"GA*", b"GAME"));
+        assert!(dos::matches(b"G?ME", b"GAME"));
+        assert!(dos::matches(b"GAME*", b"GAME"));
+        assert!(!dos::matches(b"GA", b"GAME"));
+        assert!(!dos::matches(b"GAMES", b"GAME"));
+        assert!(!dos::matches(b"G?ME", b"GME"));
+        assert_eq!(dos::directory_pattern(b"$"), Some(&b"*"[..]));
+        assert_eq!(dos::directory_pattern(b"$0:A*"), Some(&b"A*"[..]));
+        assert_eq!(dos::directory_pattern(b"GAME"), None);
+
+        let mut data = std::vec![0u8; diskimage::Format::D64.blocks() as usize * 256];
+        let mut image = diskimage::DiskImage::new(&mut data[..]).unwrap();
+        image.format(b"PATTERNS", Some(b"01")).unwrap();
+        assert_eq!(
+            image.open(2, &io::OpenName::parse(b"DATA*,S,W")),
+            Err(dos::DosError::InvalidFilename)
+        );
+
+        let mut ram = [0u8; 8192];
+        let mut vid_ram = [0u8; 2048];
+        let screen_content = {
+            let mem = Ram::new(&mut ram, &mut vid_ram, &mut image);
+            let mut cpu = Cpu::new(&mem);
+            cpu.reset();
+
+            run(&mem, &mut cpu, "10 print \"hi\"\rsave\"game\",8\r", 3_000_000);
+            run(&mem, &mut cpu, "new\rload\"g?m*\",8\r", 3_000_000);
+            run(&mem, &mut cpu, "list\r", 1_000_000);
+
+            screen_as_string(&mem)
+        };
+        println!("{}\n", screen_content);
+
+        let lines: Vec<&str> = screen_content.lines().map(|line| line.trim()).collect();
+        assert!(lines.windows(3).any(|w| w == ["LIST", "", "10 PRINT \"HI\""]));
+
+        assert_eq!(dos::execute(b"C:GAME2=GAME", &mut image), dos::Status::OK);
+        assert_eq!(dos::execute(b"S:GA*", &mut image), dos::Status::scratched(2));
+
+        let path = std::env::temp_dir().join(std::format!("pet-patterns-{}", std::process::id()));
+        std::fs::create_dir_all(&path).unwrap();
+        std::fs::write(path.join("hello.prg"), b"\x01\x04").unwrap();
+        std::fs::write(path.join("bar.prg"), b"\x01\x04").unwrap();
+
+        let mut host = hostdir::HostDirectory::new(&path);
+        host.start_filename();
+        b"$0:H*".iter().for_each(|&b| host.next_filename_byte(b));
+        host.fname_done();
+        let len = host.load_data_len().unwrap();
+        let listing: Vec<u8> = (0..len).map(|i| host.load_data_byte(i).unwrap()).collect();
+        assert!(listing.windows(7).any(|w| w == b"\"HELLO\""));
+        assert!(!listing.windows(5).any(|w| w == b"\"BAR\""));
+
+        host.open(2, &io::OpenName::parse(b"B?R,P,R")).unwrap();
+        assert_eq!(host.read(2), Some((0x01, false)));
+        host.close(2).unwrap();
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn directory_listing() {
+        use dos::FileSystem;
+
+        let mut data = std::vec![0u8; diskimage::Format::D64.blocks() as usize * 256];
+        let mut image = diskimage::DiskImage::new(&mut data[..]).unwrap();
+        image.format(b"TEST DISK", Some(b"01")).unwrap();
+
+        image.open(2, &io::OpenName::parse(b"DATA,S,W")).unwrap();
+        for i in 0..300 {
+            image.write(2, i as u8).unwrap();
+        }
+        image.close(2).unwrap();
+        // never closed
+        image.open(3, &io::OpenName::parse(b"OPEN,S,W")).unwrap();
+        image.write(3, 1).unwrap();
+
+        image.start_filename();
+        image.next_filename_byte(b'$');
+        image.fname_done();
+        let len = image.load_data_len().unwrap();
+        let listing: Vec<u8> = (0..len).map(|i| image.load_data_byte(i).unwrap()).collect();
+        assert_eq!(&listing[..6], &[0x01, 0x04, 0x1f, 0x04, 0x00, 0x00]);
+        assert_eq!(&listing[6..31], b"\x12\"TEST DISK       \" 01 2A");
+        assert_eq!(&listing[len - 3..], &[0, 0, 0]);
+        assert_eq!(image.load_data_byte(len), Err(dos::DosError::ReadError));
+        // asking for an earlier byte starts over
+        assert_eq!(image.load_data_byte(0), Ok(0x01));
+
+        let mut ram = [0u8; 8192];
+        let mut vid_ram = [0u8; 2048];
+        let screen_content = {
+            let mem = Ram::new(&mut ram, &mut vid_ram, &mut image);
+            let mut cpu = Cpu::new(&mem);
+            cpu.reset();
+
+            run(&mem, &mut cpu, "load\"$\",8\r", 3_000_000);
+            run(&mem, &mut cpu, "list\r", 1_000_000);
+
+            screen_as_string(&mem)
+        };
+        println!("{}\n", screen_content);
+
+        let lines: Vec<&str> = screen_content.lines().map(|line| line.trim()).collect();
+        assert!(lines.contains(&"2    \"DATA\"             SEQ"));
+        // the blocks are only counted on close
+        assert!(lines.contains(&"0    \"OPEN\"            *SEQ"));
+        assert!(lines.contains(&"661 BLOCKS FREE."));
+
+        image.start_filename();
+        b"$:D*".iter().for_each(|&b| image.next_filename_byte(b));
+        image.fname_done();
+        assert_eq!(image.load_data_len(), Ok(len - 32));
+    }
+
+    fn flash_file<S: io::Storage>(fs: &mut S, name: &[u8]) -> Option<Vec<u8>> {
+        let mut open_name = name.to_vec();
+        open_name.extend_from_slice(b",R");
+        fs.open(2, &io::OpenName::parse(&open_name)).ok()?;
+        let mut content = Vec::new();
+        while let Some((value, _)) = fs.read(2) {
+            content.push(value);
+        }
+        fs.close(2).unwrap();
+        Some(content)
+    }
+
+    fn try_write_flash_file<S: io::Storage>(
+        fs: &mut S,
+        name: &[u8],
+        content: &[u8],
+    ) -> Result<(), dos::DosError> {
+        let mut open_name = name.to_vec();
+        open_name.extend_from_slice(b",S,W");
+        fs.open(2, &io::OpenName::parse(&open_name))?;
+        for &b in content {
+            fs.write(2, b)?;
+        }
+        fs.close(2)
+    }
+
+    fn write_flash_file<S: io::Storage>(fs: &mut S, name: &[u8], content: &[u8]) {
+        try_write_flash_file(fs, name, content).unwrap();
+    }
+
+    #[test]
+    fn flash_filesystem() {
+        use dos::FileSystem;
+
+        const SECTORS: usize = 32;
+        let mut data = std::vec![0xffu8; SECTORS * flashfs::SECTOR_SIZE];
+        let mut fs = flashfs::FlashFs::new(&mut data[..]).unwrap();
+        assert_eq!(fs.free_sectors(), SECTORS);
+
+        let big: Vec<u8> = (0..20_000).map(|i| (i * 7 % 251) as u8).collect();
+        write_flash_file(&mut fs, b"BIG", &big);
+        write_flash_file(&mut fs, b"EMPTY", b"");
+        assert_eq!(fs.free_sectors(), SECTORS - 6);
+        assert_eq!(flash_file(&mut fs, b"BIG"), Some(big.clone()));
+        assert_eq!(flash_file(&mut fs, b"EMPTY"), Some(Vec::new()));
+        assert_eq!(
+            fs.open(2, &io::OpenName::parse(b"BIG,S,W")),
+            Err(dos::DosError::FileExists)
+        );
+
+        // a file being written is only there once it is closed
+        fs.open(3, &io::OpenName::parse(b"HALF,S,W")).unwrap();
+        fs.write(3, 1).unwrap();
+        assert_eq!(flash_file(&mut fs, b"HALF"), None);
+        assert_eq!(
+            fs.open(4, &io::OpenName::parse(b"OTHER,S,W")),
+            Err(dos::DosError::NoChannel)
+        );
+        fs.close(3).unwrap();
+
+        write_flash_file(&mut fs, b"@:HALF", b"NEW");
+        fs.open(2, &io::OpenName::parse(b"HALF,S,A")).unwrap();
+        fs.write(2, b'!').unwrap();
+        fs.close(2).unwrap();
+        assert_eq!(flash_file(&mut fs, b"HALF"), Some(b"NEW!".to_vec()));
+
+        fs.rename(b"BIG", b"LARGE").unwrap();
+        assert_eq!(flash_file(&mut fs, b"BIG"), None);
+        fs.copy(b"LARGE", b"COPY").unwrap();
+        assert_eq!(fs.scratch(b"E*"), Ok(1));
+        assert_eq!(fs.free_sectors(), SECTORS - 11);
+
+        // everything is found again after a reset
+        let mut fs = flashfs::FlashFs::new(&mut data[..]).unwrap();
+        assert_eq!(fs.free_sectors(), SECTORS - 11);
+        assert_eq!(flash_file(&mut fs, b"LARGE"), Some(big.clone()));
+        assert_eq!(flash_file(&mut fs, b"COPY"), Some(big));
+        assert_eq!(flash_file(&mut fs, b"HALF"), Some(b"NEW!".to_vec()));
+        assert_eq!(flash_file(&mut fs, b"EMPTY"), None);
+
+        // a bad name is found before anything is deleted
+        assert_eq!(
+            dos::execute(b"N:A*,01", &mut fs),
+            dos::DosError::InvalidFilename.into()
+        );
+        assert_eq!(flash_file(&mut fs, b"HALF"), Some(b"NEW!".to_vec()));
+
+        let mut ram = [0u8; 8192];
+        let mut vid_ram = [0u8; 2048];
+        let screen_content = {
+            let mem = Ram::new(&mut ram, &mut vid_ram, &mut fs);
+            let mut cpu = Cpu::new(&mem);
+            cpu.reset();
+
+            run(&mem, &mut cpu, "10 rem flash\r", 1_000_000);
+            run(&mem, &mut cpu, "save\"prog\",8\r", 3_000_000);
+            run(&mem, &mut cpu, "new\r", 500_000);
+            run(&mem, &mut cpu, "load\"prog\",8\r", 3_000_000);
+            run(&mem, &mut cpu, "list\r", 1_000_000);
+            run(&mem, &mut cpu, "load\"$\",8\r", 3_000_000);
+            run(&mem, &mut cpu, "list\r", 1_000_000);
+
+            screen_as_string(&mem)
+        };
+        println!("{}\n", screen_content);
+
+        let lines: Vec<&str> = screen_content.lines().map(|line| line.trim()).collect();
+        assert!(lines.contains(&"10 REM FLASH"));
+        assert!(lines.contains(&"79   \"LARGE\"            SEQ"));
+        assert!(lines.contains(&"1    \"PROG\"             PRG"));
+        assert!(lines.contains(&"320 BLOCKS FREE."));
+    }
+
+    #[test]
+    fn flash_wear_is_spread() {
+        use dos::FileSystem;
+
+        const SECTORS: usize = 16;
+        let mut data = std::vec![0xffu8; SECTORS * flashfs::SECTOR_SIZE];
+        let mut fs = flashfs::FlashFs::new(&mut data[..]).unwrap();
+
+        // a file that never changes stays where it is
+        write_flash_file(&mut fs, b"KEEP", b"KEEP");
+        for i in 0..300 {
+            write_flash_file(&mut fs, b"@:LOG", &[i as u8; 5000]);
+        }
+        assert_eq!(fs.scratch(b"LOG"), Ok(1));
+
+        let counts: Vec<u32> = (1..SECTORS as u16)
+            .map(|sector| fs.erase_count(sector).unwrap().unwrap())
+            .collect();
+        println!("{:?}", counts);
+        let min = counts.iter().min().unwrap();
+        let max = counts.iter().max().unwrap();
+        assert!(max - min <= 1);
+        assert_eq!(fs.erase_count(0), Ok(Some(1)));
+        assert_eq!(flash_file(&mut fs, b"KEEP"), Some(b"KEEP".to_vec()));
+    }
+
+    // locking works the same on all backends
+    fn check_locking<S: io::Storage + dos::FileSystem>(storage: &mut S) {
+        for name in [&b"A,S,W"[..], b"B,S,W"] {
+            storage.open(2, &io::OpenName::parse(name)).unwrap();
+            storage.write(2, b'X').unwrap();
+            storage.close(2).unwrap();
+        }
+
+        assert_eq!(dos::execute(b"L:A", storage), dos::Status::OK);
+        assert_eq!(dos::execute(b"S:*", storage), dos::Status::scratched(1));
+        assert_eq!(
+            storage.open(2, &io::OpenName::parse(b"@:A,S,W")),
+            Err(dos::DosError::WriteProtectOn)
+        );
+        assert_eq!(
+            storage.open(2, &io::OpenName::parse(b"A,S,A")),
+            Err(dos::DosError::WriteProtectOn)
+        );
+
+        // the lock goes with the file
+        assert_eq!(dos::execute(b"R:C=A", storage), dos::Status::OK);
+        assert_eq!(storage.scratch(b"C"), Ok(0));
+        assert_eq!(storage.lock(b"C", Some(true)), Ok(1));
+        assert_eq!(dos::execute(b"L:C", storage), dos::Status::OK);
+        assert_eq!(dos::execute(b"S:C", storage), dos::Status::scratched(1));
+        assert_eq!(dos::execute(b"L:C", storage).code, 62);
+    }
+
+    #[test]
+    fn locked_files() {
+        use dos::listing::Directory;
+        use dos::FileSystem;
+
+        let mut data = std::vec![0xffu8; 16 * flashfs::SECTOR_SIZE];
+        let mut fs = flashfs::FlashFs::new(&mut data[..]).unwrap();
+        check_locking(&mut fs);
+        write_flash_file(&mut fs, b"LOCKED", b"DATA");
+        fs.lock(b"LOCKED", Some(true)).unwrap();
+        let mut fs = flashfs::FlashFs::new(&mut data[..]).unwrap();
+        let entry = fs.next_entry(&mut 0).unwrap().unwrap();
+        assert!(entry.locked);
+        assert_eq!(flash_file(&mut fs, b"LOCKED"), Some(b"DATA".to_vec()));
+        fs.validate().unwrap();
+        assert_eq!(fs.scratch(b"*"), Ok(0));
+        fs.format(b"", None).unwrap();
+        assert_eq!(fs.next_entry(&mut 0), Ok(None));
+
+        let mut data = std::vec![0u8; diskimage::Format::D64.blocks() as usize * 256];
+        let mut image = diskimage::DiskImage::new(&mut data[..]).unwrap();
+        image.format(b"DISK", Some(b"AB")).unwrap();
+        check_locking(&mut image);
+
+        let path = std::env::temp_dir().join(std::format!("pet-lock-{}", std::process::id()));
+        std::fs::create_dir_all(&path).unwrap();
+        check_locking(&mut hostdir::HostDirectory::new(&path));
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn flash_disks() {
+        use dos::listing::Directory;
+        use dos::FileSystem;
+
+        let mut data = std::vec![0xffu8; 32 * flashfs::SECTOR_SIZE];
+        let mut fs = flashfs::FlashFs::new(&mut data[..]).unwrap();
+        write_flash_file(&mut fs, b"MAIN", b"MAIN");
+
+        assert_eq!(dos::execute(b"MD:GAMES", &mut fs), dos::Status::OK);
+        assert_eq!(dos::execute(b"MD:TOOLS", &mut fs), dos::Status::OK);
+        assert_eq!(dos::execute(b"MD:GAMES", &mut fs).code, 63);
+        assert_eq!(dos::execute(b"CD:NONE", &mut fs).code, 62);
+        assert_eq!(dos::execute(b"CD:GAM*", &mut fs), dos::Status::OK);
+        assert_eq!(fs.disk(), 1);
+
+        // every disk has its own files
+        assert_eq!(flash_file(&mut fs, b"MAIN"), None);
+        write_flash_file(&mut fs, b"MAIN", b"GAMES");
+        write_flash_file(&mut fs, b"PACMAN", b"PAC");
+        assert_eq!(dos::execute(b"CD:TOOLS", &mut fs), dos::Status::OK);
+        assert_eq!(fs.next_entry(&mut 0), Ok(None));
+        assert_eq!(dos::execute(b"S:*", &mut fs), dos::Status::scratched(0));
+        assert_eq!(dos::execute(b"CD\x5f", &mut fs), dos::Status::OK);
+        assert_eq!(flash_file(&mut fs, b"MAIN"), Some(b"MAIN".to_vec()));
+        assert_eq!(fs.lock(b"PACMAN", Some(true)), Ok(0));
+
+        assert_eq!(dos::execute(b"RD:GAMES", &mut fs).code, 63);
+        assert_eq!(dos::execute(b"RD:BLUEPET", &mut fs).code, 26);
+        assert_eq!(dos::execute(b"RD:TOOLS", &mut fs), dos::Status::OK);
+
+        // after a reset the first disk is mounted
+        let mut fs = flashfs::FlashFs::new(&mut data[..]).unwrap();
+        let mut disks = Vec::new();
+        let mut cursor = 0;
+        while let Some((header, mounted)) = FileSystem::next_disk(&mut fs, &mut cursor).unwrap() {
+            disks.push((header.name, mounted));
+        }
+        assert_eq!(
+            disks,
+            [(*b"BLUEPET         ", true), (*b"GAMES           ", false)]
+        );
+
+        fs.change_disk(b"GAMES").unwrap();
+        assert_eq!(flash_file(&mut fs, b"MAIN"), Some(b"GAMES".to_vec()));
+        fs.format(b"ARCADE", Some(b"AR")).unwrap();
+        assert_eq!(fs.next_entry(&mut 0), Ok(None));
+        let header = fs.disk_header().unwrap();
+        assert_eq!(header.name, *b"ARCADE          ");
+        assert_eq!(header.id, *b"AR 2A");
+        fs.change_disk(b"").unwrap();
+        assert_eq!(flash_file(&mut fs, b"MAIN"), Some(b"MAIN".to_vec()));
+
+        // the numbers of removed disks are used again
+        fs.make_disk(b"DEMOS").unwrap();
+        let mut cursor = 0;
+        let mut numbers = Vec::new();
+        while let Some((disk, header)) = fs.next_numbered_disk(&mut cursor).unwrap() {
+            numbers.push((disk, header.name[0]));
+        }
+        assert_eq!(numbers, [(0, b'B'), (1, b'A'), (2, b'D')]);
+        assert_eq!(fs.scratch(b"*"), Ok(1));
+        assert_eq!(fs.free_sectors(), 32 - 2);
+
+        let mut ram = [0u8; 8192];
+        let mut vid_ram = [0u8; 2048];
+        {
+            let mem = Ram::new(&mut ram, &mut vid_ram, &mut fs);
+            let mut cpu = Cpu::new(&mem);
+            cpu.reset();
+
+            run(&mem, &mut cpu, "10 rem demo\r", 1_000_000);
+            run(&mem, &mut cpu, "open1,8,15,\"cd:demos\":close1\r", 3_000_000);
+            run(&mem, &mut cpu, "save\"prog\",8\r", 3_000_000);
+            println!("{}\n", screen_as_string(&mem));
+        }
+        assert_eq!(fs.disk(), 2);
+        assert_eq!(fs.next_entry(&mut 0).unwrap().unwrap().name(), b"PROG");
+    }
+
+    // the step it happens in is only done halfway
+    struct PowerCut<'a> {
+        flash: &'a mut [u8],
+        steps: usize,
+        cut: bool,
+    }
+
+    impl PowerCut<'_> {
+        // true if the power goes in this step
+        fn step(&mut self) -> Result<bool, flashfs::FlashError> {
+            if self.cut {
+                return Err(flashfs::FlashError);
+            }
+            self.cut = self.steps == 0;
+            self.steps = self.steps.saturating_sub(1);
+            Ok(self.cut)
+        }
+    }
+
+    impl flashfs::NorFlash for PowerCut<'_> {
+        fn capacity(&self) -> u32 {
+            self.flash.len() as u32
+        }
+
+        fn read(&mut self, address: u32, data: &mut [u8]) -> Result<(), flashfs::FlashError> {
+            match self.cut {
+                true => Err(flashfs::FlashError),
+                false => self.flash.read(address, data),
+            }
+        }
+
+        fn program(&mut self, address: u32, data: &[u8]) -> Result<(), flashfs::FlashError> {
+            if !self.step()? {
+                return self.flash.program(address, data);
+            }
+            // half of the bytes and some bits of the one after them
+            let half = data.len() / 2;
+            let mut partial = data[..half].to_vec();
+            partial.push(data[half] | 0x0f);
+            self.flash.program(address, &partial)?;
+            Err(flashfs::FlashError)
+        }
+
+        fn erase_sector(&mut self, address: u32) -> Result<(), flashfs::FlashError> {
+            if !self.step()? {
+                return self.flash.erase_sector(address);
+            }
+            let start = address as usize / flashfs::SECTOR_SIZE * flashfs::SECTOR_SIZE;
+            self.flash[start..start + flashfs::SECTOR_SIZE / 2].fill(0xff);
+            Err(flashfs::FlashError)
+        }
+    }
+
+    #[test]
+    fn flash_survives_power_cuts() {
+        use dos::FileSystem;
+
+        const SECTORS: usize = 16;
+        let old: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        let new: Vec<u8> = (0..9000).map(|i| (i / 3) as u8).collect();
+
+        let mut base = std::vec![0xffu8; SECTORS * flashfs::SECTOR_SIZE];
+        let mut fs = flashfs::FlashFs::new(&mut base[..]).unwrap();
+        write_flash_file(&mut fs, b"FILE", &old);
+        write_flash_file(&mut fs, b"KEEP", b"KEEP");
+        write_flash_file(&mut fs, b"GONE", b"GONE");
+
+        let mut steps = 0;
+        loop {
+            let mut data = base.clone();
+            let mut fs = flashfs::FlashFs::new(PowerCut {
+                flash: &mut data[..],
+                steps,
+                cut: false,
+            })
+            .unwrap();
+            let done = try_write_flash_file(&mut fs, b"@:FILE", &new)
+                .and_then(|_| fs.rename(b"KEEP", b"KEPT"))
+                .and_then(|_| fs.scratch(b"GONE"))
+                .is_ok();
+
+            let mut fs = flashfs::FlashFs::new(&mut data[..]).unwrap();
+            fs.validate().unwrap();
+            let file = flash_file(&mut fs, b"FILE").unwrap();
+            assert!(file == old || file == new, "{} steps", steps);
+            let keep = flash_file(&mut fs, b"KEEP");
+            let kept = flash_file(&mut fs, b"KEPT");
+            assert!(keep.is_some() != kept.is_some(), "{} steps", steps);
+            assert_eq!(keep.or(kept), Some(b"KEEP".to_vec()));
+            assert!(flash_file(&mut fs, b"GONE").map_or(true, |gone| gone == b"GONE"));
+
+            // nothing but the files is left over
+            let sectors = match file.len() {
+                5000 => 2,
+                _ => 3,
+            } + 1
+                + flash_file(&mut fs, b"GONE").map_or(0, |_| 1);
+            assert_eq!(fs.free_sectors(), SECTORS - sectors, "{} steps", steps);
+
+            if done {
+                break;
+            }
+            steps += 1;
+        }
+        assert!(steps > 10);
+    }
+
+    #[test]
+    fn flash_detects_corruption() {
+        use dos::FileSystem;
+
+        let mut data = std::vec![0xffu8; 8 * flashfs::SECTOR_SIZE];
+        let mut fs = flashfs::FlashFs::new(&mut data[..]).unwrap();
+        write_flash_file(&mut fs, b"DATA", &[0x55; 100]);
+        write_flash_file(&mut fs, b"NAME", &[0x55; 100]);
+        assert_eq!(flashfs::crc32(0, b"123456789"), 0xcbf4_3926);
+
+        // a bit of the content of the first file and of the name of the second one
+        data[64 + 10] ^= 0x01;
+        data[flashfs::SECTOR_SIZE + 24] ^= 0x01;
+
+        let mut fs = flashfs::FlashFs::new(&mut data[..]).unwrap();
+        assert_eq!(flash_file(&mut fs, b"NAME"), None);
+        assert_eq!(flash_file(&mut fs, b"DATA").map(|data| data.len()), Some(99));
+        fs.validate().unwrap();
+        assert_eq!(flash_file(&mut fs, b"DATA"), None);
+        assert_eq!(fs.free_sectors(), 8);
+    }
+
+    // one end of a serial line between two threads, timeouts are 100 times shorter
+    struct Line {
+        rx: std::sync::mpsc::Receiver<u8>,
+        tx: std::sync::mpsc::Sender<u8>,
+        // every how many bytes one gets damaged or lost, 0 for none
+        damage: usize,
+        lose: usize,
+        written: usize,
+    }
+
+    fn line(damage: usize, lose: usize) -> (Line, Line) {
+        let (a_tx, b_rx) = std::sync::mpsc::channel();
+        let (b_tx, a_rx) = std::sync::mpsc::channel();
+        let a = Line {
+            rx: a_rx,
+            tx: a_tx,
+            damage,
+            lose,
+            written: 0,
+        };
+        let b = Line {
+            rx: b_rx,
+            tx: b_tx,
+            damage: 0,
+            lose: 0,
+            written: 0,
+        };
+        (a, b)
+    }
+
+    impl xmodem::Transport for Line {
+        fn read(&mut self, timeout: u32) -> Option<u8> {
+            let timeout = std::time::Duration::from_micros(timeout as u64 * 10);
+            self.rx.recv_timeout(timeout).ok()
+        }
+
+        fn write(&mut self, data: &[u8]) {
+            for &b in data {
+                self.written += 1;
+                let b = match self.damage {
+                    0 => b,
+                    damage if self.written % damage == 0 => b ^ 0x10,
+                    _ => b,
+                };
+                if self.lose == 0 || self.written % self.lose != 0 {
+                    self.tx.send(b).ok();
+                }
+            }
+        }
+    }
+
+    fn flash_content(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+    }
+
+    #[test]
+    fn ymodem_transfers() {
+        let mut from_data = std::vec![0xffu8; 32 * flashfs::SECTOR_SIZE];
+        let mut to_data = std::vec![0xffu8; 32 * flashfs::SECTOR_SIZE];
+        let mut to = flashfs::FlashFs::new(&mut to_data[..]).unwrap();
+
+        // exactly a block, one byte more, long blocks with a short one at the end and
+        // a file ending in SUBs
+        let mut subs = flash_content(300);
+        subs.extend_from_slice(&[0x1a; 5]);
+        let files: [(&[u8], Vec<u8>); 5] = [
+            (b"BLOCK", flash_content(128)),
+            (b"MORE", flash_content(129)),
+            (b"LONG", flash_content(3 * 1024 + 100)),
+            (b"SUBS", subs),
+            (b"EMPTY", Vec::new()),
+        ];
+        let mut from = flashfs::FlashFs::new(&mut from_data[..]).unwrap();
+        for (name, content) in &files {
+            write_flash_file(&mut from, name, content);
+        }
+
+        for (damage, lose) in [(0, 0), (2999, 0), (0, 3001), (4001, 5003)] {
+            for (name, content) in &files {
+                // the sender gets a copy of the flash for its thread
+                let (mut sender, mut receiver) = line(damage, lose);
+                let mut data = from_data.clone();
+                let sent_name = name.to_vec();
+                let sent = std::thread::spawn(move || {
+                    let mut from = flashfs::FlashFs::new(&mut data[..]).unwrap();
+                    xmodem::send(&mut sender, &mut from, &sent_name)
+                });
+                let received = xmodem::receive(&mut receiver, &mut to, None);
+                assert_eq!(sent.join().unwrap(), Ok(()));
+                assert_eq!(received, Ok(1));
+                assert_eq!(flash_file(&mut to, name).as_ref(), Some(content));
+            }
+        }
+
+        // the type comes with the extension
+        let mut cursor = 0;
+        use dos::listing::Directory;
+        let entry = to.next_entry(&mut cursor).unwrap().unwrap();
+        assert_eq!(entry.file_type, io::FileType::Seq);
+        let mut from = flashfs::FlashFs::new(&mut from_data[..]).unwrap();
+        assert_eq!(
+            xmodem::send(&mut line(0, 0).0, &mut from, b"MISSING"),
+            Err(xmodem::TransferError::Storage(dos::DosError::FileNotFound))
+        );
+
+        // a damaged file isn't sent short
+        let mut damaged = from_data.clone();
+        damaged[64 + 10] ^= 0x01;
+        let mut from = flashfs::FlashFs::new(&mut damaged[..]).unwrap();
+        assert_eq!(
+            xmodem::send(&mut line(0, 0).0, &mut from, b"BLOCK"),
+            Err(xmodem::TransferError::Storage(dos::DosError::ReadError))
+        );
+
+        // a broken transfer keeps the old file
+        let mut data = std::vec![0xffu8; 8 * flashfs::SECTOR_SIZE];
+        let mut newer = flashfs::FlashFs::new(&mut data[..]).unwrap();
+        write_flash_file(&mut newer, b"LONG", &flash_content(2000)[1000..]);
+        let (mut sender, mut receiver) = line(0, 7);
+        let sent = std::thread::spawn(move || {
+            let mut from = flashfs::FlashFs::new(&mut data[..]).unwrap();
+            xmodem::send(&mut sender, &mut from, b"LONG")
+        });
+        assert!(xmodem::receive(&mut receiver, &mut to, None).is_err());
+        assert!(sent.join().unwrap().is_err());
+        assert_eq!(flash_file(&mut to, b"LONG").as_ref(), Some(&files[2].1));
+    }
+
+    #[test]
+    fn xmodem_receives() {
+        use xmodem::Transport;
+
+        let mut data = std::vec![0xffu8; 16 * flashfs::SECTOR_SIZE];
+        let mut fs = flashfs::FlashFs::new(&mut data[..]).unwrap();
+
+        // a minimal sender: two short blocks, the second padded with SUB
+        let content = flash_content(200);
+        let (mut sender, mut receiver) = line(0, 0);
+        let sent = content.clone();
+        let sender = std::thread::spawn(move || {
+            assert_eq!(sender.read(100_000), Some(b'C'));
+            for (number, chunk) in sent.chunks(128).enumerate() {
+                let mut block = [0x1au8; 128];
+                block[..chunk.len()].copy_from_slice(chunk);
+                let number = number as u8 + 1;
+                sender.write(&[0x01, number, !number]);
+                sender.write(&block);
+                sender.write(&xmodem::crc16(&block).to_be_bytes());
+                assert_eq!(sender.read(100_000), Some(0x06));
+            }
+            sender.write(&[0x04]);
+            assert_eq!(sender.read(100_000), Some(0x15));
+            sender.write(&[0x04]);
+            assert_eq!(sender.read(100_000), Some(0x06));
+        });
+        let received = xmodem::receive(&mut receiver, &mut fs, Some(b"GAME,P"));
+        sender.join().unwrap();
+        assert_eq!(received, Ok(1));
+        assert_eq!(flash_file(&mut fs, b"GAME"), Some(content.clone()));
+        assert_eq!(xmodem::crc16(b"123456789"), 0x31c3);
+
+        // without a name there is nowhere to put it
+        let (mut sender, mut receiver) = line(0, 0);
+        let sender = std::thread::spawn(move || {
+            assert_eq!(sender.read(100_000), Some(b'C'));
+            let block = [0u8; 128];
+            sender.write(&[0x01, 1, !1]);
+            sender.write(&block);
+            sender.write(&xmodem::crc16(&block).to_be_bytes());
+        });
+        let received = xmodem::receive(&mut receiver, &mut fs, None);
+        sender.join().unwrap();
+        assert_eq!(received, Err(xmodem::TransferError::NoName));
+
+        // the old file is only replaced once the new one is complete
+        let (mut sender, mut receiver) = line(0, 0);
+        let sender = std::thread::spawn(move || {
+            assert_eq!(sender.read(100_000), Some(b'C'));
+            let block = [0u8; 128];
+            sender.write(&[0x01, 1, !1]);
+            sender.write(&block);
+            sender.write(&xmodem::crc16(&block).to_be_bytes());
+            assert_eq!(sender.read(100_000), Some(0x06));
+            sender.write(&[0x18, 0x18]);
+        });
+        let received = xmodem::receive(&mut receiver, &mut fs, Some(b"GAME,P"));
+        sender.join().unwrap();
+        assert_eq!(received, Err(xmodem::TransferError::Cancelled));
+        assert_eq!(flash_file(&mut fs, b"GAME"), Some(content));
+        assert_eq!(flash_file(&mut fs, b"XMODEM.PART"), None);
+    }
+
+    fn contains(data: &[u8], pattern: &[u8]) -> bool {
+        data.windows(pattern.len()).any(|window| window == pattern)
+    }
+
+    #[test]
+    fn fat16_card() {
+        use dos::listing::Directory;
+        use dos::FileSystem;
+
+        let mut data = std::vec![0u8; 16 << 20];
+        assert_eq!(
+            fatfs::FatFs::new(&mut data[..]).err(),
+            Some(dos::DosError::DriveNotReady)
+        );
+        fatfs::format(&mut &mut data[..], b"CARD").unwrap();
+        let mut fs = fatfs::FatFs::new(&mut data[..]).unwrap();
+        assert_eq!(fs.fat_type(), fatfs::FatType::Fat16);
+        assert_eq!(fs.cluster_size(), 2048);
+        let free = fs.free_clusters().unwrap();
+
+        let big = flash_content(20_000);
+        write_flash_file(&mut fs, b"BIG", &big);
+        write_flash_file(&mut fs, b"\xc7AME OVER", b"GAME");
+        assert_eq!(fs.free_clusters(), Ok(free - 11));
+        assert_eq!(flash_file(&mut fs, b"\xc2\xc9\xc7"), Some(big.clone()));
+        assert_eq!(flash_file(&mut fs, b"\xc7AME*"), Some(b"GAME".to_vec()));
+        assert_eq!(
+            fs.open(2, &io::OpenName::parse(b"BIG,S,W")),
+            Err(dos::DosError::FileExists)
+        );
+        fs.open(2, &io::OpenName::parse(b"BIG,P,R")).unwrap_err();
+
+        write_flash_file(&mut fs, b"@:BIG", b"NEW");
+        fs.open(2, &io::OpenName::parse(b"BIG,S,A")).unwrap();
+        fs.write(2, b'!').unwrap();
+        fs.close(2).unwrap();
+        assert_eq!(fs.free_clusters(), Ok(free - 2));
+        fs.copy(b"BIG", b"COPY").unwrap();
+        fs.rename(b"COPY", b"A LONGER NAME").unwrap();
+        assert_eq!(
+            flash_file(&mut fs, b"A LONGER NAME"),
+            Some(b"NEW!".to_vec())
+        );
+        assert_eq!(fs.scratch(b"A*"), Ok(1));
+        assert_eq!(fs.free_clusters(), Ok(free - 2));
+
+        let mut ram = [0u8; 8192];
+        let mut vid_ram = [0u8; 2048];
+        let screen_content = {
+            let mem = Ram::new(&mut ram, &mut vid_ram, &mut fs);
+            let mut cpu = Cpu::new(&mem);
+            cpu.reset();
+
+            run(&mem, &mut cpu, "10 rem fat\r", 1_000_000);
+            run(&mem, &mut cpu, "save\"prog\",8\r", 3_000_000);
+            run(&mem, &mut cpu, "new\r", 500_000);
+            run(&mem, &mut cpu, "load\"prog\",8\r", 3_000_000);
+            run(&mem, &mut cpu, "list\r", 1_000_000);
+            run(&mem, &mut cpu, "load\"$\",8\r", 3_000_000);
+            run(&mem, &mut cpu, "list\r", 1_000_000);
+
+            screen_as_string(&mem)
+        };
+        println!("{}\n", screen_content);
+
+        let lines: Vec<&str> = screen_content.lines().map(|line| line.trim()).collect();
+        assert!(lines.contains(&"10 REM FAT"));
+        assert!(lines.contains(&"1    \"PROG\"             PRG"));
+        assert!(lines.contains(&"65535 BLOCKS FREE."));
+
+        // names that fit 8.3 are short ones, others get a long name
+        let data = fs.into_device().unwrap();
+        assert!(contains(data, b"BIG     SEQ"));
+        assert!(contains(data, b"PROG    PRG"));
+        assert!(contains(data, b"GAMEOV~1SEQ"));
+
+        let mut fs = fatfs::FatFs::new(data).unwrap();
+        assert_eq!(fs.free_clusters(), Ok(free - 3));
+        assert_eq!(
+            flash_file(&mut fs, b"PROG"),
+            Some(b"\x01\x04\x0b\x04\x0a\x00\x8f FAT\x00\x00\x00".to_vec())
+        );
+        let header = fs.disk_header().unwrap();
+        assert_eq!(&header.name[..4], b"CARD");
+        assert_eq!(fs.format(b"A*", None), Err(dos::DosError::InvalidFilename));
+        assert_eq!(fs.format(b"CARD", Some(b"")), Err(dos::DosError::SyntaxError));
+        assert_eq!(fs.free_clusters(), Ok(free - 3));
+        fs.format(b"EMPTY", Some(b"01")).unwrap();
+        assert_eq!(fs.next_entry(&mut 0), Ok(None));
+        assert_eq!(fs.free_clusters(), Ok(free));
+        assert_eq!(&fs.disk_header().unwrap().name[..5], b"EMPTY");
+    }
+
+    #[test]
+    fn fat32_card_with_partition() {
+        use dos::FileSystem;
+
+        // the volume starts at 1 MB like on a card formatted by a PC
+        const START: usize = 2048;
+        let mut data = std::vec![0u8; (START + 82_000) * fatfs::SECTOR_SIZE];
+        fatfs::format_as(
+            &mut &mut data[START * fatfs::SECTOR_SIZE..],
+            b"BIG CARD",
+            fatfs::FatType::Fat32,
+        )
+        .unwrap();
+        let partition = &mut data[446..462];
+        partition[4] = 0x0c;
+        partition[8..12].copy_from_slice(&(START as u32).to_le_bytes());
+        partition[12..16].copy_from_slice(&82_000u32.to_le_bytes());
+        data[510..512].copy_from_slice(&[0x55, 0xaa]);
+
+        let mut fs = fatfs::FatFs::new(&mut data[..]).unwrap();
+        assert_eq!(fs.fat_type(), fatfs::FatType::Fat32);
+        assert_eq!(fs.cluster_size(), 512);
+        check_locking(&mut fs);
+
+        // with a cluster a sector the root directory has to grow
+        let empty = fs.free_clusters().unwrap();
+        for i in 0..40 {
+            let name = std::format!("FILE {}", i);
+            write_flash_file(&mut fs, name.as_bytes(), &flash_content(i * 100));
+        }
+        let free = fs.free_clusters().unwrap();
+        let data = fs.into_device().unwrap();
+
+        let mut fs = fatfs::FatFs::new(data).unwrap();
+        assert_eq!(fs.free_clusters(), Ok(free));
+        for i in 0..40 {
+            // the shifted letters match the unshifted ones
+            let mut name = b"\xc6\xc9\xcc\xc5".to_vec();
+            name.extend_from_slice(std::format!(" {}", i).as_bytes());
+            assert_eq!(flash_file(&mut fs, &name), Some(flash_content(i * 100)));
+        }
+        assert_eq!(fs.scratch(b"FILE*"), Ok(40));
+        fs.initialize().unwrap();
+        // the label and 40 files with long names need 81 entries, 6 clusters that stay
+        assert_eq!(fs.free_clusters(), Ok(empty - 5));
     }
 }
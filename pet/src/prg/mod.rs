@@ -0,0 +1,160 @@
+// Programs (PRG files) put straight into memory
+//
+// A PRG file is the load address followed by the data. A BASIC program is one loaded to
+// the start of the BASIC text. Its lines are linked again and the pointers to the end of
+// the program are set like LOAD does, so RUN works right away.
+//
+// The pointers live at different places in BASIC 1 and the later versions, the version is
+// told by the start-up message in the ROMs.
+
+use crate::is_io;
+use mos6502::Memory;
+
+/// The version of BASIC in the ROMs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BasicVersion {
+    /// The original ROMs, "*** COMMODORE BASIC ***"
+    Basic1,
+    /// The upgrade ROMs, "### COMMODORE BASIC ###"
+    Basic2,
+    /// "*** COMMODORE BASIC 4.0 ***"
+    Basic4,
+}
+
+// where BASIC keeps the start of the program, of the variables, of the arrays, the end
+// of the arrays and the number of the line running
+struct Pointers {
+    txttab: u16,
+    vartab: u16,
+    arytab: u16,
+    strend: u16,
+    curlin: u16,
+}
+
+const BASIC1_POINTERS: Pointers = Pointers {
+    txttab: 0x7a,
+    vartab: 0x7c,
+    arytab: 0x7e,
+    strend: 0x80,
+    curlin: 0x88,
+};
+
+const BASIC2_POINTERS: Pointers = Pointers {
+    txttab: 0x28,
+    vartab: 0x2a,
+    arytab: 0x2c,
+    strend: 0x2e,
+    curlin: 0x36,
+};
+
+impl BasicVersion {
+    /// Tells the version by the start-up message in one of the ROMs
+    pub fn detect(roms: &[&[u8]]) -> BasicVersion {
+        let contains = |text: &[u8]| {
+            roms.iter()
+                .any(|rom| rom.windows(text.len()).any(|window| window == text))
+        };
+        if contains(b"BASIC 4") {
+            BasicVersion::Basic4
+        } else if contains(b"*** COMMODORE BASIC ***") {
+            BasicVersion::Basic1
+        } else {
+            BasicVersion::Basic2
+        }
+    }
+
+    fn pointers(self) -> &'static Pointers {
+        match self {
+            BasicVersion::Basic1 => &BASIC1_POINTERS,
+            BasicVersion::Basic2 | BasicVersion::Basic4 => &BASIC2_POINTERS,
+        }
+    }
+
+    /// The pointer the kernal stores the loaded bytes with and the flag telling a VERIFY
+    /// from a LOAD, None where the kernal isn't known well enough
+    pub(crate) fn load_pointer(self) -> Option<(u16, u16)> {
+        match self {
+            BasicVersion::Basic2 => Some((0xfb, 0x9d)),
+            _ => None,
+        }
+    }
+
+    /// Where the BASIC program starts
+    pub fn basic_start(self, mem: &dyn Memory) -> u16 {
+        read_pointer(mem, self.pointers().txttab)
+    }
+
+    /// The high byte of the number of the line running, 0xff in direct mode
+    pub fn line_high(self) -> u16 {
+        self.pointers().curlin + 1
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PrgError {
+    /// Not even the load address is there
+    TooShort,
+    /// The data runs up to or past the end of the address space
+    TooLong,
+}
+
+/// Copies the program to its load address, a BASIC program is linked and its end is set
+/// as after a LOAD. Bytes for the I/O registers are skipped. Returns the addresses the
+/// program occupies.
+pub fn load(
+    mem: &dyn Memory,
+    version: BasicVersion,
+    prg: &[u8],
+) -> Result<core::ops::Range<u16>, PrgError> {
+    if prg.len() < 2 {
+        return Err(PrgError::TooShort);
+    }
+    let start = u16::from_le_bytes([prg[0], prg[1]]);
+    let data = &prg[2..];
+    // the end has to be an address, so the last byte of memory can't be loaded
+    if start as usize + data.len() > 0xffff {
+        return Err(PrgError::TooLong);
+    }
+
+    for (i, &value) in data.iter().enumerate() {
+        let address = start + i as u16;
+        if !is_io(address) {
+            mem.set(address, value);
+        }
+    }
+    let end = start.wrapping_add(data.len() as u16);
+
+    let pointers = version.pointers();
+    if start == read_pointer(mem, pointers.txttab) {
+        link(mem, start, end);
+        for &pointer in &[pointers.vartab, pointers.arytab, pointers.strend] {
+            write_pointer(mem, pointer, end);
+        }
+    }
+    Ok(start..end)
+}
+
+// sets the link of every line to the line after it, a link with a zero high byte ends the
+// program, as in LNKPRG of the ROMs
+fn link(mem: &dyn Memory, start: u16, end: u16) {
+    let mut line = start;
+    while line < end && mem.get(line.wrapping_add(1)) != 0 {
+        let mut next = line.wrapping_add(4);
+        while next < end && mem.get(next) != 0 {
+            next += 1;
+        }
+        next = next.wrapping_add(1);
+        write_pointer(mem, line, next);
+        line = next;
+    }
+}
+
+fn read_pointer(mem: &dyn Memory, address: u16) -> u16 {
+    u16::from_le_bytes([mem.get(address), mem.get(address + 1)])
+}
+
+fn write_pointer(mem: &dyn Memory, address: u16, value: u16) {
+    let [low, high] = value.to_le_bytes();
+    mem.set(address, low);
+    mem.set(address + 1, high);
+}
@@ -0,0 +1,54 @@
+// The screen as text
+//
+// Letters and the ASCII characters are what they are, the graphic characters the closest
+// ones in Unicode. Reverse characters can't be told apart, except the reverse space
+// which is a full block.
+
+use mos6502::Memory;
+
+/// Start of the video RAM
+pub const SCREEN: u16 = 0x8000;
+pub const COLUMNS: usize = 40;
+pub const ROWS: usize = 25;
+
+/// The prompt of BASIC, "READY." in screen codes
+pub const READY: &[u8] = &[0x12, 0x05, 0x01, 0x04, 0x19, 0x2e];
+
+// the graphic characters from screen code 0x40 on
+const GRAPHICS: [char; 64] = [
+    '─', '♠', '│', '─', '─', '─', '─', '│', '│', '╮', '╰', '╯', '└', '╲', '╱', '┌', '┐', '●',
+    '─', '♥', '│', '╭', '╳', '○', '♣', '│', '♦', '┼', '▒', '│', 'π', '◥', ' ', '▌', '▄', '▔',
+    '▁', '▏', '▒', '▕', '▒', '◤', '▕', '├', '▗', '└', '┐', '▂', '┌', '┴', '┬', '┤', '▎', '▍',
+    '▐', '▔', '▀', '▃', '✓', '▖', '▝', '┘', '▘', '▚',
+];
+
+/// The character a screen code shows in the uppercase or the lowercase character set
+pub fn to_char(code: u8, lowercase: bool) -> char {
+    if code == 0xa0 {
+        return '█';
+    }
+    match code & 0x7f {
+        0x00 => '@',
+        c @ 0x01..=0x1a if lowercase => (b'a' + c - 1) as char,
+        c @ 0x01..=0x1a => (b'A' + c - 1) as char,
+        0x1b => '[',
+        0x1c => '\\',
+        0x1d => ']',
+        0x1e => '↑',
+        0x1f => '←',
+        c @ 0x20..=0x3f => c as char,
+        c @ 0x41..=0x5a if lowercase => (b'A' + c - 0x41) as char,
+        c => GRAPHICS[(c - 0x40) as usize],
+    }
+}
+
+/// true if the prompt of BASIC is somewhere on the screen
+pub fn shows_ready(mem: &dyn Memory) -> bool {
+    let end = SCREEN + (ROWS * COLUMNS - READY.len()) as u16;
+    (SCREEN..end).any(|addr| {
+        READY
+            .iter()
+            .enumerate()
+            .all(|(i, &c)| mem.get(addr + i as u16) == c)
+    })
+}
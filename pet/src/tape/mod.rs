@@ -0,0 +1,490 @@
+// Datasette (cassette #1) emulation
+//
+// The read line is connected to PIA1 CA1, the motor to PIA1 CB2, the write line to VIA PB3
+// and the sense line (any button pressed) to PIA1 PA4.
+//
+// A tape is a sequence of pulses - a pulse is the time between two falling edges on the
+// read line. While recording a pulse ends with every rising edge on the write line.
+
+const TAP_SIGNATURE: &[u8] = b"C64-TAPE-RAW";
+const TAP_HEADER_SIZE: usize = 20;
+const TAP_VERSION: usize = 12;
+const TAP_PLATFORM: usize = 13;
+const TAP_DATA_SIZE: usize = 16;
+
+const TAP_PLATFORM_PET: u8 = 3;
+
+// a pulse byte in a TAP file counts in units of 8 cycles
+const TAP_CYCLES_PER_UNIT: u32 = 8;
+
+pub trait Tape {
+    /// Length of the next pulse in cycles. None at the end of the tape.
+    fn next_pulse(&mut self) -> Option<u32>;
+
+    /// Records a pulse of the given length in cycles at the current position.
+    fn record_pulse(&mut self, cycles: u32);
+
+    fn rewind(&mut self);
+}
+
+impl<'a> core::fmt::Debug for dyn Tape + 'a {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Tape")
+    }
+}
+
+#[derive(Debug)]
+pub struct NotATapFile;
+
+/// A TAP file (version 0 or 1) in memory.
+/// Recording overwrites everything after the current position.
+pub struct TapImage<'a> {
+    data: &'a mut [u8],
+    len: usize,
+    pos: usize,
+}
+
+impl<'a> TapImage<'a> {
+    /// Uses the TAP file in the first `len` bytes of `data`.
+    /// The rest of `data` is room for recording.
+    pub fn new(data: &'a mut [u8], len: usize) -> Result<TapImage<'a>, NotATapFile> {
+        if len < TAP_HEADER_SIZE
+            || len > data.len()
+            || &data[..TAP_SIGNATURE.len()] != TAP_SIGNATURE
+        {
+            return Err(NotATapFile);
+        }
+
+        Ok(TapImage {
+            data,
+            len,
+            pos: TAP_HEADER_SIZE,
+        })
+    }
+
+    /// Creates an empty TAP file in `data` to record on.
+    pub fn blank(data: &'a mut [u8]) -> TapImage<'a> {
+        for b in data[..TAP_HEADER_SIZE].iter_mut() {
+            *b = 0;
+        }
+        data[..TAP_SIGNATURE.len()].copy_from_slice(TAP_SIGNATURE);
+        data[TAP_VERSION] = 1;
+        data[TAP_PLATFORM] = TAP_PLATFORM_PET;
+
+        TapImage {
+            data,
+            len: TAP_HEADER_SIZE,
+            pos: TAP_HEADER_SIZE,
+        }
+    }
+
+    /// Length of the TAP file in bytes including the header
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == TAP_HEADER_SIZE
+    }
+
+    fn put(&mut self, value: u8) {
+        if self.pos < self.data.len() {
+            self.data[self.pos] = value;
+            self.pos += 1;
+        }
+    }
+}
+
+impl<'a> Tape for TapImage<'a> {
+    fn next_pulse(&mut self) -> Option<u32> {
+        if self.pos >= self.len {
+            return None;
+        }
+
+        let value = self.data[self.pos];
+        self.pos += 1;
+
+        if value != 0 {
+            return Some(value as u32 * TAP_CYCLES_PER_UNIT);
+        }
+
+        if self.data[TAP_VERSION] == 0 {
+            // version 0 only knows "something longer than 255 units"
+            return Some(256 * TAP_CYCLES_PER_UNIT);
+        }
+
+        if self.pos + 3 > self.len {
+            self.pos = self.len;
+            return None;
+        }
+        let cycles = self.data[self.pos] as u32
+            | (self.data[self.pos + 1] as u32) << 8
+            | (self.data[self.pos + 2] as u32) << 16;
+        self.pos += 3;
+        Some(cycles)
+    }
+
+    fn record_pulse(&mut self, cycles: u32) {
+        let units = cycles / TAP_CYCLES_PER_UNIT;
+        if units > 0 && units < 256 {
+            self.put(units as u8);
+        } else {
+            // the header of a blank tape says version 1 - long pulses are given in cycles
+            self.put(0);
+            self.put(cycles as u8);
+            self.put((cycles >> 8) as u8);
+            self.put((cycles >> 16) as u8);
+        }
+
+        self.len = self.pos;
+        let data_size = (self.len - TAP_HEADER_SIZE) as u32;
+        for i in 0..4 {
+            self.data[TAP_DATA_SIZE + i] = (data_size >> (i * 8)) as u8;
+        }
+    }
+
+    fn rewind(&mut self) {
+        self.pos = TAP_HEADER_SIZE;
+    }
+}
+
+// pulse lengths in cycles as written by the kernal
+const SHORT_PULSE: u32 = 43 * TAP_CYCLES_PER_UNIT;
+const MEDIUM_PULSE: u32 = 62 * TAP_CYCLES_PER_UNIT;
+const LONG_PULSE: u32 = 83 * TAP_CYCLES_PER_UNIT;
+
+// the kernal writes about 27000 short pulses before the header and 5000 before the data.
+// It ignores the tape for a moment after starting the motor, so the leader before the
+// header can't be much shorter than this.
+const LEADER_PULSES: u32 = 5000;
+const DATA_LEADER_PULSES: u32 = 2000;
+const INTERBLOCK_PULSES: u32 = 80;
+const TRAILER_PULSES: u32 = 80;
+
+const HEADER_BLOCK_SIZE: usize = 192;
+const HEADER_TYPE_PRG: u8 = 1;
+const COUNTDOWN_BYTES: usize = 9;
+const PULSES_PER_BYTE: u32 = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Segment {
+    Leader(u32),
+    Block { header: bool, repeat: bool },
+    EndOfData,
+    Trailer(u32),
+    End,
+}
+
+/// Plays a PRG file (load address followed by the data) as if it was saved by the kernal.
+/// The pulses are generated on the fly with much shorter leaders than a real tape,
+/// which makes `LOAD` a lot faster.
+pub struct PrgTape<'a> {
+    name: &'a [u8],
+    prg: &'a [u8],
+    segment: usize,
+    pos: u32,
+}
+
+const PRG_TAPE_LAYOUT: [Segment; 12] = [
+    Segment::Leader(LEADER_PULSES),
+    Segment::Block {
+        header: true,
+        repeat: false,
+    },
+    Segment::EndOfData,
+    Segment::Trailer(INTERBLOCK_PULSES),
+    Segment::Block {
+        header: true,
+        repeat: true,
+    },
+    Segment::Trailer(TRAILER_PULSES),
+    Segment::Leader(DATA_LEADER_PULSES),
+    Segment::Block {
+        header: false,
+        repeat: false,
+    },
+    Segment::EndOfData,
+    Segment::Trailer(INTERBLOCK_PULSES),
+    Segment::Block {
+        header: false,
+        repeat: true,
+    },
+    Segment::Trailer(TRAILER_PULSES),
+];
+
+impl<'a> PrgTape<'a> {
+    /// `name` is given in PETSCII and at most 16 characters are used
+    pub fn new(name: &'a [u8], prg: &'a [u8]) -> PrgTape<'a> {
+        let name = &name[..name.len().min(16)];
+        PrgTape {
+            name,
+            prg,
+            segment: 0,
+            pos: 0,
+        }
+    }
+
+    fn start_address(&self) -> u16 {
+        match self.prg {
+            [lo, hi, ..] => *lo as u16 | (*hi as u16) << 8,
+            _ => 0,
+        }
+    }
+
+    fn data(&self) -> &[u8] {
+        if self.prg.len() < 2 {
+            &[]
+        } else {
+            &self.prg[2..]
+        }
+    }
+
+    fn header_byte(&self, idx: usize) -> u8 {
+        let start = self.start_address();
+        let end = start.wrapping_add(self.data().len() as u16);
+        match idx {
+            0 => HEADER_TYPE_PRG,
+            1 => start as u8,
+            2 => (start >> 8) as u8,
+            3 => end as u8,
+            4 => (end >> 8) as u8,
+            _ => *self.name.get(idx - 5).unwrap_or(&b' '),
+        }
+    }
+
+    fn payload_len(&self, header: bool) -> usize {
+        if header {
+            HEADER_BLOCK_SIZE
+        } else {
+            self.data().len()
+        }
+    }
+
+    fn payload_byte(&self, header: bool, idx: usize) -> u8 {
+        if header {
+            self.header_byte(idx)
+        } else {
+            self.data()[idx]
+        }
+    }
+
+    // countdown, payload and the xor checksum
+    fn block_byte(&self, header: bool, repeat: bool, idx: usize) -> u8 {
+        if idx < COUNTDOWN_BYTES {
+            let countdown = (COUNTDOWN_BYTES - idx) as u8;
+            return if repeat { countdown } else { countdown | 0x80 };
+        }
+
+        let idx = idx - COUNTDOWN_BYTES;
+        let len = self.payload_len(header);
+        if idx < len {
+            self.payload_byte(header, idx)
+        } else {
+            (0..len).fold(0, |checksum, i| checksum ^ self.payload_byte(header, i))
+        }
+    }
+
+    // a byte starts with a long-medium marker followed by the 8 data bits (LSB first) and
+    // an odd parity bit - a 0 is short-medium, a 1 is medium-short
+    fn byte_pulse(byte: u8, idx: u32) -> u32 {
+        match idx {
+            0 => LONG_PULSE,
+            1 => MEDIUM_PULSE,
+            _ => {
+                let bit = (idx - 2) / 2;
+                let value = if bit < 8 {
+                    (byte >> bit) & 1 == 1
+                } else {
+                    byte.count_ones() & 1 == 0
+                };
+                let first = idx & 1 == 0;
+                if value == first {
+                    MEDIUM_PULSE
+                } else {
+                    SHORT_PULSE
+                }
+            }
+        }
+    }
+}
+
+impl<'a> Tape for PrgTape<'a> {
+    fn next_pulse(&mut self) -> Option<u32> {
+        loop {
+            let segment = *PRG_TAPE_LAYOUT.get(self.segment).unwrap_or(&Segment::End);
+            let (pulses, pulse) = match segment {
+                Segment::Leader(count) | Segment::Trailer(count) => (count, SHORT_PULSE),
+                Segment::EndOfData => (
+                    2,
+                    if self.pos == 0 {
+                        LONG_PULSE
+                    } else {
+                        SHORT_PULSE
+                    },
+                ),
+                Segment::Block { header, repeat } => {
+                    let bytes = COUNTDOWN_BYTES + self.payload_len(header) + 1;
+                    let pulses = bytes as u32 * PULSES_PER_BYTE;
+                    if self.pos < pulses {
+                        let byte =
+                            self.block_byte(header, repeat, (self.pos / PULSES_PER_BYTE) as usize);
+                        (pulses, Self::byte_pulse(byte, self.pos % PULSES_PER_BYTE))
+                    } else {
+                        (pulses, 0)
+                    }
+                }
+                Segment::End => return None,
+            };
+
+            if self.pos < pulses {
+                self.pos += 1;
+                return Some(pulse);
+            }
+
+            self.segment += 1;
+            self.pos = 0;
+        }
+    }
+
+    // a PRG tape is read only
+    fn record_pulse(&mut self, _cycles: u32) {}
+
+    fn rewind(&mut self) {
+        self.segment = 0;
+        self.pos = 0;
+    }
+}
+
+#[derive(Debug)]
+pub struct Datasette<'a> {
+    tape: Option<&'a mut dyn Tape>,
+    play: bool,
+    record: bool,
+    motor: bool,
+
+    read_level: bool,
+    next_edge: u32,
+    high_cycles: u32,
+
+    write_level: bool,
+    write_cycles: u32,
+}
+
+impl<'a> Default for Datasette<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> Datasette<'a> {
+    pub fn new() -> Datasette<'a> {
+        Datasette {
+            tape: None,
+            play: false,
+            record: false,
+            motor: false,
+
+            read_level: true,
+            next_edge: 0,
+            high_cycles: 0,
+
+            write_level: true,
+            write_cycles: 0,
+        }
+    }
+
+    pub fn insert(&mut self, tape: &'a mut dyn Tape) {
+        self.stop();
+        self.tape = Some(tape);
+    }
+
+    pub fn eject(&mut self) -> Option<&'a mut dyn Tape> {
+        self.stop();
+        self.tape.take()
+    }
+
+    /// Presses PLAY
+    pub fn play(&mut self) {
+        self.play = true;
+        self.record = false;
+    }
+
+    /// Presses PLAY and RECORD
+    pub fn record(&mut self) {
+        self.play = true;
+        self.record = true;
+    }
+
+    /// Presses STOP which releases all other buttons
+    pub fn stop(&mut self) {
+        self.play = false;
+        self.record = false;
+    }
+
+    pub fn rewind(&mut self) {
+        self.stop();
+        if let Some(tape) = self.tape.as_mut() {
+            tape.rewind();
+        }
+        self.next_edge = 0;
+    }
+
+    /// true if a button is pressed
+    pub fn sense(&self) -> bool {
+        self.play
+    }
+
+    pub fn motor(&self) -> bool {
+        self.motor
+    }
+
+    pub(crate) fn set_motor(&mut self, on: bool) {
+        if on && !self.motor {
+            self.write_cycles = 0;
+        }
+        self.motor = on;
+    }
+
+    // the write line, a pulse gets recorded on every rising edge
+    pub(crate) fn write(&mut self, level: bool) {
+        if !self.write_level && level && self.motor && self.record {
+            if let Some(tape) = self.tape.as_mut() {
+                tape.record_pulse(self.write_cycles);
+            }
+            self.write_cycles = 0;
+        }
+        self.write_level = level;
+    }
+
+    // returns the new level of the read line if it changed
+    pub(crate) fn step(&mut self, cycles: u32) -> Option<bool> {
+        if !self.motor || !self.play {
+            return None;
+        }
+
+        if self.record {
+            self.write_cycles = self.write_cycles.saturating_add(cycles);
+            return None;
+        }
+
+        if self.next_edge > cycles {
+            self.next_edge -= cycles;
+            return None;
+        }
+
+        // the cycles past the edge count towards the next one
+        let late = cycles - self.next_edge;
+        let tape = self.tape.as_mut()?;
+        if self.read_level {
+            // falling edge starts the next pulse
+            let pulse = tape.next_pulse()?;
+            self.high_cycles = pulse - pulse / 2;
+            self.next_edge = (pulse / 2).saturating_sub(late);
+        } else {
+            self.next_edge = self.high_cycles.saturating_sub(late);
+        }
+        self.read_level = !self.read_level;
+
+        Some(self.read_level)
+    }
+}
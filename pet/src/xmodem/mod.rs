@@ -0,0 +1,562 @@
+// File transfers with XMODEM and YMODEM, e.g. over a serial line or the debug probe
+//
+// `receive` stores the files a YMODEM sender (like `sb` of lrzsz) sends in a `Storage`.
+// A plain XMODEM transfer carries no name, its file is stored under the name given.
+// `send` sends a file as a YMODEM batch, `rb` receives it. Blocks of 128 or 1024 bytes
+// are checked with a CRC-16 and repeated up to 10 times.
+//
+// Host filenames are mapped as for host folders (see `dos::hostname`), the extension
+// gives the file type: "hello.prg" is the program "HELLO". XMODEM pads the last block
+// with SUB (0x1a), these are dropped again, so a file sent with plain XMODEM loses SUBs at
+// its end. YMODEM sends the size and has no such problem.
+
+use crate::dos::{hostname, DosError, MAX_NAME_LEN};
+use crate::io::{FileMode, FileType, OpenName, Storage};
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+const SUB: u8 = 0x1a;
+// asks for blocks with a CRC instead of a checksum
+const CRC_MODE: u8 = b'C';
+
+const BLOCK_LEN: usize = 128;
+const LONG_BLOCK_LEN: usize = 1024;
+const MAX_RETRIES: usize = 10;
+
+// timeouts in milliseconds
+const BYTE_TIMEOUT: u32 = 1000;
+const BLOCK_TIMEOUT: u32 = 3000;
+const PURGE_TIMEOUT: u32 = 100;
+// the sender is started by hand after the receiver
+const START_TIMEOUT: u32 = 60_000;
+
+// the storage channel used for the file
+const CHANNEL: u8 = 14;
+// a file arrives under this name, it replaces the old one once it is complete
+const TEMPORARY: &[u8] = b"XMODEM.PART";
+
+/// The connection to the other computer
+pub trait Transport {
+    /// The next byte, None if none arrived within the timeout in milliseconds
+    fn read(&mut self, timeout: u32) -> Option<u8>;
+
+    fn write(&mut self, data: &[u8]);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransferError {
+    /// The other side didn't answer
+    Timeout,
+    /// The other side cancelled the transfer
+    Cancelled,
+    /// A block failed too often or came out of order
+    TooManyErrors,
+    /// A plain XMODEM file arrived but there is no name to store it under
+    NoName,
+    Storage(DosError),
+}
+
+impl From<DosError> for TransferError {
+    fn from(error: DosError) -> TransferError {
+        TransferError::Storage(error)
+    }
+}
+
+/// The CRC-16 of XMODEM (polynomial 0x1021, starting with 0)
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &b in data {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            crc = match crc & 0x8000 {
+                0 => crc << 1,
+                _ => crc << 1 ^ 0x1021,
+            };
+        }
+    }
+    crc
+}
+
+fn cancel<T: Transport>(transport: &mut T) {
+    transport.write(&[CAN, CAN, CAN]);
+}
+
+// drops what is still coming after a broken block
+fn purge<T: Transport>(transport: &mut T) {
+    while transport.read(PURGE_TIMEOUT).is_some() {}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Block {
+    Data { number: u8, len: usize },
+    End,
+}
+
+// None for a broken block or if nothing came
+fn read_block<T: Transport>(
+    transport: &mut T,
+    data: &mut [u8; LONG_BLOCK_LEN],
+    timeout: u32,
+) -> Result<Option<Block>, TransferError> {
+    let len = match transport.read(timeout) {
+        Some(SOH) => BLOCK_LEN,
+        Some(STX) => LONG_BLOCK_LEN,
+        Some(EOT) => return Ok(Some(Block::End)),
+        Some(CAN) if transport.read(BYTE_TIMEOUT) == Some(CAN) => {
+            return Err(TransferError::Cancelled)
+        }
+        Some(_) => {
+            purge(transport);
+            return Ok(None);
+        }
+        None => return Ok(None),
+    };
+
+    let mut header = [0u8; 2];
+    let mut crc = [0u8; 2];
+    let bytes = header
+        .iter_mut()
+        .chain(data[..len].iter_mut())
+        .chain(crc.iter_mut());
+    for b in bytes {
+        match transport.read(BYTE_TIMEOUT) {
+            Some(value) => *b = value,
+            None => return Ok(None),
+        }
+    }
+
+    if header[0] != !header[1] || crc16(&data[..len]) != u16::from_be_bytes(crc) {
+        purge(transport);
+        return Ok(None);
+    }
+    Ok(Some(Block::Data {
+        number: header[0],
+        len,
+    }))
+}
+
+// asks until a block comes
+fn request<T: Transport>(
+    transport: &mut T,
+    data: &mut [u8; LONG_BLOCK_LEN],
+) -> Result<Block, TransferError> {
+    for _ in 0..MAX_RETRIES {
+        transport.write(&[CRC_MODE]);
+        if let Some(block) = read_block(transport, data, BLOCK_TIMEOUT)? {
+            return Ok(block);
+        }
+    }
+    cancel(transport);
+    Err(TransferError::Timeout)
+}
+
+// a PETSCII name with the type of the file
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Name {
+    name: [u8; MAX_NAME_LEN],
+    len: usize,
+    file_type: FileType,
+}
+
+impl Name {
+    fn new(pet_name: &[u8], file_type: FileType) -> Name {
+        let len = pet_name.len().min(MAX_NAME_LEN);
+        let mut name = [0u8; MAX_NAME_LEN];
+        name[..len].copy_from_slice(&pet_name[..len]);
+        Name {
+            name,
+            len,
+            file_type,
+        }
+    }
+
+    // "dir/hello.prg" -> "HELLO" as a program, files without a known extension are programs
+    fn from_host(host: &[u8]) -> Name {
+        let host = match host.iter().rposition(|&c| c == b'/' || c == b'\\') {
+            Some(slash) => &host[slash + 1..],
+            None => host,
+        };
+        let (stem, file_type) = match host.iter().rposition(|&c| c == b'.') {
+            Some(dot) if dot > 0 => match hostname::file_type(&host[dot + 1..]) {
+                Some(file_type) => (&host[..dot], file_type),
+                None => (host, FileType::Prg),
+            },
+            _ => (host, FileType::Prg),
+        };
+
+        let mut name = Name::new(b"", file_type);
+        hostname::pet_name(stem, |c| {
+            if name.len < MAX_NAME_LEN {
+                name.name[name.len] = c;
+                name.len += 1;
+            }
+        });
+        name
+    }
+
+    fn name(&self) -> &[u8] {
+        &self.name[..self.len]
+    }
+
+    // the file is written under the name given
+    fn open_name<'a>(&self, name: &'a [u8]) -> OpenName<'a> {
+        OpenName {
+            name,
+            file_type: Some(self.file_type),
+            mode: FileMode::Write,
+            replace: true,
+        }
+    }
+}
+
+// the name and the size in the YMODEM header block: "hello.prg", 0, "1992 ...", 0
+fn parse_header(data: &[u8]) -> (&[u8], Option<u32>) {
+    let end = data.iter().position(|&c| c == 0).unwrap_or(data.len());
+    let size = data[(end + 1).min(data.len())..]
+        .iter()
+        .take_while(|c| c.is_ascii_digit())
+        .try_fold(None, |size: Option<u32>, &c| {
+            size.unwrap_or(0)
+                .checked_mul(10)
+                .and_then(|size| size.checked_add((c - b'0') as u32))
+                .map(Some)
+        })
+        .flatten();
+    (&data[..end], size)
+}
+
+/// Receives files into the storage, existing ones are replaced once the new one is
+/// complete. Returns how many files arrived. A plain XMODEM file is stored under `name`,
+/// e.g. "GAME,P".
+pub fn receive<T: Transport>(
+    transport: &mut T,
+    storage: &mut dyn Storage,
+    name: Option<&[u8]>,
+) -> Result<usize, TransferError> {
+    let mut data = [0u8; LONG_BLOCK_LEN];
+    let mut files = 0;
+    loop {
+        match request(transport, &mut data)? {
+            Block::Data { number: 0, len } => {
+                let (host, size) = parse_header(&data[..len]);
+                // an empty name ends the batch
+                if host.is_empty() {
+                    transport.write(&[ACK]);
+                    return Ok(files);
+                }
+
+                let name = Name::from_host(host);
+                transport.write(&[ACK]);
+                let first = request(transport, &mut data)?;
+                receive_file(transport, storage, &name, &mut data, first, size)?;
+                files += 1;
+            }
+            Block::Data { number: 1, len } => {
+                let name = match name {
+                    Some(name) => OpenName::parse(name),
+                    None => {
+                        cancel(transport);
+                        return Err(TransferError::NoName);
+                    }
+                };
+                let name = Name::new(name.name, name.file_type.unwrap_or(FileType::Prg));
+                let first = Block::Data { number: 1, len };
+                receive_file(transport, storage, &name, &mut data, first, None)?;
+                return Ok(files + 1);
+            }
+            Block::End => {
+                transport.write(&[ACK]);
+                return Ok(files);
+            }
+            Block::Data { .. } => {
+                cancel(transport);
+                return Err(TransferError::TooManyErrors);
+            }
+        }
+    }
+}
+
+// the file is received under a temporary name and deleted again if the transfer fails,
+// the old file is only replaced after the end. A storage that can't rename files gets
+// the file under its name right away.
+fn receive_file<T: Transport>(
+    transport: &mut T,
+    storage: &mut dyn Storage,
+    name: &Name,
+    data: &mut [u8; LONG_BLOCK_LEN],
+    first: Block,
+    size: Option<u32>,
+) -> Result<(), TransferError> {
+    let written = match storage.file_system() {
+        Some(_) => TEMPORARY,
+        None => name.name(),
+    };
+    if let Err(error) = storage.open(CHANNEL, &name.open_name(written)) {
+        cancel(transport);
+        return Err(error.into());
+    }
+
+    let result = receive_blocks(transport, storage, data, first, size);
+    let closed = storage.close(CHANNEL);
+    let fs = match storage.file_system() {
+        Some(fs) => fs,
+        None => return result.and(closed.map_err(TransferError::from)),
+    };
+    let replaced = result
+        .and(closed.map_err(TransferError::from))
+        .and_then(|_| {
+            fs.scratch(name.name())?;
+            fs.rename(TEMPORARY, name.name())?;
+            Ok(())
+        });
+    if replaced.is_err() {
+        fs.scratch(TEMPORARY).ok();
+    }
+    replaced
+}
+
+fn receive_blocks<T: Transport>(
+    transport: &mut T,
+    storage: &mut dyn Storage,
+    data: &mut [u8; LONG_BLOCK_LEN],
+    first: Block,
+    size: Option<u32>,
+) -> Result<(), TransferError> {
+    let mut expected = 1u8;
+    let mut remaining = size;
+    // SUBs at the end of a block are only written once something else follows
+    let mut subs = 0usize;
+    let mut end_seen = false;
+    let mut errors = 0;
+
+    let mut block = Some(first);
+    loop {
+        match block {
+            Some(Block::Data { number, len }) if number == expected => {
+                let len = match remaining {
+                    Some(remaining) => len.min(remaining as usize),
+                    None => len,
+                };
+                remaining = remaining.map(|remaining| remaining - len as u32);
+
+                let mut write = |value: u8| storage.write(CHANNEL, value);
+                for &value in &data[..len] {
+                    let written = match value {
+                        SUB if size.is_none() => {
+                            subs += 1;
+                            Ok(())
+                        }
+                        _ => (0..core::mem::take(&mut subs))
+                            .try_for_each(|_| write(SUB))
+                            .and_then(|_| write(value)),
+                    };
+                    if let Err(error) = written {
+                        cancel(transport);
+                        return Err(error.into());
+                    }
+                }
+
+                transport.write(&[ACK]);
+                expected = expected.wrapping_add(1);
+                errors = 0;
+            }
+            // the ACK got lost, the sender repeats the block
+            Some(Block::Data { number, .. }) if number == expected.wrapping_sub(1) => {
+                transport.write(&[ACK]);
+            }
+            Some(Block::Data { .. }) => {
+                cancel(transport);
+                return Err(TransferError::TooManyErrors);
+            }
+            // the first EOT is answered with NAK, a real end is sent again
+            Some(Block::End) if !end_seen => {
+                end_seen = true;
+                transport.write(&[NAK]);
+            }
+            Some(Block::End) => {
+                transport.write(&[ACK]);
+                return Ok(());
+            }
+            None => {
+                errors += 1;
+                if errors >= MAX_RETRIES {
+                    cancel(transport);
+                    return Err(TransferError::TooManyErrors);
+                }
+                transport.write(&[NAK]);
+            }
+        }
+        block = read_block(transport, data, BLOCK_TIMEOUT)?;
+    }
+}
+
+// waits for the receiver to ask for the next block
+fn wait_for<T: Transport>(transport: &mut T, value: u8, timeout: u32) -> Result<(), TransferError> {
+    for _ in 0..MAX_RETRIES * BLOCK_LEN {
+        match transport.read(timeout) {
+            Some(c) if c == value => return Ok(()),
+            Some(CAN) if transport.read(BYTE_TIMEOUT) == Some(CAN) => {
+                return Err(TransferError::Cancelled)
+            }
+            Some(_) => {}
+            None => return Err(TransferError::Timeout),
+        }
+    }
+    Err(TransferError::TooManyErrors)
+}
+
+fn send_block<T: Transport>(
+    transport: &mut T,
+    number: u8,
+    data: &[u8],
+) -> Result<(), TransferError> {
+    let start = match data.len() {
+        BLOCK_LEN => SOH,
+        _ => STX,
+    };
+    let crc = crc16(data).to_be_bytes();
+    for _ in 0..MAX_RETRIES {
+        transport.write(&[start, number, !number]);
+        transport.write(data);
+        transport.write(&crc);
+        match transport.read(BLOCK_TIMEOUT) {
+            Some(ACK) => return Ok(()),
+            Some(CAN) if transport.read(BYTE_TIMEOUT) == Some(CAN) => {
+                return Err(TransferError::Cancelled)
+            }
+            _ => purge(transport),
+        }
+    }
+    cancel(transport);
+    Err(TransferError::TooManyErrors)
+}
+
+// opens the file with whatever type it has
+fn open_file(storage: &mut dyn Storage, name: &[u8]) -> Result<FileType, DosError> {
+    for &file_type in &[FileType::Prg, FileType::Seq, FileType::Usr, FileType::Rel] {
+        let open_name = OpenName {
+            name,
+            file_type: Some(file_type),
+            mode: FileMode::Read,
+            replace: false,
+        };
+        match storage.open(CHANNEL, &open_name) {
+            Ok(()) => return Ok(file_type),
+            Err(DosError::FileTypeMismatch) => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Err(DosError::FileTypeMismatch)
+}
+
+// the header block with the host name and the size
+fn header(name: &[u8], file_type: FileType, size: usize, data: &mut [u8; LONG_BLOCK_LEN]) {
+    let mut len = 0;
+    let mut put = |c: u8| {
+        if len < BLOCK_LEN {
+            data[len] = c;
+            len += 1;
+        }
+    };
+    hostname::host_name(name, &mut put);
+    put(b'.');
+    hostname::extension(file_type).bytes().for_each(&mut put);
+    put(0);
+
+    let mut digits = [0u8; 10];
+    let mut count = 0;
+    let mut rest = size;
+    loop {
+        digits[count] = b'0' + (rest % 10) as u8;
+        count += 1;
+        rest /= 10;
+        if rest == 0 {
+            break;
+        }
+    }
+    digits[..count].iter().rev().for_each(|&c| put(c));
+    put(0);
+}
+
+/// Sends a file from the storage as a YMODEM batch of one file.
+/// The name must not be a pattern.
+pub fn send<T: Transport>(
+    transport: &mut T,
+    storage: &mut dyn Storage,
+    name: &[u8],
+) -> Result<(), TransferError> {
+    // the size is sent first, so the file is read twice. It ends with a byte marked as
+    // the last one, reading a damaged file stops before.
+    open_file(storage, name)?;
+    let mut size = 0;
+    let mut complete = true;
+    while let Some((_, last)) = storage.read(CHANNEL) {
+        size += 1;
+        complete = last;
+    }
+    if !complete {
+        storage.close(CHANNEL)?;
+        return Err(DosError::ReadError.into());
+    }
+    let file_type = open_file(storage, name)?;
+
+    let result = send_file(transport, storage, name, file_type, size);
+    storage.close(CHANNEL)?;
+    result
+}
+
+fn send_file<T: Transport>(
+    transport: &mut T,
+    storage: &mut dyn Storage,
+    name: &[u8],
+    file_type: FileType,
+    size: usize,
+) -> Result<(), TransferError> {
+    let mut data = [0u8; LONG_BLOCK_LEN];
+
+    wait_for(transport, CRC_MODE, START_TIMEOUT)?;
+    header(name, file_type, size, &mut data);
+    send_block(transport, 0, &data[..BLOCK_LEN])?;
+
+    wait_for(transport, CRC_MODE, BLOCK_TIMEOUT)?;
+    let mut number = 1u8;
+    let mut remaining = size;
+    while remaining > 0 {
+        let len = match remaining {
+            0..=BLOCK_LEN => BLOCK_LEN,
+            _ => LONG_BLOCK_LEN,
+        };
+        for b in data[..len].iter_mut() {
+            *b = match remaining {
+                0 => SUB,
+                _ => {
+                    remaining -= 1;
+                    storage.read(CHANNEL).ok_or(DosError::ReadError)?.0
+                }
+            };
+        }
+        send_block(transport, number, &data[..len])?;
+        number = number.wrapping_add(1);
+    }
+
+    let mut ended = false;
+    for _ in 0..MAX_RETRIES {
+        transport.write(&[EOT]);
+        if transport.read(BLOCK_TIMEOUT) == Some(ACK) {
+            ended = true;
+            break;
+        }
+    }
+    if !ended {
+        return Err(TransferError::Timeout);
+    }
+
+    // an empty header ends the batch
+    wait_for(transport, CRC_MODE, BLOCK_TIMEOUT)?;
+    data[..BLOCK_LEN].iter_mut().for_each(|b| *b = 0);
+    send_block(transport, 0, &data[..BLOCK_LEN])
+}